@@ -0,0 +1,278 @@
+// Crawling other machines without mounting their file systems: filedb agent crawls a tree
+// where it runs and sends it to filedb collect, which adds it to a db. The agent either
+// connects to a collector listening on TCP, or writes to stdout, so the collector can run it
+// through ssh. The stream is AGENT_MAGIC, the protocol version, then the compressed AgentTree.
+
+use super::*;
+
+use std::net::{TcpListener, TcpStream};
+use std::path::Component;
+use std::process::Stdio;
+
+const AGENT_MAGIC: &[u8; 12] = b"FILEDB-AGENT";
+const AGENT_PROTOCOL_VERSION: u32 = 1;
+
+// Also built by add --remote, see remote
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AgentTree
+{
+    pub(crate) host: String,
+    pub(crate) root_dir: PathBuf,
+    pub(crate) hash_algorithm: HashAlgorithm,
+    // Rooted like any tree, root_dir and the dirs above it included
+    pub(crate) file_db: FileDb,
+}
+
+// Where collect receives the tree from
+#[derive(Clone, Copy, Debug)]
+pub enum AgentSource<'a>
+{
+    // Piped in, as from ssh host filedb agent path
+    Stdin,
+    // An agent run with --connect to this address
+    Listen(&'a str),
+    // Runs filedb agent with the args (crawl options and path) on the host through ssh
+    Ssh(&'a str, &'a [String]),
+}
+
+#[cfg(unix)]
+pub(crate) fn get_host_name() -> String
+{
+    let mut name = [0u8; 256];
+    let result = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    let len = name.iter().position(|byte| *byte == 0).unwrap_or(name.len());
+    if result != 0 || len == 0 {
+        return "unknown".to_string();
+    }
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn get_host_name() -> String
+{
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn write_tree(writer: impl Write, tree: &AgentTree) -> io::Result<()>
+{
+    let mut writer = io::BufWriter::new(writer);
+    writer.write_all(AGENT_MAGIC)?;
+    writer.write_all(&AGENT_PROTOCOL_VERSION.to_le_bytes())?;
+    let mut encoder = ZlibEncoder::new(writer, Compression::fast());
+    bincode::serialize_into(&mut encoder, tree).map_err(io::Error::other)?;
+    encoder.finish()?.flush()
+}
+
+fn read_tree(reader: impl Read) -> io::Result<AgentTree>
+{
+    let mut reader = io::BufReader::new(reader);
+    let mut magic = [0u8; 12];
+    reader.read_exact(&mut magic)?;
+    if &magic != AGENT_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not sent by filedb agent"));
+    }
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != AGENT_PROTOCOL_VERSION {
+        let message = format!("protocol version {}, expected {}", version, AGENT_PROTOCOL_VERSION);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    bincode::deserialize_from(ZlibDecoder::new(reader)).map_err(io::Error::other)
+}
+
+// The tree is written to the original stdout, what the crawl prints goes to stderr instead
+#[cfg(unix)]
+fn take_stdout() -> Option<File>
+{
+    use std::os::unix::io::FromRawFd;
+    io::stdout().flush().unwrap();
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return None;
+        }
+        Some(File::from_raw_fd(fd))
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> Option<File>
+{
+    None
+}
+
+// Crawls root_dir and sends the tree to the collector at connect, or to stdout
+pub fn agent(root_dir: &Path, options: &CrawlOptions, connect: Option<&str>) -> bool
+{
+    let root_dir = normalize_path(root_dir);
+    if !root_dir.is_absolute() || !root_dir.is_dir() {
+        eprintln!("{:?} is not an absolute path to a dir", root_dir);
+        return false;
+    }
+    let stdout = match connect {
+        Some(_) => None,
+        None => match take_stdout() {
+            Some(stdout) => Some(stdout),
+            None => {
+                eprintln!("Cannot send to stdout here, use --connect");
+                return false;
+            }
+        },
+    };
+    let tree = AgentTree {
+        host: get_host_name(),
+        file_db: FileDb::crawl(&root_dir, options),
+        root_dir,
+        hash_algorithm: options.hash_algorithm.unwrap_or_default(),
+    };
+    eprintln!("Sending {} entries", tree.file_db.len().separated_string());
+    let result = match (connect, stdout) {
+        (Some(address), _) => {
+            TcpStream::connect(address).and_then(|stream| write_tree(stream, &tree))
+        }
+        (None, Some(stdout)) => write_tree(stdout, &tree),
+        (None, None) => unreachable!(),
+    };
+    if let Err(err) = result {
+        eprintln!("Error sending to collector: {}", err);
+        return false;
+    }
+    true
+}
+
+fn receive_tree(source: AgentSource) -> io::Result<AgentTree>
+{
+    match source {
+        AgentSource::Stdin => read_tree(io::stdin().lock()),
+        AgentSource::Listen(address) => {
+            let listener = TcpListener::bind(address)?;
+            println!("Waiting for agent on {}", listener.local_addr()?);
+            let (stream, peer) = listener.accept()?;
+            println!("Receiving from {}", peer);
+            read_tree(stream)
+        }
+        AgentSource::Ssh(host, args) => {
+            let mut command = String::from("filedb agent");
+            for arg in args {
+                command += " ";
+                command += &shell_escape(arg.as_bytes());
+            }
+            let mut child =
+                remote::ssh_command(host).arg(command).stdout(Stdio::piped()).spawn()?;
+            let tree = read_tree(child.stdout.take().unwrap());
+            let status = child.wait()?;
+            match tree {
+                Err(_) if !status.success() => Err(io::Error::other(format!("ssh {}", status))),
+                tree => tree,
+            }
+        }
+    }
+}
+
+// The origin of the entries collected from host, see Db::hosts
+fn get_origin(db: &mut Db, host: &str) -> u16
+{
+    let position = match db.hosts.iter().position(|known_host| known_host == host) {
+        Some(position) => position,
+        None => {
+            db.hosts.push(host.to_string());
+            db.hosts.len() - 1
+        }
+    };
+    assert!(position < u16::MAX as usize, "Too many hosts");
+    (position + 1) as u16
+}
+
+pub(crate) fn get_origin_name(hosts: &[String], origin: u16) -> &str
+{
+    match origin {
+        0 => "(local)",
+        origin => hosts.get(origin as usize - 1).map_or("(unknown)", String::as_str),
+    }
+}
+
+// Where the tree goes: below prefix, or at its path on the agent's host
+fn get_collect_path(root_dir: &Path, prefix: Option<&Path>) -> PathBuf
+{
+    match prefix {
+        Some(prefix) => {
+            let is_normal = |component: &Component| matches!(component, Component::Normal(_));
+            prefix.join(root_dir.components().filter(is_normal).collect::<PathBuf>())
+        }
+        None => root_dir.to_owned(),
+    }
+}
+
+// Adds the tree an agent sends below prefix, or at its path on the agent's host. What was
+// collected at that path before is replaced, so files deleted there are gone from the db.
+pub fn collect(file_db_name: &Path, source: AgentSource, prefix: Option<&Path>) -> bool
+{
+    if prefix.is_some_and(|prefix| !prefix.is_absolute()) {
+        eprintln!("Prefix {:?} is not absolute", prefix.unwrap());
+        return false;
+    }
+    match receive_tree(source) {
+        Ok(tree) => add_collected_tree(file_db_name, tree, prefix),
+        Err(err) => {
+            eprintln!("Error receiving tree from agent: {}", err);
+            false
+        }
+    }
+}
+
+pub(crate) fn add_collected_tree(
+    file_db_name: &Path,
+    mut tree: AgentTree,
+    prefix: Option<&Path>,
+) -> bool
+{
+    let mut db = if file_db_name.is_file() {
+        load_compressed(file_db_name)
+    } else {
+        Db { hash_algorithm: tree.hash_algorithm, ..Db::default() }
+    };
+    if db.hash_algorithm != tree.hash_algorithm {
+        let name = db.hash_algorithm.get_name();
+        eprintln!("Db is hashed with {}, crawl with --hash-algorithm {}", name, name);
+        return false;
+    }
+    let collect_path = get_collect_path(&tree.root_dir, prefix);
+    if collect_path != tree.root_dir
+        && !roots::relocate_subtree(&mut tree.file_db, &tree.root_dir, &collect_path, true)
+    {
+        eprintln!("Cannot move {:?} from {} to {:?}", tree.root_dir, tree.host, collect_path);
+        return false;
+    }
+
+    // The dirs above the collected one are shared with other trees
+    let origin = get_origin(&mut db, &tree.host);
+    if let Some(index) = tree.file_db.find_path(&collect_path) {
+        for index in tree.file_db.get_children_index().get_subtree(index) {
+            tree.file_db.entry_mut(index).origin = origin;
+        }
+    }
+
+    let file_db = &mut db.file_db;
+    let children = (!file_db.is_empty()).then(|| ChildrenIndex::new(file_db));
+    if let Some(index) = children.and_then(|children| children.find_path(file_db, &collect_path)) {
+        if !is_root_index(index) {
+            let num_removed = remove_subtrees(file_db, &HashSet::from([index]));
+            println!("Replacing {} entries collected before", num_removed.separated_string());
+        }
+    }
+    let mut path_to_index = build_all_paths_to_index_map(file_db);
+    let stats = merge_file_db(file_db, &mut path_to_index, &tree.file_db);
+    propagate_sizes(file_db);
+    propagate_hashes(file_db);
+    println!(
+        "Collected {:?} from {} at {:?}: added: {}, skipped: {}",
+        tree.root_dir,
+        tree.host,
+        collect_path,
+        stats.added.separated_string(),
+        stats.skipped.separated_string()
+    );
+    save_compressed(file_db_name, &db);
+    true
+}
@@ -0,0 +1,95 @@
+// Alerts for runs without supervision, e.g. from cron or daemon: update, verify and
+// all_files_elsewhere send a JSON summary of their results to a webhook, which is POSTed with
+// curl, and/or to a command run by the shell, which reads it from stdin:
+//
+//   {"command": "verify", "db": "/immens/immens.filedb", "host": "nas", "ok": false,
+//    "verified": 1000, "bytes": 5000000, "mismatched": 1, "missing": 0, ...}
+//
+// ok is false if something needs attention: crawl errors or an interrupted update, files that
+// fail verification, files missing elsewhere. For email, use a command like
+// mail -s filedb me@example.com.
+
+use super::*;
+
+use std::process::{Command, Stdio};
+
+// Paths listed in summaries, the counts include all
+const NOTIFY_MAX_PATHS: usize = 100;
+
+#[derive(Clone, Default, Debug)]
+pub struct NotifyTargets
+{
+    pub webhook: Option<String>,
+    pub command: Option<String>,
+}
+
+static NOTIFY_TARGETS: Mutex<NotifyTargets> =
+    Mutex::new(NotifyTargets { webhook: None, command: None });
+
+pub fn set_notify_targets(targets: NotifyTargets)
+{
+    *NOTIFY_TARGETS.lock().unwrap() = targets;
+}
+
+#[cfg(unix)]
+pub(crate) fn shell_command(command: &str) -> Command
+{
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+pub(crate) fn shell_command(command: &str) -> Command
+{
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+fn run_with_input(mut command: Command, input: &[u8]) -> io::Result<()>
+{
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+    let written = child.stdin.take().unwrap().write_all(input);
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+        return Err(io::Error::other(status.to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) fn get_notify_paths(paths: &[PathBuf]) -> Vec<String>
+{
+    let paths = paths.iter().take(NOTIFY_MAX_PATHS);
+    paths.map(|path| path.to_string_lossy().into_owned()).collect()
+}
+
+// Sends the summary of command on file_db_name, whose fields (counts, ok) are in results
+pub(crate) fn notify(command: &str, file_db_name: &Path, results: serde_json::Value)
+{
+    let targets = NOTIFY_TARGETS.lock().unwrap().clone();
+    if targets.webhook.is_none() && targets.command.is_none() {
+        return;
+    }
+    let mut summary = serde_json::json!({
+        "command": command,
+        "db": file_db_name.to_string_lossy(),
+        "host": agent::get_host_name(),
+    });
+    summary.as_object_mut().unwrap().extend(results.as_object().unwrap().clone());
+    let summary = summary.to_string() + "\n";
+    if let Some(webhook) = &targets.webhook {
+        let mut curl = Command::new("curl");
+        curl.args(["-fsS", "-m", "60", "-H", "Content-Type: application/json"]);
+        curl.args(["--data-binary", "@-", webhook]).stdout(Stdio::null());
+        if let Err(err) = run_with_input(curl, summary.as_bytes()) {
+            eprintln!("Error posting summary to {} with curl: {}", webhook, err);
+        }
+    }
+    if let Some(command) = &targets.command {
+        if let Err(err) = run_with_input(shell_command(command), summary.as_bytes()) {
+            eprintln!("Error running notify command {:?}: {}", command, err);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+// The interface for programs using filedb as library, instead of running the commands: Crawl
+// a tree into a FileDb, load and save the current tree of a db, and query and iterate its
+// entries.
+//
+//   let options = CrawlOptions::new().exclude(glob::Pattern::new("*.tmp")?).hash(false);
+//   let file_db = FileDb::crawl(Path::new("/immens"), &options);
+//   let photos = vec![vec![FindPredicate::Name(glob::Pattern::new("*.jpg")?)]];
+//   for index in file_db.query(&photos) {
+//       println!("{:?}", file_db.get_path(index));
+//   }
+
+use super::*;
+
+use std::fmt;
+use std::sync::Arc;
+
+// Stops crawls (FileDb::crawl, add, update), verify and dedup from another thread, like an
+// interrupt: They finish the current file and keep what they have. A crawl returns the tree
+// built so far, add saves a checkpoint to resume from.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken
+{
+    pub fn new() -> Self
+    {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self)
+    {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool
+    {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Receives the path of each entry crawled, e.g. to show progress in a GUI
+#[derive(Clone)]
+pub struct ProgressSink(Arc<dyn Fn(&Path) + Send + Sync>);
+
+impl ProgressSink
+{
+    pub fn report(&self, path: &Path)
+    {
+        (self.0)(path)
+    }
+}
+
+// Keeps CrawlOptions usable within catch_unwind. Keeping state shared with the sink
+// consistent after a panic is up to the caller.
+impl std::panic::RefUnwindSafe for ProgressSink {}
+impl std::panic::UnwindSafe for ProgressSink {}
+
+impl fmt::Debug for ProgressSink
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("ProgressSink")
+    }
+}
+
+// Builder methods for the options most programs set, the others are set as fields
+impl CrawlOptions
+{
+    pub fn new() -> Self
+    {
+        CrawlOptions::default()
+    }
+
+    pub fn exclude(mut self, pattern: glob::Pattern) -> Self
+    {
+        self.excludes.push(pattern);
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self
+    {
+        self.follow_links = follow_symlinks;
+        self
+    }
+
+    // Without hashing, only names, sizes and times are recorded, see no_hash
+    pub fn hash(mut self, hash: bool) -> Self
+    {
+        self.no_hash = !hash;
+        self
+    }
+
+    pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self
+    {
+        self.hash_algorithm = Some(hash_algorithm);
+        self
+    }
+
+    // Threads for hashing large files, see CrawlOptions::threads
+    pub fn threads(mut self, threads: usize) -> Self
+    {
+        self.threads = Some(threads);
+        self
+    }
+
+    pub fn progress<F: Fn(&Path) + Send + Sync + 'static>(mut self, progress: F) -> Self
+    {
+        self.progress = Some(ProgressSink(Arc::new(progress)));
+        self
+    }
+
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self
+    {
+        self.cancellation = Some(cancellation);
+        self
+    }
+}
+
+impl FileDb
+{
+    // A new tree of root_dir and the dirs above it, without a db file. Media info, image
+    // hashes and the like are kept in the db, they are only computed when adding to one.
+    pub fn crawl(root_dir: &Path, options: &CrawlOptions) -> FileDb
+    {
+        match options.threads {
+            // Large files are hashed on the pool the crawl runs in
+            Some(threads) => {
+                let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+                pool.install(|| crawl_tree(root_dir, options))
+            }
+            None => crawl_tree(root_dir, options),
+        }
+    }
+
+    // The current tree of the db
+    pub fn load(file_db_name: &Path) -> FileDb
+    {
+        let (mut file_db, children) = load_file_db_with_children(file_db_name, None);
+        file_db.set_children_index(children);
+        file_db
+    }
+
+    // Replaces the current tree of the db, keeping its snapshots and roots, or creates the
+    // db. hash_algorithm is the one the files were hashed with, that of the db if it exists.
+    pub fn save(&self, file_db_name: &Path, hash_algorithm: HashAlgorithm)
+    {
+        let mut db = if file_db_name.is_file() {
+            load_compressed(file_db_name)
+        } else {
+            Db { hash_algorithm, ..Db::default() }
+        };
+        assert!(
+            db.hash_algorithm == hash_algorithm,
+            "Db is hashed with {}, cannot save a tree hashed with {}",
+            db.hash_algorithm.get_name(),
+            hash_algorithm.get_name()
+        );
+        db.file_db = self.clone();
+        save_compressed(file_db_name, &db);
+    }
+
+    // The indexes of the entries matching expression, as for the find command
+    pub fn query(&self, expression: &FindExpression) -> Vec<EntryIndex>
+    {
+        find_matches(self, expression, get_secs(&time::SystemTime::now()))
+    }
+
+    pub fn get_path(&self, index: EntryIndex) -> PathBuf
+    {
+        get_full_path(self, index)
+    }
+
+    // All entries with their paths, in db order. The paths of parents are kept while
+    // iterating, so each path is only built once.
+    pub fn iter_paths(&self) -> impl Iterator<Item = (EntryIndex, PathBuf)> + '_
+    {
+        let mut parent_paths = HashMap::<EntryIndex, PathBuf>::new();
+        (0..self.len() as EntryIndex).map(move |index| {
+            let entry = self.get(index);
+            if is_root_index(index) {
+                return (index, PathBuf::from(entry.name));
+            }
+            let parent_path = parent_paths
+                .entry(entry.parent)
+                .or_insert_with(|| get_full_path(self, entry.parent));
+            (index, parent_path.join(entry.name))
+        })
+    }
+
+    // The entries directly beneath index, also those of archives with indexed contents
+    pub fn children(&self, index: EntryIndex) -> impl Iterator<Item = EntryIndex> + '_
+    {
+        self.get_children_index().get(index).iter().copied()
+    }
+
+    pub fn find_path(&self, path: &Path) -> Option<EntryIndex>
+    {
+        self.get_children_index().find_path(self, path)
+    }
+
+    // The entry at prefix and all entries beneath it, parents before their children. Empty if
+    // prefix is not in the db.
+    pub fn subtree(&self, prefix: &Path) -> impl Iterator<Item = EntryIndex>
+    {
+        let children = self.get_children_index();
+        let subtree = self.find_path(prefix).map_or(vec![], |index| children.get_subtree(index));
+        subtree.into_iter()
+    }
+
+    // Files and dirs with the given hash, dirs with identical contents share theirs
+    pub fn find_by_hash<'a>(&'a self, hash: &'a Hash256) -> impl Iterator<Item = EntryIndex> + 'a
+    {
+        let indexes = 0..self.len() as EntryIndex;
+        indexes.filter(move |index| self.get(*index).hash == *hash)
+    }
+}
+
+// FileDb::crawl on the current thread pool
+fn crawl_tree(root_dir: &Path, options: &CrawlOptions) -> FileDb
+{
+    let mut file_db = FileDb::new();
+    add_dir_recursive_ext(
+        root_dir,
+        &mut file_db,
+        &mut PathToIndexMap::new(),
+        &DirToFilesMap::new(),
+        Path::new(""),
+        options,
+        &ArchiveNesting::default(),
+        &VanishedFilesMap::new(),
+        None,
+    );
+    report_crawl_errors(options.error_report.as_deref());
+    propagate_sizes(&mut file_db);
+    propagate_hashes(&mut file_db);
+    file_db
+}
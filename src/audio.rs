@@ -0,0 +1,196 @@
+// Fingerprints of audio files, to find the same recording in different formats and bitrates.
+// Like Chromaprint, the start of the audio is reduced to the energy of the 12 pitch classes
+// (chroma) over time, and each frame of that to bits comparing pitch classes.
+
+use std::fs::File;
+use std::path::Path;
+
+use rustfft::num_complex::Complex;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::errors::Error;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const FRAME_STEP: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f32 = 28.0;
+const MAX_FREQ: f32 = 3520.0;
+// Chroma is averaged over this many frames, against noise from lossy encoding
+const SMOOTHING: usize = 4;
+const MAX_SECONDS: usize = 120;
+// Used bits of each frame
+const FRAME_BITS: u32 = 24;
+// Frames the fingerprints of copies may be shifted by, like for encoder delay (~1.5 s)
+const MAX_OFFSET: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AudioFingerprint
+{
+    // Of the whole file, in seconds
+    pub duration: u32,
+    pub frames: Vec<u32>,
+}
+
+// Decodes the first MAX_SECONDS of the default track, mixed down to mono at SAMPLE_RATE.
+// Returns the samples and the duration of the track in seconds.
+fn decode(path: &Path) -> Option<(Vec<f32>, u32)>
+{
+    let stream = MediaSourceStream::new(Box::new(File::open(path).ok()?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probe = symphonia::default::get_probe();
+    let mut format =
+        probe.format(&hint, stream, &Default::default(), &Default::default()).ok()?.format;
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate?;
+    let num_frames = track.codec_params.n_frames;
+    let codecs = symphonia::default::get_codecs();
+    let mut decoder = codecs.make(&track.codec_params, &Default::default()).ok()?;
+
+    let mut samples = vec![];
+    while samples.len() < MAX_SECONDS * sample_rate as usize {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(_) => break,
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(Error::DecodeError(_)) => continue,
+            Err(_) => break,
+        };
+        let channels = decoded.spec().channels.count();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        buffer.copy_interleaved_ref(decoded);
+        let frames = buffer.samples().chunks(channels);
+        samples.extend(frames.map(|frame| frame.iter().sum::<f32>() / channels as f32));
+    }
+    if samples.is_empty() {
+        return None;
+    }
+    let duration = num_frames.unwrap_or(samples.len() as u64) / sample_rate as u64;
+    Some((resample(&samples, sample_rate), duration as u32))
+}
+
+// Averages the samples falling on each output sample, which also filters frequencies that
+// would otherwise alias
+fn resample(samples: &[f32], sample_rate: u32) -> Vec<f32>
+{
+    let ratio = sample_rate as f64 / SAMPLE_RATE as f64;
+    let len = (samples.len() as f64 / ratio) as usize;
+    (0..len)
+        .map(|i| {
+            let start = (i as f64 * ratio) as usize;
+            let end = (((i + 1) as f64 * ratio) as usize).clamp(start + 1, samples.len());
+            samples[start..end].iter().sum::<f32>() / (end - start) as f32
+        })
+        .collect()
+}
+
+// Energy per pitch class of each frame, normalized
+fn get_chroma(samples: &[f32]) -> Vec<[f32; 12]>
+{
+    let fft = rustfft::FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+    let window = (0..FRAME_SIZE)
+        .map(|i| 0.54 - 0.46 * (2.0 * std::f32::consts::PI * i as f32 / FRAME_SIZE as f32).cos())
+        .collect::<Vec<_>>();
+    let pitch_classes = (0..FRAME_SIZE / 2)
+        .map(|bin| {
+            let freq = bin as f32 * SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+            // Octaves above A0
+            let octave = (freq / 27.5).log2();
+            if (MIN_FREQ..=MAX_FREQ).contains(&freq) {
+                Some(((12.0 * octave.fract()) as usize).min(11))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut chroma = vec![];
+    let mut buffer = vec![Complex::default(); FRAME_SIZE];
+    let mut start = 0;
+    while start + FRAME_SIZE <= samples.len() {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(samples[start + i] * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+        let mut frame = [0.0; 12];
+        for (bin, pitch_class) in pitch_classes.iter().enumerate() {
+            if let Some(pitch_class) = pitch_class {
+                frame[*pitch_class] += buffer[bin].norm();
+            }
+        }
+        let norm = frame.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if norm > 0.01 {
+            frame.iter_mut().for_each(|value| *value /= norm);
+        } else {
+            // Silence
+            frame = [0.0; 12];
+        }
+        chroma.push(frame);
+        start += FRAME_STEP;
+    }
+    chroma
+}
+
+// Bit i of a frame tells whether pitch class i is stronger than the next one, bit 12 + i
+// whether it is stronger than the one a minor third above
+fn get_frames(chroma: &[[f32; 12]]) -> Vec<u32>
+{
+    chroma
+        .windows(SMOOTHING)
+        .map(|window| {
+            let mut smoothed = [0.0; 12];
+            for frame in window {
+                for (sum, value) in smoothed.iter_mut().zip(frame) {
+                    *sum += value;
+                }
+            }
+            let mut bits = 0;
+            for i in 0..12 {
+                bits |= ((smoothed[i] > smoothed[(i + 1) % 12]) as u32) << i;
+                bits |= ((smoothed[i] > smoothed[(i + 3) % 12]) as u32) << (12 + i);
+            }
+            bits
+        })
+        .collect()
+}
+
+// None if path is no audio file that can be decoded, or too short
+pub fn get_audio_fingerprint(path: &Path) -> Option<AudioFingerprint>
+{
+    let (samples, duration) = decode(path)?;
+    let frames = get_frames(&get_chroma(&samples));
+    if frames.is_empty() {
+        None
+    } else {
+        Some(AudioFingerprint { duration, frames })
+    }
+}
+
+// Ratio of differing bits at the offset that matches best, 1 if there is no overlap of at
+// least half the shorter fingerprint
+pub fn get_error_rate(a: &[u32], b: &[u32]) -> f32
+{
+    let min_overlap = (a.len().min(b.len()) / 2).max(1);
+    let mut error_rate = 1.0f32;
+    for offset in 0..=MAX_OFFSET {
+        for (a, b) in [(a, b), (b, a)] {
+            let b = &b[offset.min(b.len())..];
+            let overlap = a.len().min(b.len());
+            if overlap < min_overlap {
+                continue;
+            }
+            let errors = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum::<u32>();
+            error_rate = error_rate.min(errors as f32 / (overlap as u32 * FRAME_BITS) as f32);
+        }
+    }
+    error_rate
+}
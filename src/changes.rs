@@ -0,0 +1,384 @@
+// With --append-changes, update appends what it changed to <db>.changes instead of rewriting
+// the whole db, so frequent updates of large dbs are fast. Each record has the time and the
+// entries removed, changed and added, by path. Loading applies the records in order. Once
+// they add up to 1/CHANGES_COMPACT_RATIO of the size of the db or CHANGES_MAX_RECORDS
+// records, or another command saves the db, they are compacted into it. Until then, the
+// changes command lists what changed when.
+//
+// The file is CHANGES_MAGIC and the db format version of the entries, then the records, each
+// its length and the compressed ChangeRecord. A record cut short by a crash is ignored. The
+// file is created with its first record through a temporary file, so it always has a header.
+// With a signing key, each append rewrites it that way, to sign it before it is replaced.
+
+use super::*;
+
+use std::sync::atomic::AtomicBool;
+
+const CHANGES_MAGIC: &[u8; 14] = b"FILEDB-CHANGES";
+const CHANGES_HEADER_LEN: u64 = 18;
+const CHANGES_COMPACT_RATIO: u64 = 4;
+const CHANGES_MAX_RECORDS: usize = 32;
+
+static APPEND_CHANGES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_append_changes(append_changes: bool)
+{
+    APPEND_CHANGES.store(append_changes, Ordering::SeqCst);
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ChangeRecord
+{
+    time: u64,
+    // Roots of the subtrees removed, also of entries whose type changed
+    removed: Vec<PathBuf>,
+    // Their parent indexes are not used
+    changed: Vec<(PathBuf, FileDbEntry)>,
+    // Parents before their children
+    added: Vec<(PathBuf, FileDbEntry)>,
+    // Replaces those of the db, if changed
+    link_targets: Option<HashMap<Hash256, PathBuf>>,
+}
+
+// What the changes are computed against
+pub(crate) struct ChangeBase
+{
+    pub(crate) file_db: FileDb,
+    pub(crate) link_targets: HashMap<Hash256, PathBuf>,
+}
+
+fn get_changes_name(file_db_name: &Path) -> PathBuf
+{
+    get_db_side_file_name(file_db_name, "changes")
+}
+
+pub(crate) fn has_changes(file_db_name: &Path) -> bool
+{
+    get_changes_name(file_db_name).exists()
+}
+
+// None if the whole db is saved: Without --append-changes, for snapshots, with root overrides
+// and when computing what is kept besides the tree (media info, image hashes, ...)
+pub(crate) fn get_change_base(
+    db: &Db,
+    snapshot: Option<&str>,
+    options: &CrawlOptions,
+) -> Option<ChangeBase>
+{
+    let saves_more = options.media
+        || options.phash
+        || options.audio_fingerprint
+        || options.fuzzy_hash
+        || options.chunk_hashes;
+    if !APPEND_CHANGES.load(Ordering::SeqCst)
+        || snapshot.is_some()
+        || roots::has_root_overrides()
+        || saves_more
+    {
+        return None;
+    }
+    Some(ChangeBase { file_db: db.file_db.clone(), link_targets: db.link_targets.clone() })
+}
+
+fn is_changed(old: &Entry, new: &Entry) -> bool
+{
+    old.size != new.size || old.allocated != new.allocated || old.hash != new.hash || **old != **new
+}
+
+fn get_change_record(base: &ChangeBase, db: &Db) -> ChangeRecord
+{
+    let mut record = ChangeRecord {
+        time: get_secs(&time::SystemTime::now()),
+        ..ChangeRecord::default()
+    };
+    let old_entries = build_path_to_entry_map(&base.file_db);
+    let new_entries = build_path_to_entry_map(&db.file_db);
+    let is_removed = |path: &PathBuf, old: &Entry| {
+        new_entries.get(path).is_none_or(|new| new.is_dir != old.is_dir)
+    };
+    let removed = old_entries
+        .iter()
+        .filter(|(path, old)| is_removed(path, old))
+        .map(|(path, _)| path.as_path())
+        .collect::<HashSet<_>>();
+    // Only the roots of removed subtrees
+    let is_below_removed = |path: &Path| path.ancestors().skip(1).any(|dir| removed.contains(dir));
+    record.removed = removed
+        .iter()
+        .filter(|path| !is_below_removed(path))
+        .map(|path| path.to_path_buf())
+        .collect();
+    record.removed.sort();
+    for (index, path) in db.file_db.iter_paths() {
+        let new = db.file_db.get(index);
+        match old_entries.get(&path) {
+            Some(old) if old.is_dir == new.is_dir => {
+                if is_changed(old, &new) {
+                    record.changed.push((path, new.to_entry()));
+                }
+            }
+            _ => record.added.push((path, new.to_entry())),
+        }
+    }
+    if db.link_targets != base.link_targets {
+        record.link_targets = Some(db.link_targets.clone());
+    }
+    record
+}
+
+fn set_entry(file_db: &mut FileDb, index: EntryIndex, entry: FileDbEntry)
+{
+    let mut entry_mut = file_db.entry_mut(index);
+    *entry_mut.size = entry.size;
+    *entry_mut.allocated = entry.allocated;
+    *entry_mut.hash = entry.hash;
+    *entry_mut = EntryMeta {
+        modified: entry.modified,
+        accessed: entry.accessed,
+        verified: entry.verified,
+        inode: entry.inode,
+        uid: entry.uid,
+        gid: entry.gid,
+        mode: entry.mode,
+        xattrs: entry.xattrs,
+        mime: entry.mime,
+        pre_hash: entry.pre_hash,
+        origin: entry.origin,
+    };
+}
+
+// Applying a record again changes nothing, in case the db was saved with it but the changes
+// were not removed yet
+fn apply_record(db: &mut Db, record: ChangeRecord)
+{
+    let file_db = &mut db.file_db;
+    if !record.removed.is_empty() {
+        let removed = record.removed.iter().filter_map(|path| file_db.find_path(path));
+        let removed = removed.filter(|index| !is_root_index(*index)).collect::<HashSet<_>>();
+        remove_subtrees(file_db, &removed);
+    }
+    let mut path_to_index = build_all_paths_to_index_map(file_db);
+    for (path, entry) in record.changed {
+        match path_to_index.get(path.as_os_str()) {
+            Some(index) if file_db.get(*index).is_dir == entry.is_dir => {
+                set_entry(file_db, *index, entry)
+            }
+            _ => eprintln!("{:?} not in db, not applying its change", path),
+        }
+    }
+    for (path, mut entry) in record.added {
+        if let Some(index) = path_to_index.get(path.as_os_str()) {
+            set_entry(file_db, *index, entry);
+            continue;
+        }
+        match path.parent().and_then(|parent| path_to_index.get(parent.as_os_str())) {
+            Some(parent) => {
+                entry.parent = *parent;
+                file_db.push(entry);
+                path_to_index.insert(path.into_os_string(), file_db.len() as EntryIndex - 1);
+            }
+            None => eprintln!("Parent of {:?} not in db, not adding it", path),
+        }
+    }
+    if let Some(link_targets) = record.link_targets {
+        db.link_targets = link_targets;
+    }
+}
+
+// Returns the records and the length of the file up to the last complete one. A header cut
+// short, as written by versions creating the file in place, counts as no records and length 0.
+fn read_records(changes_name: &Path) -> io::Result<(Vec<ChangeRecord>, u64)>
+{
+    let mut reader = io::BufReader::new(File::open(changes_name)?);
+    let mut header = [0u8; CHANGES_HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            eprintln!("Ignoring incomplete header of {:?}", changes_name);
+            return Ok((vec![], 0));
+        }
+        Err(err) => return Err(err),
+    }
+    let (magic, version) = header.split_at(CHANGES_MAGIC.len());
+    if magic != CHANGES_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not written by filedb"));
+    }
+    let mut version_bytes = [0u8; 4];
+    version_bytes.copy_from_slice(version);
+    let version = u32::from_le_bytes(version_bytes);
+    if version != DB_FORMAT_VERSION {
+        let message = format!("db format version {}, compact with the filedb writing it", version);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let mut records = vec![];
+    let mut len = CHANGES_HEADER_LEN;
+    loop {
+        let mut record_len = [0u8; 8];
+        match reader.read_exact(&mut record_len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        let record_len = u64::from_le_bytes(record_len);
+        let mut compressed = vec![];
+        reader.by_ref().take(record_len).read_to_end(&mut compressed)?;
+        let record = bincode::deserialize_from(ZlibDecoder::new(&compressed[..]));
+        match record {
+            Ok(record) if compressed.len() as u64 == record_len => records.push(record),
+            _ => {
+                eprintln!("Ignoring incomplete change record at the end of {:?}", changes_name);
+                break;
+            }
+        }
+        len += 8 + record_len;
+    }
+    Ok((records, len))
+}
+
+// Applies the changes appended since the db was saved, returns whether there were any
+pub(crate) fn apply_changes(file_db_name: &Path, db: &mut Db) -> io::Result<bool>
+{
+    let changes_name = get_changes_name(file_db_name);
+    if !changes_name.exists() {
+        return Ok(false);
+    }
+    signing::check_signature(&changes_name);
+    let (records, _) = read_records(&changes_name)?;
+    eprintln!("Applying {} change records", records.len());
+    let applied = !records.is_empty();
+    for record in records {
+        apply_record(db, record);
+    }
+    Ok(applied)
+}
+
+// Once the db is saved as a whole
+pub(crate) fn remove_changes(file_db_name: &Path)
+{
+    let changes_name = get_changes_name(file_db_name);
+    for name in [get_db_side_file_name(&changes_name, "sig"), changes_name] {
+        if let Err(err) = fs::remove_file(&name) {
+            assert!(err.kind() == io::ErrorKind::NotFound, "Cannot remove {:?}: {}", name, err);
+        }
+    }
+}
+
+fn write_record(file: &mut File, compressed: &[u8]) -> io::Result<()>
+{
+    file.write_all(&(compressed.len() as u64).to_le_bytes())?;
+    file.write_all(compressed)
+}
+
+// Without len, the file is created
+fn append_record(changes_name: &Path, compressed: &[u8], len: Option<u64>) -> io::Result<()>
+{
+    // With a signing key, the changes are rewritten and signed before replacing the old ones,
+    // like the db, so they never lose their signature
+    if len.is_none() || signing::is_signing() {
+        let mut records = vec![];
+        if let Some(len) = len {
+            // Drops an incomplete record
+            File::open(changes_name)?.take(len).read_to_end(&mut records)?;
+        }
+        write_file_atomically(changes_name, false, true, |file| {
+            if records.is_empty() {
+                file.write_all(CHANGES_MAGIC).unwrap();
+                file.write_all(&DB_FORMAT_VERSION.to_le_bytes()).unwrap();
+            } else {
+                file.write_all(&records).unwrap();
+            }
+            write_record(file, compressed).unwrap();
+        });
+        return Ok(());
+    }
+    let len = len.unwrap();
+    let mut file = fs::OpenOptions::new().write(true).open(changes_name)?;
+    // Drops an incomplete record
+    file.set_len(len)?;
+    file.seek(SeekFrom::Start(len))?;
+    write_record(&mut file, compressed)?;
+    file.sync_all()
+}
+
+// Appends what changed since base to the changes, or saves the whole db if they get too large
+pub(crate) fn save_changes(file_db_name: &Path, db: &Db, base: ChangeBase)
+{
+    let changes_name = get_changes_name(file_db_name);
+    let (num_records, len) = match read_records(&changes_name) {
+        Ok((records, len)) if len > 0 => (records.len(), Some(len)),
+        // Also rewrites an incomplete header
+        Ok(_) => (0, None),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (0, None),
+        Err(err) => {
+            eprintln!("Cannot read changes {:?}: {}, saving the db", changes_name, err);
+            return save_compressed(file_db_name, db);
+        }
+    };
+    let record = get_change_record(&base, db);
+    let mut encoder = ZlibEncoder::new(vec![], Compression::fast());
+    bincode::serialize_into(&mut encoder, &record).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let changes_len = len.unwrap_or(CHANGES_HEADER_LEN) + 8 + compressed.len() as u64;
+    let db_len = fs::metadata(file_db_name).unwrap().len();
+    if changes_len > db_len / CHANGES_COMPACT_RATIO || num_records >= CHANGES_MAX_RECORDS {
+        println!("Compacting changes into the db");
+        return save_compressed(file_db_name, db);
+    }
+    eprintln!("Appending changes to {:?}", changes_name);
+    append_record(&changes_name, &compressed, len).unwrap();
+    println!(
+        "Added: {}, changed: {}, removed: {}",
+        record.added.len().separated_string(),
+        record.changed.len().separated_string(),
+        record.removed.len().separated_string()
+    );
+    eprintln!("Done");
+}
+
+// Lists the changes appended since the db was last saved as a whole, with their paths
+pub fn list_changes(file_db_name: &Path, paths: bool) -> bool
+{
+    let changes_name = get_changes_name(file_db_name);
+    let records = match read_records(&changes_name) {
+        Ok((records, _)) => records,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+        Err(err) => {
+            eprintln!("Cannot read changes {:?}: {}", changes_name, err);
+            return false;
+        }
+    };
+    for record in &records {
+        println!(
+            "{} added: {}, changed: {}, removed: {}",
+            get_time_string(record.time),
+            record.added.len().separated_string(),
+            record.changed.len().separated_string(),
+            record.removed.len().separated_string()
+        );
+        if paths {
+            for (path, _) in &record.added {
+                println!("  Added: {:?}", path);
+            }
+            for (path, _) in &record.changed {
+                println!("  Changed: {:?}", path);
+            }
+            for path in &record.removed {
+                println!("  Removed: {:?}", path);
+            }
+        }
+    }
+    println!("Change records: {}", records.len());
+    true
+}
+
+// Saves the db with the changes appended since it was last saved as a whole
+pub fn compact(file_db_name: &Path) -> bool
+{
+    if !has_changes(file_db_name) {
+        println!("No changes to compact");
+        return true;
+    }
+    let db = load_compressed(file_db_name);
+    save_compressed(file_db_name, &db);
+    true
+}
@@ -0,0 +1,36 @@
+// Content defined chunks of large files (FastCDC), to find how many bytes files share even
+// when their hashes differ, like VM images or database dumps. Chunk boundaries only depend on
+// the contents around them, so inserted or removed bytes only change the chunks they are in.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::Path;
+
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Chunk
+{
+    // First half of the blake3 hash
+    pub hash: [u8; 16],
+    pub size: u32,
+}
+
+pub fn get_chunks(path: &Path) -> Option<Vec<Chunk>>
+{
+    let file = File::open(path).ok()?;
+    let chunker =
+        fastcdc::v2020::StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+    chunker
+        .map(|chunk| {
+            let chunk = chunk.ok()?;
+            let hash = blake3::hash(&chunk.data);
+            Some(Chunk {
+                hash: hash.as_bytes()[..16].try_into().unwrap(),
+                size: chunk.length as u32,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,633 @@
+// The entries of a tree, stored column by column: Names are interned, entries refer to them by
+// id, so the many files named like Makefile or .DS_Store share one copy, in memory and on disk.
+// The columns most commands scan (parents, sizes, hashes) are contiguous, so summing up sizes
+// or grouping by hash only touches the memory it needs.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+
+use serde::de::{self, DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde::ser::{SerializeTuple, Serializer};
+
+use crate::{ChildrenIndex, EntryIndex, FileDbEntry, Hash256, Xattrs};
+
+// The fields of an entry besides name, type, parent, sizes and hash
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct EntryMeta
+{
+    pub modified: u64,
+    pub accessed: u64,
+    pub verified: u64,
+    pub inode: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub xattrs: Xattrs,
+    pub mime: String,
+    pub pre_hash: u64,
+    pub origin: u16,
+}
+
+// Index into the names of a FileDb
+pub type NameId = u64;
+
+#[derive(Default, Clone)]
+pub struct FileDb
+{
+    // Distinct names, one after another. Names no entry refers to anymore stay.
+    names: Vec<u8>,
+    name_starts: Vec<u64>,
+    name_lens: Vec<u32>,
+    // By hash of the name. On collisions, the name is stored again.
+    name_lookup: HashMap<u64, NameId>,
+    name_ids: Vec<NameId>,
+    is_dirs: Vec<bool>,
+    parents: Vec<EntryIndex>,
+    sizes: Vec<u64>,
+    allocated: Vec<u64>,
+    hashes: Vec<Hash256>,
+    metas: Vec<EntryMeta>,
+    // Built on first use, dropped when entries are added, moved or removed
+    children: OnceLock<ChildrenIndex>,
+}
+
+// For passes over whole columns, see FileDb::columns_mut
+pub struct ColumnsMut<'a>
+{
+    pub is_dirs: &'a [bool],
+    pub parents: &'a [EntryIndex],
+    pub sizes: &'a mut [u64],
+    pub allocated: &'a mut [u64],
+    pub hashes: &'a mut [Hash256],
+}
+
+// An entry of a FileDb. The rest of its fields are reached through Deref.
+#[derive(Clone, Copy)]
+pub struct Entry<'a>
+{
+    pub name: &'a OsStr,
+    pub is_dir: bool,
+    pub parent: EntryIndex,
+    pub size: u64,
+    pub allocated: u64,
+    pub hash: Hash256,
+    meta: &'a EntryMeta,
+}
+
+// Renaming goes through FileDb::set_name
+pub struct EntryMut<'a>
+{
+    pub is_dir: &'a mut bool,
+    pub parent: &'a mut EntryIndex,
+    pub size: &'a mut u64,
+    pub allocated: &'a mut u64,
+    pub hash: &'a mut Hash256,
+    meta: &'a mut EntryMeta,
+}
+
+impl Deref for Entry<'_>
+{
+    type Target = EntryMeta;
+
+    fn deref(&self) -> &EntryMeta
+    {
+        self.meta
+    }
+}
+
+impl Deref for EntryMut<'_>
+{
+    type Target = EntryMeta;
+
+    fn deref(&self) -> &EntryMeta
+    {
+        self.meta
+    }
+}
+
+impl DerefMut for EntryMut<'_>
+{
+    fn deref_mut(&mut self) -> &mut EntryMeta
+    {
+        self.meta
+    }
+}
+
+impl Entry<'_>
+{
+    pub fn to_entry(self) -> FileDbEntry
+    {
+        let meta = self.meta.clone();
+        FileDbEntry {
+            name: self.name.to_os_string(),
+            is_dir: self.is_dir,
+            parent: self.parent,
+            size: self.size,
+            allocated: self.allocated,
+            modified: meta.modified,
+            accessed: meta.accessed,
+            hash: self.hash,
+            verified: meta.verified,
+            inode: meta.inode,
+            uid: meta.uid,
+            gid: meta.gid,
+            mode: meta.mode,
+            xattrs: meta.xattrs,
+            mime: meta.mime,
+            pre_hash: meta.pre_hash,
+            origin: meta.origin,
+        }
+    }
+}
+
+impl fmt::Debug for Entry<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        self.to_entry().fmt(f)
+    }
+}
+
+impl FileDb
+{
+    pub fn new() -> Self
+    {
+        FileDb::default()
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.parents.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.parents.is_empty()
+    }
+
+    fn get_name(&self, id: NameId) -> &OsStr
+    {
+        let start = self.name_starts[id as usize] as usize;
+        let bytes = &self.names[start..start + self.name_lens[id as usize] as usize];
+        // Safe as the bytes were taken from an OsStr as a whole
+        unsafe { OsStr::from_encoded_bytes_unchecked(bytes) }
+    }
+
+    fn intern(&mut self, name: &OsStr) -> NameId
+    {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(id) = self.name_lookup.get(&hash) {
+            if self.get_name(*id) == name {
+                return *id;
+            }
+        }
+        let id = self.name_starts.len() as NameId;
+        let bytes = name.as_encoded_bytes();
+        self.name_starts.push(self.names.len() as u64);
+        self.name_lens.push(bytes.len() as u32);
+        self.names.extend_from_slice(bytes);
+        self.name_lookup.insert(hash, id);
+        id
+    }
+
+    pub fn num_names(&self) -> usize
+    {
+        self.name_starts.len()
+    }
+
+    pub fn get(&self, index: EntryIndex) -> Entry<'_>
+    {
+        let index = index as usize;
+        Entry {
+            name: self.get_name(self.name_ids[index]),
+            is_dir: self.is_dirs[index],
+            parent: self.parents[index],
+            size: self.sizes[index],
+            allocated: self.allocated[index],
+            hash: self.hashes[index],
+            meta: &self.metas[index],
+        }
+    }
+
+    pub fn entry_mut(&mut self, index: EntryIndex) -> EntryMut<'_>
+    {
+        self.children.take();
+        let index = index as usize;
+        EntryMut {
+            is_dir: &mut self.is_dirs[index],
+            parent: &mut self.parents[index],
+            size: &mut self.sizes[index],
+            allocated: &mut self.allocated[index],
+            hash: &mut self.hashes[index],
+            meta: &mut self.metas[index],
+        }
+    }
+
+    pub fn set_name(&mut self, index: EntryIndex, name: &OsStr)
+    {
+        self.name_ids[index as usize] = self.intern(name);
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Entry<'_>> + ExactSizeIterator
+    {
+        (0..self.len()).map(move |index| self.get(index as EntryIndex))
+    }
+
+    pub fn columns_mut(&mut self) -> ColumnsMut<'_>
+    {
+        ColumnsMut {
+            is_dirs: &self.is_dirs,
+            parents: &self.parents,
+            sizes: &mut self.sizes,
+            allocated: &mut self.allocated,
+            hashes: &mut self.hashes,
+        }
+    }
+
+    pub fn push(&mut self, entry: FileDbEntry)
+    {
+        let name = self.intern(&entry.name);
+        self.push_stored(StoredEntry {
+            name,
+            is_dir: entry.is_dir,
+            parent: entry.parent,
+            size: entry.size,
+            allocated: entry.allocated,
+            modified: entry.modified,
+            accessed: entry.accessed,
+            hash: entry.hash,
+            verified: entry.verified,
+            inode: entry.inode,
+            uid: entry.uid,
+            gid: entry.gid,
+            mode: entry.mode,
+            xattrs: entry.xattrs,
+            mime: entry.mime,
+            pre_hash: entry.pre_hash,
+            origin: entry.origin,
+        });
+    }
+
+    fn push_stored(&mut self, entry: StoredEntry)
+    {
+        self.children.take();
+        self.name_ids.push(entry.name);
+        self.is_dirs.push(entry.is_dir);
+        self.parents.push(entry.parent);
+        self.sizes.push(entry.size);
+        self.allocated.push(entry.allocated);
+        self.hashes.push(entry.hash);
+        self.metas.push(EntryMeta {
+            modified: entry.modified,
+            accessed: entry.accessed,
+            verified: entry.verified,
+            inode: entry.inode,
+            uid: entry.uid,
+            gid: entry.gid,
+            mode: entry.mode,
+            xattrs: entry.xattrs,
+            mime: entry.mime,
+            pre_hash: entry.pre_hash,
+            origin: entry.origin,
+        });
+    }
+
+    // The names of removed entries are only reclaimed when truncating to 0
+    pub fn truncate(&mut self, len: usize)
+    {
+        self.children.take();
+        if len == 0 {
+            self.names.clear();
+            self.name_starts.clear();
+            self.name_lens.clear();
+            self.name_lookup.clear();
+        }
+        self.name_ids.truncate(len);
+        self.is_dirs.truncate(len);
+        self.parents.truncate(len);
+        self.sizes.truncate(len);
+        self.allocated.truncate(len);
+        self.hashes.truncate(len);
+        self.metas.truncate(len);
+    }
+
+    pub(crate) fn get_children_index(&self) -> &ChildrenIndex
+    {
+        self.children.get_or_init(|| ChildrenIndex::new(self))
+    }
+
+    // For the index stored with the db
+    pub(crate) fn set_children_index(&mut self, children: ChildrenIndex)
+    {
+        self.children = OnceLock::from(children);
+    }
+
+    pub fn shrink_to_fit(&mut self)
+    {
+        self.names.shrink_to_fit();
+        self.name_starts.shrink_to_fit();
+        self.name_lens.shrink_to_fit();
+        self.name_lookup.shrink_to_fit();
+        self.name_ids.shrink_to_fit();
+        self.is_dirs.shrink_to_fit();
+        self.parents.shrink_to_fit();
+        self.sizes.shrink_to_fit();
+        self.allocated.shrink_to_fit();
+        self.hashes.shrink_to_fit();
+        self.metas.shrink_to_fit();
+    }
+}
+
+impl FromIterator<FileDbEntry> for FileDb
+{
+    fn from_iter<I: IntoIterator<Item = FileDbEntry>>(entries: I) -> Self
+    {
+        let mut file_db = FileDb::new();
+        file_db.extend(entries);
+        file_db
+    }
+}
+
+impl Extend<FileDbEntry> for FileDb
+{
+    fn extend<I: IntoIterator<Item = FileDbEntry>>(&mut self, entries: I)
+    {
+        for entry in entries {
+            self.push(entry);
+        }
+    }
+}
+
+impl PartialEq for FileDb
+{
+    fn eq(&self, other: &FileDb) -> bool
+    {
+        self.len() == other.len()
+            && self.iter().zip(other.iter()).all(|(a, b)| {
+                a.name == b.name
+                    && a.is_dir == b.is_dir
+                    && a.parent == b.parent
+                    && a.size == b.size
+                    && a.allocated == b.allocated
+                    && a.hash == b.hash
+                    && a.meta == b.meta
+            })
+    }
+}
+
+impl Eq for FileDb {}
+
+impl fmt::Debug for FileDb
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// FileDbEntry as stored, with the id of its name
+#[derive(Deserialize)]
+struct StoredEntry
+{
+    name: NameId,
+    is_dir: bool,
+    parent: EntryIndex,
+    size: u64,
+    allocated: u64,
+    modified: u64,
+    accessed: u64,
+    hash: Hash256,
+    verified: u64,
+    inode: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    xattrs: Xattrs,
+    mime: String,
+    pre_hash: u64,
+    origin: u16,
+}
+
+// Serialized like StoredEntry, without copying the entry first
+#[derive(Serialize)]
+struct SerializedEntry<'a>
+{
+    name: NameId,
+    is_dir: bool,
+    parent: EntryIndex,
+    size: u64,
+    allocated: u64,
+    modified: u64,
+    accessed: u64,
+    hash: &'a Hash256,
+    verified: u64,
+    inode: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    xattrs: &'a Xattrs,
+    mime: &'a str,
+    pre_hash: u64,
+    origin: u16,
+}
+
+struct Names<'a>(&'a FileDb);
+
+impl serde::Serialize for Names<'_>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        serializer.collect_seq((0..self.0.num_names()).map(|id| self.0.get_name(id as NameId)))
+    }
+}
+
+struct Entries<'a>(&'a FileDb);
+
+impl serde::Serialize for Entries<'_>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        let file_db = self.0;
+        serializer.collect_seq((0..file_db.len()).map(|index| {
+            let meta = &file_db.metas[index];
+            SerializedEntry {
+                name: file_db.name_ids[index],
+                is_dir: file_db.is_dirs[index],
+                parent: file_db.parents[index],
+                size: file_db.sizes[index],
+                allocated: file_db.allocated[index],
+                modified: meta.modified,
+                accessed: meta.accessed,
+                hash: &file_db.hashes[index],
+                verified: meta.verified,
+                inode: meta.inode,
+                uid: meta.uid,
+                gid: meta.gid,
+                mode: meta.mode,
+                xattrs: &meta.xattrs,
+                mime: &meta.mime,
+                pre_hash: meta.pre_hash,
+                origin: meta.origin,
+            }
+        }))
+    }
+}
+
+// The names, then the entries
+impl serde::Serialize for FileDb
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&Names(self))?;
+        tuple.serialize_element(&Entries(self))?;
+        tuple.end()
+    }
+}
+
+impl StoredEntry
+{
+    fn into_entry(self, name: OsString) -> FileDbEntry
+    {
+        FileDbEntry {
+            name,
+            is_dir: self.is_dir,
+            parent: self.parent,
+            size: self.size,
+            allocated: self.allocated,
+            modified: self.modified,
+            accessed: self.accessed,
+            hash: self.hash,
+            verified: self.verified,
+            inode: self.inode,
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode,
+            xattrs: self.xattrs,
+            mime: self.mime,
+            pre_hash: self.pre_hash,
+            origin: self.origin,
+        }
+    }
+}
+
+// Hands the entries to take one by one, so they are never all in memory as StoredEntry. take
+// returns false for entries with a name id out of range.
+struct EntriesSeed<F>(F);
+
+impl<'de, F: FnMut(StoredEntry) -> bool> DeserializeSeed<'de> for EntriesSeed<F>
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error>
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, F: FnMut(StoredEntry) -> bool> Visitor<'de> for EntriesSeed<F>
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("a sequence of entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error>
+    {
+        while let Some(entry) = seq.next_element::<StoredEntry>()? {
+            if !(self.0)(entry) {
+                return Err(de::Error::custom("name id out of range"));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct FileDbVisitor;
+
+impl<'de> Visitor<'de> for FileDbVisitor
+{
+    type Value = FileDb;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("names and entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<FileDb, A::Error>
+    {
+        let names: Vec<OsString> =
+            seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let mut file_db = FileDb::new();
+        let name_ids = names.iter().map(|name| file_db.intern(name)).collect::<Vec<_>>();
+        let take = |mut entry: StoredEntry| match name_ids.get(entry.name as usize) {
+            Some(name_id) => {
+                entry.name = *name_id;
+                file_db.push_stored(entry);
+                true
+            }
+            None => false,
+        };
+        let entries = EntriesSeed(take);
+        seq.next_element_seed(entries)?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(file_db)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FileDb
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FileDb, D::Error>
+    {
+        deserializer.deserialize_tuple(2, FileDbVisitor)
+    }
+}
+
+// Deserializes a FileDb without storing it, handing each entry to the closure instead. Only
+// the names are kept in memory.
+pub struct FileDbStream<F>(pub F);
+
+impl<'de, F: FnMut(FileDbEntry)> DeserializeSeed<'de> for FileDbStream<F>
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error>
+    {
+        deserializer.deserialize_tuple(2, self)
+    }
+}
+
+impl<'de, F: FnMut(FileDbEntry)> Visitor<'de> for FileDbStream<F>
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("names and entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error>
+    {
+        let names: Vec<OsString> =
+            seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let take = |entry: StoredEntry| match names.get(entry.name as usize) {
+            Some(name) => {
+                (self.0)(entry.into_entry(name.clone()));
+                true
+            }
+            None => false,
+        };
+        let entries = EntriesSeed(take);
+        seq.next_element_seed(entries)?.ok_or_else(|| de::Error::invalid_length(1, &"entries"))?;
+        Ok(())
+    }
+}
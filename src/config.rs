@@ -0,0 +1,98 @@
+// Defaults for the command line, read from $XDG_CONFIG_HOME/filedb/config.toml
+// (~/.config/filedb/config.toml) or the file given with --config:
+//
+//   db = "/immens/immens.filedb"
+//   protect = ["/immens/originals/**"]
+//   limit-rate = "100M"
+//   threads = 4
+//   compression = 6
+//   wait = true
+//   notify-command = "mail -s filedb me@example.com"
+//   trusted-key = "/home/me/.config/filedb/sign-key.pub"
+//
+//   [crawl]
+//   hash-algorithm = "xxh128"
+//   xattrs = true
+//   archive-depth = 2
+//   exclude = ["*.tmp", "node_modules"]
+//
+// Options given on the command line take precedence, protected paths are added to those
+// given. The crawl table holds crawl options (add, update) by their name without --.
+
+use super::*;
+
+use std::env;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config
+{
+    // The db of commands given without path_to_filedb
+    pub db: Option<PathBuf>,
+    pub protect: Vec<String>,
+    pub limit_rate: Option<String>,
+    // Hashing threads for large files and zlib level of saved dbs, see --threads, --compression
+    pub threads: Option<usize>,
+    pub compression: Option<u32>,
+    pub no_cache: bool,
+    pub wait: bool,
+    // Receive the summaries of update, verify and all_files_elsewhere, see alerts
+    pub notify_webhook: Option<String>,
+    pub notify_command: Option<String>,
+    // Sign dbs when saving, only load those signed, see signing
+    pub sign_key: Option<PathBuf>,
+    pub trusted_key: Option<PathBuf>,
+    // Append the changes of update to the db instead of saving it, see changes
+    pub append_changes: bool,
+    pub crawl: toml::Table,
+}
+
+impl Config
+{
+    // The crawl options as arguments with their values, if any
+    pub fn get_crawl_args(&self) -> Result<Vec<(String, Option<String>)>, String>
+    {
+        let mut args = vec![];
+        for (name, value) in &self.crawl {
+            let option = format!("--{}", name);
+            match value {
+                toml::Value::Boolean(true) => args.push((option, None)),
+                toml::Value::Boolean(false) => (),
+                toml::Value::String(value) => args.push((option, Some(value.clone()))),
+                toml::Value::Integer(value) => args.push((option, Some(value.to_string()))),
+                // Options given several times
+                toml::Value::Array(values) if values.iter().all(toml::Value::is_str) => {
+                    for value in values {
+                        args.push((option.clone(), value.as_str().map(String::from)));
+                    }
+                }
+                _ => return Err(format!("Invalid value for crawl option {} in config", name)),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn get_default_config_name() -> Option<PathBuf>
+{
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) if !config_home.is_empty() => PathBuf::from(config_home),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("filedb/config.toml"))
+}
+
+// Without config_name, the default config is read if it exists. A given one must exist.
+pub fn load_config(config_name: Option<&Path>) -> Result<Config, String>
+{
+    let config_name = match config_name {
+        Some(config_name) => config_name.to_owned(),
+        None => match get_default_config_name() {
+            Some(config_name) if config_name.exists() => config_name,
+            _ => return Ok(Config::default()),
+        },
+    };
+    let contents = fs::read_to_string(&config_name)
+        .map_err(|err| format!("Cannot read config {:?}: {}", config_name, err))?;
+    toml::from_str(&contents).map_err(|err| format!("Invalid config {:?}: {}", config_name, err))
+}
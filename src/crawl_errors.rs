@@ -0,0 +1,127 @@
+// Errors on single entries while crawling (unreadable files, entries vanishing during the
+// crawl, IO errors) don't stop add and update. They are collected here and summarized at the
+// end, optionally written to a JSON report with --error-report. A crawl runs on one thread,
+// so the errors are collected per thread.
+
+use super::*;
+
+use std::cell::{Cell, RefCell};
+
+// Paths listed per kind in the summary, the report has all of them
+const MAX_SUMMARY_PATHS: usize = 20;
+
+thread_local! {
+    static CRAWL_ERRORS: RefCell<Vec<CrawlError>> = const { RefCell::new(Vec::new()) };
+    // All errors recorded on the thread, also those already reported
+    static NUM_CRAWL_ERRORS: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CrawlErrorKind
+{
+    PermissionDenied,
+    Vanished,
+    Io,
+}
+
+impl CrawlErrorKind
+{
+    fn from_io_error(err: &io::Error) -> Self
+    {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => CrawlErrorKind::PermissionDenied,
+            io::ErrorKind::NotFound => CrawlErrorKind::Vanished,
+            _ => CrawlErrorKind::Io,
+        }
+    }
+
+    fn description(self) -> &'static str
+    {
+        match self {
+            CrawlErrorKind::PermissionDenied => "permission denied",
+            CrawlErrorKind::Vanished => "vanished",
+            CrawlErrorKind::Io => "IO error",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CrawlError
+{
+    pub path: PathBuf,
+    pub kind: CrawlErrorKind,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct CrawlErrorReport<'a>
+{
+    num_permission_denied: usize,
+    num_vanished: usize,
+    num_io: usize,
+    errors: &'a [CrawlError],
+}
+
+pub fn record_crawl_error(path: &Path, err: &io::Error)
+{
+    eprintln!("Error accessing {:?}: {}", path, err);
+    let error = CrawlError {
+        path: path.to_owned(),
+        kind: CrawlErrorKind::from_io_error(err),
+        message: err.to_string(),
+    };
+    CRAWL_ERRORS.with(|errors| errors.borrow_mut().push(error));
+    NUM_CRAWL_ERRORS.with(|num_errors| num_errors.set(num_errors.get() + 1));
+}
+
+// For the metrics of daemon, which counts the errors of each update
+pub fn get_num_crawl_errors() -> usize
+{
+    NUM_CRAWL_ERRORS.with(Cell::get)
+}
+
+pub fn take_crawl_errors() -> Vec<CrawlError>
+{
+    CRAWL_ERRORS.with(|errors| errors.take())
+}
+
+// Prints counts and paths of the errors collected since the last call, and writes them to
+// report_name if given
+pub fn report_crawl_errors(report_name: Option<&Path>)
+{
+    let errors = take_crawl_errors();
+    let count = |kind| errors.iter().filter(|error| error.kind == kind).count();
+    if let Some(report_name) = report_name {
+        let report = CrawlErrorReport {
+            num_permission_denied: count(CrawlErrorKind::PermissionDenied),
+            num_vanished: count(CrawlErrorKind::Vanished),
+            num_io: count(CrawlErrorKind::Io),
+            errors: &errors,
+        };
+        let report = serde_json::to_string_pretty(&report).unwrap();
+        fs::write(report_name, report + "\n").unwrap();
+        println!("Wrote error report {:?}", report_name);
+    }
+    if errors.is_empty() {
+        return;
+    }
+    println!("Errors while crawling: {}", errors.len().separated_string());
+    for kind in [CrawlErrorKind::PermissionDenied, CrawlErrorKind::Vanished, CrawlErrorKind::Io] {
+        let paths: Vec<&Path> = errors
+            .iter()
+            .filter(|error| error.kind == kind)
+            .map(|error| error.path.as_path())
+            .collect();
+        if paths.is_empty() {
+            continue;
+        }
+        println!("  {}: {}", kind.description(), paths.len().separated_string());
+        for path in paths.iter().take(MAX_SUMMARY_PATHS) {
+            println!("    {}", format_path_for_output(path));
+        }
+        if paths.len() > MAX_SUMMARY_PATHS {
+            println!("    ... and {} more", (paths.len() - MAX_SUMMARY_PATHS).separated_string());
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// Keeps a db current as a service: Runs update every interval and prints what each run
+// changed. Given an address to listen on, metrics for Prometheus are served at /metrics:
+//   filedb_entries, filedb_files, filedb_bytes              The current tree
+//   filedb_runs_total, filedb_failed_runs_total             Updates since the start
+//   filedb_last_run_duration_seconds, filedb_last_run_errors, filedb_last_run_timestamp_seconds
+//   filedb_last_run_changes{kind="added"|"removed"|"size_changed"|"content_changed"}
+// The db is only locked while updating, other commands can use it in between.
+
+use super::*;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the wait for the next run checks for interrupts
+const DAEMON_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default, Debug)]
+pub(crate) struct DaemonMetrics
+{
+    pub(crate) entries: usize,
+    pub(crate) files: usize,
+    pub(crate) bytes: u64,
+    pub(crate) runs: u64,
+    pub(crate) failed_runs: u64,
+    pub(crate) last_run_duration: f64,
+    // Crawl errors of the last run, see crawl_errors
+    pub(crate) last_run_errors: usize,
+    pub(crate) last_run_end: u64,
+    pub(crate) last_run_diff: DiffResult,
+}
+
+fn write_metric(text: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)])
+{
+    text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        text.push_str(&format!("{}{} {}\n", name, labels, value));
+    }
+}
+
+// In the text format of Prometheus
+pub(crate) fn format_metrics(metrics: &DaemonMetrics) -> String
+{
+    let mut text = String::new();
+    let gauge = |text: &mut String, name, help, value| {
+        write_metric(text, name, "gauge", help, &[("", value)]);
+    };
+    gauge(&mut text, "filedb_entries", "Entries in the db", metrics.entries as f64);
+    gauge(&mut text, "filedb_files", "Files in the db", metrics.files as f64);
+    gauge(&mut text, "filedb_bytes", "Total size of the files in the db", metrics.bytes as f64);
+    let help = "Updates run since the daemon started";
+    write_metric(&mut text, "filedb_runs_total", "counter", help, &[("", metrics.runs as f64)]);
+    let failed_runs = [("", metrics.failed_runs as f64)];
+    write_metric(&mut text, "filedb_failed_runs_total", "counter", "Updates failed", &failed_runs);
+    let help = "Duration of the last update";
+    gauge(&mut text, "filedb_last_run_duration_seconds", help, metrics.last_run_duration);
+    let help = "Files and dirs the last update could not read";
+    gauge(&mut text, "filedb_last_run_errors", help, metrics.last_run_errors as f64);
+    let help = "When the last update ended";
+    gauge(&mut text, "filedb_last_run_timestamp_seconds", help, metrics.last_run_end as f64);
+    let diff = &metrics.last_run_diff;
+    let changes = [
+        ("{kind=\"added\"}", diff.added.len() as f64),
+        ("{kind=\"removed\"}", diff.removed.len() as f64),
+        ("{kind=\"size_changed\"}", diff.size_changed.len() as f64),
+        ("{kind=\"content_changed\"}", diff.content_changed.len() as f64),
+    ];
+    let help = "Paths the last update changed";
+    write_metric(&mut text, "filedb_last_run_changes", "gauge", help, &changes);
+    text
+}
+
+// Updates root_dir, or all roots without one, and prints the changes. Returns false if the
+// update failed.
+pub(crate) fn run_update(
+    file_db_name: &Path,
+    root_dir: Option<&Path>,
+    options: &CrawlOptions,
+    metrics: &Mutex<DaemonMetrics>,
+) -> bool
+{
+    println!("{} Updating", get_time_string(get_secs(&time::SystemTime::now())));
+    let start = Instant::now();
+    let num_errors = crawl_errors::get_num_crawl_errors();
+    let lock = lock_db(file_db_name, true);
+    let file_db_before = load_file_db(file_db_name, None);
+    let scope = root_dir.map_or(UpdateScope::AllRoots, UpdateScope::Root);
+    let success = update_helper(file_db_name, scope, None, options);
+    let file_db = load_file_db(file_db_name, None);
+    drop(lock);
+    let diff = diff_file_dbs(&file_db_before, &file_db);
+    print_diff_result(&diff, false);
+
+    let mut metrics = metrics.lock().unwrap();
+    metrics.entries = file_db.len();
+    metrics.files = file_db.iter().filter(|entry| !entry.is_dir).count();
+    metrics.bytes = file_db.iter().filter(|entry| !entry.is_dir).map(|entry| entry.size).sum();
+    metrics.runs += 1;
+    if !success {
+        metrics.failed_runs += 1;
+    }
+    metrics.last_run_duration = start.elapsed().as_secs_f64();
+    metrics.last_run_errors = crawl_errors::get_num_crawl_errors() - num_errors;
+    metrics.last_run_end = get_secs(&time::SystemTime::now());
+    metrics.last_run_diff = diff;
+    success
+}
+
+fn serve_metrics(server: &tiny_http::Server, metrics: &Mutex<DaemonMetrics>)
+{
+    let content_type = "text/plain; version=0.0.4";
+    let content_type = tiny_http::Header::from_bytes("Content-Type", content_type).unwrap();
+    for request in server.incoming_requests() {
+        let response = if request.url() == "/metrics" {
+            let text = format_metrics(&metrics.lock().unwrap());
+            tiny_http::Response::from_string(text).with_header(content_type.clone())
+        } else {
+            tiny_http::Response::from_string("not found\n").with_status_code(404)
+        };
+        if let Err(err) = request.respond(response) {
+            eprintln!("Error responding: {}", err);
+        }
+    }
+}
+
+// Runs update every interval until interrupted, or cancelled through options
+pub fn daemon(
+    file_db_name: &Path,
+    root_dir: Option<&Path>,
+    interval: Duration,
+    listen: Option<&str>,
+    options: &CrawlOptions,
+) -> bool
+{
+    if !file_db_name.is_file() {
+        eprintln!("{:?} does not exist, add the paths to keep current first", file_db_name);
+        return false;
+    }
+    install_interrupt_handler();
+    let metrics = Arc::new(Mutex::new(DaemonMetrics::default()));
+    let server = match listen.map(tiny_http::Server::http) {
+        Some(Ok(server)) => {
+            println!("Serving metrics on http://{}/metrics", listen.unwrap());
+            Some(Arc::new(server))
+        }
+        Some(Err(err)) => {
+            eprintln!("Error serving on {}: {}", listen.unwrap(), err);
+            return false;
+        }
+        None => None,
+    };
+    let server_thread = server.clone().map(|server| {
+        let metrics = metrics.clone();
+        thread::spawn(move || serve_metrics(&server, &metrics))
+    });
+
+    let cancellation = options.cancellation.as_ref();
+    while !is_cancelled(cancellation) {
+        run_update(file_db_name, root_dir, options, &metrics);
+        let next_run = Instant::now() + interval;
+        let next_run_secs = get_secs(&time::SystemTime::now()) + interval.as_secs();
+        println!("Next update at {}", get_time_string(next_run_secs));
+        while !is_cancelled(cancellation) && Instant::now() < next_run {
+            let remaining = next_run.saturating_duration_since(Instant::now());
+            thread::sleep(DAEMON_POLL_INTERVAL.min(remaining));
+        }
+    }
+    if let (Some(server), Some(server_thread)) = (server, server_thread) {
+        server.unblock();
+        server_thread.join().unwrap();
+    }
+    true
+}
@@ -0,0 +1,104 @@
+// Runs a command per result of dedup (each dupe) and all_files_elsewhere (each missing file),
+// for workflows filedb has no command for:
+//
+//   filedb db all_files_elsewhere /photos --exec 'rclone copyto {} remote:backup{}'
+//   filedb db dedup --exec 'setfattr -n user.dupe-of -v {kept} {}'
+//
+// {} is replaced with the path, {kept} with the copy dedup keeps. sh gets them as its
+// positional parameters, so paths with any bytes, also newlines, arrive unchanged. The commands
+// run in parallel, at most jobs at a time.
+
+use super::*;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ExecCommand
+{
+    pub command: String,
+    pub jobs: usize,
+}
+
+// A path and the kept copy, if any
+pub(crate) type ExecTarget = (PathBuf, Option<PathBuf>);
+
+fn substitute_paths(command: &str, path: &str, kept: Option<&str>) -> String
+{
+    let mut substituted = String::new();
+    let mut rest = command;
+    while let Some(start) = rest.find('{') {
+        substituted += &rest[..start];
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{}") {
+            substituted += path;
+            rest = after;
+        } else if let (Some(after), Some(kept)) = (rest.strip_prefix("{kept}"), kept) {
+            substituted += kept;
+            rest = after;
+        } else {
+            substituted.push('{');
+            rest = &rest[1..];
+        }
+    }
+    substituted + rest
+}
+
+// As run, only for messages
+fn get_display_command(command: &str, (path, kept): &ExecTarget) -> String
+{
+    let kept = kept.as_ref().map(|kept| shell_escape(&get_path_bytes(kept)));
+    substitute_paths(command, &shell_escape(&get_path_bytes(path)), kept.as_deref())
+}
+
+fn get_shell_command(command: &str, (path, kept): &ExecTarget) -> std::process::Command
+{
+    let kept_parameter = kept.as_ref().map(|_| "\"$2\"");
+    let mut shell = alerts::shell_command(&substitute_paths(command, "\"$1\"", kept_parameter));
+    // $0, then the paths
+    shell.arg("sh").arg(path).args(kept);
+    shell
+}
+
+// Returns the number of commands that failed
+pub(crate) fn run_exec(exec: &ExecCommand, targets: &[ExecTarget], dry_run: bool) -> usize
+{
+    if dry_run {
+        for target in targets {
+            println!("Would run: {}", get_display_command(&exec.command, target));
+        }
+        return 0;
+    }
+    install_interrupt_handler();
+    let next = AtomicUsize::new(0);
+    let num_run = AtomicUsize::new(0);
+    let num_failed = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..exec.jobs.max(1) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= targets.len() || is_interrupted() {
+                    break;
+                }
+                num_run.fetch_add(1, Ordering::SeqCst);
+                let status = get_shell_command(&exec.command, &targets[index]).status();
+                let error = match status {
+                    Ok(status) if status.success() => continue,
+                    Ok(status) => status.to_string(),
+                    Err(err) => err.to_string(),
+                };
+                let command = get_display_command(&exec.command, &targets[index]);
+                eprintln!("Command failed ({}): {}", error, command);
+                num_failed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+    let (num_run, num_failed) = (num_run.into_inner(), num_failed.into_inner());
+    println!("Commands run: {}, failed: {}", num_run.separated_string(), num_failed);
+    num_failed
+}
+
+// Runs exec for each of paths, e.g. the missing ones all_files_elsewhere returns. Returns
+// whether all commands succeeded.
+pub fn exec_for_paths(exec: &ExecCommand, paths: &[PathBuf], dry_run: bool) -> bool
+{
+    let targets = paths.iter().map(|path| (path.clone(), None)).collect::<Vec<_>>();
+    run_exec(exec, &targets, dry_run) == 0
+}
@@ -0,0 +1,227 @@
+// Context triggered piecewise hashes like those of ssdeep, to find files whose contents are
+// mostly identical. A rolling hash over a few bytes splits the contents where it hits a
+// trigger value depending on the block size, and each piece adds one character of its hash to
+// the digest. Edits only change the characters of the pieces they touch.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const ROLLING_WINDOW: usize = 7;
+const MIN_BLOCK_SIZE: u32 = 3;
+const DIGEST_LENGTH: usize = 64;
+const HASH_PRIME: u32 = 0x0100_0193;
+const HASH_INIT: u32 = 0x2802_1967;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+// Smaller block sizes than the one for the file size digested as well, for contents with
+// too few pieces at that
+const NUM_SMALLER_BLOCK_SIZES: usize = 4;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FuzzyHash
+{
+    pub block_size: u32,
+    pub digest: String,
+    // At twice the block size
+    pub double_digest: String,
+}
+
+impl fmt::Display for FuzzyHash
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}:{}:{}", self.block_size, self.digest, self.double_digest)
+    }
+}
+
+#[derive(Default)]
+struct RollingHash
+{
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl RollingHash
+{
+    fn update(&mut self, byte: u8) -> u32
+    {
+        let old = self.window[self.n % ROLLING_WINDOW];
+        self.h2 = self.h2.wrapping_sub(self.h1).wrapping_add(ROLLING_WINDOW as u32 * byte as u32);
+        self.h1 = self.h1.wrapping_add(byte as u32).wrapping_sub(old as u32);
+        self.window[self.n % ROLLING_WINDOW] = byte;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ byte as u32;
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+// Digest at one block size, of at most max_length characters. Once that is reached, the
+// last character covers the rest of the contents.
+struct Digester
+{
+    block_size: u32,
+    max_length: usize,
+    hash: u32,
+    digest: Vec<u8>,
+}
+
+impl Digester
+{
+    fn new(block_size: u32, max_length: usize) -> Self
+    {
+        Digester { block_size, max_length, hash: HASH_INIT, digest: vec![] }
+    }
+
+    fn update(&mut self, byte: u8, rolling_hash: u32)
+    {
+        self.hash = self.hash.wrapping_mul(HASH_PRIME) ^ byte as u32;
+        if rolling_hash % self.block_size == self.block_size - 1
+            && self.digest.len() < self.max_length - 1
+        {
+            self.digest.push(BASE64[self.hash as usize % 64]);
+            self.hash = HASH_INIT;
+        }
+    }
+
+    fn finish(mut self, rolling_hash: u32) -> String
+    {
+        if rolling_hash != 0 {
+            self.digest.push(BASE64[self.hash as usize % 64]);
+        }
+        String::from_utf8(self.digest).unwrap()
+    }
+}
+
+fn compute<R: Read>(mut reader: R, size: u64) -> io::Result<FuzzyHash>
+{
+    let mut block_size = MIN_BLOCK_SIZE;
+    while (block_size as u64) * (DIGEST_LENGTH as u64) < size {
+        block_size *= 2;
+    }
+    // From twice block_size down, each digested at full and half length
+    let block_sizes = (0..NUM_SMALLER_BLOCK_SIZES + 2)
+        .map(|i| (2 * block_size) >> i)
+        .filter(|block_size| *block_size >= MIN_BLOCK_SIZE)
+        .collect::<Vec<_>>();
+    let mut digesters = block_sizes
+        .iter()
+        .map(|block_size| {
+            let digest = Digester::new(*block_size, DIGEST_LENGTH);
+            (digest, Digester::new(*block_size, DIGEST_LENGTH / 2))
+        })
+        .collect::<Vec<_>>();
+
+    let mut rolling_hash = RollingHash::default();
+    let mut last_hash = 0;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        for byte in &buf[..len] {
+            last_hash = rolling_hash.update(*byte);
+            for (digest, half_digest) in &mut digesters {
+                digest.update(*byte, last_hash);
+                half_digest.update(*byte, last_hash);
+            }
+        }
+    }
+
+    let digests = digesters
+        .into_iter()
+        .map(|(digest, half_digest)| (digest.finish(last_hash), half_digest.finish(last_hash)))
+        .collect::<Vec<_>>();
+    // Like ssdeep, halve the block size while the digest is too short
+    let mut i = 1;
+    while i + 1 < digests.len() && digests[i].0.len() <= DIGEST_LENGTH / 2 {
+        i += 1;
+    }
+    Ok(FuzzyHash {
+        block_size: block_sizes[i],
+        digest: digests[i].0.clone(),
+        double_digest: digests[i - 1].1.clone(),
+    })
+}
+
+pub fn get_fuzzy_hash(path: &Path) -> Option<FuzzyHash>
+{
+    let file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    compute(io::BufReader::new(file), size).ok()
+}
+
+// Runs of more than 3 equal characters carry little information
+fn eliminate_sequences(digest: &str) -> Vec<u8>
+{
+    let digest = digest.as_bytes();
+    let mut result = vec![];
+    for (i, c) in digest.iter().enumerate() {
+        if i < 3 || digest[i - 3..i].iter().any(|previous| previous != c) {
+            result.push(*c);
+        }
+    }
+    result
+}
+
+// Substrings of ROLLING_WINDOW characters of digest. Digests are only similar if they share
+// one.
+pub fn get_grams(digest: &str) -> HashSet<Vec<u8>>
+{
+    eliminate_sequences(digest).windows(ROLLING_WINDOW).map(|gram| gram.to_vec()).collect()
+}
+
+// Insertions and deletions cost 1, substitutions 2
+fn get_edit_distance(a: &[u8], b: &[u8]) -> usize
+{
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut next_row = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = row[j] + if a_char == b_char { 0 } else { 2 };
+            next_row[j + 1] = substitution.min(row[j + 1] + 1).min(next_row[j] + 1);
+        }
+        row = next_row;
+    }
+    row[b.len()]
+}
+
+// 0 (different) to 100 (identical)
+fn score_digests(a: &str, b: &str, block_size: u32) -> u32
+{
+    if get_grams(a).is_disjoint(&get_grams(b)) {
+        return 0;
+    }
+    let (a, b) = (eliminate_sequences(a), eliminate_sequences(b));
+    let distance = get_edit_distance(&a, &b) * DIGEST_LENGTH / (a.len() + b.len());
+    let score = 100 - (100 * distance / DIGEST_LENGTH).min(100) as u32;
+    // Matches of few small pieces may be coincidence
+    if block_size >= (99 + ROLLING_WINDOW as u32) / ROLLING_WINDOW as u32 * MIN_BLOCK_SIZE {
+        score
+    } else {
+        score.min(block_size / MIN_BLOCK_SIZE * a.len().min(b.len()) as u32)
+    }
+}
+
+// 0 (different) to 100 (identical), 0 if the block sizes do not allow comparing
+pub fn get_similarity(a: &FuzzyHash, b: &FuzzyHash) -> u32
+{
+    if a.block_size == b.block_size {
+        if a.digest == b.digest {
+            return 100;
+        }
+        let score = score_digests(&a.digest, &b.digest, a.block_size);
+        score.max(score_digests(&a.double_digest, &b.double_digest, 2 * a.block_size))
+    } else if a.block_size == 2 * b.block_size {
+        score_digests(&a.digest, &b.double_digest, a.block_size)
+    } else if b.block_size == 2 * a.block_size {
+        score_digests(&a.double_digest, &b.digest, b.block_size)
+    } else {
+        0
+    }
+}
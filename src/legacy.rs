@@ -0,0 +1,56 @@
+// The db format written by older versions: Just the compressed FileDb, without header. It is
+// converted to the current format on load and never written. Files are hashed with blake3.
+
+use super::{new_db, Db, EntryIndex, FileDbEntry};
+
+use std::ffi::OsString;
+
+#[derive(Deserialize)]
+pub struct FileDbEntryV1
+{
+    name: OsString,
+    is_dir: bool,
+    parent: u32,
+    size: u64,
+    modified: u64,
+    accessed: u64,
+    hash: [u8; 32],
+}
+
+// The root has no parent, which was u32::MAX
+fn upgrade_parent(parent: u32) -> EntryIndex
+{
+    if parent == u32::MAX {
+        EntryIndex::MAX
+    } else {
+        parent as EntryIndex
+    }
+}
+
+pub fn upgrade_v1(file_db: Vec<FileDbEntryV1>) -> Db
+{
+    new_db(
+        file_db
+            .into_iter()
+            .map(|entry| FileDbEntry {
+                name: entry.name,
+                is_dir: entry.is_dir,
+                parent: upgrade_parent(entry.parent),
+                size: entry.size,
+                allocated: entry.size,
+                modified: entry.modified,
+                accessed: entry.accessed,
+                hash: entry.hash,
+                verified: 0,
+                inode: 0,
+                uid: 0,
+                gid: 0,
+                mode: 0,
+                xattrs: vec![],
+                mime: String::new(),
+                pre_hash: 0,
+                origin: 0,
+            })
+            .collect(),
+    )
+}
@@ -1,9 +1,23 @@
 /// Global settings
-const DECOMPRESS_ARCHIVES: bool = false;
-
 // BACKUP_DIR is ignored for deduplication, so files stored there won't be reported as dupes
 const BACKUP_DIR: &str = "/immens/_backups";
 
+// Keep the previous db as <db>.bak when saving
+const KEEP_DB_BACKUP: bool = true;
+
+// While adding, write a checkpoint to <db>.checkpoint after this many new entries or this
+// much time, whatever comes first. An interrupted add can be continued with add --resume.
+const CHECKPOINT_INTERVAL_ENTRIES: usize = 100_000;
+const CHECKPOINT_INTERVAL: time::Duration = time::Duration::from_secs(10 * 60);
+
+// Archives within archives are unpacked up to this depth by default, see CrawlOptions
+const DEFAULT_ARCHIVE_DEPTH: usize = 4;
+const DEFAULT_ARCHIVE_MAX_NESTED_SIZE: u64 = 16 << 30;
+// Archives exceeding one of these when unpacking are added as plain files, see CrawlOptions
+const DEFAULT_ARCHIVE_MAX_SIZE: u64 = 16 << 30;
+const DEFAULT_ARCHIVE_MAX_ENTRIES: u64 = 1_000_000;
+const DEFAULT_ARCHIVE_MAX_RATIO: u64 = 10_000;
+
 #[macro_use]
 extern crate serde_derive;
 
@@ -11,13 +25,63 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serial_test;
 
+mod agent;
+mod alerts;
+mod api;
+mod audio;
+mod changes;
+mod chunks;
+mod columns;
+mod config;
+mod crawl_errors;
+mod daemon;
+mod exec;
+mod fuzzy;
+mod legacy;
+mod media;
+mod rar;
+mod rclone;
+mod remote;
+mod roots;
+mod safety;
+mod server;
+mod signing;
+mod trash;
+mod tui;
+mod watch;
+
+pub use agent::{agent, collect, AgentSource};
+pub use alerts::{set_notify_targets, NotifyTargets};
+pub use api::{CancellationToken, ProgressSink};
+pub use changes::{compact, list_changes, set_append_changes};
+pub use columns::{Entry, EntryMeta, FileDb};
+pub use config::{load_config, Config};
+pub use daemon::daemon;
+pub use exec::{exec_for_paths, ExecCommand};
+pub use rclone::import_rclone;
+pub use remote::add_remote;
+pub use roots::{name_root, rebase, remove_root, roots_list, set_root_overrides};
+pub use safety::ProtectedPaths;
+pub use signing::{set_signing_keys, SigningKeys};
+pub use watch::watch;
+
 use std::{
     collections::HashMap, collections::HashSet, fs, fs::File, io, path::Path, path::PathBuf, time,
 };
 use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::cell::Cell;
+use std::sync::Once;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use chrono::Local;
+use chrono::prelude::DateTime;
+
+use rand::Rng;
 
 use flate2::Compression;
-use flate2::write::ZlibEncoder;
+use flate2::write::{GzEncoder, ZlibEncoder};
 use flate2::read::{GzDecoder, ZlibDecoder};
 
 use fs_extra::dir::CopyOptions;
@@ -32,34 +96,292 @@ use walkdir::WalkDir;
 
 use xz::read::XzDecoder;
 
-type Hash256 = [u8; 32];
+use bzip2::read::BzDecoder;
+
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+// Set by the signal handler, long running crawls stop at the next file and save what they have
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INSTALL_INTERRUPT_HANDLER: Once = Once::new();
+
+// Only installed for commands that can save partial results, a second signal aborts
+fn install_interrupt_handler()
+{
+    INSTALL_INTERRUPT_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            eprintln!("Interrupted, finishing current file (interrupt again to abort)");
+        })
+        .unwrap();
+    });
+}
+
+pub fn is_interrupted() -> bool
+{
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Interrupted by signal or cancelled by the program embedding filedb
+fn is_cancelled(cancellation: Option<&api::CancellationToken>) -> bool
+{
+    is_interrupted() || cancellation.is_some_and(api::CancellationToken::is_cancelled)
+}
+
+// Settings for crawling the file system in add and update
+#[derive(Clone, Debug)]
+pub struct CrawlOptions
+{
+    // Add the contents of archives beneath them, as if the archive was a directory
+    pub index_archives: bool,
+    // Maximum nesting of archives to unpack, 1 means archives within archives are added
+    // as plain files
+    pub archive_depth: usize,
+    // Archives within archives are added as plain files once all enclosing archives
+    // together unpacked to more than this many bytes
+    pub archive_max_nested_size: u64,
+    // Limits for unpacking a single archive: Unpacked bytes, number of entries and ratio of
+    // unpacked to archive size. Archives exceeding one are added as plain files.
+    pub archive_max_size: u64,
+    pub archive_max_entries: u64,
+    pub archive_max_ratio: u64,
+    // Capture extended attributes, they are then part of the hashes of dirs
+    pub xattrs: bool,
+    // Capture Finder flags, quarantine and resource fork sizes on macOS, stored with the
+    // xattrs, see get_mac_metadata
+    pub mac_metadata: bool,
+    // Add the named NTFS streams of files as entries file:stream next to them, Windows only
+    pub alternate_streams: bool,
+    // Descend into the dirs symlinks and junctions point to instead of adding them as links.
+    // Links leading back to a dir above them are skipped.
+    pub follow_links: bool,
+    // Do not descend into dirs on other file systems than the root dir, like mount points of
+    // backup drives or network shares. The dirs themselves are added, empty.
+    pub one_file_system: bool,
+    // Also descend into mounted virtual file systems like /proc, see is_virtual_file_system
+    pub virtual_file_systems: bool,
+    // Leave out what backup tools leave out: Files and dirs flagged nodump, and the contents
+    // of cache dirs tagged with CACHEDIR.TAG
+    pub skip_backup_excluded: bool,
+    // Detect the MIME type of files from their contents
+    pub mime: bool,
+    // Read capture date, camera and dimensions of photos and videos
+    pub media: bool,
+    // Compute perceptual hashes of images, to find similar ones
+    pub phash: bool,
+    // Compute fingerprints of audio files, to find the same recordings in other formats
+    pub audio_fingerprint: bool,
+    // Compute similarity digests of files, to find mostly identical ones
+    pub fuzzy_hash: bool,
+    // Compute content defined chunk hashes of files of at least chunk_min_file_size, to find
+    // bytes shared by files with different contents
+    pub chunk_hashes: bool,
+    pub chunk_min_file_size: u64,
+    // Leave the hashes of files empty, for a quick inventory. The hash command computes them.
+    pub no_hash: bool,
+    // Only compute pre-hashes of files, their hashes are computed by dedup if they may have
+    // copies, or by the hash command
+    pub lazy_hash: bool,
+    // None for the one of the db, which new dbs take from here, blake3 by default
+    pub hash_algorithm: Option<HashAlgorithm>,
+    // Threads FileDb::crawl hashes large files on, all cores by default. The commands take
+    // them from --threads.
+    pub threads: Option<usize>,
+    // Write the errors on entries collected while crawling to this JSON file
+    pub error_report: Option<PathBuf>,
+    // Leave out entries whose names match one of these, dirs with their contents
+    pub excludes: Vec<glob::Pattern>,
+    // Called with the path of each entry crawled, see CrawlOptions::progress
+    pub progress: Option<api::ProgressSink>,
+    // Stops the crawl like an interrupt, see CancellationToken
+    pub cancellation: Option<api::CancellationToken>,
+}
+
+impl Default for CrawlOptions
+{
+    fn default() -> Self
+    {
+        CrawlOptions {
+            index_archives: false,
+            archive_depth: DEFAULT_ARCHIVE_DEPTH,
+            archive_max_nested_size: DEFAULT_ARCHIVE_MAX_NESTED_SIZE,
+            archive_max_size: DEFAULT_ARCHIVE_MAX_SIZE,
+            archive_max_entries: DEFAULT_ARCHIVE_MAX_ENTRIES,
+            archive_max_ratio: DEFAULT_ARCHIVE_MAX_RATIO,
+            xattrs: false,
+            mac_metadata: false,
+            alternate_streams: false,
+            follow_links: false,
+            one_file_system: false,
+            virtual_file_systems: false,
+            skip_backup_excluded: false,
+            mime: false,
+            media: false,
+            phash: false,
+            audio_fingerprint: false,
+            fuzzy_hash: false,
+            chunk_hashes: false,
+            chunk_min_file_size: 64 << 20,
+            no_hash: false,
+            lazy_hash: false,
+            hash_algorithm: None,
+            threads: None,
+            error_report: None,
+            excludes: vec![],
+            progress: None,
+            cancellation: None,
+        }
+    }
+}
+
+pub type Hash256 = [u8; 32];
+
+// Algorithm of the hashes of files, the same for all trees of a db, as hashes of different
+// algorithms never match. Shorter hashes are padded with zeros. Dirs are always hashed with
+// blake3 over the hashes of their entries.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm
+{
+    #[default]
+    Blake3,
+    Sha256,
+    Xxh128,
+}
+
+impl HashAlgorithm
+{
+    // In the order of their ids
+    const ALL: [HashAlgorithm; 3] =
+        [HashAlgorithm::Blake3, HashAlgorithm::Sha256, HashAlgorithm::Xxh128];
+
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        HashAlgorithm::ALL.iter().copied().find(|algorithm| algorithm.get_name() == name)
+    }
+
+    pub fn get_name(self) -> &'static str
+    {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh128 => "xxh128",
+        }
+    }
+
+    // Stored in the db header
+    fn get_id(self) -> u8
+    {
+        HashAlgorithm::ALL.iter().position(|algorithm| *algorithm == self).unwrap() as u8
+    }
+}
+
+// Extended attribute names and values, sorted by name
+pub type Xattrs = Vec<(OsString, Vec<u8>)>;
 const EMPTY_HASH: Hash256 = [0 as u8; 32];
-type PathToIndexMap = HashMap<OsString, u32>;
-type DirToFilesMap = HashMap<u32, Vec<u32>>;
+// Position of an entry in its FileDb, 64 bits so that even dbs of whole data centers fit
+pub type EntryIndex = u64;
+type PathToIndexMap = HashMap<OsString, EntryIndex>;
+type DirToFilesMap = HashMap<EntryIndex, Vec<EntryIndex>>;
+// Files that disappeared during update, by (size, modified, device, inode). Inodes are only
+// unique per device.
+type VanishedFilesMap = HashMap<(u64, u64, u64, u64), FileDbEntry>;
 
 #[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Debug, Clone)]
-struct FileDbEntry
+pub struct FileDbEntry
 {
-    name: OsString,
-    is_dir: bool,
-    parent: u32,
-    size: u64,
-    modified: u64,
+    pub name: OsString,
+    pub is_dir: bool,
+    pub parent: EntryIndex,
+    pub size: u64,
+    pub allocated: u64, // Bytes allocated on disk, less than size for sparse files
+    pub modified: u64,
     //created: u64, // Not supported on file system
-    accessed: u64,
-    hash: Hash256,
+    pub accessed: u64,
+    pub hash: Hash256,
+    pub verified: u64, // Last verification against the file contents, 0 if never
+    pub inode: u64,    // Used to recognize moved files, 0 if unknown
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32, // File type and permission bits as in st_mode, 0 if unknown
+    pub xattrs: Xattrs, // Only captured with CrawlOptions::xattrs
+    pub mime: String,   // Only detected with CrawlOptions::mime, empty if unknown
+    pub pre_hash: u64,  // Of files added with CrawlOptions::lazy_hash, see get_pre_hash, 0 if none
+    pub origin: u16,    // The host the entry was crawled on, see Db::hosts
+}
+
+use columns::FileDbStream;
+use crawl_errors::{record_crawl_error, report_crawl_errors};
+
+// Files added with --no-hash or that could not be read have no hash until the hash command
+// computes it, and neither have the dirs containing them
+fn is_hashed(entry: &Entry) -> bool
+{
+    entry.hash != EMPTY_HASH
+}
+
+// The file type bits of mode, as on unix. Links are marked like this on all platforms.
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_LINK: u32 = 0o120000;
+
+// Symlinks and junctions, added without following them. They have no size, their hash is
+// that of the target.
+fn is_link(entry: &EntryMeta) -> bool
+{
+    entry.mode & MODE_TYPE_MASK == MODE_LINK
+}
+
+// Files written by older versions contain just the compressed FileDb, without header, see
+// legacy. Since version 2, the version is followed by the id of the HashAlgorithm, and the Db
+// by the ChildrenIndex of the current tree.
+const DB_MAGIC: &[u8; 6] = b"FILEDB";
+const DB_FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot
+{
+    name: String,
+    created: u64,
+    file_db: FileDb,
 }
 
-type FileDb = Vec<FileDbEntry>;
+// On-disk contents: The current tree plus any named snapshots
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Db
+{
+    file_db: FileDb,
+    snapshots: Vec<Snapshot>,
+    // Sampled verification rotates through all files, entries verified before this are due
+    verify_cycle_start: u64,
+    // Photos and videos by hash, so copies share theirs. Read with CrawlOptions::media.
+    media: HashMap<Hash256, media::MediaInfo>,
+    // Perceptual hashes of images by hash, computed with CrawlOptions::phash
+    image_hashes: HashMap<Hash256, u64>,
+    // Computed with CrawlOptions::audio_fingerprint
+    audio_fingerprints: HashMap<Hash256, audio::AudioFingerprint>,
+    // Computed with CrawlOptions::fuzzy_hash
+    fuzzy_hashes: HashMap<Hash256, fuzzy::FuzzyHash>,
+    // Computed with CrawlOptions::chunk_hashes
+    chunks: HashMap<Hash256, Vec<chunks::Chunk>>,
+    // See roots
+    roots: Vec<roots::NamedRoot>,
+    // Targets of symlinks and junctions by their hashes, see get_link_hash
+    link_targets: HashMap<Hash256, PathBuf>,
+    // The other machines entries were collected from (agent, add --remote, import-rclone).
+    // Origin 0 is the machine the db is updated on, origin n is hosts[n - 1].
+    hosts: Vec<String>,
+    // Stored in the header, so it can be checked without loading the db
+    #[serde(skip)]
+    hash_algorithm: HashAlgorithm,
+}
 
 #[cfg(test)]
 mod tests
 {
     use std::path::PathBuf;
-    use std::time::{UNIX_EPOCH, Duration};
+    use std::net::TcpListener;
 
-    use chrono::Local;
-    use chrono::prelude::DateTime;
     use fs_extra::dir::copy;
 
     use super::*;
@@ -76,11 +398,11 @@ mod tests
         path_buf.push(testname);
         let path = path_buf.as_path();
         if WRITE_EXPECTED_RESULTS {
-            save_compressed(path, file_db);
+            save_compressed(path, &new_db(file_db.clone()));
         } else {
-            let file_db_expected = load_compressed(path);
+            let file_db_expected = load_compressed(path).file_db;
             assert!(file_db.len() == file_db_expected.len());
-            for (entry, expected_entry) in file_db.iter().zip(file_db_expected) {
+            for (entry, expected_entry) in file_db.iter().zip(file_db_expected.iter()) {
                 assert!(entry.name == expected_entry.name);
                 assert!(entry.is_dir == expected_entry.is_dir);
                 assert!(entry.parent == expected_entry.parent);
@@ -95,18 +417,11 @@ mod tests
         file_db.iter().map(|entry| entry.size).collect::<Vec<u64>>()
     }
 
-    fn _get_time_string(epoch_seconds: u64) -> String
-    {
-        let d = UNIX_EPOCH + Duration::from_secs(epoch_seconds);
-        let datetime = DateTime::<Local>::from(d);
-        datetime.format("%Y-%m-%d %H:%M:%S.%f").to_string()
-    }
-
     fn dump_file_db(file_db: &FileDb)
     {
         println!("{} entries", file_db.len());
         for (i, entry) in file_db.iter().enumerate() {
-            let full_path = get_full_path(file_db, i as u32);
+            let full_path = get_full_path(file_db, i as EntryIndex);
             // println!("#{}: {:?}, size: {}, modified: {}, accessed: {}",
             //         i, full_path, entry.size, get_time_string(entry.modified), get_time_string(entry.accessed));
             println!("#{}: {:?}, size: {}", i, full_path, entry.size);
@@ -116,24 +431,44 @@ mod tests
     #[test]
     fn test_propagate_basic()
     {
-        let mut file_db = Vec::new();
+        let mut file_db = FileDb::new();
         file_db.push(FileDbEntry {
             name: OsString::from("/"),
             is_dir: true,
-            parent: std::u32::MAX,
+            parent: EntryIndex::MAX,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             name: OsString::from("file.txt"),
             is_dir: false,
             parent: 0,
             size: 10,
+            allocated: 10,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         propagate_sizes(&mut file_db);
         assert_eq!(get_sizes(&file_db), vec!(10, 10));
@@ -142,60 +477,120 @@ mod tests
     #[test]
     fn test_propagate_uneven_levels()
     {
-        let mut file_db = Vec::new();
+        let mut file_db = FileDb::new();
         file_db.push(FileDbEntry {
             name: OsString::from("/test"),
             is_dir: true,
-            parent: std::u32::MAX,
+            parent: EntryIndex::MAX,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             name: OsString::from("a"),
             is_dir: true,
             parent: 0,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             name: OsString::from("b"),
             is_dir: true,
             parent: 1,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             name: OsString::from("c"),
             is_dir: true,
             parent: 2,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             name: OsString::from("dd"),
             is_dir: false,
             parent: 3,
             size: 10,
+            allocated: 10,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             name: OsString::from("b"),
             is_dir: false,
             parent: 0,
             size: 100,
+            allocated: 100,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         propagate_sizes(&mut file_db);
         assert_eq!(get_sizes(&file_db), vec!(110, 10, 10, 10, 10, 100));
@@ -204,16 +599,26 @@ mod tests
     #[test]
     fn test_propagate_incremental()
     {
-        let mut file_db = Vec::new();
+        let mut file_db = FileDb::new();
         file_db.push(FileDbEntry {
             // 0, /
             name: OsString::from("/"),
             is_dir: true,
-            parent: std::u32::MAX,
+            parent: EntryIndex::MAX,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             // 1, /d1
@@ -221,9 +626,19 @@ mod tests
             is_dir: true,
             parent: 0,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             // 2, /d1/d2
@@ -231,9 +646,19 @@ mod tests
             is_dir: true,
             parent: 1,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             // 3, /d1/d2/d3
@@ -241,9 +666,19 @@ mod tests
             is_dir: true,
             parent: 2,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             // 4, /d1/f1
@@ -251,9 +686,19 @@ mod tests
             is_dir: false,
             parent: 1,
             size: 100,
+            allocated: 100,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             // 5, /d1/d2/f2
@@ -261,9 +706,19 @@ mod tests
             is_dir: false,
             parent: 2,
             size: 10,
+            allocated: 10,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
 
         propagate_sizes(&mut file_db);
@@ -275,9 +730,19 @@ mod tests
             is_dir: true,
             parent: 2,
             size: 0,
+            allocated: 0,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         file_db.push(FileDbEntry {
             // 7, /d1/d2/d4/f3
@@ -285,9 +750,19 @@ mod tests
             is_dir: false,
             parent: 6,
             size: 200,
+            allocated: 200,
             modified: 1,
             accessed: 1,
             hash: EMPTY_HASH,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
         });
         propagate_sizes(&mut file_db);
         assert_eq!(
@@ -305,9 +780,15 @@ mod tests
         assert!(is_archive(Path::new("archive.tgz")));
         assert!(is_archive(Path::new("archive.tar.gz")));
         assert!(is_archive(Path::new("archive.tar.xz")));
+        assert!(is_archive(Path::new("archive.zip")));
+        assert!(is_archive(Path::new("archive.7z")));
+        assert!(is_archive(Path::new("archive.bz2")));
+        assert!(is_archive(Path::new("archive.tar.bz2")));
+        assert!(is_archive(Path::new("archive.zst")));
+        assert!(is_archive(Path::new("archive.tar.zst")));
+        assert!(is_archive(Path::new("archive.rar")));
 
         assert!(!is_archive(Path::new("archivetar")));
-        assert!(!is_archive(Path::new("archive.zip")));
         assert!(!is_archive(Path::new("archivezip")));
         assert!(!is_archive(Path::new("archivegz")));
         assert!(!is_archive(Path::new("archivexz")));
@@ -354,7 +835,7 @@ mod tests
         } else {
             path.push("simple");
         }
-        crawl_add(&mut file_db, &path);
+        crawl_add(&mut file_db, &path, HashAlgorithm::Blake3);
         dump_file_db(&file_db);
         check_expected_results("add_new_dir_within_root_2", &file_db);
     }
@@ -381,8 +862,6 @@ mod tests
         println!("Adding dir {:?}", root_dir);
         add_root_path_components(root_dir, &mut file_db, &mut path_to_index);
         dump_file_db(&file_db);
-
-        file_db.clear();
     }
 
     #[test]
@@ -410,996 +889,10506 @@ mod tests
     }
 
     #[test]
-    fn test_update()
+    fn test_prune_deleted_dir()
     {
-        let (_, path) = copy_to_work_dir("simple", "update");
-        let file_db = crawl_initial(&path);
-        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
-        file_db_name.push("test_update.db");
-        save_compressed(&file_db_name, &file_db);
-        update(&file_db_name, &path);
+        let (_, path) = copy_to_work_dir("simple", "prune_deleted_dir");
+        let mut file_db = crawl_initial(&path);
+        let len_before = file_db.len();
+        // b contains d, which contains f2
+        fs::remove_dir_all(path.join("simple/b")).unwrap();
+        prune_deleted_paths(&mut file_db);
+        assert_eq!(file_db.len(), len_before - 3);
+        for index in 0..file_db.len() {
+            let entry_path = get_full_path(&file_db, index as EntryIndex);
+            assert!(!entry_path.starts_with(path.join("simple/b")));
+        }
     }
 
     #[test]
-    fn test_mv()
+    fn test_index_archives()
     {
-        let (_, path) = copy_to_work_dir("simple", "mv");
-        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
-        file_db_name.push("test_mv.db");
-        {
-            let file_db = crawl_initial(&path);
-            dump_file_db(&file_db);
-            check_expected_results("mv_before", &file_db);
-            save_compressed(&file_db_name, &file_db);
-        }
-        mv(
-            &file_db_name,
-            Path::new("/home/mrich/projects/filedb/test_work/mv/simple/b"),
-            Path::new("/home/mrich/projects/filedb/test_work/mv/simple/a"),
+        let (_, work_dir) = copy_to_work_dir("compressed", "index_archives");
+        let path = work_dir.join("compressed");
+        let mut file_db = FileDb::new();
+        let options = CrawlOptions { index_archives: true, ..CrawlOptions::default() };
+        add_dir_recursive_ext(
+            &path,
+            &mut file_db,
+            &mut PathToIndexMap::new(),
+            &DirToFilesMap::new(),
+            Path::new(""),
+            &options,
+            &ArchiveNesting::default(),
+            &VanishedFilesMap::new(),
+            None,
         );
-        let file_db_new = load_compressed(&file_db_name);
-        dump_file_db(&file_db_new);
-        check_expected_results("mv_after", &file_db_new);
-    }
-}
-
-fn get_secs(time: &time::SystemTime) -> u64
-{
-    time.duration_since(time::SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-}
-
-fn is_root_index(entry_index: u32) -> bool
-{
-    entry_index == 0
-}
+        dump_file_db(&file_db);
+        // The expected db was crawled relative to the test data dir, and unpacked in a different order
+        let get_entries = |file_db: &FileDb, prefix: &Path| {
+            (0..file_db.len())
+                .filter_map(|index| {
+                    let entry = file_db.get(index as EntryIndex);
+                    let path = get_full_path(file_db, index as EntryIndex);
+                    let path = path.strip_prefix(prefix).ok()?.to_path_buf();
+                    if !path.starts_with("compressed") {
+                        return None;
+                    }
+                    Some((path, entry.is_dir, entry.size, entry.hash))
+                })
+                .collect::<HashSet<_>>()
+        };
+        let mut expected_path = PathBuf::from(EXPECTED_DATA_DIR);
+        expected_path.push("decompress_all");
+        let file_db_expected = load_compressed(&expected_path).file_db;
+        // The expected db predates zip support, test.zip has the same contents as test.tgz
+        let mut expected_entries = get_entries(&file_db_expected, Path::new(""));
+        expected_entries.retain(|(path, ..)| path != Path::new("compressed/test.zip"));
+        let zip_entries = expected_entries
+            .iter()
+            .filter_map(|(path, is_dir, size, hash)| {
+                let path = path.strip_prefix("compressed/test.tgz").ok()?;
+                Some((Path::new("compressed/test.zip").join(path), *is_dir, *size, *hash))
+            })
+            .collect::<Vec<_>>();
+        expected_entries.extend(zip_entries);
+        assert_eq!(get_entries(&file_db, &work_dir), expected_entries);
 
-fn get_full_path(file_db: &FileDb, entry_index: u32) -> PathBuf
-{
-    let mut components: Vec<&OsString> = vec![];
-    let mut index = entry_index;
-    let mut seen_paths = HashSet::<u32>::new();
-    loop {
-        let entry = &file_db[index as usize];
-        components.push(&entry.name);
-        if is_root_index(index) {
-            break;
-        }
-        seen_paths.insert(index);
-        assert!(!seen_paths.contains(&entry.parent)); // Detect loops
-        index = entry.parent;
+        // Archive contents cannot be found on the file system, but must not be pruned
+        let len_before = file_db.len();
+        prune_deleted_paths(&mut file_db);
+        assert_eq!(file_db.len(), len_before);
     }
-    let result: PathBuf = components.iter().rev().collect();
-    result
-}
 
-fn propagate_sizes(file_db: &mut FileDb)
-{
-    // Reset so incremental works, too.
-    for entry in file_db.iter_mut() {
-        if entry.is_dir {
-            entry.size = 0;
+    #[test]
+    fn test_index_archive_formats()
+    {
+        let (_, work_dir) = copy_to_work_dir("archives", "index_archive_formats");
+        let path = work_dir.join("archives");
+        let mut file_db = FileDb::new();
+        let mut path_to_index = PathToIndexMap::new();
+        add_dir_recursive_ext(
+            &path,
+            &mut file_db,
+            &mut path_to_index,
+            &DirToFilesMap::new(),
+            Path::new(""),
+            &CrawlOptions { index_archives: true, ..CrawlOptions::default() },
+            &ArchiveNesting::default(),
+            &VanishedFilesMap::new(),
+            None,
+        );
+        dump_file_db(&file_db);
+        let paths_to_index = build_all_paths_to_index_map(&file_db);
+        let get_entry = |relative_path: &str| {
+            let index = paths_to_index.get(path.join(relative_path).as_os_str()).unwrap();
+            file_db.get(*index)
+        };
+        let f2_path = format!("{}/simple/b/d/f2", TEST_DATA_DIR);
+        let f2_hash = get_hash_for_file(Path::new(&f2_path), HashAlgorithm::Blake3).unwrap();
+        for archive in &["test.7z/test", "test.tar.bz2/test.tar/test", "test.tar.zst/test.tar/test"] {
+            assert!(get_entry(archive).is_dir);
+            assert!(get_entry(&format!("{}/c", archive)).is_dir);
+            assert_eq!(get_entry(&format!("{}/a/f1", archive)).size, 0);
+            assert_eq!(get_entry(&format!("{}/b/d/f2", archive)).hash, f2_hash);
         }
-    }
 
-    let mut levels: Vec<u16> = vec![std::u16::MAX; file_db.len()];
-    levels[0] = 0;
-    let mut max_level = 0;
-    for i in 1..file_db.len() {
-        let parent_index = file_db[i].parent as usize;
-        assert!(
-            levels[parent_index] != std::u16::MAX,
-            "i {:?} parent_index {:?}",
-            i,
-            parent_index
+        // Rar is only listed, without hashes
+        assert!(get_entry("test.rar").is_dir);
+        assert!(get_entry("test.rar/test/c").is_dir);
+        let f2 = get_entry("test.rar/test/b/d/f2");
+        assert_eq!(f2.size, 12);
+        assert_eq!(f2.hash, EMPTY_HASH);
+
+        // Names leaving the archive are skipped
+        let archive_path = path.join("test.rar");
+        let metadata = fs::metadata(&archive_path).unwrap();
+        let rar_entries = ["x/../../y", "/etc/z", "./z", "test/new"].map(|name| rar::RarEntry {
+            name: PathBuf::from(name),
+            is_dir: false,
+            size: 1,
+        });
+        let num_entries = file_db.len();
+        let mut path_to_index = build_all_paths_to_index_map(&file_db);
+        add_rar_entries(
+            &mut file_db,
+            &mut path_to_index,
+            &archive_path,
+            &metadata,
+            rar_entries.into(),
         );
-        levels[i] = levels[parent_index] + 1;
-        if levels[i] > max_level {
-            max_level = levels[i];
-        }
+        assert_eq!(file_db.len(), num_entries + 1);
+        assert_eq!(file_db.get(num_entries as EntryIndex).name, "new");
     }
-    // Propagate sizes from bottom to top
-    // Level 0 is the root, don't propagate
-    for level_to_propagate in (1..max_level + 1).rev() {
-        for (i, level) in levels.iter().enumerate() {
-            if *level == level_to_propagate {
-                let parent_index = file_db[i].parent as usize;
-                file_db[parent_index].size += file_db[i].size;
+
+    #[test]
+    fn test_rar_invalid_data_size()
+    {
+        let (_, work_dir) = copy_to_work_dir("archives", "rar_invalid_data_size");
+        let rar_name = work_dir.join("invalid.rar");
+        for data_size in [1 << 40, i64::MAX as u64 + 1, u64::MAX] {
+            // RAR 5 main header followed by data_size bytes
+            let mut header = vec![1, 0x02];
+            let mut value = data_size;
+            while value >= 0x80 {
+                header.push((value & 0x7f) as u8 | 0x80);
+                value >>= 7;
             }
+            header.push(value as u8);
+            let mut archive = b"Rar!\x1a\x07\x01\x00\0\0\0\0".to_vec();
+            archive.push(header.len() as u8);
+            archive.extend(header);
+            fs::write(&rar_name, archive).unwrap();
+            assert!(rar::list(&rar_name).is_err());
         }
     }
-}
 
-fn propagate_hashes(file_db: &mut FileDb)
-{
-    // Reset so incremental works, too.
-    for entry in file_db.iter_mut() {
-        if entry.is_dir {
-            entry.hash = EMPTY_HASH;
-        }
+    #[test]
+    fn test_archive_depth()
+    {
+        let (_, work_dir) = copy_to_work_dir("archives", "archive_depth");
+        let path = work_dir.join("archives");
+        let crawl = |options: &CrawlOptions| {
+            let mut file_db = FileDb::new();
+            add_dir_recursive_ext(
+                &path,
+                &mut file_db,
+                &mut PathToIndexMap::new(),
+                &DirToFilesMap::new(),
+                Path::new(""),
+                options,
+                &ArchiveNesting::default(),
+                &VanishedFilesMap::new(),
+                None,
+            );
+            let paths_to_index = build_all_paths_to_index_map(&file_db);
+            let is_dir = |relative_path: &str| {
+                let index = paths_to_index.get(path.join(relative_path).as_os_str())?;
+                Some(file_db.get(*index).is_dir)
+            };
+            (is_dir("test.tar.bz2/test.tar"), is_dir("test.tar.bz2/test.tar/test"))
+        };
+        let options = CrawlOptions { index_archives: true, ..CrawlOptions::default() };
+        assert_eq!(crawl(&options), (Some(true), Some(true)));
+        // The tar within the bz2 is not unpacked anymore
+        assert_eq!(
+            crawl(&CrawlOptions { archive_depth: 1, ..options.clone() }),
+            (Some(false), None)
+        );
+        assert_eq!(
+            crawl(&CrawlOptions { archive_max_nested_size: 0, ..options.clone() }),
+            (Some(false), None)
+        );
     }
 
-    let mut levels: Vec<u16> = vec![std::u16::MAX; file_db.len()];
-    let mut dir_to_entries = HashMap::<u32, Vec<u32>>::new();
-    levels[0] = 0;
-    let mut max_level = 0;
-    for i in 1..file_db.len() {
-        if file_db[i].is_dir {
-            // Files already have hashes. They are kept at std::u16::MAX and thus never processed by the loop below.
-            let parent_index = file_db[i].parent as usize;
-            assert!(levels[parent_index] != std::u16::MAX);
-            levels[i] = levels[parent_index] + 1;
-            assert!(levels[i] < std::u16::MAX);
-            if levels[i] > max_level {
-                max_level = levels[i];
-            }
-            dir_to_entries.entry(i as u32).or_insert(Vec::<u32>::new());
-        }
-        let entry = dir_to_entries
-            .entry(file_db[i].parent)
-            .or_insert(Vec::<u32>::new());
-        (*entry).push(i as u32);
-    }
+    #[test]
+    fn test_unpack_limits()
+    {
+        let (test_dir, work_dir) = copy_to_work_dir("compressed", "unpack_limits");
+        let tar = test_dir.join("test.tar");
+        let options = CrawlOptions::default();
+        assert!(decompress_to_tmp_dir(&tar, &options).is_ok());
+        let few_entries = CrawlOptions { archive_max_entries: 3, ..options.clone() };
+        assert!(decompress_to_tmp_dir(&tar, &few_entries).is_err());
+        let small = CrawlOptions { archive_max_size: 100, ..options.clone() };
+        assert!(decompress_to_tmp_dir(&tar, &small).is_err());
 
-    // Propagate sizes from bottom to top
-    // Level 0 is the root, don't propagate
-    assert!(max_level < std::u16::MAX - 1);
-    for level_to_propagate in (1..max_level + 1).rev() {
-        for (entry_index, level) in levels.iter().enumerate() {
-            if *level == level_to_propagate {
-                // This will only hit dir entries
-                let dir_entries = dir_to_entries.get_mut(&(entry_index as u32)).unwrap();
-                dir_entries.sort_by_key(|entry| &file_db[*entry as usize].name);
-                let mut hasher = blake3::Hasher::new();
-                for dir_entry in dir_entries {
-                    hasher.update(&file_db[*dir_entry as usize].hash);
-                }
-                file_db[entry_index].hash = hasher.finalize().into();
-                let ent = &file_db[entry_index];
-                assert!(ent.hash != EMPTY_HASH, "{:?}", ent.name);
-            }
-        }
-    }
-    // All entries except the root
-    assert!(file_db
-        .iter()
-        .skip(1)
-        .filter(|entry| entry.hash == EMPTY_HASH)
-        .peekable()
-        .peek()
-        .is_none());
+        // 8 MB of zeros compress by a factor of about 1000
+        let zeros_gz = work_dir.join("zeros.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&zeros_gz).unwrap(), Compression::best());
+        encoder.write_all(&vec![0u8; 8 << 20]).unwrap();
+        encoder.finish().unwrap();
+        assert!(decompress_to_tmp_dir(&zeros_gz, &options).is_ok());
+        let low_ratio = CrawlOptions { archive_max_ratio: 100, ..options.clone() };
+        assert!(decompress_to_tmp_dir(&zeros_gz, &low_ratio).is_err());
 
-    // let empty_hashes = file_db.iter().enumerate().filter(|(index, entry)| entry.hash == EMPTY_HASH).collect::<Vec<_>>();
-    // for (index, _) in empty_hashes {
-    //     let p = get_full_path(&file_db, index as u32);
-    //     println!("{:?}", p);
-    // }
-}
+        // Exceeding archives are added as files
+        let mut file_db = FileDb::new();
+        add_dir_recursive_ext(
+            &work_dir.join("compressed"),
+            &mut file_db,
+            &mut PathToIndexMap::new(),
+            &DirToFilesMap::new(),
+            Path::new(""),
+            &CrawlOptions { index_archives: true, ..few_entries },
+            &ArchiveNesting::default(),
+            &VanishedFilesMap::new(),
+            None,
+        );
+        let paths_to_index = build_all_paths_to_index_map(&file_db);
+        let tar_path = work_dir.join("compressed/test.tar");
+        let tar_entry = file_db.get(*paths_to_index.get(tar_path.as_os_str()).unwrap());
+        assert!(!tar_entry.is_dir);
+        assert_eq!(tar_entry.hash, get_hash_for_file(&tar, HashAlgorithm::Blake3).unwrap());
+    }
 
-fn save_compressed(filename: &Path, file_db: &FileDb)
-{
-    println!("Saving db to {:?}", filename);
-    let writer = io::BufWriter::new(File::create(filename).unwrap());
-    let encoder = ZlibEncoder::new(writer, Compression::fast());
-    bincode::serialize_into(encoder, &file_db).unwrap();
-    println!("Done");
-}
+    #[test]
+    fn test_dedup_hardlink()
+    {
+        use std::os::unix::fs::MetadataExt;
 
-fn load_compressed(filename: &Path) -> FileDb
-{
-    println!("Loading db from {:?}", filename);
-    let reader = io::BufReader::new(File::open(filename).unwrap());
-    let decoder = ZlibDecoder::new(reader);
-    let file_db = bincode::deserialize_from(decoder).unwrap();
-    println!("Done");
-    file_db
-}
+        let (_, work_dir) = copy_to_work_dir("simple", "dedup_hardlink");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/dupe1"), "dupe").unwrap();
+        fs::write(path.join("a/dupe2"), "dupe").unwrap();
+        fs::write(path.join("b/other"), "othr").unwrap();
+        let mut file_db = crawl_initial(&path);
+        // Same hash but different content must not be linked
+        let hash = file_db.iter().find(|entry| entry.name == "dupe1").unwrap().hash;
+        let index = file_db.iter().position(|entry| entry.name == "other").unwrap();
+        *file_db.entry_mut(index as EntryIndex).hash = hash;
+        let file_db_name = work_dir.join("test_dedup_hardlink.db");
+        save_compressed(&file_db_name, &new_db(file_db));
 
-fn get_ext(path: &Path) -> Option<&str>
-{
-    let ext = path.extension();
-    if ext.is_none() {
-        return None;
-    } else {
-        return Some(ext.unwrap().to_str().unwrap());
+        let options = RemoveOptions::default();
+        let filter = DupeFilter::default();
+        let inode = |name: &str| fs::metadata(path.join(name)).unwrap().ino();
+        let dupe2_inode = inode("a/dupe2");
+        dedup(&file_db_name, DedupAction::Hardlink, &[], &filter, &options, false, None);
+        // a/dupe2 has the lowest path, it is kept and c/dupe1 linked to it
+        assert_eq!(inode("a/dupe2"), dupe2_inode);
+        assert_eq!(inode("c/dupe1"), dupe2_inode);
+        assert_ne!(inode("b/other"), dupe2_inode);
+        assert_eq!(fs::read_to_string(path.join("b/other")).unwrap(), "othr");
+        let file_db = load_file_db(&file_db_name, None);
+        let entry = file_db.iter().find(|entry| entry.name == "dupe1").unwrap();
+        assert_eq!(entry.inode, dupe2_inode);
     }
-}
 
-fn is_archive(path: &Path) -> bool
-{
-    let ext = get_ext(path);
-    if ext.is_none() {
-        return false;
-    }
-    let ext_str = ext.unwrap();
-    return ext_str == "tar" || ext_str == "xz" || ext_str == "gz" || ext_str == "tgz";
-}
+    #[test]
+    fn test_dedup_exec()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dedup_exec");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/dupe1"), "dupe").unwrap();
+        fs::write(path.join("a/it's {}"), "dupe").unwrap();
+        let file_db_name = work_dir.join("test_dedup_exec.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
 
-fn decompress_zip(path: &Path, tmp_dir: &TempDir)
-{
-    println!("decompressing {:?}", path);
+        let out_name = shell_escape(&get_path_bytes(&work_dir.join("out")));
+        let command = format!("printf '%s|%s\\n' {{}} {{kept}} >> {}", out_name);
+        let exec = ExecCommand { command, jobs: 2 };
+        let options = RemoveOptions::default();
+        let filter = DupeFilter { min_size: 1, ..DupeFilter::default() };
+        dedup(&file_db_name, DedupAction::Exec(&exec), &[], &filter, &options, false, None);
+        let out = fs::read_to_string(work_dir.join("out")).unwrap();
+        let (dupe, kept) = out.trim_end().split_once('|').unwrap();
+        let mut paths = [dupe, kept].map(PathBuf::from);
+        paths.sort();
+        assert_eq!(paths, [path.join("a/it's {}"), path.join("c/dupe1")]);
 
-    let file = File::open(path).unwrap();
+        // Failing commands are reported
+        let exec = ExecCommand { command: "test -d {}".to_string(), jobs: 1 };
+        assert!(exec_for_paths(&exec, &[path.join("a")], false));
+        assert!(!exec_for_paths(&exec, &[path.join("a"), path.join("a/f1")], false));
+        // Also with names sh cannot quote
+        let name = path.join("a/new\nline");
+        fs::write(&name, "").unwrap();
+        let exec = ExecCommand { command: "test -f {}".to_string(), jobs: 1 };
+        assert!(exec_for_paths(&exec, &[name], false));
+    }
 
-    let mut archive = zip::ZipArchive::new(file).unwrap();
+    #[test]
+    fn test_dedup_reflink()
+    {
+        use std::os::unix::fs::MetadataExt;
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        #[allow(deprecated)]
-        let outpath_rel = file.sanitized_name();
-        let outpath = PathBuf::from(tmp_dir.path()).join(outpath_rel);
+        let (_, work_dir) = copy_to_work_dir("simple", "dedup_reflink");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/dupe1"), "dupe").unwrap();
+        fs::write(path.join("a/dupe2"), "dupe").unwrap();
+        let file_db_name = work_dir.join("test_dedup_reflink.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
 
-        if (&*file.name()).ends_with('/') {
-            println!("zip creating dir {:?}", outpath);
-            fs::create_dir_all(&outpath).unwrap();
-        } else {
-            if let Some(p) = outpath.parent() {
+        // Works whether or not the file system supports reflinks, either way both files stay
+        let options = RemoveOptions::default();
+        let filter = DupeFilter::default();
+        dedup(&file_db_name, DedupAction::Reflink, &[], &filter, &options, false, None);
+        let metadata = |name: &str| fs::metadata(path.join(name)).unwrap();
+        assert_ne!(metadata("c/dupe1").ino(), metadata("a/dupe2").ino());
+        assert_eq!(fs::read_to_string(path.join("a/dupe2")).unwrap(), "dupe");
+        let file_db = load_file_db(&file_db_name, None);
+        let entry = file_db.iter().find(|entry| entry.name == "dupe2").unwrap();
+        assert_eq!(entry.inode, metadata("a/dupe2").ino());
+    }
+
+    #[test]
+    fn test_shared_bytes()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "shared_bytes");
+        let path = work_dir.join("simple");
+        let data = vec![7; 64 << 10];
+        fs::write(path.join("a/copy1"), &data).unwrap();
+        fs::write(path.join("a/copy2"), &data).unwrap();
+        fs::hard_link(path.join("a/copy1"), path.join("c/link")).unwrap();
+        assert_eq!(get_shared_bytes(&path.join("a/copy1"), &path.join("a/copy2")), 0);
+        assert_eq!(get_shared_bytes(&path.join("a/copy1"), &path.join("c/link")), 64 << 10);
+        // Where the file system reports extents
+        if let Ok(extents) = get_extents(&path.join("a/copy1")) {
+            assert!(get_extents_overlap(&extents, &extents) >= 64 << 10);
+            let extents_copy = get_extents(&path.join("a/copy2")).unwrap();
+            assert_eq!(get_extents_overlap(&extents, &extents_copy), 0);
+        }
+        assert_eq!(get_extents_overlap(&[(0, 10), (20, 10)], &[(5, 20)]), 10);
+    }
+
+    #[test]
+    fn test_keep_rules()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "keep_rules");
+        let path = work_dir.join("simple");
+        let mut file_db = crawl_initial(&path);
+        let f1 = file_db.iter().position(|entry| entry.name == "f1").unwrap() as EntryIndex;
+        let f2 = file_db.iter().position(|entry| entry.name == "f2").unwrap() as EntryIndex;
+        file_db.entry_mut(f1).modified = 100;
+        file_db.entry_mut(f2).modified = 200;
+        let kept = |keep_rules: &[KeepRule]| choose_kept(&file_db, keep_rules, &[f2, f1]);
+        // Without rules, the lowest path
+        assert_eq!(kept(&[]), f1);
+        assert_eq!(kept(&[KeepRule::ShortestPath]), f1);
+        assert_eq!(kept(&[KeepRule::Newest]), f2);
+        assert_eq!(kept(&[KeepRule::Oldest]), f1);
+        assert_eq!(kept(&[KeepRule::PathPrefix(path.join("a"))]), f1);
+        // Later rules only decide ties
+        assert_eq!(kept(&[KeepRule::PathPrefix(path.join("c")), KeepRule::Oldest]), f1);
+        assert_eq!(kept(&[KeepRule::Newest, KeepRule::ShortestPath]), f2);
+    }
+
+    #[test]
+    fn test_remove_dupes_verify_content()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "remove_dupes_verify_content");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/backup"), "abcd").unwrap();
+        fs::write(path.join("a/original"), "wxyz").unwrap();
+        let mut file_db = crawl_initial(&path);
+        let hash = file_db.iter().find(|entry| entry.name == "original").unwrap().hash;
+        let index = file_db.iter().position(|entry| entry.name == "backup").unwrap();
+        *file_db.entry_mut(index as EntryIndex).hash = hash;
+        let file_db_name = work_dir.join("test_remove_dupes_verify_content.db");
+        save_compressed(&file_db_name, &new_db(file_db));
+
+        let options = RemoveOptions { verify_content: true, ..RemoveOptions::default() };
+        let remove_dupes = || {
+            let dir = path.join("c");
+            all_files_elsewhere(
+                &file_db_name,
+                &dir,
+                None,
+                None,
+                true,
+                &[],
+                &options,
+                ElsewhereOutput::Report,
+                None,
+            )
+        };
+        remove_dupes();
+        assert!(path.join("c/backup").exists());
+        fs::write(path.join("c/backup"), "wxyz").unwrap();
+        remove_dupes();
+        assert!(!path.join("c/backup").exists());
+        assert!(path.join("a/original").exists());
+    }
+
+    #[test]
+    fn test_dedup_plan()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dedup_plan");
+        let path = work_dir.join("simple");
+        let files = [
+            ("a/dupe1", "dupe"),
+            ("b/dupe2", "dupe"),
+            ("c/dupe3", "dupe"),
+            ("a/changed1", "changed"),
+            ("c/changed2", "changed"),
+        ];
+        for (name, contents) in files {
+            fs::write(path.join(name), contents).unwrap();
+        }
+        let file_db_name = work_dir.join("test_dedup_plan.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let plan_name = work_dir.join("plan.json");
+        let keep_rules = [KeepRule::PathPrefix(path.join("a"))];
+        let filter = DupeFilter::default();
+        dedup_plan(&file_db_name, &plan_name, PlanAction::Remove, &keep_rules, &filter);
+
+        let plan = fs::read_to_string(&plan_name).unwrap();
+        let plan: DedupPlan = serde_json::from_str(&plan).unwrap();
+        let mut paths = plan.entries.iter().map(|entry| entry.path.clone()).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, [path.join("b/dupe2"), path.join("c/changed2"), path.join("c/dupe3")]);
+        assert!(plan.entries.iter().all(|entry| entry.kept_path.starts_with(path.join("a"))));
+
+        // Edited plan: c/dupe3 stays. Changed files are not removed.
+        let entries = plan.entries.into_iter().filter(|entry| !entry.path.ends_with("dupe3"));
+        let plan = DedupPlan { entries: entries.collect() };
+        fs::write(&plan_name, serde_json::to_string(&plan).unwrap()).unwrap();
+        fs::write(path.join("c/changed2"), "other").unwrap();
+        assert!(apply_plan(&file_db_name, &plan_name, &RemoveOptions::default()));
+        assert!(!path.join("b/dupe2").exists());
+        assert!(path.join("c/dupe3").exists());
+        assert!(path.join("c/changed2").exists());
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(!file_db.iter().any(|entry| entry.name == "dupe2"));
+        assert!(!apply_plan(&file_db_name, &work_dir.join("missing.json"), &Default::default()));
+    }
+
+    #[test]
+    fn test_dupe_filter()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dupe_filter");
+        let path = work_dir.join("simple");
+        let files = [
+            ("a/small1", "ab".to_string()),
+            ("b/small2", "ab".to_string()),
+            ("c/small3", "ab".to_string()),
+            ("a/medium1", "cd".repeat(10)),
+            ("b/medium2", "cd".repeat(10)),
+            ("c/medium3", "cd".repeat(10)),
+            ("a/large1", "ef".repeat(50)),
+            ("b/large2", "ef".repeat(50)),
+        ];
+        for (name, contents) in files {
+            fs::write(path.join(name), contents).unwrap();
+        }
+        let file_db = crawl_initial(&path);
+        let get_group_sizes = |min_size, min_group_savings| {
+            let filter = DupeFilter { min_size, min_group_savings };
+            let groups = get_dupe_groups(&file_db, &filter);
+            groups.iter().map(|(size, indices)| (*size, indices.len())).collect::<Vec<_>>()
+        };
+        assert_eq!(get_group_sizes(10, 0), [(100, 2), (20, 3)]);
+        // Saves 100 and 40 bytes
+        assert_eq!(get_group_sizes(0, 50), [(100, 2)]);
+        assert_eq!(get_group_sizes(0, 4), [(100, 2), (20, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn test_protected_paths()
+    {
+        let patterns = ["/immens/originals/**", "/home/*/keep"].map(String::from);
+        let protected = ProtectedPaths::new(&patterns).unwrap();
+        let is_protected = |path: &str| protected.get_protecting(Path::new(path)).is_some();
+        assert!(is_protected("/immens/originals/2020/img.jpg"));
+        assert!(is_protected("/immens/originals"));
+        // Contains protected paths
+        assert!(is_protected("/immens"));
+        assert!(is_protected("/"));
+        assert!(!is_protected("/immens/originals_old/img.jpg"));
+        assert!(!is_protected("/immens/copies"));
+        assert!(is_protected("/home/user/keep/notes.txt"));
+        assert!(is_protected("/home/user"));
+        assert!(!is_protected("/home/user/other"));
+        assert!(ProtectedPaths::new(&["/a/[b".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_protected_paths_kept()
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let (_, work_dir) = copy_to_work_dir("simple", "protected_paths_kept");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/dupe1"), "dupe").unwrap();
+        fs::write(path.join("a/dupe2"), "dupe").unwrap();
+        let file_db_name = work_dir.join("test_protected_paths_kept.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let pattern = format!("{}/**", path.join("a").display());
+        let options = RemoveOptions {
+            protected: ProtectedPaths::new(&[pattern]).unwrap(),
+            ..RemoveOptions::default()
+        };
+
+        let keep_rules = [KeepRule::PathPrefix(path.join("c"))];
+        let filter = DupeFilter::default();
+        dedup(&file_db_name, DedupAction::Hardlink, &keep_rules, &filter, &options, false, None);
+        let inode = |name: &str| fs::metadata(path.join(name)).unwrap().ino();
+        assert_ne!(inode("c/dupe1"), inode("a/dupe2"));
+        let dir = path.join("a");
+        all_files_elsewhere(
+            &file_db_name,
+            &dir,
+            None,
+            None,
+            true,
+            &[],
+            &options,
+            ElsewhereOutput::Report,
+            None,
+        );
+        assert!(path.join("a/dupe2").exists());
+        rm_recursive(&file_db_name, &path, &options);
+        rm_recursive(&file_db_name, &path.join("a/dupe2"), &options);
+        assert!(path.join("a/dupe2").exists());
+    }
+
+    #[test]
+    fn test_keep_last_copy()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "keep_last_copy");
+        let path = work_dir.join("simple");
+        for name in ["a/dupe1", "b/dupe2", "c/dupe3", "c/dupe4"] {
+            fs::write(path.join(name), "dupe").unwrap();
+        }
+        let file_db_name = work_dir.join("test_keep_last_copy.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let mut db = load_compressed(&file_db_name);
+        let index = |name: &str| db.file_db.iter().position(|entry| entry.name == name).unwrap();
+        let index = |name: &str| index(name) as EntryIndex;
+        let (dupe1, dupe2) = (index("dupe1"), index("dupe2"));
+        let (dupe3, dupe4, f2) = (index("dupe3"), index("dupe4"), index("f2"));
+
+        // Each the kept copy of the other, or not a copy in the db
+        let removals = [(dupe1, dupe2), (dupe2, dupe1), (dupe3, f2)];
+        remove_dupes(&file_db_name, &mut db, &removals, &RemoveOptions::default());
+        assert!(["a/dupe1", "b/dupe2", "c/dupe3"].iter().all(|name| path.join(name).exists()));
+        // Stale db: Kept copies changed or are gone
+        fs::write(path.join("c/dupe4"), "changed").unwrap();
+        fs::remove_file(path.join("b/dupe2")).unwrap();
+        let removals = [(dupe3, dupe4), (dupe1, dupe2)];
+        remove_dupes(&file_db_name, &mut db, &removals, &RemoveOptions::default());
+        assert!(path.join("a/dupe1").exists() && path.join("c/dupe3").exists());
+        fs::remove_file(path.join("a/dupe1")).unwrap();
+        let options = RemoveOptions::default();
+        let dir = path.join("c");
+        all_files_elsewhere(
+            &file_db_name,
+            &dir,
+            None,
+            None,
+            true,
+            &[],
+            &options,
+            ElsewhereOutput::Report,
+            None,
+        );
+        assert!(path.join("c/dupe3").exists());
+    }
+
+    #[test]
+    fn test_all_files_elsewhere_backup_first()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "all_files_elsewhere_backup_first");
+        let path = work_dir.join("simple");
+        // The path in backup_dir sorts before its copy
+        fs::write(path.join("a/backup"), "data").unwrap();
+        fs::write(path.join("c/original"), "data").unwrap();
+        let file_db_name = work_dir.join("test_all_files_elsewhere_backup_first.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let options = RemoveOptions::default();
+        let dir = path.join("a");
+        all_files_elsewhere(
+            &file_db_name,
+            &dir,
+            None,
+            None,
+            true,
+            &[],
+            &options,
+            ElsewhereOutput::Report,
+            None,
+        );
+        assert!(!path.join("a/backup").exists());
+        assert!(path.join("c/original").exists());
+    }
+
+    #[test]
+    fn test_all_files_elsewhere_against()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "all_files_elsewhere_against");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/photo"), "photo").unwrap();
+        fs::write(path.join("a/doc"), "doc").unwrap();
+        let file_db_name = work_dir.join("test_all_files_elsewhere_against.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let (_, backup_work_dir) = copy_to_work_dir("simple", "all_files_elsewhere_against_backup");
+        let backup_path = backup_work_dir.join("simple");
+        fs::write(backup_path.join("b/photo_copy"), "photo").unwrap();
+        let backup_db_name = backup_work_dir.join("backup.db");
+        save_compressed(&backup_db_name, &new_db(crawl_initial(&backup_path)));
+
+        let options = RemoveOptions::default();
+        let get_missing = |other_dir: Option<&Path>, against: Option<&Path>| {
+            all_files_elsewhere(
+                &file_db_name,
+                &path.join("a"),
+                other_dir,
+                against,
+                false,
+                &[],
+                &options,
+                ElsewhereOutput::Quiet,
+                None,
+            )
+        };
+        assert_eq!(get_missing(None, None).len(), 2);
+        // Empty files are not checked
+        assert_eq!(get_missing(None, Some(&backup_db_name)), [path.join("a/doc")]);
+        let missing = get_missing(Some(&backup_path.join("c")), Some(&backup_db_name));
+        assert_eq!(missing.len(), 2);
+        let list_name = work_dir.join("missing.txt");
+        write_files_from(&list_name, &path, &missing).unwrap();
+        let list = fs::read(&list_name).unwrap();
+        let mut names = list.split(|c| *c == 0).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [&b""[..], b"a/doc", b"a/photo"]);
+    }
+
+    #[test]
+    fn test_sync_missing()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "sync_missing");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/photo"), "photo").unwrap();
+        fs::write(path.join("b/photo_copy"), "photo").unwrap();
+        fs::create_dir(path.join("a/docs")).unwrap();
+        fs::write(path.join("a/docs/doc"), "doc").unwrap();
+        fs::write(path.join("a/docs/changed"), "changed").unwrap();
+        let file_db_name = work_dir.join("test_sync_missing.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        fs::write(path.join("a/docs/changed"), "CHANGED").unwrap();
+
+        let dest_dir = work_dir.join("dest");
+        assert!(!sync_missing(&file_db_name, &path.join("a"), &dest_dir, None, false));
+        assert_eq!(fs::read_to_string(dest_dir.join("docs/doc")).unwrap(), "doc");
+        assert!(!dest_dir.join("photo").exists());
+        // Neither the changed file nor its temporary copy
+        assert_eq!(fs::read_dir(dest_dir.join("docs")).unwrap().count(), 1);
+        fs::write(path.join("a/docs/changed"), "changed").unwrap();
+        assert!(sync_missing(&file_db_name, &path.join("a"), &dest_dir, None, false));
+        assert_eq!(fs::read_dir(dest_dir.join("docs")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_hash_missing()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "hash_missing");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/big"), "contents").unwrap();
+        fs::write(path.join("b/big_copy"), "contents").unwrap();
+        let file_db_name = work_dir.join("test_hash_missing.db");
+        let options = CrawlOptions { no_hash: true, ..CrawlOptions::default() };
+        add(&file_db_name, &path, None, false, &options);
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(file_db.iter().skip(1).all(|entry| !is_hashed(&entry)));
+        assert!(get_dupe_groups(&file_db, &DupeFilter::default()).is_empty());
+
+        // Only the files in range, dirs stay unhashed while files below them are
+        assert!(hash_missing(&file_db_name, None, 1, Some(12)));
+        let file_db = load_file_db(&file_db_name, None);
+        let get_entry = |name: &str| file_db.iter().find(|entry| entry.name == name).unwrap();
+        let big_hash = get_hash_for_file(&path.join("a/big"), HashAlgorithm::Blake3).unwrap();
+        assert_eq!(get_entry("big").hash, big_hash);
+        assert!(is_hashed(&get_entry("f2")));
+        assert!(!is_hashed(&get_entry("f1")));
+        assert!(!is_hashed(&get_entry("a")));
+        assert!(is_hashed(&get_entry("b")));
+        assert_eq!(get_dupe_groups(&file_db, &DupeFilter::default()).len(), 1);
+
+        assert!(hash_missing(&file_db_name, Some(&path.join("a")), 0, None));
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(file_db.iter().skip(1).all(|entry| is_hashed(&entry)));
+    }
+
+    #[test]
+    fn test_lazy_hash()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "lazy_hash");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/copy1"), "same").unwrap();
+        fs::write(path.join("b/copy2"), "same").unwrap();
+        fs::write(path.join("c/other"), "diff").unwrap();
+        // Only the middle differs, which the pre-hash does not cover
+        let mut contents = vec![0; 3 * PRE_HASH_BLOCK_SIZE as usize];
+        fs::write(path.join("a/large1"), &contents).unwrap();
+        contents[PRE_HASH_BLOCK_SIZE as usize + 1] = 1;
+        fs::write(path.join("b/large2"), &contents).unwrap();
+        let file_db_name = work_dir.join("test_lazy_hash.db");
+        let options = CrawlOptions { lazy_hash: true, ..CrawlOptions::default() };
+        add(&file_db_name, &path, None, false, &options);
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(file_db.iter().filter(|entry| !entry.is_dir).all(|entry| has_lazy_hash(&entry)));
+
+        let file_db = load_for_dedup(&file_db_name, None).file_db;
+        let get_entry = |name: &str| file_db.iter().find(|entry| entry.name == name).unwrap();
+        for name in ["copy1", "copy2", "large1", "large2"] {
+            assert!(is_hashed(&get_entry(name)));
+        }
+        assert!(!is_hashed(&get_entry("other")));
+        assert!(!is_hashed(&get_entry("f2")));
+        let groups = get_dupe_groups(&file_db, &DupeFilter::default());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].1.len(), 2);
+        // Saved, so nothing is left to hash
+        let mut file_db = load_file_db(&file_db_name, None);
+        assert!(!hash_lazy_candidates(&mut file_db, HashAlgorithm::Blake3));
+    }
+
+    #[test]
+    fn test_hash_algorithm()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "hash_algorithm");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_hash_algorithm.db");
+        let sha256 = CrawlOptions {
+            hash_algorithm: Some(HashAlgorithm::Sha256),
+            ..CrawlOptions::default()
+        };
+        add(&file_db_name, &path, None, false, &sha256);
+        assert_eq!(read_hash_algorithm(&file_db_name), HashAlgorithm::Sha256);
+        let get_f2_hash = || {
+            let file_db = load_file_db(&file_db_name, None);
+            let hash = file_db.iter().find(|entry| entry.name == "f2").unwrap().hash;
+            hash
+        };
+        let contents = fs::read(path.join("b/d/f2")).unwrap();
+        let expected: Hash256 = <sha2::Sha256 as sha2::Digest>::digest(&contents).into();
+        assert_eq!(get_f2_hash(), expected);
+
+        // Kept by update, and by saving
+        fs::write(path.join("b/d/f2"), "changed").unwrap();
+        update(&file_db_name, &path, None, &CrawlOptions::default());
+        let mut db = load_compressed(&file_db_name);
+        assert_eq!(db.hash_algorithm, HashAlgorithm::Sha256);
+        let hash = get_hash_for_file(&path.join("b/d/f2"), HashAlgorithm::Sha256).unwrap();
+        assert_eq!(get_f2_hash(), hash);
+        save_compressed(&file_db_name, &db);
+        assert_eq!(read_hash_algorithm(&file_db_name), HashAlgorithm::Sha256);
+
+        let blake3 = CrawlOptions {
+            hash_algorithm: Some(HashAlgorithm::Blake3),
+            ..CrawlOptions::default()
+        };
+        assert!(std::panic::catch_unwind(|| update(&file_db_name, &path, None, &blake3)).is_err());
+
+        // Other dbs cannot be compared to it
+        let other_db_name = work_dir.join("other.db");
+        db.hash_algorithm = HashAlgorithm::Xxh128;
+        save_compressed(&other_db_name, &db);
+        let result = std::panic::catch_unwind(|| {
+            check_same_hash_algorithm(&file_db_name, &other_db_name)
+        });
+        assert!(result.is_err());
+
+        let hash = get_hash_for_file(&path.join("b/d/f2"), HashAlgorithm::Xxh128).unwrap();
+        assert_ne!(hash, EMPTY_HASH);
+        assert_eq!(hash[16..], [0; 16]);
+    }
+
+    #[test]
+    fn test_rehash()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "rehash");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/rotten"), "contents").unwrap();
+        let file_db_name = work_dir.join("test_rehash.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        // Same size and time, as if corrupted
+        let modified = fs::metadata(path.join("a/rotten")).unwrap().modified().unwrap();
+        fs::write(path.join("a/rotten"), "CONTENTS").unwrap();
+        let file = fs::OpenOptions::new().write(true).open(path.join("a/rotten")).unwrap();
+        file.set_times(fs::FileTimes::new().set_modified(modified)).unwrap();
+        fs::write(path.join("b/d/f2"), "grown").unwrap();
+        let get_entry = |file_db: &FileDb, name: &str| {
+            file_db.iter().find(|entry| entry.name == name).unwrap().to_entry()
+        };
+        let rotten_hash = get_entry(&load_file_db(&file_db_name, None), "rotten").hash;
+
+        assert!(rehash(&file_db_name, None, RehashSelection::Stale));
+        let file_db = load_file_db(&file_db_name, None);
+        let f2 = get_entry(&file_db, "f2");
+        assert_eq!(f2.size, 5);
+        let f2_hash = get_hash_for_file(&path.join("b/d/f2"), HashAlgorithm::Blake3).unwrap();
+        assert_eq!(f2.hash, f2_hash);
+        assert_eq!(get_entry(&file_db, "rotten").hash, rotten_hash);
+
+        // An interrupted run that got to rotten already
+        let mut db = load_compressed(&file_db_name);
+        let index = db.file_db.iter().position(|entry| entry.name == "rotten").unwrap();
+        db.file_db.entry_mut(index as EntryIndex).verified = 2;
+        save_compressed(&file_db_name, &db);
+        let started_name = get_db_side_file_name(&file_db_name, "rehash");
+        fs::write(&started_name, "1").unwrap();
+        assert!(rehash(&file_db_name, None, RehashSelection::All));
+        assert_eq!(get_entry(&load_file_db(&file_db_name, None), "rotten").hash, rotten_hash);
+        assert!(!started_name.exists());
+        assert!(rehash(&file_db_name, Some(&path.join("a")), RehashSelection::All));
+        let rotten = get_entry(&load_file_db(&file_db_name, None), "rotten");
+        let hash = get_hash_for_file(&path.join("a/rotten"), HashAlgorithm::Blake3).unwrap();
+        assert_eq!(rotten.hash, hash);
+    }
+
+    #[test]
+    fn test_pack_files()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "pack_files");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/doc"), "doc").unwrap();
+        let paths = [path.join("a/doc"), path.join("b/d/f2")];
+
+        let tar_name = work_dir.join("missing.tar.zst");
+        pack_files(&tar_name, &path, &paths).unwrap();
+        let mut archive = Archive::new(ZstdDecoder::new(File::open(&tar_name).unwrap()).unwrap());
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                (entry.path().unwrap().into_owned(), contents)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], (PathBuf::from("a/doc"), "doc".to_string()));
+        assert_eq!(entries[1].0, Path::new("b/d/f2"));
+
+        let zip_name = work_dir.join("missing.zip");
+        pack_files(&zip_name, &path, &paths).unwrap();
+        let mut zip = zip::ZipArchive::new(File::open(&zip_name).unwrap()).unwrap();
+        let mut contents = String::new();
+        zip.by_name("a/doc").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "doc");
+        assert_eq!(zip.len(), 2);
+        assert!(pack_files(&work_dir.join("missing.rar"), &path, &paths).is_err());
+    }
+
+    #[test]
+    fn test_hash_parallel()
+    {
+        // More than one buffer, the last one partial
+        let mut data = vec![0u8; PARALLEL_HASH_BUFFER_SIZE + 12345];
+        rand::thread_rng().fill(&mut data[..]);
+        let hash: Hash256 = blake3::hash(&data).into();
+        assert_eq!(get_hash_parallel(io::Cursor::new(&data)).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_hash_no_cache()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "hash_no_cache");
+        let file_name = work_dir.join("data");
+        let data = vec![7u8; NO_CACHE_STEP_SIZE as usize * 3 / 2];
+        fs::write(&file_name, &data).unwrap();
+        set_no_cache(true);
+        let hash = get_hash_for_file(&file_name, HashAlgorithm::Blake3).unwrap();
+        set_no_cache(false);
+        assert_eq!(hash, Hash256::from(blake3::hash(&data)));
+    }
+
+    #[test]
+    fn test_limit_rate()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "limit_rate");
+        let file_name = work_dir.join("data");
+        fs::write(&file_name, vec![7u8; 1 << 20]).unwrap();
+        let start = time::Instant::now();
+        set_limit_rate(4 << 20);
+        get_hash_for_file(&file_name, HashAlgorithm::Blake3).unwrap();
+        set_limit_rate(0);
+        assert!(start.elapsed() >= time::Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_restore()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "restore");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/backup"), "data").unwrap();
+        fs::write(path.join("a/original"), "data").unwrap();
+        let modified = fs::metadata(path.join("c/backup")).unwrap().modified().unwrap();
+        let file_db_name = work_dir.join("test_restore.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let options = RemoveOptions::default();
+        let dir = path.join("c");
+        all_files_elsewhere(
+            &file_db_name,
+            &dir,
+            None,
+            None,
+            true,
+            &[],
+            &options,
+            ElsewhereOutput::Report,
+            None,
+        );
+        assert!(!path.join("c/backup").exists());
+
+        let journal_name = fs::read_dir(&work_dir)
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().path())
+            .find(|path| path.extension() == Some(OsStr::new("journal")))
+            .unwrap();
+        restore(&journal_name, false);
+        assert_eq!(fs::read_to_string(path.join("c/backup")).unwrap(), "data");
+        let restored_modified = fs::metadata(path.join("c/backup")).unwrap().modified().unwrap();
+        assert_eq!(get_secs(&restored_modified), get_secs(&modified));
+    }
+
+    #[test]
+    fn test_dedup_review()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dedup_review");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/dupe1"), "dupe").unwrap();
+        fs::write(path.join("a/dupe2"), "dupe").unwrap();
+        fs::write(path.join("b/dupe3"), "dupe").unwrap();
+        let file_db_name = work_dir.join("test_dedup_review.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+
+        let mut db = load_compressed(&file_db_name);
+        let keep_rules = [KeepRule::PathPrefix(path.join("a"))];
+        let mut review = get_dupe_review(&db.file_db, &keep_rules, &DupeFilter::default());
+        let group = review.groups.iter().position(|group| {
+            group.files.iter().any(|file| file.path.ends_with("dupe1"))
+        });
+        review.group = group.unwrap();
+        let selected = &review.groups[review.group];
+        assert_eq!(selected.files.len(), 3);
+        assert!(selected.files[selected.selected].path.ends_with("a/dupe2"));
+        review.keep_only_selected();
+        assert_eq!(review.removed_bytes(), 8);
+        // The last kept copy can't be marked
+        review.toggle_remove();
+        assert_eq!(review.removed_bytes(), 8);
+        review.select_prev();
+        review.toggle_remove();
+        assert_eq!(review.removed_bytes(), 4);
+
+        let removals = review.removals();
+        assert_eq!(removals.len(), 1);
+        remove_dupes(&file_db_name, &mut db, &removals, &RemoveOptions::default());
+        let names = ["c/dupe1", "a/dupe2", "b/dupe3"];
+        assert_eq!(names.iter().filter(|name| path.join(name).exists()).count(), 2);
+        let file_db = load_file_db(&file_db_name, None);
+        let is_dupe = |entry: &Entry| entry.name.to_string_lossy().starts_with("dupe");
+        assert_eq!(file_db.iter().filter(is_dupe).count(), 2);
+    }
+
+    #[test]
+    fn test_dedup_review_dirs()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dedup_review_dirs");
+        let path = work_dir.join("simple");
+        for dir in ["a/dir1", "c/dir2"] {
+            fs::create_dir(path.join(dir)).unwrap();
+            fs::write(path.join(dir).join("dupe"), "dupe").unwrap();
+        }
+        let file_db_name = work_dir.join("test_dedup_review_dirs.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+
+        let mut db = load_compressed(&file_db_name);
+        propagate_hashes(&mut db.file_db);
+        let keep_rules = [KeepRule::PathPrefix(path.join("a"))];
+        let mut review = get_dupe_review(&db.file_db, &keep_rules, &DupeFilter::default());
+        // Only the files of the dirs
+        assert_eq!(review.groups.len(), 1);
+        let selected = &review.groups[0];
+        assert!(selected.files[selected.selected].path.ends_with("a/dir1/dupe"));
+        review.keep_only_selected();
+        let removals = review.removals();
+        remove_dupes(&file_db_name, &mut db, &removals, &RemoveOptions::default());
+        assert!(!path.join("c/dir2/dupe").exists());
+
+        let journal_name = fs::read_dir(&work_dir)
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().path())
+            .find(|path| path.extension() == Some(OsStr::new("journal")))
+            .unwrap();
+        restore(&journal_name, false);
+        assert_eq!(fs::read_to_string(path.join("c/dir2/dupe")).unwrap(), "dupe");
+    }
+
+    #[test]
+    fn test_browser()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let mut file_db = crawl_initial(&path);
+        propagate_sizes(&mut file_db);
+        let mut browser = tui::Browser::new(&file_db);
+        assert_eq!(get_full_path(&file_db, browser.dir()), path);
+        let names = |browser: &tui::Browser| {
+            let entries = browser.entries().iter();
+            entries.map(|index| file_db.get(*index).name.to_os_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&browser), vec!["b", "c", "a"]);
+        browser.enter();
+        assert_eq!(names(&browser), vec!["d"]);
+        browser.set_order(tui::BrowseOrder::Name);
+        browser.leave();
+        assert_eq!(names(&browser), vec!["a", "b", "c"]);
+        assert_eq!(browser.selected, 1);
+        // Files can't be entered
+        browser.select_prev();
+        assert!(browser.enter());
+        assert!(!browser.enter());
+        assert_eq!(names(&browser), vec!["f1"]);
+    }
+
+    #[test]
+    fn test_server()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let mut file_db = crawl_initial(&path);
+        propagate_sizes(&mut file_db);
+        let index = server::Index::new(&file_db);
+
+        let (status, stats) = index.handle("/stats");
+        assert_eq!(status, 200);
+        assert_eq!(stats["files"], 2);
+        assert_eq!(stats["size"], 12);
+        let url = format!("/ls?path={}", path.join("b").to_str().unwrap());
+        let (_, entries) = index.handle(&url);
+        assert_eq!(entries[0]["path"], path.join("b/d").to_str().unwrap());
+        assert_eq!(entries[0]["is_dir"], true);
+        assert_eq!(index.handle("/ls?path=/missing").0, 404);
+        let (_, entries) = index.handle("/search?name=f%3F");
+        // In the order readdir returned them
+        let mut paths = entries.as_array().unwrap().iter().map(|entry| &entry["path"]);
+        let f2_path = path.join("b/d/f2");
+        let f2 = paths.position(|path| path == f2_path.to_str().unwrap()).unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), 2);
+        let hash = entries[f2]["hash"].as_str().unwrap().to_string();
+        let (_, entries) = index.handle(&format!("/hash/{}", hash));
+        assert_eq!(entries[0]["path"], path.join("b/d/f2").to_str().unwrap());
+        assert_eq!(index.handle("/unknown").0, 404);
+    }
+
+    #[test]
+    fn test_find()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let file_db = crawl_initial(&path);
+        // Ages relative to f1, testdata keeps the times it was checked out with
+        let f1_index = file_db.iter().position(|entry| entry.name == "f1").unwrap();
+        let now = file_db.get(f1_index as EntryIndex).modified;
+        // Sorted, matches come in the order readdir returned them
+        let names = |expression: &FindExpression| {
+            let matches = find_matches(&file_db, expression, now);
+            let mut names = matches
+                .iter()
+                .map(|index| file_db.get(*index).name.to_os_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let pattern = |pattern: &str| glob::Pattern::new(pattern).unwrap();
+
+        assert_eq!(names(&vec![vec![FindPredicate::Name(pattern("f?"))]]), vec!["f1", "f2"]);
+        assert_eq!(names(&vec![vec![FindPredicate::IName(pattern("F2"))]]), vec!["f2"]);
+        let larger = FindPredicate::Size(std::cmp::Ordering::Greater, 0);
+        let file = FindPredicate::IsDir(false);
+        assert_eq!(names(&vec![vec![larger.clone(), file.clone()]]), vec!["f2"]);
+        let in_a = FindPredicate::PathPrefix(path.join("a"));
+        assert_eq!(names(&vec![vec![larger, file], vec![in_a]]), vec!["a", "f1", "f2"]);
+        let old = FindPredicate::Age(std::cmp::Ordering::Greater, parse_duration("1y").unwrap());
+        let old_f1 = vec![vec![old, FindPredicate::Name(pattern("f1"))]];
+        assert!(names(&old_f1).is_empty());
+        let two_years = parse_duration("2y").unwrap();
+        assert_eq!(find_matches(&file_db, &old_f1, now + two_years), [f1_index as EntryIndex]);
+        assert_eq!(parse_duration("30"), parse_duration("30d"));
+        assert_eq!(parse_duration("2h"), Some(7200));
+    }
+
+    #[test]
+    fn test_has()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let file_db = crawl_initial(&path);
+        let file_path = path.join("b/d/f2");
+        let hash = get_hash_for_file(&file_path, HashAlgorithm::Blake3).unwrap();
+        let copies = find_copies(&file_db, &hash, Some(12));
+        assert_eq!(copies.len(), 1);
+        assert_eq!(get_full_path(&file_db, copies[0]), file_path);
+        assert!(find_copies(&file_db, &hash, Some(13)).is_empty());
+        assert_eq!(parse_hash_string(&get_hash_string(&hash)), Some(hash));
+        assert_eq!(parse_hash_string("12"), None);
+    }
+
+    #[test]
+    fn test_children_index()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "children_index");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_children_index.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+
+        let (db, children) = load_compressed_with_children(&file_db_name);
+        let children = children.unwrap();
+        assert_eq!(children, ChildrenIndex::new(&db.file_db));
+        for (index, entry) in db.file_db.iter().enumerate().skip(1) {
+            assert!(children.get(entry.parent).contains(&(index as EntryIndex)));
+        }
+        let f2 = children.find_path(&db.file_db, &path.join("b/d/f2")).unwrap();
+        assert_eq!(db.file_db.get(f2).name, "f2");
+        assert_eq!(children.find_path(&db.file_db, &path.join("b/f2")), None);
+        assert!(ls(&file_db_name, &path.join("b"), None));
+        assert!(!ls(&file_db_name, &path.join("missing"), None));
+    }
+
+    #[test]
+    fn test_extension_stats()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "extension_stats");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/movie.MKV"), "12345").unwrap();
+        fs::write(path.join("c/other.mkv"), "123").unwrap();
+        fs::write(path.join("b/image.jpg"), "1").unwrap();
+        let file_db = crawl_initial(&path);
+        let indices = (0..file_db.len() as EntryIndex).collect::<Vec<_>>();
+        let extension_stats = get_extension_stats(&file_db, &indices);
+        let expected = vec![
+            ("".to_string(), 2, 12),
+            ("mkv".to_string(), 2, 8),
+            ("jpg".to_string(), 1, 1),
+        ];
+        assert_eq!(extension_stats, expected);
+    }
+
+    #[test]
+    fn test_owner_stats()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let mut file_db = crawl_initial(&path);
+        let f2_index = file_db.iter().position(|entry| entry.name == "f2").unwrap();
+        file_db.entry_mut(f2_index as EntryIndex).mode = 0;
+        let f1 = file_db.iter().find(|entry| entry.name == "f1").unwrap();
+        let uid = f1.uid;
+        let indices = (0..file_db.len() as EntryIndex).collect::<Vec<_>>();
+        let owner_stats = get_owner_stats(&file_db, &indices);
+        assert_eq!(owner_stats, vec![(None, 1, 12), (Some(uid), 1, 0)]);
+        assert_eq!(get_owner_name(None), "(unknown)");
+        assert_eq!(get_user_name(0).as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn test_sparse_files()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "sparse_files");
+        let path = work_dir.join("simple");
+        File::create(path.join("a/sparse")).unwrap().set_len(10 << 20).unwrap();
+        fs::write(path.join("a/dense"), vec![1; 1 << 20]).unwrap();
+        let mut file_db = crawl_initial(&path);
+        propagate_sizes(&mut file_db);
+        let get_entry = |name: &str| file_db.iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(get_entry("sparse").size, 10 << 20);
+        assert!(get_entry("sparse").allocated < 1 << 20);
+        assert!(get_entry("dense").allocated >= 1 << 20);
+        let dir_allocated = get_entry("sparse").allocated + get_entry("dense").allocated;
+        assert_eq!(get_entry("a").allocated, dir_allocated);
+
+        let sparse_files = get_sparse_files(&file_db, None, 1 << 20, 2.0);
+        assert_eq!(sparse_files.len(), 1);
+        assert_eq!(file_db.get(sparse_files[0]).name, "sparse");
+        assert!(get_sparse_files(&file_db, Some(&path.join("b")), 1 << 20, 2.0).is_empty());
+    }
+
+    #[test]
+    fn test_size_histogram()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "size_histogram");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/4k"), vec![0; 4096]).unwrap();
+        fs::write(path.join("c/big"), "big").unwrap();
+        let mut file_db = crawl_initial(&path);
+        let index = file_db.iter().position(|entry| entry.name == "big").unwrap();
+        *file_db.entry_mut(index as EntryIndex).size = 2 << 30;
+        let indices = (0..file_db.len() as EntryIndex).collect::<Vec<_>>();
+        let histogram = get_size_histogram(&file_db, &indices);
+        let expected = vec![(1, 0), (1, 12), (1, 4096), (0, 0), (0, 0), (0, 0), (1, 2 << 30)];
+        assert_eq!(histogram, expected);
+    }
+
+    #[test]
+    fn test_age_histogram()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let mut file_db = crawl_initial(&path);
+        let day = 24 * 60 * 60;
+        let now = 10000 * day;
+        let file_indices = (0..file_db.len() as EntryIndex).filter(|i| !file_db.get(*i).is_dir);
+        for (index, file_index) in file_indices.collect::<Vec<_>>().into_iter().enumerate() {
+            let mut entry = file_db.entry_mut(file_index);
+            *entry.size = 1 << index;
+            entry.modified = now - [0, 100, 1000, 3000][index % 4] * day;
+            entry.accessed = now + day;
+        }
+        let indices = (0..file_db.len() as EntryIndex).collect::<Vec<_>>();
+        let num_files = file_db.iter().filter(|entry| !entry.is_dir).count() as u64;
+        let histogram = get_age_histogram(&file_db, &indices, now, |entry| entry.modified);
+        assert_eq!(histogram, vec![(1, 1), (1, 2), (0, 0), (0, 0)]);
+        let histogram = get_age_histogram(&file_db, &indices, now, |entry| entry.accessed);
+        assert_eq!(histogram, vec![(num_files, 3), (0, 0), (0, 0), (0, 0)]);
+
+        let index = file_db.iter().position(|entry| entry.name == "f1").unwrap();
+        file_db.entry_mut(index as EntryIndex).accessed = now - 400 * day;
+        let cold_files = get_cold_files(&file_db, None, 365 * day, now);
+        assert_eq!(cold_files.len(), 1);
+        assert_eq!(file_db.get(cold_files[0]).name, "f1");
+        assert!(get_cold_files(&file_db, Some(&path.join("b")), 365 * day, now).is_empty());
+    }
+
+    #[test]
+    fn test_du()
+    {
+        assert_eq!(format_du_size(0, false), "0");
+        assert_eq!(format_du_size(1025, false), "2");
+        assert_eq!(format_du_size(1000, true), "1000");
+        assert_eq!(format_du_size(4096, true), "4.0K");
+        assert_eq!(format_du_size(4097, true), "4.1K");
+        assert_eq!(format_du_size(10 * 1024 - 1, true), "10K");
+        assert_eq!(format_du_size(1024 * 1024 - 1, true), "1.0M");
+        assert_eq!(format_du_size(3 << 30, true), "3.0G");
+
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let file_db = crawl_initial(&path);
+        let children = ChildrenIndex::new(&file_db);
+        let index = children.find_path(&file_db, &path).unwrap();
+        let paths = |max_depth| {
+            let mut indices = vec![];
+            get_du_indices(&file_db, &children, index, 0, max_depth, &mut indices);
+            indices.iter().map(|index| get_full_path(&file_db, *index)).collect::<Vec<_>>()
+        };
+        // Siblings are listed in the order readdir returned them, so compare sorted. Dirs come
+        // after their subdirs.
+        let sorted = |mut paths: Vec<PathBuf>| {
+            paths.sort();
+            paths
+        };
+        let all_paths = paths(None);
+        let position = |name| all_paths.iter().position(|du_path| *du_path == path.join(name));
+        assert!(position("b/d") < position("b"));
+        assert_eq!(all_paths.last(), Some(&path));
+        let expected = ["", "a", "b", "b/d", "c"].map(|name| path.join(name));
+        assert_eq!(sorted(all_paths.clone()), expected);
+        assert_eq!(sorted(paths(Some(1))), ["", "a", "b", "c"].map(|name| path.join(name)));
+        assert_eq!(paths(Some(0)), vec![path.clone()]);
+    }
+
+    #[test]
+    fn test_children_index_maps()
+    {
+        let path = Path::new(TEST_DATA_DIR).join("simple");
+        let file_db = crawl_initial(&path);
+        let children = ChildrenIndex::new(&file_db);
+        let b = children.find_path(&file_db, &path.join("b")).unwrap();
+        let subtree = children.get_subtree(b);
+        let paths = subtree.iter().map(|index| get_full_path(&file_db, *index)).collect::<Vec<_>>();
+        assert_eq!(paths, ["b", "b/d", "b/d/f2"].map(|name| path.join(name)));
+
+        let mut expected = build_all_paths_to_index_map(&file_db);
+        expected.retain(|_, index| file_db.get(*index).is_dir);
+        assert_eq!(build_path_to_index_map(&file_db, &children), expected);
+        let dir_to_files = build_dir_to_files_map(&file_db, &children);
+        assert_eq!(dir_to_files.len(), expected.len());
+        assert!(dir_to_files[&b].is_empty());
+        assert_eq!(dir_to_files[&subtree[1]], vec![subtree[2]]);
+    }
+
+    #[test]
+    fn test_dry_run()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dry_run");
+        let path = work_dir.join("simple");
+        fs::write(path.join("c/dupe1"), "dupe").unwrap();
+        fs::write(path.join("a/dupe2"), "dupe").unwrap();
+        let file_db_name = work_dir.join("test_dry_run.db");
+        let file_db = crawl_initial(&path);
+        save_compressed(&file_db_name, &new_db(file_db.clone()));
+
+        let options = RemoveOptions { dry_run: true, ..RemoveOptions::default() };
+        rm_recursive(&file_db_name, &path.join("b"), &options);
+        mv(&file_db_name, &path.join("a"), &path.join("c"), true);
+        let dir = path.join("c");
+        all_files_elsewhere(
+            &file_db_name,
+            &dir,
+            None,
+            None,
+            true,
+            &[],
+            &options,
+            ElsewhereOutput::Report,
+            None,
+        );
+        let filter = DupeFilter::default();
+        dedup(&file_db_name, DedupAction::Hardlink, &[], &filter, &options, false, None);
+        for name in &["b/d/f2", "a/f1", "c/dupe1", "a/dupe2"] {
+            assert!(path.join(name).exists());
+        }
+        assert_eq!(load_file_db(&file_db_name, None), file_db);
+    }
+
+    #[test]
+    fn test_remove_to_trash()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "remove_to_trash");
+        let path = work_dir.join("simple");
+        let trash_dir = work_dir.join("Trash");
+        fs::write(path.join("a/x y"), "1").unwrap();
+        trash::move_to_trash_dir(&path.join("a/x y"), &trash_dir).unwrap();
+        fs::write(path.join("a/x y"), "2").unwrap();
+        trash::move_to_trash_dir(&path.join("a/x y"), &trash_dir).unwrap();
+        assert!(!path.join("a/x y").exists());
+        assert_eq!(fs::read_to_string(trash_dir.join("files/x y")).unwrap(), "1");
+        assert_eq!(fs::read_to_string(trash_dir.join("files/x y.2")).unwrap(), "2");
+        let info = fs::read_to_string(trash_dir.join("info/x y.2.trashinfo")).unwrap();
+        let encoded_path = path.join("a/x%20y").to_str().unwrap().to_owned();
+        assert!(info.starts_with(&format!("[Trash Info]\nPath={}\nDeletionDate=", encoded_path)));
+
+        let quarantine_dir = work_dir.join("quarantine");
+        let file_db_name = work_dir.join("test_remove_to_trash.db");
+        save_compressed(&file_db_name, &new_db(crawl_initial(&path)));
+        let options = RemoveOptions {
+            target: RemoveTarget::Quarantine(quarantine_dir.clone()),
+            ..RemoveOptions::default()
+        };
+        rm_recursive_confirmed(&file_db_name, &path.join("b"), &options);
+        assert!(!path.join("b").exists());
+        let quarantined = quarantine_dir.join(path.join("b/d/f2").strip_prefix("/").unwrap());
+        assert!(quarantined.exists());
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(file_db.iter().all(|entry| entry.name != "f2"));
+    }
+
+    #[test]
+    fn test_update()
+    {
+        let (_, path) = copy_to_work_dir("simple", "update");
+        let file_db = crawl_initial(&path);
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        file_db_name.push("test_update.db");
+        save_compressed(&file_db_name, &new_db(file_db));
+        update(&file_db_name, &path, None, &CrawlOptions::default());
+    }
+
+    #[test]
+    fn test_owner_and_mode()
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let (_, path) = copy_to_work_dir("simple", "owner_and_mode");
+        let f1_path = path.join("simple/a/f1");
+        fs::set_permissions(&f1_path, fs::Permissions::from_mode(0o640)).unwrap();
+        let file_db = crawl_initial(&path);
+        let f1_index = file_db.iter().position(|entry| entry.name == "f1").unwrap();
+        let metadata = fs::metadata(&f1_path).unwrap();
+        let entry = file_db.get(f1_index as EntryIndex);
+        assert_eq!(entry.mode, 0o100640);
+        assert_eq!((entry.uid, entry.gid), (metadata.uid(), metadata.gid()));
+
+        let file_db_name = Path::new(TEST_WORK_DIR).join("test_owner_and_mode.db");
+        save_compressed(&file_db_name, &new_db(file_db));
+        fs::set_permissions(&f1_path, fs::Permissions::from_mode(0o600)).unwrap();
+        update(&file_db_name, &path, None, &CrawlOptions::default());
+        let file_db = load_file_db(&file_db_name, None);
+        assert_eq!(file_db.get(f1_index as EntryIndex).mode, 0o100600);
+    }
+
+    #[test]
+    fn test_xattrs()
+    {
+        let (_, path) = copy_to_work_dir("simple", "xattrs");
+        xattr::set(path.join("simple/a/f1"), "user.filedb_test", b"value").unwrap();
+        let crawl = |xattrs| {
+            let mut file_db = FileDb::new();
+            add_dir_recursive_ext(
+                &path,
+                &mut file_db,
+                &mut PathToIndexMap::new(),
+                &DirToFilesMap::new(),
+                Path::new(""),
+                &CrawlOptions { xattrs, ..CrawlOptions::default() },
+                &ArchiveNesting::default(),
+                &VanishedFilesMap::new(),
+                None,
+            );
+            propagate_hashes(&mut file_db);
+            file_db
+        };
+        let get_entry = |file_db: &FileDb, name: &str| {
+            file_db.iter().find(|entry| entry.name == name).unwrap().to_entry()
+        };
+        let file_db = crawl(true);
+        let expected = vec![(OsString::from("user.filedb_test"), b"value".to_vec())];
+        assert_eq!(get_entry(&file_db, "f1").xattrs, expected);
+        let file_db_without = crawl(false);
+        assert!(get_entry(&file_db_without, "f1").xattrs.is_empty());
+        assert_eq!(get_entry(&file_db, "f1").hash, get_entry(&file_db_without, "f1").hash);
+        assert_ne!(get_entry(&file_db, "a").hash, get_entry(&file_db_without, "a").hash);
+        assert_eq!(get_entry(&file_db, "b").hash, get_entry(&file_db_without, "b").hash);
+    }
+
+    #[test]
+    fn test_links()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "links");
+        let path = work_dir.join("simple");
+        std::os::unix::fs::symlink("../b", path.join("a/to_b")).unwrap();
+        std::os::unix::fs::symlink("../..", path.join("b/d/loop")).unwrap();
+        std::os::unix::fs::symlink("missing", path.join("a/broken")).unwrap();
+        let file_db_name = work_dir.join("test_links.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let db = load_compressed(&file_db_name);
+        let children = ChildrenIndex::new(&db.file_db);
+        let find = |p: &str| children.find_path(&db.file_db, &path.join(p));
+        let to_b = db.file_db.get(find("a/to_b").unwrap());
+        assert!(is_link(&to_b) && !to_b.is_dir && to_b.size == 0);
+        assert_eq!(db.link_targets[&to_b.hash], Path::new("../b"));
+        assert!(find("a/to_b/d").is_none());
+        assert!(find("a/broken").is_some() && find("b/d/loop").is_some());
+        // Kept by update
+        update(&file_db_name, &path, None, &CrawlOptions::default());
+        assert_eq!(load_file_db(&file_db_name, None).len(), db.file_db.len());
+
+        let followed_name = work_dir.join("test_links_followed.db");
+        let options = CrawlOptions { follow_links: true, ..CrawlOptions::default() };
+        add(&followed_name, &path, None, false, &options);
+        let file_db = load_file_db(&followed_name, None);
+        let children = ChildrenIndex::new(&file_db);
+        let find = |p: &str| children.find_path(&file_db, &path.join(p));
+        assert!(file_db.get(find("a/to_b").unwrap()).is_dir);
+        assert!(find("a/to_b/d/f2").is_some());
+        assert!(find("a/broken").is_none() && find("b/d/loop").is_none());
+        update(&followed_name, &path, None, &options);
+        assert_eq!(load_file_db(&followed_name, None).len(), file_db.len());
+    }
+
+    #[test]
+    fn test_one_file_system()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "one_file_system");
+        let path = work_dir.join("simple");
+        let device = |path: &Path| get_device(&fs::metadata(path).unwrap());
+        // Otherwise the whole file system would be crawled
+        if device(&path) == device(Path::new("/")) {
+            return;
+        }
+        std::os::unix::fs::symlink("/", path.join("a/other")).unwrap();
+        let options = CrawlOptions {
+            follow_links: true,
+            one_file_system: true,
+            ..CrawlOptions::default()
+        };
+        let mut file_db = FileDb::new();
+        add_dir_recursive_ext(
+            &path,
+            &mut file_db,
+            &mut PathToIndexMap::new(),
+            &DirToFilesMap::new(),
+            Path::new(""),
+            &options,
+            &ArchiveNesting::default(),
+            &VanishedFilesMap::new(),
+            None,
+        );
+        let children = ChildrenIndex::new(&file_db);
+        let other = children.find_path(&file_db, &path.join("a/other")).unwrap();
+        assert!(file_db.get(other).is_dir && children.get(other).is_empty());
+        assert!(children.find_path(&file_db, &path.join("b/d/f2")).is_some());
+    }
+
+    #[test]
+    fn test_skip_backup_excluded()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "skip_backup_excluded");
+        let path = work_dir.join("simple");
+        fs::create_dir(path.join("a/cache")).unwrap();
+        fs::write(path.join("a/cache/CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55")
+            .unwrap();
+        fs::write(path.join("c/not_a_cache"), "Signature: 8a477f597d28d172789f06886806bc55")
+            .unwrap();
+        let chattr = |file: &str| {
+            std::process::Command::new("chattr").arg("+d").arg(path.join(file)).status()
+        };
+        let has_nodump = chattr("a/f1").is_ok_and(|status| status.success());
+        let crawl = |skip_backup_excluded| {
+            let mut file_db = FileDb::new();
+            add_dir_recursive_ext(
+                &path,
+                &mut file_db,
+                &mut PathToIndexMap::new(),
+                &DirToFilesMap::new(),
+                Path::new(""),
+                &CrawlOptions { skip_backup_excluded, ..CrawlOptions::default() },
+                &ArchiveNesting::default(),
+                &VanishedFilesMap::new(),
+                None,
+            );
+            file_db
+        };
+        let file_db = crawl(true);
+        let children = ChildrenIndex::new(&file_db);
+        let find = |p: &str| children.find_path(&file_db, &path.join(p));
+        assert!(children.get(find("a/cache").unwrap()).is_empty());
+        assert!(find("c/not_a_cache").is_some());
+        if has_nodump {
+            assert!(chattr("b").unwrap().success());
+            let file_db = crawl(true);
+            let children = ChildrenIndex::new(&file_db);
+            let find = |p: &str| children.find_path(&file_db, &path.join(p));
+            assert!(find("a/f1").is_none() && find("b").is_none());
+            assert!(find("a").is_some());
+        }
+        assert_eq!(crawl(false).len(), file_db.len() + 1 + has_nodump as usize);
+    }
+
+    #[test]
+    fn test_config()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_config");
+        let config_name = work_dir.join("config.toml");
+        fs::write(
+            &config_name,
+            "db = \"/immens/immens.filedb\"\nprotect = [\"/immens/originals/**\"]\n\
+             threads = 4\ncompression = 6\n\
+             [crawl]\nxattrs = true\nmime = false\nhash-algorithm = \"xxh128\"\n\
+             archive-depth = 2\n",
+        )
+        .unwrap();
+        let config = load_config(Some(&config_name)).unwrap();
+        assert_eq!(config.db, Some(PathBuf::from("/immens/immens.filedb")));
+        assert_eq!(config.protect, ["/immens/originals/**"]);
+        assert_eq!((config.threads, config.compression), (Some(4), Some(6)));
+        assert!(!config.wait);
+        let mut crawl_args = config.get_crawl_args().unwrap();
+        crawl_args.sort();
+        let option = |name: &str, value: Option<&str>| (name.to_string(), value.map(String::from));
+        assert_eq!(
+            crawl_args,
+            [
+                option("--archive-depth", Some("2")),
+                option("--hash-algorithm", Some("xxh128")),
+                option("--xattrs", None)
+            ]
+        );
+
+        fs::write(&config_name, "colors = true\n").unwrap();
+        assert!(load_config(Some(&config_name)).is_err());
+        assert!(load_config(Some(&work_dir.join("missing.toml"))).is_err());
+    }
+
+    #[test]
+    fn test_library_api()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_library_api");
+        let path = work_dir.join("simple");
+        let crawled = std::sync::Arc::new(Mutex::new(vec![]));
+        let progress = crawled.clone();
+        let options = CrawlOptions::new()
+            .exclude(glob::Pattern::new("f1").unwrap())
+            .hash_algorithm(HashAlgorithm::Xxh128)
+            .threads(2)
+            .progress(move |path| progress.lock().unwrap().push(path.to_owned()));
+        let file_db = FileDb::crawl(&path, &options);
+        assert!(crawled.lock().unwrap().contains(&path.join("b/d/f2")));
+        assert!(!crawled.lock().unwrap().contains(&path.join("a/f1")));
+
+        let files = vec![vec![FindPredicate::IsDir(false)]];
+        let paths: Vec<_> =
+            file_db.query(&files).into_iter().map(|index| file_db.get_path(index)).collect();
+        assert_eq!(paths, [path.join("b/d/f2")]);
+
+        let file_db_name = work_dir.join("library_api.filedb");
+        file_db.save(&file_db_name, HashAlgorithm::Xxh128);
+        assert_eq!(FileDb::load(&file_db_name), file_db);
+        assert_eq!(read_hash_algorithm(&file_db_name), HashAlgorithm::Xxh128);
+    }
+
+    #[test]
+    fn test_entry_iterators()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_entry_iterators");
+        let path = work_dir.join("simple");
+        let mut file_db = FileDb::crawl(&path, &CrawlOptions::new());
+        for (index, entry_path) in file_db.iter_paths() {
+            assert_eq!(entry_path, get_full_path(&file_db, index));
+        }
+
+        let b = file_db.find_path(&path.join("b")).unwrap();
+        let d = file_db.find_path(&path.join("b/d")).unwrap();
+        let f2 = file_db.find_path(&path.join("b/d/f2")).unwrap();
+        assert_eq!(file_db.children(b).collect::<Vec<_>>(), [d]);
+        assert_eq!(file_db.subtree(&path.join("b")).collect::<Vec<_>>(), [b, d, f2]);
+        assert_eq!(file_db.subtree(&path.join("missing")).count(), 0);
+        let hash = file_db.get(f2).hash;
+        assert_eq!(file_db.find_by_hash(&hash).collect::<Vec<_>>(), [f2]);
+
+        // Entries added later are found, too
+        let mut entry = file_db.get(f2).to_entry();
+        entry.name = "f3".into();
+        file_db.push(entry);
+        let f3 = (file_db.len() - 1) as EntryIndex;
+        assert_eq!(file_db.children(d).collect::<Vec<_>>(), [f2, f3]);
+        assert_eq!(file_db.find_by_hash(&hash).collect::<Vec<_>>(), [f2, f3]);
+    }
+
+    #[test]
+    fn test_cancellation()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_cancellation");
+        let path = work_dir.join("simple");
+        let full_len = FileDb::crawl(&path, &CrawlOptions::new()).len();
+
+        // Cancelled while crawling a, the tree so far is returned
+        let cancellation = CancellationToken::new();
+        let cancel = cancellation.clone();
+        let options = CrawlOptions::new()
+            .cancellation(cancellation.clone())
+            .progress(move |path| if path.ends_with("a") { cancel.cancel() });
+        let file_db = FileDb::crawl(&path, &options);
+        assert!(file_db.find_path(&path.join("a")).is_some());
+        assert!(file_db.find_path(&path.join("a/f1")).is_none());
+        assert!(file_db.len() < full_len);
+
+        let file_db_name = work_dir.join("cancellation.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        verify(&file_db_name, VerifySample::All, Some(&cancellation));
+        assert!(load_file_db(&file_db_name, None).iter().all(|entry| entry.verified == 0));
+        verify(&file_db_name, VerifySample::All, Some(&CancellationToken::new()));
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(file_db.iter().filter(|entry| !entry.is_dir).all(|entry| entry.verified > 0));
+    }
+
+    #[test]
+    fn test_agent_collect()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_agent_collect");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("agent_collect.filedb");
+        let collect_from_agent = |prefix: &Path| {
+            let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let file_db_name = file_db_name.clone();
+            let prefix = prefix.to_owned();
+            let collector = std::thread::spawn(move || {
+                let source = AgentSource::Listen(&address.to_string());
+                collect(&file_db_name, source, Some(&prefix))
+            });
+            // Until the collector listens
+            let address = address.to_string();
+            while !agent(&path, &CrawlOptions::new(), Some(&address)) {
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+            assert!(collector.join().unwrap());
+        };
+
+        let prefix = work_dir.join("hosts/other");
+        collect_from_agent(&prefix);
+        let collected_path = prefix.join(path.strip_prefix("/").unwrap());
+        let file_db = FileDb::load(&file_db_name);
+        let f2 = file_db.find_path(&collected_path.join("b/d/f2")).unwrap();
+        assert_eq!(file_db.get(f2).size, 12);
+        assert!(file_db.find_path(&path).is_none());
+
+        // Collecting again replaces the files collected before
+        fs::remove_file(path.join("a/f1")).unwrap();
+        collect_from_agent(&prefix);
+        let file_db = FileDb::load(&file_db_name);
+        assert!(file_db.find_path(&collected_path.join("a/f1")).is_none());
+        assert!(file_db.find_path(&collected_path.join("b/d/f2")).is_some());
+    }
+
+    #[test]
+    fn test_origins()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_origins");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/photo"), "photo").unwrap();
+        let file_db_name = work_dir.join("origins.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        fs::write(path.join("b/doc"), "doc").unwrap();
+
+        let prefix = work_dir.join("hosts");
+        for host in ["nas", "laptop", "nas"] {
+            let tree = agent::AgentTree {
+                host: host.to_string(),
+                root_dir: path.clone(),
+                hash_algorithm: HashAlgorithm::default(),
+                file_db: FileDb::crawl(&path, &CrawlOptions::new()),
+            };
+            assert!(agent::add_collected_tree(&file_db_name, tree, Some(&prefix.join(host))));
+        }
+        let db = load_compressed(&file_db_name);
+        assert_eq!(db.hosts, ["nas", "laptop"]);
+        let file_db = &db.file_db;
+        let get_origin = |path: &Path| file_db.get(file_db.find_path(path).unwrap()).origin;
+        let nas_path = prefix.join("nas").join(path.strip_prefix("/").unwrap());
+        assert_eq!(get_origin(&path.join("a/photo")), 0);
+        assert_eq!(get_origin(&nas_path.join("a/photo")), 1);
+        assert_eq!(get_origin(&nas_path), 1);
+        assert_eq!(get_origin(&prefix.join("nas")), 0);
+
+        // f2 and the photo are on all hosts, the doc only on the others, f1 is empty
+        let indices = (0..file_db.len() as EntryIndex).collect::<Vec<_>>();
+        let stats = get_host_stats(file_db, &indices);
+        let get_stats = |origin: usize| {
+            (stats[origin].files, stats[origin].files_on_other_hosts, stats[origin].bytes)
+        };
+        assert_eq!(get_stats(0), (3, 2, 17));
+        assert_eq!(get_stats(1), (4, 3, 20));
+        assert_eq!(get_stats(2), (4, 3, 20));
+    }
+
+    #[test]
+    fn test_add_remote()
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let (_, work_dir) = copy_to_work_dir("simple", "test_add_remote");
+        let path = work_dir.join("simple");
+        std::os::unix::fs::symlink("f1", path.join("a/link")).unwrap();
+        fs::hard_link(path.join("a/f1"), path.join("b/f1_link")).unwrap();
+        // Runs the command here instead of on the host
+        let ssh = work_dir.join("ssh");
+        fs::write(&ssh, "#!/bin/sh\nshift\nexec sh -c \"$1\"\n").unwrap();
+        fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("FILEDB_SSH", &ssh);
+
+        // sha256sum is hashing on the host, the others stream the files if not installed
+        for algorithm in HashAlgorithm::ALL {
+            let options = CrawlOptions::new().hash_algorithm(algorithm);
+            let expected = FileDb::crawl(&path, &options);
+            let file_db_name = work_dir.join(format!("remote_{}.filedb", algorithm.get_name()));
+            let remote = format!("host:{}", path.to_str().unwrap());
+            let prefix = work_dir.join("hosts/host");
+            assert!(add_remote(&file_db_name, &remote, Some(&prefix), &options));
+            let file_db = FileDb::load(&file_db_name);
+            let collected_path = prefix.join(path.strip_prefix("/").unwrap());
+            for relative_path in ["a/f1", "a/link", "b/f1_link", "b/d/f2", "b/d", ""] {
+                let index = expected.find_path(&path.join(relative_path)).unwrap();
+                let expected_entry = expected.get(index);
+                let index = file_db.find_path(&collected_path.join(relative_path)).unwrap();
+                let entry = file_db.get(index);
+                assert_eq!(entry.hash, expected_entry.hash, "{}", relative_path);
+                assert_eq!(entry.size, expected_entry.size);
+                assert_eq!(entry.modified, expected_entry.modified);
+                assert_eq!(entry.inode, expected_entry.inode);
+                assert_eq!(entry.mode, expected_entry.mode);
+            }
+        }
+        let file_db_name = work_dir.join("relative.filedb");
+        assert!(!add_remote(&file_db_name, "host:relative", None, &CrawlOptions::new()));
+    }
+
+    #[test]
+    fn test_daemon_run_update()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_daemon_run_update");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("daemon.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        fs::write(path.join("a/new"), "new").unwrap();
+        fs::write(path.join("a/f1"), "f1").unwrap();
+        fs::remove_dir(path.join("c")).unwrap();
+
+        let metrics = Mutex::new(daemon::DaemonMetrics::default());
+        let options = CrawlOptions::default();
+        assert!(daemon::run_update(&file_db_name, None, &options, &metrics));
+        {
+            let metrics = metrics.lock().unwrap();
+            assert_eq!(metrics.entries, FileDb::load(&file_db_name).len());
+            assert_eq!((metrics.files, metrics.bytes, metrics.runs), (3, 17, 1));
+            assert_eq!(metrics.last_run_diff.added, vec![path.join("a/new").to_str().unwrap()]);
+            assert_eq!(metrics.last_run_diff.removed, vec![path.join("c").to_str().unwrap()]);
+            assert_eq!(metrics.last_run_diff.size_changed.len(), 1);
+            let text = daemon::format_metrics(&metrics);
+            assert!(text.contains("# TYPE filedb_bytes gauge\nfiledb_bytes 17\n"));
+            assert!(text.contains("filedb_last_run_changes{kind=\"added\"} 1\n"));
+        }
+
+        // Nothing changed since
+        assert!(daemon::run_update(&file_db_name, Some(&path), &options, &metrics));
+        let metrics = metrics.lock().unwrap();
+        assert_eq!((metrics.runs, metrics.failed_runs, metrics.last_run_errors), (2, 0, 0));
+        assert!(metrics.last_run_diff.added.is_empty());
+        assert!(metrics.last_run_diff.size_changed.is_empty());
+    }
+
+    #[test]
+    fn test_notify()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_notify");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/f1"), "f1").unwrap();
+        let file_db_name = work_dir.join("notify.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let summaries_name = work_dir.join("summaries");
+        let command = format!("cat >> {}", shell_escape(&get_path_bytes(&summaries_name)));
+        set_notify_targets(NotifyTargets { webhook: None, command: Some(command) });
+
+        fs::remove_file(path.join("b/d/f2")).unwrap();
+        verify(&file_db_name, VerifySample::All, None);
+        update_all_roots(&file_db_name, None, &CrawlOptions::default());
+        all_files_elsewhere(
+            &file_db_name,
+            &path.join("a"),
+            None,
+            None,
+            false,
+            &[],
+            &RemoveOptions::default(),
+            ElsewhereOutput::Quiet,
+            None,
+        );
+        set_notify_targets(NotifyTargets::default());
+
+        // Other tests may notify at the same time
+        let summaries = fs::read_to_string(&summaries_name).unwrap();
+        let summaries = summaries
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|summary| summary["db"] == file_db_name.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0]["command"], "verify");
+        assert_eq!(summaries[0]["ok"], false);
+        assert_eq!((&summaries[0]["verified"], &summaries[0]["missing"]), (&2.into(), &1.into()));
+        assert_eq!(summaries[1]["command"], "update");
+        assert_eq!(summaries[1]["ok"], true);
+        assert_eq!(summaries[2]["command"], "all_files_elsewhere");
+        assert_eq!(summaries[2]["files_missing"], 1);
+        assert_eq!(summaries[2]["missing"][0], path.join("a/f1").to_str().unwrap());
+    }
+
+    #[test]
+    fn test_signing()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_signing");
+        let file_db_name = work_dir.join("signed.filedb");
+        add(&file_db_name, &work_dir.join("simple"), None, false, &CrawlOptions::default());
+        let generate_key = |name: &str| {
+            let key = work_dir.join(name);
+            let status = std::process::Command::new("ssh-keygen")
+                .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+                .arg(&key)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            (key.clone(), get_db_side_file_name(&key, "pub"))
+        };
+        let (key, public_key) = generate_key("key");
+        let (_, other_public_key) = generate_key("other_key");
+
+        assert!(signing::check_signature_with(&file_db_name, &public_key).is_err());
+        let signed = |save: &dyn Fn()| {
+            set_signing_keys(SigningKeys { sign_key: Some(key.clone()), trusted_key: None });
+            save();
+            set_signing_keys(SigningKeys::default());
+        };
+        signed(&|| save_compressed(&file_db_name, &load_compressed(&file_db_name)));
+        signing::check_signature_with(&file_db_name, &public_key).unwrap();
+        assert!(signing::check_signature_with(&file_db_name, &other_public_key).is_err());
+        // A private key is not trusted
+        assert!(signing::check_signature_with(&file_db_name, &key).is_err());
+        let mut db = load_compressed(&file_db_name);
+        *db.file_db.entry_mut(1).size += 1;
+        save_compressed(&file_db_name, &db);
+        assert!(signing::check_signature_with(&file_db_name, &public_key).is_err());
+
+        // Saving signs the new db before it replaces the old one
+        let signature_name = get_db_side_file_name(&file_db_name, "sig");
+        let tmp_signature_name = get_db_side_file_name(&signature_name, "tmp");
+        let stale_signature = fs::read(&signature_name).unwrap();
+        signed(&|| save_compressed(&file_db_name, &db));
+        signing::check_signature_with(&file_db_name, &public_key).unwrap();
+        assert!(!tmp_signature_name.exists());
+        // As left by a crash between renaming the db and its signature
+        fs::rename(&signature_name, &tmp_signature_name).unwrap();
+        fs::write(&signature_name, stale_signature).unwrap();
+        signing::check_signature_with(&file_db_name, &public_key).unwrap();
+
+        // Appended changes are signed too, large enough a db for them not to be compacted
+        let path = work_dir.join("simple");
+        fs::create_dir(path.join("many")).unwrap();
+        for i in 0..200 {
+            fs::write(path.join("many").join(i.to_string()), i.to_string()).unwrap();
+        }
+        let file_db_name = work_dir.join("changes.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let changes_name = get_db_side_file_name(&file_db_name, "changes");
+        let updated_name = work_dir.join("updated.filedb");
+        // Creates the changes, then appends to them. The last key fails to sign.
+        let missing_key = work_dir.join("missing_key");
+        for (name, sign_key) in [("a/new1", &key), ("a/new2", &key), ("a/new3", &missing_key)] {
+            let db = load_compressed(&file_db_name);
+            let base = changes::ChangeBase { file_db: db.file_db, link_targets: db.link_targets };
+            fs::write(path.join(name), name).unwrap();
+            fs::copy(&file_db_name, &updated_name).unwrap();
+            update(&updated_name, &path, None, &CrawlOptions::default());
+            let updated = load_compressed(&updated_name);
+            set_signing_keys(SigningKeys { sign_key: Some(sign_key.clone()), trusted_key: None });
+            let saved = std::panic::catch_unwind(|| {
+                changes::save_changes(&file_db_name, &updated, base);
+            });
+            set_signing_keys(SigningKeys::default());
+            assert_eq!(saved.is_ok(), sign_key == &key);
+            signing::check_signature_with(&changes_name, &public_key).unwrap();
+        }
+        let db = load_compressed(&file_db_name);
+        assert!(db.file_db.iter().any(|entry| entry.name == "new2"));
+        assert!(!db.file_db.iter().any(|entry| entry.name == "new3"));
+    }
+
+    #[test]
+    fn test_changes()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_changes");
+        let path = work_dir.join("simple");
+        // Large enough for the changes not to be compacted right away
+        fs::create_dir(path.join("many")).unwrap();
+        for i in 0..200 {
+            fs::write(path.join("many").join(i.to_string()), i.to_string()).unwrap();
+        }
+        let file_db_name = work_dir.join("changes.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let saved = fs::read(&file_db_name).unwrap();
+
+        fs::write(path.join("a/f1"), "changed").unwrap();
+        fs::remove_dir_all(path.join("b")).unwrap();
+        fs::create_dir(path.join("c/e")).unwrap();
+        fs::write(path.join("c/e/f3"), "added").unwrap();
+        let updated_name = work_dir.join("updated.filedb");
+        fs::copy(&file_db_name, &updated_name).unwrap();
+        update(&updated_name, &path, None, &CrawlOptions::default());
+        let updated = load_compressed(&updated_name);
+        let db = load_compressed(&file_db_name);
+        let base = changes::ChangeBase { file_db: db.file_db, link_targets: db.link_targets };
+        changes::save_changes(&file_db_name, &updated, base);
+        assert_eq!(fs::read(&file_db_name).unwrap(), saved);
+        assert!(changes::has_changes(&file_db_name));
+        assert!(list_changes(&file_db_name, true));
+
+        let get_entries = |file_db: &FileDb| {
+            let mut entries = file_db
+                .iter_paths()
+                .map(|(index, path)| {
+                    let entry = file_db.get(index);
+                    (path, entry.size, entry.hash, entry.modified)
+                })
+                .collect::<Vec<_>>();
+            entries.sort();
+            entries
+        };
+        let expected = get_entries(&updated.file_db);
+        let mut db = load_compressed(&file_db_name);
+        assert_eq!(get_entries(&db.file_db), expected);
+        // Applying them again, as after a crash before they were removed, changes nothing
+        assert!(changes::apply_changes(&file_db_name, &mut db).unwrap());
+        assert_eq!(get_entries(&db.file_db), expected);
+
+        assert!(compact(&file_db_name));
+        assert!(!changes::has_changes(&file_db_name));
+        assert_ne!(fs::read(&file_db_name).unwrap(), saved);
+        assert_eq!(get_entries(&FileDb::load(&file_db_name)), expected);
+    }
+
+    #[test]
+    fn test_changes_incomplete_header()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "changes_incomplete_header");
+        let path = work_dir.join("simple");
+        fs::create_dir(path.join("many")).unwrap();
+        for i in 0..200 {
+            fs::write(path.join("many").join(i.to_string()), i.to_string()).unwrap();
+        }
+        let file_db_name = work_dir.join("changes.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let num_entries = load_file_db(&file_db_name, None).len();
+        let changes_name = get_db_side_file_name(&file_db_name, "changes");
+
+        // Unreadable changes are reported, the db still loads
+        fs::write(&changes_name, "NOT-FILEDB-CHANGES").unwrap();
+        assert_eq!(load_file_db(&file_db_name, None).len(), num_entries);
+        // As left by a crash while creating the file
+        fs::write(&changes_name, "FILEDB-CH").unwrap();
+        assert_eq!(load_file_db(&file_db_name, None).len(), num_entries);
+        assert!(list_changes(&file_db_name, false));
+
+        // The next changes replace it
+        fs::write(path.join("a/new"), "new").unwrap();
+        let updated_name = work_dir.join("updated.filedb");
+        fs::copy(&file_db_name, &updated_name).unwrap();
+        update(&updated_name, &path, None, &CrawlOptions::default());
+        let updated = load_compressed(&updated_name);
+        let db = load_compressed(&file_db_name);
+        let base = changes::ChangeBase { file_db: db.file_db, link_targets: db.link_targets };
+        changes::save_changes(&file_db_name, &updated, base);
+        assert!(fs::read(&changes_name).unwrap().starts_with(b"FILEDB-CHANGES"));
+        assert_eq!(load_file_db(&file_db_name, None).len(), num_entries + 1);
+    }
+
+    #[test]
+    fn test_import_rclone()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_import_rclone");
+        let path = work_dir.join("simple");
+        fs::write(path.join("a/photo"), "photo").unwrap();
+        fs::write(path.join("a/doc"), "doc").unwrap();
+        let file_db_name = work_dir.join("local.filedb");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+
+        // Only the photo has a blake3 hash in the bucket
+        let photo_hash = get_hash_string(&blake3::hash(b"photo").into());
+        let listing = serde_json::json!([
+            {"Path": "photos", "Name": "photos", "Size": -1, "MimeType": "inode/directory",
+             "ModTime": "2024-05-01T10:00:00Z", "IsDir": true},
+            {"Path": "photos/2024/photo.jpg", "Name": "photo.jpg", "Size": 5,
+             "MimeType": "image/jpeg", "ModTime": "2024-05-01T10:00:00.5+02:00", "IsDir": false,
+             "Hashes": {"blake3": photo_hash, "md5": "e2d2a5f4b3b0c1a4e0a7e6a1d3b1c1f0"}},
+            {"Path": "docs/doc", "Name": "doc", "Size": 3, "ModTime": "2024-05-01T10:00:00Z",
+             "IsDir": false, "Hashes": {"md5": "d3c1e6a0b2b1f2e4d5c6b7a8f9e0d1c2"}},
+        ]);
+        let listing_name = work_dir.join("bucket.json");
+        fs::write(&listing_name, listing.to_string()).unwrap();
+        let cloud_db_name = work_dir.join("cloud.filedb");
+        assert!(import_rclone(&cloud_db_name, &listing_name, None, None));
+
+        let file_db = FileDb::load(&cloud_db_name);
+        let photo = file_db.find_path(Path::new("/rclone/bucket/photos/2024/photo.jpg")).unwrap();
+        let photo = file_db.get(photo);
+        assert_eq!(photo.size, 5);
+        assert_eq!(photo.modified, 1714550400);
+        assert_eq!(photo.mime, "image/jpeg");
+        let missing = all_files_elsewhere(
+            &file_db_name,
+            &path.join("a"),
+            None,
+            Some(&cloud_db_name),
+            false,
+            &[],
+            &RemoveOptions::default(),
+            ElsewhereOutput::Quiet,
+            None,
+        );
+        assert_eq!(missing, [path.join("a/doc")]);
+
+        // Importing again replaces the objects imported before
+        fs::write(&listing_name, "[]").unwrap();
+        assert!(import_rclone(&cloud_db_name, &listing_name, None, None));
+        let file_db = FileDb::load(&cloud_db_name);
+        assert!(file_db.find_path(Path::new("/rclone/bucket/photos")).is_none());
+        assert!(file_db.find_path(Path::new("/rclone/bucket")).is_some());
+    }
+
+    #[test]
+    fn test_crawl_errors()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "test_crawl_errors");
+        let path = work_dir.join("simple");
+        // Opening a socket fails, even as root
+        let _listener = std::os::unix::net::UnixListener::bind(path.join("a/socket")).unwrap();
+        let file_db_name = work_dir.join("crawl_errors.filedb");
+        let report_name = work_dir.join("errors.json");
+        let options =
+            CrawlOptions { error_report: Some(report_name.clone()), ..Default::default() };
+        add(&file_db_name, &path, None, false, &options);
+
+        // The crawl continued past the socket
+        let db = load_compressed(&file_db_name);
+        let children = ChildrenIndex::new(&db.file_db);
+        assert!(children.find_path(&db.file_db, &path.join("a/socket")).is_some());
+        assert!(children.find_path(&db.file_db, &path.join("b/d/f2")).is_some());
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&report_name).unwrap()).unwrap();
+        assert_eq!(report["num_io"], 1);
+        assert_eq!(report["num_permission_denied"], 0);
+        assert_eq!(report["errors"][0]["path"], path.join("a/socket").to_str().unwrap());
+        assert_eq!(report["errors"][0]["kind"], "io");
+        assert!(crawl_errors::take_crawl_errors().is_empty());
+    }
+
+    #[test]
+    fn test_virtual_file_systems()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "virtual_file_systems");
+        let path = work_dir.join("simple");
+        if !is_virtual_file_system(Path::new("/proc")) {
+            return;
+        }
+        std::os::unix::fs::symlink("/proc", path.join("a/proc")).unwrap();
+        let mut file_db = FileDb::new();
+        add_dir_recursive_ext(
+            &path,
+            &mut file_db,
+            &mut PathToIndexMap::new(),
+            &DirToFilesMap::new(),
+            Path::new(""),
+            &CrawlOptions { follow_links: true, ..CrawlOptions::default() },
+            &ArchiveNesting::default(),
+            &VanishedFilesMap::new(),
+            None,
+        );
+        let children = ChildrenIndex::new(&file_db);
+        let proc = children.find_path(&file_db, &path.join("a/proc")).unwrap();
+        assert!(file_db.get(proc).is_dir && children.get(proc).is_empty());
+        assert!(!is_virtual_file_system(&path));
+    }
+
+    #[test]
+    fn test_mime()
+    {
+        let (_, path) = copy_to_work_dir("simple", "mime");
+        let png = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0, 0, 0, 0x0d];
+        fs::write(path.join("simple/a/no_extension"), png).unwrap();
+        let mut file_db = FileDb::new();
+        add_dir_recursive_ext(
+            &path,
+            &mut file_db,
+            &mut PathToIndexMap::new(),
+            &DirToFilesMap::new(),
+            Path::new(""),
+            &CrawlOptions { mime: true, ..CrawlOptions::default() },
+            &ArchiveNesting::default(),
+            &VanishedFilesMap::new(),
+            None,
+        );
+        let get_entry = |name: &str| file_db.iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(get_entry("no_extension").mime, "image/png");
+        assert_eq!(get_entry("f2").mime, "");
+        assert_eq!(get_entry("a").mime, "");
+        let expression = vec![vec![FindPredicate::Mime(glob::Pattern::new("image/*").unwrap())]];
+        let matches = find_matches(&file_db, &expression, 0);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(file_db.get(matches[0]).name, "no_extension");
+    }
+
+    #[test]
+    fn test_media()
+    {
+        use exif::{Field, In, Tag, Value};
+
+        let jpeg = |width: u16, height: u16| {
+            let ascii = |value: &[u8]| Value::Ascii(vec![value.to_vec()]);
+            let fields = [
+                (Tag::Make, ascii(b"Canon")),
+                (Tag::Model, ascii(b"Canon EOS 5D")),
+                (Tag::DateTimeOriginal, ascii(b"2020:01:02 03:04:05")),
+            ]
+            .map(|(tag, value)| Field { tag, ifd_num: In::PRIMARY, value });
+            let mut writer = exif::experimental::Writer::new();
+            for field in &fields {
+                writer.push_field(field);
+            }
+            let mut tiff = io::Cursor::new(vec![]);
+            writer.write(&mut tiff, false).unwrap();
+            let tiff = tiff.into_inner();
+            let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+            jpeg.extend(((tiff.len() + 8) as u16).to_be_bytes());
+            jpeg.extend(b"Exif\0\0");
+            jpeg.extend(tiff);
+            // Start of frame with one component
+            jpeg.extend([0xff, 0xc0, 0, 11, 8]);
+            jpeg.extend(height.to_be_bytes());
+            jpeg.extend(width.to_be_bytes());
+            jpeg.extend([1, 1, 0x11, 0, 0xff, 0xd9]);
+            jpeg
+        };
+        let mp4_box = |box_type: &[u8; 4], content: &[u8]| {
+            let mut mp4_box = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+            mp4_box.extend(box_type);
+            mp4_box.extend(content);
+            mp4_box
+        };
+        // 2020-01-02 03:04:05 UTC since 1904
+        let mut mvhd = vec![0; 4];
+        mvhd.extend(((1577934245u64 + 2082844800) as u32).to_be_bytes());
+        mvhd.extend([0; 92]);
+        let mut tkhd = vec![0; 4 + 20 + 52];
+        tkhd.extend((1920u32 << 16).to_be_bytes());
+        tkhd.extend((1080u32 << 16).to_be_bytes());
+        let moov = [mp4_box(b"mvhd", &mvhd), mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd))].concat();
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\x02\0isomiso2mp41");
+        let mp4 = [ftyp, mp4_box(b"moov", &moov)].concat();
+
+        let (_, path) = copy_to_work_dir("simple", "media");
+        fs::write(path.join("simple/a/photo.jpg"), jpeg(4000, 3000)).unwrap();
+        fs::write(path.join("simple/b/resized.JPG"), jpeg(800, 600)).unwrap();
+        fs::write(path.join("simple/a/copy.jpg"), jpeg(800, 600)).unwrap();
+        fs::write(path.join("simple/a/clip.mp4"), mp4).unwrap();
+        let mut db = new_db(crawl_initial(&path));
+        update_media_info(&mut db, None);
+        let get_info = |name: &str| {
+            let entry = db.file_db.iter().find(|entry| entry.name == name).unwrap();
+            db.media[&entry.hash].clone()
+        };
+        let photo = media::MediaInfo {
+            taken: Some("2020-01-02 03:04:05".to_string()),
+            camera: Some("Canon EOS 5D".to_string()),
+            width: 4000,
+            height: 3000,
+        };
+        assert_eq!(get_info("photo.jpg"), photo);
+        assert_eq!((get_info("resized.JPG").width, get_info("resized.JPG").height), (800, 600));
+        let clip = media::MediaInfo {
+            taken: photo.taken.clone(),
+            camera: None,
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(get_info("clip.mp4"), clip);
+        assert_eq!(db.media.len(), 3);
+
+        let media_files = get_media_files(&db.file_db, &db.media, None);
+        assert_eq!(media_files.len(), 4);
+        let same_shots = get_same_shots(&db.file_db, &media_files);
+        assert_eq!(same_shots.len(), 1);
+        let names = same_shots[0].iter().map(|index| db.file_db.get(*index).name);
+        assert_eq!(names.collect::<Vec<_>>()[0], "photo.jpg");
+        assert_eq!(same_shots[0].len(), 3);
+    }
+
+    #[test]
+    fn test_similar_images()
+    {
+        let gradient = |width: u32, height: u32| {
+            image::GrayImage::from_fn(width, height, |x, y| {
+                image::Luma([((x * 255 / width + y * 64 / height) % 256) as u8])
+            })
+        };
+        let (_, path) = copy_to_work_dir("simple", "similar_images");
+        gradient(640, 480).save(path.join("simple/a/photo.png")).unwrap();
+        gradient(160, 120).save(path.join("simple/b/thumb.png")).unwrap();
+        gradient(640, 480).save(path.join("simple/b/copy.png")).unwrap();
+        let checkers = image::GrayImage::from_fn(640, 480, |x, y| {
+            image::Luma([if (x / 80 + y / 80) % 2 == 0 { 0 } else { 255 }])
+        });
+        checkers.save(path.join("simple/a/other.png")).unwrap();
+        let mut db = new_db(crawl_initial(&path));
+        update_image_hashes(&mut db, None);
+        assert_eq!(db.image_hashes.len(), 3);
+
+        let groups = get_similar_images(&db.file_db, &db.image_hashes, None, 4);
+        assert_eq!(groups.len(), 1);
+        let names = groups[0].iter().map(|index| db.file_db.get(*index).name);
+        let names = names.collect::<Vec<_>>();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[2], "thumb.png");
+        let prefix = path.join("simple/a");
+        assert!(get_similar_images(&db.file_db, &db.image_hashes, Some(&prefix), 4).is_empty());
+    }
+
+    #[test]
+    fn test_similar_audio()
+    {
+        use rand::SeedableRng;
+
+        // 16 bit PCM WAV of notes given as MIDI numbers, a quarter second each, with overtones
+        let wav = |notes: &[u8], sample_rate: u32, channels: u16, volume: f32| {
+            let mut samples = vec![];
+            for (n, note) in notes.iter().enumerate() {
+                let freq = 440.0 * 2f32.powf((*note as f32 - 69.0) / 12.0);
+                for i in 0..sample_rate / 4 {
+                    let t = (n as u32 * sample_rate / 4 + i) as f32 / sample_rate as f32;
+                    let phase = 2.0 * std::f32::consts::PI * freq * t;
+                    let value =
+                        phase.sin() + 0.5 * (2.0 * phase).sin() + 0.25 * (3.0 * phase).sin();
+                    let sample = (value * volume * 16000.0) as i16;
+                    for _ in 0..channels {
+                        samples.extend(sample.to_le_bytes());
+                    }
+                }
+            }
+            let mut wav = b"RIFF".to_vec();
+            wav.extend((36 + samples.len() as u32).to_le_bytes());
+            wav.extend(b"WAVEfmt ");
+            wav.extend(16u32.to_le_bytes());
+            wav.extend(1u16.to_le_bytes());
+            wav.extend(channels.to_le_bytes());
+            wav.extend(sample_rate.to_le_bytes());
+            wav.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+            wav.extend((channels * 2).to_le_bytes());
+            wav.extend(16u16.to_le_bytes());
+            wav.extend(b"data");
+            wav.extend((samples.len() as u32).to_le_bytes());
+            wav.extend(samples);
+            wav
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let song = (0..80).map(|_| rng.gen_range(48..84)).collect::<Vec<_>>();
+        let other_song = (0..80).map(|_| rng.gen_range(48..84)).collect::<Vec<_>>();
+
+        let (_, path) = copy_to_work_dir("simple", "similar_audio");
+        fs::write(path.join("simple/a/song.wav"), wav(&song, 44100, 2, 1.0)).unwrap();
+        fs::write(path.join("simple/b/song_mono.wav"), wav(&song, 22050, 1, 0.7)).unwrap();
+        fs::write(path.join("simple/b/other.wav"), wav(&other_song, 44100, 2, 1.0)).unwrap();
+        let mut db = new_db(crawl_initial(&path));
+        update_audio_fingerprints(&mut db, None);
+        assert_eq!(db.audio_fingerprints.len(), 3);
+        let get_fingerprint = |name: &str| {
+            let entry = db.file_db.iter().find(|entry| entry.name == name).unwrap();
+            &db.audio_fingerprints[&entry.hash]
+        };
+        assert_eq!(get_fingerprint("song.wav").duration, 20);
+        let song_frames = &get_fingerprint("song.wav").frames;
+        let error_rate =
+            |name: &str| audio::get_error_rate(song_frames, &get_fingerprint(name).frames);
+        assert!(error_rate("song_mono.wav") < 0.05);
+        assert!(error_rate("other.wav") > 0.3);
+
+        let groups = get_similar_audio(&db.file_db, &db.audio_fingerprints, None, 0.15);
+        assert_eq!(groups.len(), 1);
+        let names = groups[0].iter().map(|index| db.file_db.get(*index).name);
+        assert_eq!(names.collect::<Vec<_>>(), ["song.wav", "song_mono.wav"]);
+    }
+
+    #[test]
+    fn test_near_dupes()
+    {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let words = ["file", "db", "hash", "copy", "backup", "disk", "tree", "size", "the", "a"];
+        let mut get_text = || {
+            (0..4000).map(|_| words[rng.gen_range(0..words.len())]).collect::<Vec<_>>()
+        };
+        let mut text = get_text();
+        let other_text = get_text();
+        let (_, path) = copy_to_work_dir("simple", "near_dupes");
+        fs::write(path.join("simple/a/doc.txt"), text.join(" ")).unwrap();
+        text[1000] = "edited";
+        text.insert(3000, "inserted");
+        fs::write(path.join("simple/b/doc_edited.txt"), text.join(" ")).unwrap();
+        fs::write(path.join("simple/b/other.txt"), other_text.join(" ")).unwrap();
+        let mut db = new_db(crawl_initial(&path));
+        update_fuzzy_hashes(&mut db, None);
+        let get_fuzzy_hash = |name: &str| {
+            let entry = db.file_db.iter().find(|entry| entry.name == name).unwrap();
+            &db.fuzzy_hashes[&entry.hash]
+        };
+        let doc = get_fuzzy_hash("doc.txt");
+        assert_eq!(fuzzy::get_similarity(doc, get_fuzzy_hash("other.txt")), 0);
+        assert_eq!(fuzzy::get_similarity(doc, doc), 100);
+
+        let near_dupes = get_near_dupes(&db.file_db, &db.fuzzy_hashes, None, 60);
+        assert_eq!(near_dupes.len(), 1);
+        let (score, a, b) = near_dupes[0];
+        assert!(score >= 80);
+        let names = [a, b].map(|index| db.file_db.get(index).name.to_os_string());
+        assert!(names.contains(&OsString::from("doc.txt")));
+        assert!(names.contains(&OsString::from("doc_edited.txt")));
+    }
+
+    #[test]
+    fn test_chunk_dupes()
+    {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut get_data = |len: usize| (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+        let image = get_data(6 << 20);
+        let mut changed_image = image.clone();
+        changed_image.splice(3 << 20..3 << 20, get_data(1000));
+        let other = get_data(6 << 20);
+        let (_, path) = copy_to_work_dir("simple", "chunk_dupes");
+        fs::write(path.join("simple/a/disk.img"), &image).unwrap();
+        fs::write(path.join("simple/b/disk.img"), &changed_image).unwrap();
+        fs::write(path.join("simple/b/other.img"), &other).unwrap();
+        let mut db = new_db(crawl_initial(&path));
+        update_chunks(&mut db, None, 1 << 20);
+        assert_eq!(db.chunks.len(), 3);
+        let chunk_sizes = db.chunks.values().flatten().map(|chunk| chunk.size as u64);
+        assert_eq!(chunk_sizes.sum::<u64>(), (18 << 20) + 1000);
+
+        let (chunk_dupes, savable) = get_chunk_dupes(&db.file_db, &db.chunks, None);
+        assert_eq!(chunk_dupes.len(), 1);
+        let (shared, a, b) = chunk_dupes[0];
+        assert!(shared > 4 << 20 && shared < 6 << 20);
+        assert_eq!(savable, shared);
+        let names = [a, b].map(|index| db.file_db.get(index).name.to_os_string());
+        assert_eq!(names, [OsString::from("disk.img"), OsString::from("disk.img")]);
+    }
+
+    #[test]
+    fn test_dedup_dirs()
+    {
+        let (_, path) = copy_to_work_dir("simple", "dedup_dirs");
+        let simple = path.join("simple");
+        copy(simple.join("b"), simple.join("c"), &CopyOptions::new()).unwrap();
+        fs::write(simple.join("a/f3"), "other").unwrap();
+        let mut file_db = crawl_initial(&path);
+        propagate_sizes(&mut file_db);
+        propagate_hashes(&mut file_db);
+
+        // b/d and c/b/d are only reported through b and c/b
+        let groups = get_dupe_dir_groups(&file_db, None);
+        assert_eq!(groups.len(), 1);
+        let (size, indices) = &groups[0];
+        assert_eq!(*size, 12);
+        let paths = indices.iter().map(|index| get_full_path(&file_db, *index));
+        assert_eq!(paths.collect::<Vec<_>>(), [simple.join("b"), simple.join("c/b")]);
+        assert!(get_dupe_dir_groups(&file_db, Some(&simple.join("c"))).is_empty());
+    }
+
+    #[test]
+    fn test_similar_dirs()
+    {
+        let (_, path) = copy_to_work_dir("simple", "similar_dirs");
+        let simple = path.join("simple");
+        for i in 0..10 {
+            fs::write(simple.join(format!("b/d/f{}", 10 + i)), format!("file {}", i)).unwrap();
+        }
+        copy(simple.join("b"), simple.join("c"), &CopyOptions::new()).unwrap();
+        fs::write(simple.join("b/d/f10"), "changed").unwrap();
+        fs::write(simple.join("c/b/new"), "new").unwrap();
+        let mut file_db = crawl_initial(&path);
+        propagate_sizes(&mut file_db);
+        propagate_hashes(&mut file_db);
+        let children = ChildrenIndex::new(&file_db);
+        let find = |path: &Path| children.find_path(&file_db, path).unwrap();
+
+        let (b, c_b) = (find(&simple.join("b")), find(&simple.join("c/b")));
+        let comparison = compare_dirs(&file_db, &children, b, c_b);
+        assert_eq!((comparison.num_shared, comparison.num_files), (20, 23));
+        assert_eq!(comparison.only_a, [find(&simple.join("b/d/f10"))]);
+        let only_b = [find(&simple.join("c/b/d/f10")), find(&simple.join("c/b/new"))];
+        assert_eq!(comparison.only_b, only_b);
+
+        // b/d and c/b/d are only reported through b and c/b
+        let similar_dirs = get_similar_dirs(&file_db, &children, 80.0, 10);
+        assert_eq!(similar_dirs.len(), 1);
+        assert_eq!((similar_dirs[0].0, similar_dirs[0].1), (b, c_b));
+        assert!(get_similar_dirs(&file_db, &children, 95.0, 10).is_empty());
+    }
+
+    #[test]
+    fn test_update_detects_moves()
+    {
+        let (_, path) = copy_to_work_dir("simple", "update_detects_moves");
+        let mut file_db = crawl_initial(&path);
+        // A hash that cannot result from hashing shows that f1 is not hashed again
+        let f1_index = file_db.iter().position(|entry| entry.name == "f1").unwrap();
+        *file_db.entry_mut(f1_index as EntryIndex).hash = [7; 32];
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        file_db_name.push("test_update_detects_moves.db");
+        save_compressed(&file_db_name, &new_db(file_db));
+
+        fs::rename(path.join("simple/a/f1"), path.join("simple/b/d/f1_moved")).unwrap();
+        update(&file_db_name, &path, None, &CrawlOptions::default());
+        let file_db = load_file_db(&file_db_name, None);
+        assert!(!file_db.iter().any(|entry| entry.name == "f1"));
+        let moved_index = file_db.iter().position(|entry| entry.name == "f1_moved").unwrap();
+        assert_eq!(file_db.get(moved_index as EntryIndex).hash, [7; 32]);
+        let moved_path = get_full_path(&file_db, moved_index as EntryIndex);
+        assert!(moved_path.ends_with("simple/b/d/f1_moved"));
+    }
+
+    #[test]
+    fn test_vanished_files_device()
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let (_, path) = copy_to_work_dir("simple", "vanished_files_device");
+        let mut file_db = crawl_initial(&path);
+        let f1_index = file_db.iter().position(|entry| entry.name == "f1").unwrap();
+        *file_db.entry_mut(f1_index as EntryIndex).hash = [7; 32];
+        fs::rename(path.join("simple/a/f1"), path.join("simple/b/d/f1_moved")).unwrap();
+        let vanished_files = prune_deleted_paths(&mut file_db);
+        let metadata = fs::metadata(path.join("simple/b/d/f1_moved")).unwrap();
+        let (size, modified) = (metadata.len(), get_secs(&metadata.modified().unwrap()));
+        let key = (size, modified, metadata.dev(), metadata.ino());
+        assert!(vanished_files.contains_key(&key));
+
+        // The same inode on another device is another file
+        let mut vanished_elsewhere = VanishedFilesMap::new();
+        let other_key = (size, modified, metadata.dev() + 1, metadata.ino());
+        vanished_elsewhere.insert(other_key, vanished_files[&key].clone());
+        let children = ChildrenIndex::new(&file_db);
+        let mut path_to_index = build_path_to_index_map(&file_db, &children);
+        let dir_to_files = build_dir_to_files_map(&file_db, &children);
+        add_dir_recursive_ext(
+            &path,
+            &mut file_db,
+            &mut path_to_index,
+            &dir_to_files,
+            Path::new(""),
+            &CrawlOptions::default(),
+            &ArchiveNesting::default(),
+            &vanished_elsewhere,
+            None,
+        );
+        let moved = file_db.iter().find(|entry| entry.name == "f1_moved").unwrap();
+        assert_ne!(moved.hash, [7; 32]);
+    }
+
+    #[test]
+    fn test_mv()
+    {
+        let (_, path) = copy_to_work_dir("simple", "mv");
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        file_db_name.push("test_mv.db");
+        {
+            let file_db = crawl_initial(&path);
+            dump_file_db(&file_db);
+            check_expected_results("mv_before", &file_db);
+            save_compressed(&file_db_name, &new_db(file_db));
+        }
+        mv(
+            &file_db_name,
+            Path::new("/home/mrich/projects/filedb/test_work/mv/simple/b"),
+            Path::new("/home/mrich/projects/filedb/test_work/mv/simple/a"),
+            false,
+        );
+        let file_db_new = load_compressed(&file_db_name).file_db;
+        dump_file_db(&file_db_new);
+        check_expected_results("mv_after", &file_db_new);
+    }
+
+    #[test]
+    fn test_mv_db_only()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "mv_db_only");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_mv_db_only.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let get_hash = |path: &Path| {
+            let file_db = load_file_db(&file_db_name, None);
+            let index = ChildrenIndex::new(&file_db).find_path(&file_db, path);
+            index.map(|index| file_db.get(index).hash)
+        };
+        let hash = get_hash(&path.join("b/d/f2"));
+
+        // Not moved on disk yet
+        assert!(!mv_db_only(&file_db_name, &path.join("b/d"), &path.join("a"), false));
+        fs::rename(path.join("b/d"), path.join("a/d")).unwrap();
+        assert!(mv_db_only(&file_db_name, &path.join("b/d"), &path.join("a"), false));
+        assert_eq!(get_hash(&path.join("a/d/f2")), hash);
+        assert_eq!(get_hash(&path.join("b/d/f2")), None);
+        // b is kept although empty now
+        assert!(get_hash(&path.join("b")).is_some());
+
+        // Into a dir added after the one moved
+        let later_path = work_dir.join("later");
+        fs::create_dir_all(&later_path).unwrap();
+        add(&file_db_name, &later_path, None, false, &CrawlOptions::default());
+        fs::rename(path.join("a/d"), later_path.join("d")).unwrap();
+        assert!(mv_db_only(&file_db_name, &path.join("a/d"), &later_path, false));
+        assert_eq!(get_hash(&later_path.join("d/f2")), hash);
+        assert_eq!(get_hash(&path.join("a/d")), None);
+    }
+
+    #[test]
+    fn test_forget()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "forget");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_forget.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let file_db = load_file_db(&file_db_name, None);
+        let num_entries = file_db.len();
+        let root_size = file_db.get(0).size;
+
+        assert!(!forget(&file_db_name, &path.join("missing"), false));
+        assert!(forget(&file_db_name, &path.join("b"), true));
+        assert_eq!(load_file_db(&file_db_name, None).len(), num_entries);
+        assert!(forget(&file_db_name, &path.join("b"), false));
+        let file_db = load_file_db(&file_db_name, None);
+        // b, d and f2
+        assert_eq!(file_db.len(), num_entries - 3);
+        assert!(ChildrenIndex::new(&file_db).find_path(&file_db, &path.join("b")).is_none());
+        assert_eq!(file_db.get(0).size, root_size - 12);
+        // Still on disk
+        assert!(path.join("b/d/f2").exists());
+    }
+
+    #[test]
+    fn test_file_db_columns()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "file_db_columns");
+        let path = work_dir.join("simple");
+        let mut file_db = crawl_initial(&path);
+        let f2 = file_db.iter().position(|entry| entry.name == "f2").unwrap() as EntryIndex;
+        // Names are interned
+        let num_names = file_db.num_names();
+        file_db.set_name(f2, OsStr::new("f1"));
+        assert_eq!(file_db.num_names(), num_names);
+        file_db.set_name(f2, OsStr::new("renamed"));
+        assert_eq!(file_db.num_names(), num_names + 1);
+        assert_eq!(get_full_path(&file_db, f2), path.join("b/d/renamed"));
+        let entries = file_db.iter().map(|entry| entry.to_entry()).collect::<Vec<_>>();
+        assert_eq!(entries.iter().cloned().collect::<FileDb>(), file_db);
+
+        // Stored with the names
+        let file_db_name = work_dir.join("test_file_db_columns.db");
+        save_compressed(&file_db_name, &new_db(file_db.clone()));
+        let loaded = load_file_db(&file_db_name, None);
+        assert_eq!(loaded, file_db);
+        assert_eq!(loaded.get(f2).name, "renamed");
+        assert_eq!(loaded.get(f2).size, 12);
+    }
+
+    #[test]
+    fn test_partitions()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "partitions");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_partitions.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        assert!(load_partition(&file_db_name, &path.join("b")).is_none());
+        partition(&file_db_name);
+
+        let file_db = load_partition(&file_db_name, &path.join("b/d")).unwrap();
+        let children = ChildrenIndex::new(&file_db);
+        let f2 = children.find_path(&file_db, &path.join("b/d/f2")).unwrap();
+        assert_eq!(file_db.get(f2).size, 12);
+        assert!(children.find_path(&file_db, &path.join("a")).is_none());
+        // Above the top-level dirs a, b and c
+        assert!(load_partition(&file_db_name, &path).is_none());
+
+        // Kept current by saving
+        fs::write(path.join("b/new"), "new").unwrap();
+        update(&file_db_name, &path, None, &CrawlOptions::default());
+        let file_db = load_partition(&file_db_name, &path.join("b")).unwrap();
+        assert!(file_db.iter().any(|entry| entry.name == "new"));
+        let mut file = fs::OpenOptions::new().append(true).open(&file_db_name).unwrap();
+        file.write_all(b"changed elsewhere").unwrap();
+        assert!(load_partition(&file_db_name, &path.join("b")).is_none());
+    }
+
+    #[test]
+    fn test_stream_entries()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "stream_entries");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_stream_entries.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        add(&file_db_name, &path, Some("snapshot"), false, &CrawlOptions::default());
+        let file_db = load_file_db(&file_db_name, None);
+        let expected = (0..file_db.len() as EntryIndex)
+            .map(|index| (get_full_path(&file_db, index), file_db.get(index).to_entry()))
+            .collect::<Vec<_>>();
+
+        let mut streamed = vec![];
+        let visit = |path: &Path, entry: &FileDbEntry| streamed.push((path.into(), entry.clone()));
+        assert!(stream_entries(&file_db_name, visit));
+        assert_eq!(streamed, expected);
+        // Snapshots are loaded
+        let mut paths = vec![];
+        for_each_entry(&file_db_name, Some("snapshot"), |path, _| paths.push(path.to_owned()));
+        assert_eq!(paths, expected.into_iter().map(|(path, _)| path).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_dump_options()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "dump_options");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_dump_options.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        let dump_paths = |options: &DumpOptions| {
+            let mut paths = vec![];
+            for_each_dump_entry(&file_db_name, None, options, |entry_path, _| {
+                paths.push(entry_path.strip_prefix(&path).unwrap().to_owned())
+            });
+            paths
+        };
+
+        let options = DumpOptions {
+            prefix: Some(path.clone()),
+            is_dir: Some(false),
+            sort: Some(DumpOrder::Name),
+            ..Default::default()
+        };
+        let files = [PathBuf::from("a/f1"), PathBuf::from("b/d/f2")];
+        assert_eq!(dump_paths(&options), files);
+        let options = DumpOptions {
+            prefix: Some(path.clone()),
+            max_depth: Some(1),
+            sort: Some(DumpOrder::Name),
+            ..Default::default()
+        };
+        let top_level = ["", "a", "b", "c"].map(PathBuf::from);
+        assert_eq!(dump_paths(&options), top_level);
+        let options = DumpOptions {
+            prefix: Some(path.join("b")),
+            is_dir: Some(false),
+            min_size: 12,
+            sort: Some(DumpOrder::Size),
+            ..Default::default()
+        };
+        assert_eq!(dump_paths(&options), [PathBuf::from("b/d/f2")]);
+        // Unrelated prefix
+        let options = DumpOptions { prefix: Some(work_dir.join("other")), ..Default::default() };
+        assert!(dump_paths(&options).is_empty());
+    }
+
+    #[test]
+    fn test_normalize_path()
+    {
+        assert_eq!(normalize_path(Path::new("/a/b/")), Path::new("/a/b"));
+        assert_eq!(normalize_path(Path::new("/a//b/./")), Path::new("/a/b"));
+        assert_eq!(normalize_path(Path::new("/")), Path::new("/"));
+        assert_eq!(normalize_path(Path::new("a/b//")), Path::new("a/b"));
+    }
+
+    #[test]
+    fn test_long_paths()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "long_paths");
+        let path = work_dir.join("simple");
+        let mut long_dir = path.clone();
+        for i in 0..6 {
+            long_dir.push(format!("{}{}", i, "d".repeat(60)));
+        }
+        fs::create_dir_all(&long_dir).unwrap();
+        fs::write(long_dir.join("f"), b"long").unwrap();
+        let file_db = crawl_initial(&path);
+        let children = ChildrenIndex::new(&file_db);
+        for index in 0..file_db.len() as EntryIndex {
+            let full_path = get_full_path(&file_db, index);
+            assert_eq!(children.find_path(&file_db, &full_path), Some(index));
+        }
+        let f = children.find_path(&file_db, &long_dir.join("f")).unwrap();
+        let hash = get_hash_for_file(&long_dir.join("f"), HashAlgorithm::default()).unwrap();
+        assert_eq!(file_db.get(f).hash, hash);
+    }
+
+    #[test]
+    fn test_path_formats()
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let format = |path: &[u8], format: PathFormat| {
+            let mut out = vec![];
+            write_path(&mut out, Path::new(OsStr::from_bytes(path)), format).unwrap();
+            String::from_utf8_lossy(&out).into_owned()
+        };
+        assert_eq!(format(b"/a/b-1.txt", PathFormat::Escaped), "/a/b-1.txt\n");
+        assert_eq!(format(b"/a b/it's", PathFormat::Escaped), "'/a b/it'\\''s'\n");
+        assert_eq!(format(b"/a\nb\xff", PathFormat::Escaped), "$'/a\\x0ab\\xff'\n");
+        assert_eq!(format(b"/a\nb", PathFormat::Lines), "/a\\nb\n");
+        assert_eq!(format(b"/a\nb", PathFormat::NullTerminated), "/a\nb\0");
+    }
+
+    #[test]
+    fn test_named_roots()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "named_roots");
+        let path = work_dir.join("simple");
+        let mut db = new_db(crawl_initial(&path));
+        propagate_sizes(&mut db.file_db);
+        db.roots.push(roots::NamedRoot { name: "drive".to_string(), path: path.clone() });
+        let num_entries = db.file_db.len();
+        let root_size = db.file_db.get(0).size;
+        let mount_dir = PathBuf::from(TEST_WORK_DIR).join("named_roots_mount");
+        let new_path = mount_dir.join("drive");
+        let _ = fs::remove_dir_all(&mount_dir);
+        fs::create_dir_all(&mount_dir).unwrap();
+        fs::rename(&path, &new_path).unwrap();
+
+        let bindings = [("drive".to_string(), new_path.clone())];
+        assert!(roots::bind_roots(&mut db, &bindings));
+        assert!(!roots::bind_roots(&mut db, &bindings));
+        assert_eq!(db.roots[0].path, new_path);
+        let file_db = &db.file_db;
+        let children = ChildrenIndex::new(file_db);
+        let index = children.find_path(file_db, &new_path.join("b/d/f2")).unwrap();
+        assert_eq!(file_db.get(index).size, 12);
+        // work_dir is left empty and removed, named_roots_mount added instead
+        assert!(children.find_path(file_db, &work_dir).is_none());
+        assert_eq!(file_db.len(), num_entries);
+        assert_eq!(file_db.get(0).size, root_size);
+        assert!((1..file_db.len() as EntryIndex).all(|index| file_db.get(index).parent < index));
+        let file_db_name = mount_dir.join("test_named_roots.db");
+        save_compressed(&file_db_name, &db);
+        assert_eq!(load_compressed(&file_db_name).roots, db.roots);
+        // Not possible on top of an existing path
+        assert!(!roots::relocate_subtree(&mut db.file_db, &new_path, &mount_dir, true));
+        let _ = fs::remove_dir_all(&mount_dir);
+    }
+
+    #[test]
+    fn test_relocate_subtree_into_later_dir()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "relocate_subtree");
+        let path = work_dir.join("simple");
+        let later_path = work_dir.join("later");
+        fs::create_dir_all(later_path.join("e")).unwrap();
+        fs::write(later_path.join("e/f3"), "f3").unwrap();
+        let file_db_name = work_dir.join("test_relocate_subtree.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        add(&file_db_name, &later_path, None, false, &CrawlOptions::default());
+        let mut file_db = load_file_db(&file_db_name, None);
+        let num_entries = file_db.len();
+        let children = ChildrenIndex::new(&file_db);
+        let b_index = children.find_path(&file_db, &path.join("b")).unwrap();
+        let e_index = children.find_path(&file_db, &later_path.join("e")).unwrap();
+        assert!(b_index < e_index);
+        let b_size = file_db.get(b_index).size;
+
+        // new is added between e and b
+        let new_path = later_path.join("e/new/b");
+        assert!(roots::relocate_subtree(&mut file_db, &path.join("b"), &new_path, false));
+        assert_eq!(file_db.len(), num_entries + 1);
+        assert!((1..file_db.len() as EntryIndex).all(|index| file_db.get(index).parent < index));
+        let children = ChildrenIndex::new(&file_db);
+        assert!(children.find_path(&file_db, &new_path.join("d/f2")).is_some());
+        assert!(children.find_path(&file_db, &path.join("b")).is_none());
+        let e_index = children.find_path(&file_db, &later_path.join("e")).unwrap();
+        assert_eq!(file_db.get(e_index).size, b_size + 2);
+    }
+
+    #[test]
+    fn test_update_all_roots()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "update_all_roots");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_update_all_roots.db");
+        for root_dir in [path.join("a"), path.join("b")] {
+            add(&file_db_name, &root_dir, None, false, &CrawlOptions::default());
+        }
+        let mut db = load_compressed(&file_db_name);
+        roots::register_root(&mut db, &path.join("b/d"));
+        roots::register_root(&mut db, &work_dir.join("other/a"));
+        let names = db.roots.iter().map(|root| root.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "d", "a-2"]);
+        // b/d is updated with b
+        assert_eq!(roots::get_update_roots(&db)[..2], [path.join("a"), path.join("b")]);
+        db.roots.truncate(3);
+        save_compressed(&file_db_name, &db);
+
+        fs::write(path.join("a/new"), "new").unwrap();
+        fs::write(path.join("b/d/new"), "new").unwrap();
+        update_all_roots(&file_db_name, None, &CrawlOptions::default());
+        let file_db = load_file_db(&file_db_name, None);
+        let children = ChildrenIndex::new(&file_db);
+        assert!(children.find_path(&file_db, &path.join("a/new")).is_some());
+        assert!(children.find_path(&file_db, &path.join("b/d/new")).is_some());
+        assert!(remove_root(&file_db_name, "d"));
+        assert!(!remove_root(&file_db_name, "d"));
+        assert_eq!(load_compressed(&file_db_name).roots.len(), 2);
+    }
+
+    #[test]
+    fn test_update_prefix()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "update_prefix");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_update_prefix.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        fs::write(path.join("a/new"), "new").unwrap();
+        fs::write(path.join("b/d/new"), "new").unwrap();
+        fs::remove_file(path.join("b/d/f2")).unwrap();
+        let options = CrawlOptions::default();
+
+        assert!(!update_prefix(&file_db_name, &work_dir, None, &options));
+        assert!(!update_prefix(&file_db_name, &path.join("missing"), None, &options));
+        assert!(update_prefix(&file_db_name, &path.join("b"), None, &options));
+        let file_db = load_file_db(&file_db_name, None);
+        let children = ChildrenIndex::new(&file_db);
+        let has_path = |relative_path| children.find_path(&file_db, &path.join(relative_path));
+        assert!(has_path("b/d/new").is_some());
+        assert!(has_path("b/d/f2").is_none());
+        // Outside of the prefix
+        assert!(has_path("a/new").is_none());
+    }
+
+    #[test]
+    fn test_rebase()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "rebase");
+        let path = work_dir.join("simple");
+        let file_db_name = work_dir.join("test_rebase.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        add(&file_db_name, &path, Some("snapshot"), false, &CrawlOptions::default());
+        let mut db = load_compressed(&file_db_name);
+        db.roots.push(roots::NamedRoot { name: "drive".to_string(), path: path.join("b") });
+        save_compressed(&file_db_name, &db);
+        let new_path = work_dir.join("remounted");
+        fs::rename(&path, &new_path).unwrap();
+
+        assert!(!rebase(&file_db_name, &work_dir.join("missing"), &new_path));
+        assert!(rebase(&file_db_name, &path, &new_path));
+        let db = load_compressed(&file_db_name);
+        let roots = db.roots.iter().map(|root| root.path.clone()).collect::<Vec<_>>();
+        assert_eq!(roots, [new_path.clone(), new_path.join("b")]);
+        for file_db in [&db.file_db, &db.snapshots[0].file_db] {
+            let children = ChildrenIndex::new(file_db);
+            assert!(children.find_path(file_db, &new_path.join("b/d/f2")).is_some());
+            assert!(children.find_path(file_db, &path).is_none());
+        }
+    }
+
+    #[test]
+    fn test_rebase_into_later_dir()
+    {
+        let (_, work_dir) = copy_to_work_dir("simple", "rebase_into_later_dir");
+        let path = work_dir.join("simple");
+        let data_path = work_dir.join("data");
+        fs::create_dir_all(&data_path).unwrap();
+        fs::write(data_path.join("f3"), "f3").unwrap();
+        let file_db_name = work_dir.join("test_rebase_into_later_dir.db");
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        // Indexed after simple
+        add(&file_db_name, &data_path, None, false, &CrawlOptions::default());
+        let new_path = data_path.join("simple");
+        fs::rename(&path, &new_path).unwrap();
+
+        assert!(rebase(&file_db_name, &path, &new_path));
+        let file_db = load_file_db(&file_db_name, None);
+        assert!((1..file_db.len() as EntryIndex).all(|index| file_db.get(index).parent < index));
+        let children = ChildrenIndex::new(&file_db);
+        assert!(children.find_path(&file_db, &new_path.join("b/d/f2")).is_some());
+        assert!(children.find_path(&file_db, &path).is_none());
+    }
+
+    #[test]
+    fn test_save_keeps_backup()
+    {
+        let (_, path) = copy_to_work_dir("simple", "save");
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        file_db_name.push("test_save.db");
+        let _ = fs::remove_file(&file_db_name);
+        let mut db = new_db(crawl_initial(&path));
+        save_compressed(&file_db_name, &db);
+        let len_before = db.file_db.len();
+        db.file_db.truncate(len_before - 1);
+        save_compressed(&file_db_name, &db);
+
+        assert!(!get_db_side_file_name(&file_db_name, "tmp").exists());
+        assert_eq!(load_compressed(&file_db_name).file_db.len(), len_before - 1);
+        let db_bak = load_compressed(&get_db_side_file_name(&file_db_name, "bak"));
+        assert_eq!(db_bak.file_db.len(), len_before);
+    }
+
+    #[test]
+    fn test_lock_db()
+    {
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        fs::create_dir_all(&file_db_name).unwrap();
+        file_db_name.push("test_lock.db");
+        let lock = lock_db(&file_db_name, false);
+        assert!(lock.is_some());
+        assert!(lock_db(&file_db_name, false).is_none());
+        drop(lock);
+        assert!(lock_db(&file_db_name, false).is_some());
+    }
+
+    #[test]
+    fn test_snapshots()
+    {
+        let (_, path) = copy_to_work_dir("simple", "snapshots");
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        file_db_name.push("test_snapshots.db");
+        let _ = fs::remove_file(&file_db_name);
+        add(&file_db_name, &path, None, false, &CrawlOptions::default());
+        add(&file_db_name, &path, Some("2024-06-01"), false, &CrawlOptions::default());
+        add(&file_db_name, &path, Some("2024-06-02"), false, &CrawlOptions::default());
+        snapshots_list(&file_db_name);
+
+        let get_names_and_hashes = |file_db: &FileDb| {
+            file_db
+                .iter()
+                .map(|entry| (entry.name.to_os_string(), entry.hash))
+                .collect::<Vec<_>>()
+        };
+        let file_db = load_file_db(&file_db_name, None);
+        let file_db_snapshot = load_file_db(&file_db_name, Some("2024-06-01"));
+        assert_eq!(get_names_and_hashes(&file_db_snapshot), get_names_and_hashes(&file_db));
+        snapshot_delete(&file_db_name, "2024-06-01");
+        let db = load_compressed(&file_db_name);
+        assert_eq!(db.snapshots.len(), 1);
+        assert_eq!(db.snapshots[0].name, "2024-06-02");
+        assert_eq!(db.file_db, file_db);
+    }
+
+    #[test]
+    fn test_resume_add()
+    {
+        let (_, path) = copy_to_work_dir("simple", "resume_add");
+        let mut file_db_name = PathBuf::from(TEST_WORK_DIR);
+        file_db_name.push("test_resume_add.db");
+        let _ = fs::remove_file(&file_db_name);
+        let checkpoint_name = get_db_side_file_name(&file_db_name, "checkpoint");
+
+        // Simulate an add interrupted after the first few entries
+        let mut file_db = crawl_initial(&path);
+        let expected_paths = (0..file_db.len())
+            .map(|index| get_full_path(&file_db, index as EntryIndex))
+            .collect::<HashSet<_>>();
+        file_db.truncate(file_db.len() - 3);
+        let header = CheckpointHeader {
+            root_dir: path.clone(),
+            snapshot: None,
+            last_path: get_full_path(&file_db, (file_db.len() - 1) as EntryIndex),
+        };
+        save_checkpoint(&checkpoint_name, &header, &file_db);
+
+        add(&file_db_name, &path, None, true, &CrawlOptions::default());
+        assert!(!checkpoint_name.exists());
+        let file_db = load_file_db(&file_db_name, None);
+        let paths = (0..file_db.len())
+            .map(|index| get_full_path(&file_db, index as EntryIndex))
+            .collect::<HashSet<_>>();
+        assert_eq!(paths, expected_paths);
+        assert_eq!(file_db.len(), expected_paths.len());
+    }
+
+    #[test]
+    fn test_diff_file_dbs()
+    {
+        let mut path_buf = PathBuf::from(TEST_DATA_DIR);
+        path_buf.push("simple");
+        let file_db_a = crawl_initial(&path_buf);
+        let mut file_db_b = file_db_a.clone();
+        // f2 changes size, f1 changes content, d gets a new file
+        let f2_index = file_db_b.iter().position(|entry| entry.name == "f2").unwrap();
+        *file_db_b.entry_mut(f2_index as EntryIndex).size += 1;
+        let f1_index = file_db_b.iter().position(|entry| entry.name == "f1").unwrap();
+        *file_db_b.entry_mut(f1_index as EntryIndex).hash = [1; 32];
+        let mut new_entry = file_db_b.get(f2_index as EntryIndex).to_entry();
+        new_entry.name = OsString::from("f3");
+        file_db_b.push(new_entry);
+
+        let result = diff_file_dbs(&file_db_a, &file_db_b);
+        assert_eq!(result.added.len(), 1);
+        assert!(result.added[0].ends_with("simple/b/d/f3"));
+        assert!(result.removed.is_empty());
+        assert_eq!(result.size_changed.len(), 1);
+        assert!(result.size_changed[0].path.ends_with("simple/b/d/f2"));
+        assert_eq!(result.content_changed.len(), 1);
+        assert!(result.content_changed[0].ends_with("simple/a/f1"));
+
+        let result = diff_file_dbs(&file_db_b, &file_db_a);
+        assert_eq!(result.removed.len(), 1);
+        assert!(result.added.is_empty());
+    }
+
+    #[test]
+    fn test_merge_file_db()
+    {
+        let mut path_a = PathBuf::from(TEST_DATA_DIR);
+        path_a.push("simple/a");
+        let mut path_b = PathBuf::from(TEST_DATA_DIR);
+        path_b.push("simple/b");
+        let file_db_a = crawl_initial(&path_a);
+        let file_db_b = crawl_initial(&path_b);
+
+        let mut file_db = FileDb::new();
+        let mut path_to_index = PathToIndexMap::new();
+        merge_file_db(&mut file_db, &mut path_to_index, &file_db_a);
+        let stats = merge_file_db(&mut file_db, &mut path_to_index, &file_db_b);
+        assert_eq!(stats.added, 3);
+        assert_eq!(stats.collisions, 0);
+        // simple/ without the empty dir c
+        let mut path_simple = PathBuf::from(TEST_DATA_DIR);
+        path_simple.push("simple");
+        assert_eq!(file_db.len(), crawl_initial(&path_simple).len() - 1);
+        for index in 0..file_db.len() {
+            let path = get_full_path(&file_db, index as EntryIndex);
+            assert_eq!(path_to_index[path.as_os_str()], index as EntryIndex);
+        }
+
+        let stats = merge_file_db(&mut file_db, &mut path_to_index, &file_db_b);
+        assert_eq!(stats.added, 0);
+        assert_eq!(stats.collisions, 1);
+        propagate_sizes(&mut file_db);
+        propagate_hashes(&mut file_db);
+    }
+
+    #[test]
+    fn test_set_operations()
+    {
+        let mut path_simple = PathBuf::from(TEST_DATA_DIR);
+        path_simple.push("simple");
+        let mut path_b = path_simple.clone();
+        path_b.push("b");
+        let file_db_a = crawl_initial(&path_simple);
+        let file_db_b = crawl_initial(&path_b);
+        let get_names = |file_db: &FileDb, indices: &[EntryIndex]| {
+            indices
+                .iter()
+                .map(|index| file_db.get(*index).name.to_os_string())
+                .collect::<Vec<_>>()
+        };
+
+        let (indices_a, indices_b) =
+            select_set_operation(&file_db_a, &file_db_b, SetOperation::Subtract);
+        assert_eq!(get_names(&file_db_a, &indices_a), vec!["f1"]);
+        assert!(indices_b.is_empty());
+        let (indices_a, _) = select_set_operation(&file_db_a, &file_db_b, SetOperation::Intersect);
+        assert_eq!(get_names(&file_db_a, &indices_a), vec!["f2"]);
+        let (indices_a, indices_b) =
+            select_set_operation(&file_db_b, &file_db_a, SetOperation::UnionByHash);
+        assert_eq!(get_names(&file_db_b, &indices_a), vec!["f2"]);
+        assert_eq!(get_names(&file_db_a, &indices_b), vec!["f1"]);
+
+        // Only f1 and its parents
+        let extracted = extract_entries(&file_db_a, &indices_b);
+        assert_eq!(extracted.len(), 9);
+        assert_eq!(get_full_path(&extracted, 8), get_full_path(&file_db_a, indices_b[0]));
+    }
+
+    #[test]
+    fn test_parse_size()
+    {
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("4kb"), Some(4096));
+        assert_eq!(parse_size("1.5G"), Some(3 << 29));
+        assert_eq!(parse_size("2T"), Some(2 << 40));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn test_select_entries_to_verify()
+    {
+        use rand::SeedableRng;
+
+        let mut path_buf = PathBuf::from(TEST_DATA_DIR);
+        path_buf.push("simple");
+        let mut file_db = crawl_initial(&path_buf);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert!(select_entries_to_verify(&file_db, 0, u64::MAX, &mut rng).is_empty());
+        // Budget is exceeded by the first file
+        assert_eq!(select_entries_to_verify(&file_db, 10, 1, &mut rng).len(), 1);
+        let all = select_entries_to_verify(&file_db, 10, u64::MAX, &mut rng);
+        assert_eq!(all.len(), 2);
+        file_db.entry_mut(all[0]).verified = 10;
+        let remaining = select_entries_to_verify(&file_db, 10, u64::MAX, &mut rng);
+        assert_eq!(remaining, vec![all[1]]);
+    }
+
+    #[test]
+    fn test_verify_entry()
+    {
+        let (_, path) = copy_to_work_dir("simple", "verify");
+        let file_db = crawl_initial(&path);
+        let index = file_db.iter().position(|entry| entry.name == "f2").unwrap() as EntryIndex;
+        let f2_path = get_full_path(&file_db, index);
+        let verify = || verify_entry(&f2_path, &file_db.get(index), HashAlgorithm::Blake3);
+        assert_eq!(verify(), VerifyResult::Ok);
+
+        // Same size and modification time, different content
+        let modified = fs::metadata(&f2_path).unwrap().modified().unwrap();
+        let mut contents = fs::read(&f2_path).unwrap();
+        contents[0] ^= 1;
+        fs::write(&f2_path, &contents).unwrap();
+        File::options().write(true).open(&f2_path).unwrap().set_modified(modified).unwrap();
+        assert_eq!(verify(), VerifyResult::Mismatch);
+
+        contents.push(0);
+        fs::write(&f2_path, &contents).unwrap();
+        assert_eq!(verify(), VerifyResult::Changed);
+        fs::remove_file(&f2_path).unwrap();
+        assert_eq!(verify(), VerifyResult::Missing);
+    }
+
+    #[test]
+    fn test_remove_subtrees()
+    {
+        let mut path_buf = PathBuf::from(TEST_DATA_DIR);
+        path_buf.push("simple");
+        let mut file_db = crawl_initial(&path_buf);
+        let len_before = file_db.len();
+        let b_index = file_db.iter().position(|entry| entry.name == "b").unwrap();
+        let mut subtree_roots = HashSet::new();
+        subtree_roots.insert(b_index as EntryIndex);
+        // b, b/d, b/d/f2
+        assert_eq!(remove_subtrees(&mut file_db, &subtree_roots), 3);
+        assert_eq!(file_db.len(), len_before - 3);
+        let f1_index = file_db.iter().position(|entry| entry.name == "f1").unwrap();
+        assert!(get_full_path(&file_db, f1_index as EntryIndex).ends_with("simple/a/f1"));
+        propagate_sizes(&mut file_db);
+    }
+
+    #[test]
+    fn test_watch_apply_events()
+    {
+        use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+        use notify::{Event, EventKind};
+
+        let (_, mut path) = copy_to_work_dir("simple", "watch");
+        path.push("simple");
+        let mut file_db = crawl_initial(&path);
+        let mut path_to_index = build_all_paths_to_index_map(&file_db);
+
+        // New file, modified file, removed dir and renamed dir
+        let new_file = path.join("a/new");
+        fs::write(&new_file, "new").unwrap();
+        let f2 = path.join("b/d/f2");
+        fs::write(&f2, "changed content").unwrap();
+        let c = path.join("c");
+        fs::remove_dir(&c).unwrap();
+        let b = path.join("b");
+        let e = path.join("a/e");
+        fs::rename(&b, &e).unwrap();
+        let hash_f2 = file_db.get(path_to_index[f2.as_os_str()]).hash;
+        let events = vec![
+            Event::new(EventKind::Create(CreateKind::File)).add_path(new_file),
+            Event::new(EventKind::Modify(ModifyKind::Any)).add_path(f2),
+            Event::new(EventKind::Remove(RemoveKind::Folder)).add_path(c),
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path(b)
+                .add_path(e.clone()),
+        ];
+        watch::apply_events(&mut file_db, &mut path_to_index, &path, events, HashAlgorithm::Blake3);
+
+        // f2 was moved with b, the rename event does not re-hash it
+        let f2_moved_index = path_to_index[e.join("d/f2").as_os_str()];
+        assert_eq!(file_db.get(f2_moved_index).hash, hash_f2);
+
+        let crawled = crawl_initial(&path);
+        let mut paths = build_all_paths_to_index_map(&file_db).into_keys().collect::<Vec<_>>();
+        let mut crawled_paths =
+            build_all_paths_to_index_map(&crawled).into_keys().collect::<Vec<_>>();
+        paths.sort();
+        crawled_paths.sort();
+        assert_eq!(paths, crawled_paths);
+    }
+
+    #[test]
+    fn test_watch_receive_batch()
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        // Events keep arriving, the batch still ends
+        let sender = std::thread::spawn(move || {
+            while tx.send(()).is_ok() {
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+        });
+        let start = time::Instant::now();
+        let events = watch::receive_batch(&rx, time::Duration::from_secs(1)).unwrap();
+        assert!(!events.is_empty());
+        assert!(start.elapsed() < time::Duration::from_secs(3));
+        drop(rx);
+        sender.join().unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        drop(tx);
+        assert!(watch::receive_batch(&rx, time::Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn test_watch_rename_into_new_dir()
+    {
+        use notify::event::{CreateKind, ModifyKind, RenameMode};
+        use notify::{Event, EventKind};
+
+        let (_, mut path) = copy_to_work_dir("simple", "watch_rename");
+        path.push("simple");
+        let mut file_db = crawl_initial(&path);
+        let mut path_to_index = build_all_paths_to_index_map(&file_db);
+        let hash_f2 = file_db.get(path_to_index[path.join("b/d/f2").as_os_str()]).hash;
+
+        // new is added after b, then b is moved into it
+        let new_dir = path.join("new");
+        fs::create_dir(&new_dir).unwrap();
+        let events = vec![
+            Event::new(EventKind::Create(CreateKind::Folder)).add_path(new_dir.clone())
+        ];
+        watch::apply_events(&mut file_db, &mut path_to_index, &path, events, HashAlgorithm::Blake3);
+        let b = path.join("b");
+        let moved = new_dir.join("b");
+        assert!(path_to_index[b.as_os_str()] < path_to_index[new_dir.as_os_str()]);
+        fs::rename(&b, &moved).unwrap();
+        let events = vec![
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path(b)
+                .add_path(moved.clone()),
+        ];
+        watch::apply_events(&mut file_db, &mut path_to_index, &path, events, HashAlgorithm::Blake3);
+
+        assert!((1..file_db.len() as EntryIndex).all(|index| file_db.get(index).parent < index));
+        assert_eq!(file_db.get(path_to_index[moved.join("d/f2").as_os_str()]).hash, hash_f2);
+        propagate_sizes(&mut file_db);
+    }
+
+    #[test]
+    fn test_load_legacy_format()
+    {
+        let mut path_buf = PathBuf::from(EXPECTED_DATA_DIR);
+        path_buf.push("simple");
+        let db = load_compressed(&path_buf);
+        assert_eq!(db.file_db.len(), 13);
+        assert!(db.snapshots.is_empty());
+    }
+}
+
+fn get_secs(time: &time::SystemTime) -> u64
+{
+    time.duration_since(time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(unix)]
+fn get_inode(metadata: &fs::Metadata) -> u64
+{
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn get_inode(_metadata: &fs::Metadata) -> u64
+{
+    0
+}
+
+#[cfg(unix)]
+fn get_allocated(metadata: &fs::Metadata) -> u64
+{
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn get_allocated(metadata: &fs::Metadata) -> u64
+{
+    metadata.len()
+}
+
+// uid, gid and mode
+#[cfg(unix)]
+fn get_owner_and_mode(metadata: &fs::Metadata) -> (u32, u32, u32)
+{
+    use std::os::unix::fs::MetadataExt;
+    (metadata.uid(), metadata.gid(), metadata.mode())
+}
+
+#[cfg(not(unix))]
+fn get_owner_and_mode(_metadata: &fs::Metadata) -> (u32, u32, u32)
+{
+    (0, 0, 0)
+}
+
+fn set_owner_and_mode(entry: &mut EntryMeta, metadata: &fs::Metadata)
+{
+    (entry.uid, entry.gid, entry.mode) = get_owner_and_mode(metadata);
+    // Not part of the mode on Windows
+    if metadata.file_type().is_symlink() {
+        entry.mode = (entry.mode & !MODE_TYPE_MASK) | MODE_LINK;
+    }
+}
+
+// Of the file itself, not following symlinks. Empty if the file system lacks support.
+#[cfg(unix)]
+fn get_xattrs(path: &Path) -> Xattrs
+{
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(err) => {
+            if err.raw_os_error() != Some(libc::ENOTSUP) {
+                eprintln!("Error listing xattrs of {:?}: {}", path, err);
+            }
+            return vec![];
+        }
+    };
+    let mut xattrs = names
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name, value))
+        })
+        .collect::<Vec<_>>();
+    xattrs.sort();
+    xattrs
+}
+
+#[cfg(not(unix))]
+fn get_xattrs(_path: &Path) -> Xattrs
+{
+    vec![]
+}
+
+// The metadata of Mac files that backups tend to lose, as xattrs: The file flags (hidden,
+// locked, ...), Finder info, quarantine and the size of the resource fork. The fork itself
+// may be large, it is only stored with CrawlOptions::xattrs.
+#[cfg(target_os = "macos")]
+fn get_mac_metadata(path: &Path, metadata: &fs::Metadata) -> Xattrs
+{
+    use std::os::macos::fs::MetadataExt;
+    // Names of the values that are not xattrs themselves
+    const FLAGS_NAME: &str = "filedb.flags";
+    const RESOURCE_FORK_SIZE_NAME: &str = "filedb.resource_fork_size";
+
+    let flags = metadata.st_flags().to_le_bytes().to_vec();
+    let mut xattrs = vec![(OsString::from(FLAGS_NAME), flags)];
+    for name in ["com.apple.FinderInfo", "com.apple.quarantine"] {
+        if let Ok(Some(value)) = xattr::get(path, name) {
+            xattrs.push((OsString::from(name), value));
+        }
+    }
+    if let Ok(Some(fork)) = xattr::get(path, "com.apple.ResourceFork") {
+        let size = (fork.len() as u64).to_le_bytes().to_vec();
+        xattrs.push((OsString::from(RESOURCE_FORK_SIZE_NAME), size));
+    }
+    xattrs
+}
+
+#[cfg(not(target_os = "macos"))]
+fn get_mac_metadata(_path: &Path, _metadata: &fs::Metadata) -> Xattrs
+{
+    vec![]
+}
+
+// The xattrs captured with options
+fn get_entry_xattrs(path: &Path, metadata: &fs::Metadata, options: &CrawlOptions) -> Xattrs
+{
+    let mut xattrs = if options.xattrs { get_xattrs(path) } else { vec![] };
+    if options.mac_metadata {
+        xattrs.extend(get_mac_metadata(path, metadata));
+        xattrs.sort();
+        xattrs.dedup();
+    }
+    xattrs
+}
+
+// Names and sizes of the named streams of a file, without the unnamed one holding its contents
+#[cfg(windows)]
+fn get_alternate_streams(path: &Path) -> Vec<(OsString, u64)>
+{
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+        WIN32_FIND_STREAM_DATA,
+    };
+
+    let wide_path = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+    let data_ptr = &mut data as *mut WIN32_FIND_STREAM_DATA as *mut std::ffi::c_void;
+    let handle =
+        unsafe { FindFirstStreamW(wide_path.as_ptr(), FindStreamInfoStandard, data_ptr, 0) };
+    if handle == INVALID_HANDLE_VALUE {
+        return vec![];
+    }
+    let mut streams = vec![];
+    loop {
+        let len = data.cStreamName.iter().position(|c| *c == 0).unwrap_or(0);
+        // Stream names are :name:$DATA, the unnamed one is ::$DATA
+        let stream_name = String::from_utf16_lossy(&data.cStreamName[..len]);
+        let name = stream_name.strip_prefix(':').and_then(|name| name.strip_suffix(":$DATA"));
+        if let Some(name) = name.filter(|name| !name.is_empty()) {
+            streams.push((OsString::from(name), data.StreamSize as u64));
+        }
+        if unsafe { FindNextStreamW(handle, data_ptr) } == 0 {
+            break;
+        }
+    }
+    unsafe { FindClose(handle) };
+    streams
+}
+
+#[cfg(not(windows))]
+fn get_alternate_streams(_path: &Path) -> Vec<(OsString, u64)>
+{
+    vec![]
+}
+
+// Detected from the first bytes of the file, empty if unknown or unreadable
+fn get_mime(path: &Path) -> String
+{
+    match infer::get_from_path(path) {
+        Ok(Some(file_type)) => file_type.mime_type().to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(unix)]
+fn get_device(metadata: &fs::Metadata) -> u64
+{
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+#[cfg(not(unix))]
+fn get_device(_metadata: &fs::Metadata) -> u64
+{
+    0
+}
+
+// File systems the kernel generates the contents of (proc, sysfs, cgroup, ...) and those only
+// in memory (tmpfs for /run and /dev), detected by their type. Reading some of their files
+// blocks forever, e.g. /proc/kmsg.
+#[cfg(target_os = "linux")]
+fn is_virtual_file_system(path: &Path) -> bool
+{
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    const VIRTUAL_FS_MAGICS: [u32; 22] = [
+        0x9fa0,     // proc
+        0x62656572, // sysfs
+        0x01021994, // tmpfs, devtmpfs
+        0x1cd1,     // devpts
+        0x27e0eb,   // cgroup
+        0x63677270, // cgroup2
+        0x64626720, // debugfs
+        0x74726163, // tracefs
+        0x73636673, // securityfs
+        0xf97cff8c, // selinuxfs
+        0x6165676c, // pstore
+        0xcafe4a11, // bpf
+        0x62656570, // configfs
+        0x65735543, // fusectl
+        0x19800202, // mqueue
+        0x958458f6, // hugetlbfs
+        0xde5e81e4, // efivarfs
+        0x42494e4d, // binfmt_misc
+        0x0187,     // autofs
+        0x6e736673, // nsfs
+        0x50495045, // pipefs
+        0x858458f6, // ramfs
+    ];
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut statfs) } != 0 {
+        return false;
+    }
+    VIRTUAL_FS_MAGICS.contains(&(statfs.f_type as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_virtual_file_system(_path: &Path) -> bool
+{
+    false
+}
+
+// Flagged with chattr +d on Linux, chflags nodump on macOS
+#[cfg(target_os = "linux")]
+fn is_nodump(path: &Path, _metadata: &fs::Metadata) -> bool
+{
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut statx: libc::statx = unsafe { std::mem::zeroed() };
+    let flags = libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_DONT_SYNC;
+    if unsafe { libc::statx(libc::AT_FDCWD, path.as_ptr(), flags, 0, &mut statx) } != 0 {
+        return false;
+    }
+    statx.stx_attributes & libc::STATX_ATTR_NODUMP as u64 != 0
+}
+
+#[cfg(target_os = "macos")]
+fn is_nodump(_path: &Path, metadata: &fs::Metadata) -> bool
+{
+    use std::os::macos::fs::MetadataExt;
+    metadata.st_flags() & libc::UF_NODUMP != 0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_nodump(_path: &Path, _metadata: &fs::Metadata) -> bool
+{
+    false
+}
+
+// Tagged as holding a cache by a CACHEDIR.TAG starting with the signature, see
+// https://bford.info/cachedir/
+fn is_cache_dir(path: &Path) -> bool
+{
+    const SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+    let mut start = vec![];
+    let read = File::open(path.join("CACHEDIR.TAG"))
+        .and_then(|file| file.take(SIGNATURE.len() as u64).read_to_end(&mut start));
+    read.is_ok() && start == SIGNATURE
+}
+
+fn get_time_string(epoch_seconds: u64) -> String
+{
+    let d = time::UNIX_EPOCH + time::Duration::from_secs(epoch_seconds);
+    let datetime = DateTime::<Local>::from(d);
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn is_root_index(entry_index: EntryIndex) -> bool
+{
+    entry_index == 0
+}
+
+// The entries beneath each entry, in db order. The children of entry i are
+// indices[offsets[i]..offsets[i + 1]], which takes less memory than a Vec per entry.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+struct ChildrenIndex
+{
+    offsets: Vec<EntryIndex>,
+    indices: Vec<EntryIndex>,
+}
+
+impl ChildrenIndex
+{
+    fn new(file_db: &FileDb) -> Self
+    {
+        let mut offsets = vec![0; file_db.len() + 1];
+        for (index, entry) in file_db.iter().enumerate() {
+            if !is_root_index(index as EntryIndex) {
+                offsets[entry.parent as usize + 1] += 1;
+            }
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut next = offsets.clone();
+        let mut indices = vec![0; file_db.len().saturating_sub(1)];
+        for (index, entry) in file_db.iter().enumerate() {
+            if !is_root_index(index as EntryIndex) {
+                let parent = entry.parent as usize;
+                indices[next[parent] as usize] = index as EntryIndex;
+                next[parent] += 1;
+            }
+        }
+        ChildrenIndex { offsets, indices }
+    }
+
+    fn get(&self, index: EntryIndex) -> &[EntryIndex]
+    {
+        let index = index as usize;
+        &self.indices[self.offsets[index] as usize..self.offsets[index + 1] as usize]
+    }
+
+    // index and all entries beneath it, parents before their children
+    fn get_subtree(&self, index: EntryIndex) -> Vec<EntryIndex>
+    {
+        let mut subtree = vec![index];
+        let mut i = 0;
+        while i < subtree.len() {
+            subtree.extend_from_slice(self.get(subtree[i]));
+            i += 1;
+        }
+        subtree
+    }
+
+    fn get_mut(&mut self, index: EntryIndex) -> &mut [EntryIndex]
+    {
+        let index = index as usize;
+        &mut self.indices[self.offsets[index] as usize..self.offsets[index + 1] as usize]
+    }
+
+    // Follows the components of an absolute path from the root
+    fn find_path(&self, file_db: &FileDb, path: &Path) -> Option<EntryIndex>
+    {
+        let mut index = 0;
+        for component in path.components() {
+            // The drive of Windows paths is part of the name of the root
+            let name = match component {
+                std::path::Component::Prefix(_) | std::path::Component::RootDir => continue,
+                std::path::Component::Normal(name) => name,
+                _ => return None,
+            };
+            let children = self.get(index);
+            index = *children.iter().find(|child| file_db.get(**child).name == name)?;
+        }
+        Some(index)
+    }
+}
+
+fn get_full_path(file_db: &FileDb, entry_index: EntryIndex) -> PathBuf
+{
+    let mut components: Vec<&OsStr> = vec![];
+    let mut index = entry_index;
+    let mut seen_paths = HashSet::<EntryIndex>::new();
+    loop {
+        let entry = file_db.get(index);
+        components.push(entry.name);
+        if is_root_index(index) {
+            break;
+        }
+        seen_paths.insert(index);
+        assert!(!seen_paths.contains(&entry.parent)); // Detect loops
+        index = entry.parent;
+    }
+    let result: PathBuf = components.iter().rev().collect();
+    result
+}
+
+fn propagate_sizes(file_db: &mut FileDb)
+{
+    let columns = file_db.columns_mut();
+    // Reset so incremental works, too.
+    for (i, is_dir) in columns.is_dirs.iter().enumerate() {
+        if *is_dir {
+            columns.sizes[i] = 0;
+            columns.allocated[i] = 0;
+        }
+    }
+
+    let mut levels: Vec<u16> = vec![std::u16::MAX; columns.parents.len()];
+    levels[0] = 0;
+    let mut max_level = 0;
+    for i in 1..levels.len() {
+        let parent_index = columns.parents[i] as usize;
+        assert!(
+            levels[parent_index] != std::u16::MAX,
+            "i {:?} parent_index {:?}",
+            i,
+            parent_index
+        );
+        levels[i] = levels[parent_index] + 1;
+        if levels[i] > max_level {
+            max_level = levels[i];
+        }
+    }
+    // Propagate sizes from bottom to top
+    // Level 0 is the root, don't propagate
+    for level_to_propagate in (1..max_level + 1).rev() {
+        for (i, level) in levels.iter().enumerate() {
+            if *level == level_to_propagate {
+                let parent_index = columns.parents[i] as usize;
+                columns.sizes[parent_index] += columns.sizes[i];
+                columns.allocated[parent_index] += columns.allocated[i];
+            }
+        }
+    }
+}
+
+fn propagate_hashes(file_db: &mut FileDb)
+{
+    // Reset so incremental works, too.
+    let columns = file_db.columns_mut();
+    for (i, is_dir) in columns.is_dirs.iter().enumerate() {
+        if *is_dir {
+            columns.hashes[i] = EMPTY_HASH;
+        }
+    }
+
+    let mut levels: Vec<u16> = vec![std::u16::MAX; file_db.len()];
+    let mut dir_to_entries = HashMap::<EntryIndex, Vec<EntryIndex>>::new();
+    levels[0] = 0;
+    let mut max_level = 0;
+    for i in 1..file_db.len() {
+        if file_db.get(i as EntryIndex).is_dir {
+            // Files already have hashes. They are kept at std::u16::MAX and thus never processed by the loop below.
+            let parent_index = file_db.get(i as EntryIndex).parent as usize;
+            assert!(levels[parent_index] != std::u16::MAX);
+            levels[i] = levels[parent_index] + 1;
+            assert!(levels[i] < std::u16::MAX);
+            if levels[i] > max_level {
+                max_level = levels[i];
+            }
+            dir_to_entries.entry(i as EntryIndex).or_insert(Vec::<EntryIndex>::new());
+        }
+        let entry = dir_to_entries
+            .entry(file_db.get(i as EntryIndex).parent)
+            .or_insert(Vec::<EntryIndex>::new());
+        (*entry).push(i as EntryIndex);
+    }
+
+    // Propagate sizes from bottom to top
+    // Level 0 is the root, don't propagate
+    assert!(max_level < std::u16::MAX - 1);
+    for level_to_propagate in (1..max_level + 1).rev() {
+        for (entry_index, level) in levels.iter().enumerate() {
+            if *level == level_to_propagate {
+                // This will only hit dir entries
+                let dir_entries = dir_to_entries.get_mut(&(entry_index as EntryIndex)).unwrap();
+                dir_entries.sort_by_key(|entry| file_db.get(*entry).name);
+                // Unknown until all files below are hashed
+                if dir_entries.iter().any(|entry| !is_hashed(&file_db.get(*entry))) {
+                    continue;
+                }
+                let mut hasher = blake3::Hasher::new();
+                for dir_entry in dir_entries {
+                    let entry = file_db.get(*dir_entry);
+                    hasher.update(&entry.hash);
+                    // Without xattrs, hashes stay the same as before they were captured
+                    for (name, value) in &entry.xattrs {
+                        hasher.update(&(name.len() as u64).to_le_bytes());
+                        hasher.update(name.as_encoded_bytes());
+                        hasher.update(&(value.len() as u64).to_le_bytes());
+                        hasher.update(value);
+                    }
+                }
+                *file_db.entry_mut(entry_index as EntryIndex).hash = hasher.finalize().into();
+                let ent = file_db.get(entry_index as EntryIndex);
+                assert!(ent.hash != EMPTY_HASH, "{:?}", ent.name);
+            }
+        }
+    }
+    // All entries except the root, files not hashed yet and the dirs containing them
+    let unhashed_parents = file_db
+        .iter()
+        .skip(1)
+        .filter(|entry| !is_hashed(entry))
+        .map(|entry| entry.parent)
+        .collect::<HashSet<_>>();
+    assert!(file_db
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, entry)| entry.is_dir && !is_hashed(entry))
+        .all(|(index, _)| unhashed_parents.contains(&(index as EntryIndex))));
+
+    // let empty_hashes = file_db.iter().enumerate().filter(|(index, entry)| entry.hash == EMPTY_HASH).collect::<Vec<_>>();
+    // for (index, _) in empty_hashes {
+    //     let p = get_full_path(&file_db, index as EntryIndex);
+    //     println!("{:?}", p);
+    // }
+}
+
+// Returns <db>.<suffix>, for files kept next to the db
+fn get_db_side_file_name(file_db_name: &Path, suffix: &str) -> PathBuf
+{
+    let mut name = file_db_name.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Writes to <filename>.tmp, syncs it and renames it over filename, so a crash while writing
+// never leaves a truncated file behind. With sign, it is signed before, see signing.
+fn write_file_atomically(
+    filename: &Path,
+    keep_backup: bool,
+    sign: bool,
+    write: impl FnOnce(&mut File),
+)
+{
+    let tmp_filename = get_db_side_file_name(filename, "tmp");
+    {
+        let mut file = File::create(&tmp_filename).unwrap();
+        write(&mut file);
+        file.sync_all().unwrap();
+    }
+    let signature_name =
+        if sign { signing::sign_before_rename(&tmp_filename, filename) } else { None };
+    if keep_backup && filename.is_file() {
+        let bak_filename = get_db_side_file_name(filename, "bak");
+        let _ = fs::remove_file(&bak_filename);
+        if fs::hard_link(filename, &bak_filename).is_err() {
+            fs::copy(filename, &bak_filename).unwrap();
+        }
+    }
+    fs::rename(&tmp_filename, filename).unwrap();
+    if let Some(signature_name) = signature_name {
+        fs::rename(signature_name, get_db_side_file_name(filename, "sig")).unwrap();
+    }
+    // Make the rename itself durable
+    #[cfg(unix)]
+    {
+        let parent = filename.parent().unwrap();
+        let dir_name = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        File::open(dir_name).unwrap().sync_all().unwrap();
+    }
+}
+
+// Set by --compression, zlib level of saved dbs
+static COMPRESSION_LEVEL: AtomicU32 = AtomicU32::new(1);
+
+// 0 to 9, higher levels make dbs smaller but saving slower
+pub fn set_compression(level: u32)
+{
+    COMPRESSION_LEVEL.store(level, Ordering::SeqCst);
+}
+
+fn get_compression() -> Compression
+{
+    Compression::new(COMPRESSION_LEVEL.load(Ordering::SeqCst))
+}
+
+fn save_compressed(filename: &Path, db: &Db)
+{
+    eprintln!("Saving db to {:?}", filename);
+    write_file_atomically(filename, KEEP_DB_BACKUP, true, |file| {
+        let mut writer = io::BufWriter::new(file);
+        writer.write_all(DB_MAGIC).unwrap();
+        writer.write_all(&DB_FORMAT_VERSION.to_le_bytes()).unwrap();
+        writer.write_all(&[db.hash_algorithm.get_id()]).unwrap();
+        let mut encoder = ZlibEncoder::new(writer, get_compression());
+        bincode::serialize_into(&mut encoder, db).unwrap();
+        bincode::serialize_into(&mut encoder, &ChildrenIndex::new(&db.file_db)).unwrap();
+        encoder.finish().unwrap().flush().unwrap();
+    });
+    changes::remove_changes(filename);
+    if get_db_side_file_name(filename, "partitions").exists() {
+        save_partitions(filename, &db.file_db);
+    }
+    eprintln!("Done");
+}
+
+// Returns format version and hash algorithm, None for files without header
+fn read_db_header<R: Read>(filename: &Path, reader: &mut R) -> Option<(u32, HashAlgorithm)>
+{
+    let mut magic = [0u8; 6];
+    if reader.read_exact(&mut magic).is_err() || &magic != DB_MAGIC {
+        return None;
+    }
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version).unwrap();
+    let version = u32::from_le_bytes(version);
+    let mut id = [0u8; 1];
+    reader.read_exact(&mut id).unwrap();
+    match HashAlgorithm::ALL.get(id[0] as usize) {
+        Some(hash_algorithm) => Some((version, *hash_algorithm)),
+        None => panic!("Unsupported hash algorithm {} in {:?}", id[0], filename),
+    }
+}
+
+// Without loading the db
+fn read_hash_algorithm(filename: &Path) -> HashAlgorithm
+{
+    let mut file = File::open(filename).unwrap();
+    read_db_header(filename, &mut file).map_or(HashAlgorithm::Blake3, |(_, algorithm)| algorithm)
+}
+
+// Hashes of different algorithms never match, so dbs can only be compared using the same
+fn check_same_hash_algorithm(filename_a: &Path, filename_b: &Path)
+{
+    let algorithm_a = read_hash_algorithm(filename_a);
+    let algorithm_b = read_hash_algorithm(filename_b);
+    if algorithm_a != algorithm_b {
+        panic!(
+            "Cannot compare {:?} hashed with {} to {:?} hashed with {}",
+            filename_a,
+            algorithm_a.get_name(),
+            filename_b,
+            algorithm_b.get_name()
+        );
+    }
+}
+
+// Also returns the stored children index, which older versions lack
+fn load_compressed_with_children(filename: &Path) -> (Db, Option<ChildrenIndex>)
+{
+    eprintln!("Loading db from {:?}", filename);
+    signing::check_signature(filename);
+    let mut reader = io::BufReader::new(File::open(filename).unwrap());
+    let header = read_db_header(filename, &mut reader);
+    let mut result = if let Some((version, _)) = header {
+        let mut decoder = ZlibDecoder::new(reader);
+        match version {
+            DB_FORMAT_VERSION => {
+                let db = bincode::deserialize_from(&mut decoder).unwrap();
+                (db, Some(bincode::deserialize_from(decoder).unwrap()))
+            }
+            version => panic!("Unsupported db format version {} in {:?}", version, filename),
+        }
+    } else {
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        (legacy::upgrade_v1(bincode::deserialize_from(ZlibDecoder::new(reader)).unwrap()), None)
+    };
+    if let Some((_, hash_algorithm)) = header {
+        result.0.hash_algorithm = hash_algorithm;
+    }
+    // The stored index is that of the tree before the changes
+    match changes::apply_changes(filename, &mut result.0) {
+        Ok(true) => result.1 = None,
+        Ok(false) => {}
+        Err(err) => eprintln!("Cannot apply changes to {:?}, ignoring them: {}", filename, err),
+    }
+    if roots::apply_root_overrides(&mut result.0) {
+        result.1 = None;
+    }
+    eprintln!("Done");
+    result
+}
+
+fn load_compressed(filename: &Path) -> Db
+{
+    load_compressed_with_children(filename).0
+}
+
+fn new_db(file_db: FileDb) -> Db
+{
+    Db {
+        file_db,
+        snapshots: Vec::new(),
+        verify_cycle_start: 0,
+        media: HashMap::new(),
+        image_hashes: HashMap::new(),
+        audio_fingerprints: HashMap::new(),
+        fuzzy_hashes: HashMap::new(),
+        chunks: HashMap::new(),
+        roots: Vec::new(),
+        link_targets: HashMap::new(),
+        hosts: Vec::new(),
+        hash_algorithm: HashAlgorithm::default(),
+    }
+}
+
+fn get_snapshot_index(db: &Db, snapshot: &str) -> Option<usize>
+{
+    db.snapshots.iter().position(|s| s.name == snapshot)
+}
+
+// snapshot None refers to the current tree
+fn get_file_db_mut<'a>(db: &'a mut Db, snapshot: Option<&str>) -> &'a mut FileDb
+{
+    match snapshot {
+        None => &mut db.file_db,
+        Some(name) => match get_snapshot_index(db, name) {
+            Some(index) => &mut db.snapshots[index].file_db,
+            None => panic!("Snapshot {:?} not found", name),
+        },
+    }
+}
+
+fn load_file_db(filename: &Path, snapshot: Option<&str>) -> FileDb
+{
+    let mut db = load_compressed(filename);
+    std::mem::take(get_file_db_mut(&mut db, snapshot))
+}
+
+fn load_file_db_with_children(filename: &Path, snapshot: Option<&str>) -> (FileDb, ChildrenIndex)
+{
+    let (mut db, stored_children) = load_compressed_with_children(filename);
+    let file_db = std::mem::take(get_file_db_mut(&mut db, snapshot));
+    // Only the current tree has a stored index
+    let children = match stored_children {
+        Some(children) if snapshot.is_none() => children,
+        _ => ChildrenIndex::new(&file_db),
+    };
+    (file_db, children)
+}
+
+// <db>.partitions splits the current tree by top-level dir, so commands on one subtree only
+// decompress that part. It starts with a PartitionIndex, followed by one compressed FileDb per
+// top-level dir, holding its subtree and the dirs above it. Created by the partition command,
+// saving the db keeps it current from then on.
+#[derive(Serialize, Deserialize)]
+struct PartitionIndex
+{
+    // Length and modification time of the db, partitions of any other are ignored
+    db_stamp: (u64, u64, u32),
+    // Top-level dir and offset of its partition in the file
+    partitions: Vec<(PathBuf, u64)>,
+}
+
+fn get_db_stamp(file_db_name: &Path) -> (u64, u64, u32)
+{
+    let metadata = fs::metadata(file_db_name).unwrap();
+    let modified = metadata.modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap();
+    (metadata.len(), modified.as_secs(), modified.subsec_nanos())
+}
+
+// The children of the first dir from the root down with more than one child, or a file
+fn get_top_level_dirs(file_db: &FileDb, children: &ChildrenIndex) -> Vec<EntryIndex>
+{
+    let mut index = 0;
+    loop {
+        match children.get(index) {
+            [child] if file_db.get(*child).is_dir => index = *child,
+            _ => break,
+        }
+    }
+    children.get(index).iter().filter(|child| file_db.get(**child).is_dir).copied().collect()
+}
+
+fn save_partitions(file_db_name: &Path, file_db: &FileDb)
+{
+    let children = ChildrenIndex::new(file_db);
+    let mut index = PartitionIndex { db_stamp: get_db_stamp(file_db_name), partitions: vec![] };
+    let mut compressed = vec![];
+    if !file_db.is_empty() {
+        for top_level_dir in get_top_level_dirs(file_db, &children) {
+            let partition = extract_entries(file_db, &children.get_subtree(top_level_dir));
+            let mut encoder = ZlibEncoder::new(vec![], get_compression());
+            bincode::serialize_into(&mut encoder, &partition).unwrap();
+            index.partitions.push((get_full_path(file_db, top_level_dir), 0));
+            compressed.push(encoder.finish().unwrap());
+        }
+    }
+    // Offsets have a fixed size, so setting them does not change that of the index
+    let mut offset = bincode::serialized_size(&index).unwrap();
+    for ((_, partition_offset), partition) in index.partitions.iter_mut().zip(&compressed) {
+        *partition_offset = offset;
+        offset += partition.len() as u64;
+    }
+    let partitions_name = get_db_side_file_name(file_db_name, "partitions");
+    write_file_atomically(&partitions_name, false, false, |file| {
+        let mut writer = io::BufWriter::new(file);
+        bincode::serialize_into(&mut writer, &index).unwrap();
+        for partition in &compressed {
+            writer.write_all(partition).unwrap();
+        }
+        writer.flush().unwrap();
+    });
+}
+
+// The partition containing path, None if there is none or the partitions are outdated
+fn load_partition(file_db_name: &Path, path: &Path) -> Option<FileDb>
+{
+    // Partitions lack the changes appended since the db was saved
+    let has_changes = changes::has_changes(file_db_name);
+    if roots::has_root_overrides() || signing::is_checking_signatures() || has_changes {
+        return None;
+    }
+    let file = File::open(get_db_side_file_name(file_db_name, "partitions")).ok()?;
+    let mut reader = io::BufReader::new(file);
+    let index: PartitionIndex = bincode::deserialize_from(&mut reader).ok()?;
+    if index.db_stamp != get_db_stamp(file_db_name) {
+        return None;
+    }
+    let (top_level_dir, offset) =
+        index.partitions.iter().find(|(top_level_dir, _)| path.starts_with(top_level_dir))?;
+    eprintln!("Loading partition {:?} of {:?}", top_level_dir, file_db_name);
+    reader.seek(SeekFrom::Start(*offset)).unwrap();
+    let file_db = bincode::deserialize_from(ZlibDecoder::new(reader)).unwrap();
+    eprintln!("Done");
+    Some(file_db)
+}
+
+// Like load_file_db_with_children, but only the partition containing path if there is one.
+// Entries outside of path may be missing.
+fn load_file_db_for_path(
+    filename: &Path,
+    path: &Path,
+    snapshot: Option<&str>,
+) -> (FileDb, ChildrenIndex)
+{
+    if snapshot.is_none() {
+        if let Some(file_db) = load_partition(filename, path) {
+            let children = ChildrenIndex::new(&file_db);
+            return (file_db, children);
+        }
+    }
+    load_file_db_with_children(filename, snapshot)
+}
+
+// Writes <db>.partitions, see PartitionIndex
+pub fn partition(file_db_name: &Path)
+{
+    let db = load_compressed(file_db_name);
+    save_partitions(file_db_name, &db.file_db);
+    let children = ChildrenIndex::new(&db.file_db);
+    let num_partitions =
+        if db.file_db.is_empty() { 0 } else { get_top_level_dirs(&db.file_db, &children).len() };
+    println!("Wrote {} partitions", num_partitions);
+}
+
+// Advisory lock on <db>.lock, released when dropped
+pub struct DbLock
+{
+    _file: File,
+}
+
+// Returns None if another process holds the lock and wait is false
+pub fn lock_db(file_db_name: &Path, wait: bool) -> Option<DbLock>
+{
+    let lock_file_name = get_db_side_file_name(file_db_name, "lock");
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_file_name)
+        .unwrap();
+    if wait {
+        if file.try_lock().is_err() {
+            println!("Waiting for lock {:?}", lock_file_name);
+            file.lock().unwrap();
+        }
+    } else if let Err(err) = file.try_lock() {
+        match err {
+            fs::TryLockError::WouldBlock => return None,
+            fs::TryLockError::Error(err) => panic!("Error locking {:?}: {}", lock_file_name, err),
+        }
+    }
+    Some(DbLock { _file: file })
+}
+
+fn get_ext(path: &Path) -> Option<&str>
+{
+    let ext = path.extension();
+    if ext.is_none() {
+        return None;
+    } else {
+        return Some(ext.unwrap().to_str().unwrap());
+    }
+}
+
+fn is_archive(path: &Path) -> bool
+{
+    let ext = get_ext(path);
+    if ext.is_none() {
+        return false;
+    }
+    let ext_str = ext.unwrap();
+    return ext_str == "tar"
+        || ext_str == "xz"
+        || ext_str == "gz"
+        || ext_str == "tgz"
+        || ext_str == "zip"
+        || ext_str == "7z"
+        || ext_str == "bz2"
+        || ext_str == "zst"
+        || ext_str == "rar";
+}
+
+// Ratios of small archives are not meaningful, e.g. a few KB of zeros compress extremely well
+const MIN_UNPACKED_SIZE_FOR_RATIO: u64 = 1 << 20;
+
+// Limits for unpacking a single archive, so zip bombs or broken archives cannot fill the
+// temp volume. Exceeding one fails the unpacking.
+struct UnpackBudget
+{
+    max_size: u64,
+    max_entries: u64,
+    max_ratio: u64,
+    archive_size: u64,
+    size: Cell<u64>,
+    entries: Cell<u64>,
+}
+
+impl UnpackBudget
+{
+    fn new(archive_size: u64, options: &CrawlOptions) -> Self
+    {
+        UnpackBudget {
+            max_size: options.archive_max_size,
+            max_entries: options.archive_max_entries,
+            max_ratio: options.archive_max_ratio,
+            archive_size,
+            size: Cell::new(0),
+            entries: Cell::new(0),
+        }
+    }
+
+    fn add_entry(&self) -> io::Result<()>
+    {
+        self.entries.set(self.entries.get() + 1);
+        if self.entries.get() > self.max_entries {
+            return Err(limit_exceeded(format!("more than {} entries", self.max_entries)));
+        }
+        Ok(())
+    }
+
+    fn add_bytes(&self, len: u64) -> io::Result<()>
+    {
+        let size = self.size.get() + len;
+        self.size.set(size);
+        if size > self.max_size {
+            return Err(limit_exceeded(format!("more than {} bytes unpacked", self.max_size)));
+        }
+        if size > MIN_UNPACKED_SIZE_FOR_RATIO && size / self.archive_size.max(1) > self.max_ratio {
+            return Err(limit_exceeded(format!("compression ratio above {}", self.max_ratio)));
+        }
+        Ok(())
+    }
+}
+
+fn limit_exceeded(message: String) -> io::Error
+{
+    io::Error::other(format!("Limit exceeded, {}", message))
+}
+
+// Counts everything read against the budget
+struct BudgetReader<'a, R>
+{
+    inner: R,
+    budget: &'a UnpackBudget,
+}
+
+impl<'a, R: Read> Read for BudgetReader<'a, R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let len = self.inner.read(buf)?;
+        self.budget.add_bytes(len as u64)?;
+        Ok(len)
+    }
+}
+
+fn decompress_zip(path: &Path, tmp_dir: &TempDir, budget: &UnpackBudget) -> io::Result<()>
+{
+    println!("decompressing {:?}", path);
+
+    let file = File::open(path)?;
+
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    for i in 0..archive.len() {
+        budget.add_entry()?;
+        let mut file = archive.by_index(i)?;
+        #[allow(deprecated)]
+        let outpath_rel = file.sanitized_name();
+        let outpath = PathBuf::from(tmp_dir.path()).join(outpath_rel);
+
+        if (&*file.name()).ends_with('/') {
+            println!("zip creating dir {:?}", outpath);
+            fs::create_dir_all(&outpath)?;
+        } else {
+            if let Some(p) = outpath.parent() {
                 if !p.exists() {
-                    fs::create_dir_all(&p).unwrap();
+                    fs::create_dir_all(&p)?;
+                }
+            }
+            let mut outfile = File::create(&outpath)?;
+            io::copy(&mut BudgetReader { inner: &mut file, budget }, &mut outfile)?;
+        }
+
+        // Get and Set permissions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(mode) = file.unix_mode() {
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decompress_tar(reader: impl Read, tmp_dir: &TempDir, budget: &UnpackBudget) -> io::Result<()>
+{
+    let mut archive = Archive::new(BudgetReader { inner: reader, budget });
+    for entry in archive.entries()? {
+        budget.add_entry()?;
+        entry?.unpack_in(tmp_dir.path())?;
+    }
+    Ok(())
+}
+
+fn decompress_7z(path: &Path, tmp_dir: &TempDir, budget: &UnpackBudget) -> io::Result<()>
+{
+    let to_io_error = |err: sevenz_rust::Error| io::Error::other(err.to_string());
+    sevenz_rust::decompress_file_with_extract_fn(path, tmp_dir.path(), |entry, reader, dest| {
+        budget.add_entry().map_err(sevenz_rust::Error::io)?;
+        let mut reader = BudgetReader { inner: reader, budget };
+        sevenz_rust::default_entry_extract_fn(entry, &mut reader, dest)
+    })
+    .map_err(to_io_error)
+}
+
+// For compressed single files like .gz, the file is unpacked without the extension
+fn decompress_single_file(
+    path: &Path,
+    decoder: impl Read,
+    tmp_dir: &TempDir,
+    budget: &UnpackBudget,
+) -> io::Result<()>
+{
+    budget.add_entry()?;
+    let mut path_buf = PathBuf::from(tmp_dir.path());
+    path_buf.push(path.file_stem().unwrap());
+    let mut dest_file = File::create(path_buf)?;
+    io::copy(&mut BudgetReader { inner: decoder, budget }, &mut dest_file)?;
+    Ok(())
+}
+
+fn decompress_to_tmp_dir(path: &Path, options: &CrawlOptions) -> io::Result<TempDir>
+{
+    println!("Depacking {:?}", path);
+
+    let ext_str = get_ext(path).unwrap();
+    let file = File::open(path)?;
+    let budget = UnpackBudget::new(file.metadata()?.len(), options);
+    let tmp_dir = TempDir::new("filedb-decomp").unwrap();
+    match ext_str {
+        "gz" => decompress_single_file(path, GzDecoder::new(file), &tmp_dir, &budget)?,
+        "tar" => decompress_tar(file, &tmp_dir, &budget)?,
+        "tgz" => decompress_tar(GzDecoder::new(file), &tmp_dir, &budget)?,
+        "xz" => decompress_single_file(path, XzDecoder::new(file), &tmp_dir, &budget)?,
+        "zip" => decompress_zip(path, &tmp_dir, &budget)?,
+        "7z" => decompress_7z(path, &tmp_dir, &budget)?,
+        "bz2" => decompress_single_file(path, BzDecoder::new(file), &tmp_dir, &budget)?,
+        "zst" => decompress_single_file(path, ZstdDecoder::new(file)?, &tmp_dir, &budget)?,
+        _ => panic!("unreachable"),
+    }
+    Ok(tmp_dir)
+}
+
+// The archives enclosing the one currently being crawled, outermost first
+#[derive(Default)]
+struct ArchiveNesting
+{
+    hashes: Vec<Hash256>,
+    unpacked_size: u64,
+}
+
+enum ArchiveContents
+{
+    Unpacked { tmp_dir: TempDir, hash: Hash256, size: u64 },
+    Listed(Vec<rar::RarEntry>),
+}
+
+fn get_dir_size(path: &Path) -> u64
+{
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|dir_entry| dir_entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// Returns None if the archive is to be added as plain file. display_path is the path of
+// the archive in the db, path its location on disk (possibly in an enclosing tmp dir).
+fn read_archive(
+    path: &Path,
+    display_path: &Path,
+    options: &CrawlOptions,
+    nesting: &ArchiveNesting,
+) -> Option<ArchiveContents>
+{
+    if nesting.hashes.len() >= options.archive_depth {
+        println!("Archive depth limit reached, adding as file {:?}", display_path);
+        return None;
+    }
+    if get_ext(path) == Some("rar") {
+        return match rar::list(path) {
+            Ok(entries) => Some(ArchiveContents::Listed(entries)),
+            Err(err) => {
+                eprintln!("Error listing rar {:?}: {}, adding as file", display_path, err);
+                None
+            }
+        };
+    }
+    if !nesting.hashes.is_empty() && nesting.unpacked_size > options.archive_max_nested_size {
+        eprintln!("Enclosing archives too large, adding as file {:?}", display_path);
+        return None;
+    }
+    // An archive containing itself would be unpacked over and over
+    let hash = get_hash_for_file(path, options.hash_algorithm.unwrap_or_default()).ok()?;
+    if nesting.hashes.contains(&hash) {
+        eprintln!("Archive contains itself, adding as file {:?}", display_path);
+        return None;
+    }
+    let tmp_dir = match decompress_to_tmp_dir(path, options) {
+        Ok(tmp_dir) => tmp_dir,
+        Err(err) => {
+            eprintln!("Error unpacking archive {:?}: {}, adding as file", display_path, err);
+            return None;
+        }
+    };
+    let size = get_dir_size(tmp_dir.path());
+    let nested_size = nesting.unpacked_size + size;
+    if !nesting.hashes.is_empty() && nested_size > options.archive_max_nested_size {
+        eprintln!("Nested archive too large, adding as file {:?}", display_path);
+        return None;
+    }
+    Some(ArchiveContents::Unpacked { tmp_dir, hash, size })
+}
+
+// Adds the listed contents of a rar archive beneath archive_path, which must already be in
+// the db as dir. The archive's times are used for all entries. Rar contents cannot be
+// unpacked, so files are not hashed and never count as copies of other files.
+fn add_rar_entries(
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+    archive_path: &Path,
+    archive_metadata: &fs::Metadata,
+    rar_entries: Vec<rar::RarEntry>,
+)
+{
+    let modified = get_secs(&archive_metadata.modified().unwrap());
+    let accessed = get_secs(&archive_metadata.accessed().unwrap());
+    for rar_entry in rar_entries {
+        // Names like x/../../y would escape the archive or add entries named ..
+        let is_normal = |component| matches!(component, std::path::Component::Normal(_));
+        let path = archive_path.join(&rar_entry.name);
+        if !rar_entry.name.components().all(is_normal)
+            || path_to_index.contains_key(path.as_os_str())
+        {
+            continue;
+        }
+        // Parent dirs are not necessarily listed, or listed after their contents
+        let mut parent_index = *path_to_index.get(archive_path.as_os_str()).unwrap();
+        let mut dir_path = archive_path.to_path_buf();
+        for component in rar_entry.name.parent().unwrap().components() {
+            dir_path.push(component);
+            parent_index = match path_to_index.get(dir_path.as_os_str()) {
+                Some(index) => *index,
+                None => {
+                    let index = add_file_db_entry(
+                        file_db,
+                        FileDbEntry {
+                            name: component.as_os_str().to_os_string(),
+                            is_dir: true,
+                            parent: parent_index,
+                            size: 0,
+                            allocated: 0,
+                            modified,
+                            accessed,
+                            hash: EMPTY_HASH,
+                            verified: 0,
+                            inode: 0,
+                            uid: 0,
+                            gid: 0,
+                            mode: 0,
+                            xattrs: vec![],
+                            mime: String::new(),
+                            pre_hash: 0,
+                            origin: 0,
+                        },
+                    );
+                    path_to_index.insert(dir_path.as_os_str().to_owned(), index);
+                    index
+                }
+            };
+        }
+        println!("Adding {:?}", path);
+        let index = add_file_db_entry(
+            file_db,
+            FileDbEntry {
+                name: path.file_name().unwrap().to_os_string(),
+                is_dir: rar_entry.is_dir,
+                parent: parent_index,
+                size: if rar_entry.is_dir { 0 } else { rar_entry.size },
+                allocated: if rar_entry.is_dir { 0 } else { rar_entry.size },
+                modified,
+                accessed,
+                hash: EMPTY_HASH,
+                verified: 0,
+                inode: 0,
+                uid: 0,
+                gid: 0,
+                mode: 0,
+                xattrs: vec![],
+                mime: String::new(),
+                pre_hash: 0,
+                origin: 0,
+            },
+        );
+        if rar_entry.is_dir {
+            path_to_index.insert(path.into_os_string(), index);
+        }
+    }
+}
+
+// Rebuilds path from its components, so the same dir is always stored the same way: Without
+// trailing separators, and on Windows with the drive letter in upper case and without the
+// \\?\ of verbatim paths
+fn normalize_path(path: &Path) -> PathBuf
+{
+    use std::path::{Component, Prefix};
+    path.components()
+        .map(|component| match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    OsString::from(format!("{}:", letter.to_ascii_uppercase() as char))
+                }
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    let mut unc = OsString::from(r"\\");
+                    unc.push(server);
+                    unc.push(r"\");
+                    unc.push(share);
+                    unc
+                }
+                _ => prefix.as_os_str().to_owned(),
+            },
+            component => component.as_os_str().to_owned(),
+        })
+        .collect()
+}
+
+// For accessing paths of more than MAX_PATH characters, also on network shares. The db keeps
+// the canonical form from normalize_path.
+#[cfg(windows)]
+fn get_extended_length_path(path: &Path) -> PathBuf
+{
+    use std::path::{Component, Prefix};
+    let mut components = path.components();
+    let mut extended_path = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:\", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut unc = OsString::from(r"\\?\UNC\");
+                unc.push(server);
+                unc.push(r"\");
+                unc.push(share);
+                unc.push(r"\");
+                PathBuf::from(unc)
+            }
+            _ => return path.to_path_buf(),
+        },
+        _ => return path.to_path_buf(),
+    };
+    // Windows leaves extended-length paths as they are, so they must not contain . or ..
+    for component in components {
+        match component {
+            Component::Normal(name) => extended_path.push(name),
+            Component::ParentDir => {
+                extended_path.pop();
+            }
+            _ => {}
+        }
+    }
+    extended_path
+}
+
+#[cfg(not(windows))]
+fn get_extended_length_path(path: &Path) -> PathBuf
+{
+    path.to_path_buf()
+}
+
+fn replace_prefix(path: &Path, replace_from: &Path, replace_to: &Path) -> PathBuf
+{
+    if replace_to.as_os_str().len() == 0 {
+        return path.to_path_buf();
+    }
+    if replace_to.as_os_str().len() > 0 {
+        let path_stripped = path.strip_prefix(replace_from).unwrap();
+        if path_stripped.as_os_str().is_empty() {
+            replace_to.to_path_buf()
+        } else {
+            replace_to.to_path_buf().join(path_stripped)
+        }
+    } else {
+        path.to_path_buf()
+    }
+}
+
+// Files at least this large are hashed on all cores, read in buffers of the second size
+const PARALLEL_HASH_MIN_SIZE: u64 = 64 << 20;
+const PARALLEL_HASH_BUFFER_SIZE: usize = 16 << 20;
+
+// Set by --threads: Large files are hashed on num_threads threads of the global rayon pool
+// instead of all cores. Only possible before the pool is used.
+pub fn set_threads(num_threads: usize) -> Result<(), String>
+{
+    let builder = rayon::ThreadPoolBuilder::new().num_threads(num_threads);
+    builder.build_global().map_err(|err| err.to_string())
+}
+
+// Set by --no-cache, for all commands
+static NO_CACHE: AtomicBool = AtomicBool::new(false);
+// Read files are dropped from the page cache in steps of this size
+const NO_CACHE_STEP_SIZE: u64 = 16 << 20;
+
+// Drop files from the page cache once they are hashed, so hashing large trees does not evict
+// what other programs use
+pub fn set_no_cache(no_cache: bool)
+{
+    NO_CACHE.store(no_cache, Ordering::SeqCst);
+}
+
+// Set by --limit-rate, bytes per second, 0 for no limit
+static LIMIT_RATE: AtomicU64 = AtomicU64::new(0);
+// Start and bytes read since, of all files hashed
+static RATE_WINDOW: Mutex<Option<(time::Instant, u64)>> = Mutex::new(None);
+
+// Limit the bytes read per second for hashing, 0 for no limit
+pub fn set_limit_rate(bytes_per_sec: u64)
+{
+    LIMIT_RATE.store(bytes_per_sec, Ordering::SeqCst);
+}
+
+// Sleeps as long as reading len more bytes is ahead of the rate limit
+fn throttle(len: u64)
+{
+    let limit_rate = LIMIT_RATE.load(Ordering::Relaxed);
+    if limit_rate == 0 {
+        return;
+    }
+    let now = time::Instant::now();
+    let mut window = RATE_WINDOW.lock().unwrap();
+    let (start, bytes) = window.get_or_insert((now, 0));
+    *bytes += len;
+    let due = *start + time::Duration::from_secs_f64(*bytes as f64 / limit_rate as f64);
+    if due > now {
+        std::thread::sleep(due - now);
+    } else if now - due > time::Duration::from_secs(1) {
+        // Not reading for a while, like while listing dirs, must not allow bursts after
+        *window = Some((now, 0));
+    }
+}
+
+// Reads a file for hashing, dropping what was read from the page cache with --no-cache
+struct HashReader
+{
+    file: File,
+    pos: u64,
+    // Up to here
+    dropped_pos: u64,
+}
+
+impl HashReader
+{
+    fn new(file: File) -> Self
+    {
+        HashReader { file, pos: 0, dropped_pos: 0 }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn drop_cache(&mut self)
+    {
+        use std::os::unix::io::AsRawFd;
+        let len = (self.pos - self.dropped_pos) as libc::off_t;
+        let fd = self.file.as_raw_fd();
+        // Only advice, failing is harmless
+        unsafe {
+            libc::posix_fadvise(fd, self.dropped_pos as libc::off_t, len, libc::POSIX_FADV_DONTNEED)
+        };
+        self.dropped_pos = self.pos;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn drop_cache(&mut self) {}
+}
+
+impl Read for HashReader
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let len = self.file.read(buf)?;
+        self.pos += len as u64;
+        throttle(len as u64);
+        if NO_CACHE.load(Ordering::Relaxed) && self.pos - self.dropped_pos >= NO_CACHE_STEP_SIZE {
+            self.drop_cache();
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for HashReader
+{
+    fn drop(&mut self)
+    {
+        if NO_CACHE.load(Ordering::Relaxed) && self.pos > self.dropped_pos {
+            self.drop_cache();
+        }
+    }
+}
+
+fn get_hash_parallel<R: Read>(mut reader: R) -> io::Result<Hash256>
+{
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = Vec::with_capacity(PARALLEL_HASH_BUFFER_SIZE);
+    loop {
+        buf.clear();
+        // Full buffers, so the tree halves are large
+        (&mut reader).take(PARALLEL_HASH_BUFFER_SIZE as u64).read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update_rayon(&buf);
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn get_hash_for_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<Hash256>
+{
+    let file = File::open(get_extended_length_path(path))?;
+    let size = file.metadata()?.len();
+    get_hash_for_reader(HashReader::new(file), size, algorithm)
+}
+
+// size decides whether Blake3 hashes in parallel
+fn get_hash_for_reader<R: Read>(
+    mut reader: R,
+    size: u64,
+    algorithm: HashAlgorithm,
+) -> io::Result<Hash256>
+{
+    let mut hash = EMPTY_HASH;
+    match algorithm {
+        HashAlgorithm::Blake3 if size >= PARALLEL_HASH_MIN_SIZE => {
+            return get_hash_parallel(reader);
+        }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(&mut reader, &mut hasher)?;
+            hash = hasher.finalize().into();
+        }
+        HashAlgorithm::Sha256 => {
+            let mut hasher = sha2::Sha256::default();
+            io::copy(&mut reader, &mut hasher)?;
+            hash = sha2::Digest::finalize(hasher).into();
+        }
+        HashAlgorithm::Xxh128 => {
+            let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let len = reader.read(&mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                hasher.update(&buf[..len]);
+            }
+            hash[..16].copy_from_slice(&hasher.digest128().to_le_bytes());
+        }
+    }
+    Ok(hash)
+}
+
+// Read from the start and the end of files for their pre-hashes
+const PRE_HASH_BLOCK_SIZE: u64 = 64 << 10;
+
+// xxh3 of the size and the first and last PRE_HASH_BLOCK_SIZE bytes, never 0. Files with
+// different pre-hashes differ, those with equal ones need their full hashes compared.
+fn get_pre_hash(path: &Path) -> io::Result<u64>
+{
+    let mut file = File::open(get_extended_length_path(path))?;
+    let size = file.metadata()?.len();
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    hasher.update(&size.to_le_bytes());
+    let mut buf = vec![];
+    (&mut file).take(PRE_HASH_BLOCK_SIZE).read_to_end(&mut buf)?;
+    hasher.update(&buf);
+    if size > 2 * PRE_HASH_BLOCK_SIZE {
+        file.seek(SeekFrom::Start(size - PRE_HASH_BLOCK_SIZE))?;
+    }
+    buf.clear();
+    file.take(PRE_HASH_BLOCK_SIZE).read_to_end(&mut buf)?;
+    hasher.update(&buf);
+    Ok(hasher.digest().max(1))
+}
+
+fn add_file_db_entry(file_db: &mut FileDb, file_db_entry: FileDbEntry) -> EntryIndex
+{
+    file_db.push(file_db_entry);
+    (file_db.len() - 1) as EntryIndex
+}
+
+fn add_root_path_components(
+    root_dir: &Path,
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+)
+{
+    assert!(
+        !path_to_index.contains_key(root_dir.as_os_str()),
+        "Existing path added, not supported"
+    );
+
+    // Add all components of the root dir, including "/", as separate entries, since WalkDir won't report them
+    let mut root_dir_prefix = root_dir;
+    let mut components = Vec::<&OsStr>::new();
+    let mut found;
+    loop {
+        found = path_to_index.contains_key(root_dir_prefix.as_os_str());
+        if found || root_dir_prefix.parent().is_none() {
+            break;
+        } else {
+            components.push(root_dir_prefix.file_name().unwrap());
+            root_dir_prefix = root_dir_prefix.parent().unwrap();
+        }
+    }
+    let mut parent_index = EntryIndex::MAX;
+    if found {
+        parent_index = *path_to_index.get(root_dir_prefix.as_os_str()).unwrap();
+    } else {
+        components.push(root_dir_prefix.as_os_str());
+    }
+    let mut root_dir_tmp = PathBuf::new();
+    root_dir_tmp.push(root_dir_prefix);
+    for dir_name in components.into_iter().rev() {
+        root_dir_tmp.push(dir_name);
+        let metadata = fs::metadata(&root_dir_tmp).unwrap();
+        let (uid, gid, mode) = get_owner_and_mode(&metadata);
+        let file_db_entry = FileDbEntry {
+            name: dir_name.to_owned(),
+            is_dir: true,
+            parent: parent_index,
+            size: 0,
+            allocated: 0,
+            modified: get_secs(&metadata.modified().unwrap()),
+            accessed: get_secs(&metadata.accessed().unwrap()),
+            hash: EMPTY_HASH,
+            verified: 0,
+            inode: get_inode(&metadata),
+            uid,
+            gid,
+            mode,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
+        };
+        parent_index = file_db.len() as EntryIndex;
+        add_file_db_entry(file_db, file_db_entry);
+        path_to_index.insert(root_dir_tmp.as_os_str().to_owned(), parent_index);
+    }
+}
+
+fn build_dir_to_files_map(file_db: &FileDb, children: &ChildrenIndex) -> DirToFilesMap
+{
+    let mut dir_to_files_map = DirToFilesMap::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        if entry.is_dir {
+            let files = children
+                .get(index as EntryIndex)
+                .iter()
+                .filter(|child| !file_db.get(**child).is_dir)
+                .copied()
+                .collect();
+            dir_to_files_map.insert(index as EntryIndex, files);
+        }
+    }
+    dir_to_files_map
+}
+
+// Works for files and dirs, returns 0 for dirs
+fn get_hash_for_path(path: &Path, is_dir: bool, algorithm: HashAlgorithm) -> Hash256
+{
+    let mut hash = EMPTY_HASH;
+    if !is_dir {
+        let hash_result = get_hash_for_file(path, algorithm);
+        if hash_result.is_err() {
+            eprintln!("Error accessing {:?}", path);
+        } else {
+            hash = hash_result.unwrap();
+        }
+    }
+    hash
+}
+
+fn is_excluded(name: &OsStr, options: &CrawlOptions) -> bool
+{
+    let name = name.to_string_lossy();
+    options.excludes.iter().any(|pattern| pattern.matches(&name))
+}
+
+// Links are hashed with blake3 whatever the hash algorithm of the db, the hash only serves to
+// look up the target in Db::link_targets. Links to the same target share it.
+fn get_link_hash(target: &Path) -> Hash256
+{
+    blake3::hash(&get_path_bytes(target)).into()
+}
+
+// Walking errors caused by links, which are skipped when following them
+fn is_link_error(err: &io::Error) -> bool
+{
+    err.kind() == io::ErrorKind::NotFound
+}
+
+// Adds the named streams of the file at path as files file:stream in the same dir, with the
+// times of the file. Streams already among the existing files of the dir are skipped.
+fn add_alternate_streams(
+    file_db: &mut FileDb,
+    parent_index: EntryIndex,
+    path: &Path,
+    metadata: &fs::Metadata,
+    existing: &[EntryIndex],
+    options: &CrawlOptions,
+)
+{
+    let file_name = path.file_name().unwrap();
+    for (stream_name, size) in get_alternate_streams(path) {
+        let mut name = file_name.to_owned();
+        name.push(":");
+        name.push(&stream_name);
+        if existing.iter().any(|index| file_db.get(*index).name == name) {
+            continue;
+        }
+        let mut stream_path = path.as_os_str().to_owned();
+        stream_path.push(":");
+        stream_path.push(&stream_name);
+        let stream_path = PathBuf::from(stream_path);
+        println!("Adding stream {:?}", stream_path);
+        let (hash, pre_hash) = if options.no_hash {
+            (EMPTY_HASH, 0)
+        } else if options.lazy_hash {
+            match get_pre_hash(&stream_path) {
+                Ok(pre_hash) => (EMPTY_HASH, pre_hash),
+                Err(err) => {
+                    record_crawl_error(&stream_path, &err);
+                    (EMPTY_HASH, 0)
+                }
+            }
+        } else {
+            let algorithm = options.hash_algorithm.unwrap_or_default();
+            match get_hash_for_file(&stream_path, algorithm) {
+                Ok(hash) => (hash, 0),
+                Err(err) => {
+                    record_crawl_error(&stream_path, &err);
+                    (EMPTY_HASH, 0)
+                }
+            }
+        };
+        let file_db_entry = FileDbEntry {
+            name,
+            is_dir: false,
+            parent: parent_index,
+            size,
+            allocated: size,
+            modified: get_secs(&metadata.modified().unwrap()),
+            accessed: get_secs(&metadata.accessed().unwrap()),
+            hash,
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash,
+            origin: 0,
+        };
+        add_file_db_entry(file_db, file_db_entry);
+    }
+}
+
+fn add_dir_recursive(
+    root_dir: &Path,
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+    dir_to_file_indexes: &DirToFilesMap,
+    replace_prefix_to: &Path,
+    hash_algorithm: HashAlgorithm,
+) -> bool
+{
+    add_dir_recursive_ext(
+        root_dir,
+        file_db,
+        path_to_index,
+        dir_to_file_indexes,
+        replace_prefix_to,
+        &CrawlOptions { hash_algorithm: Some(hash_algorithm), ..Default::default() },
+        &ArchiveNesting::default(),
+        &VanishedFilesMap::new(),
+        None,
+    )
+}
+
+// Files matching one in vanished_files were moved, their hash is taken over instead of
+// hashing again. Returns false if interrupted.
+#[allow(clippy::too_many_arguments)]
+fn add_dir_recursive_ext(
+    root_dir_: &Path,
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+    dir_to_file_indexes: &DirToFilesMap,
+    replace_prefix_to: &Path,
+    options: &CrawlOptions,
+    nesting: &ArchiveNesting,
+    vanished_files: &VanishedFilesMap,
+    mut checkpointer: Option<&mut Checkpointer>,
+) -> bool
+{
+    println!("Adding dir {:?}", root_dir_);
+    assert!(root_dir_.is_absolute());
+
+    let is_update = dir_to_file_indexes.len() > 0;
+
+    let root_dir_buf = normalize_path(root_dir_);
+    let root_dir = root_dir_buf.as_path();
+
+    // For archives, the archive itself was already added as root
+    if !is_update && replace_prefix_to.as_os_str().is_empty() {
+        add_root_path_components(root_dir, file_db, path_to_index);
+    }
+
+    // The walked paths are only used for access, those in the db are below root_dir
+    let walk_root = get_extended_length_path(root_dir);
+    let root_device = fs::metadata(&walk_root).map_or(0, |metadata| get_device(&metadata));
+    let mut walker = WalkDir::new(&walk_root)
+        .follow_links(options.follow_links)
+        .same_file_system(options.one_file_system)
+        .contents_first(false)
+        .into_iter();
+    'walker: while let Some(result_dir_entry) = walker.next() {
+        let dir_entry = match result_dir_entry {
+            Ok(dir_entry) => dir_entry,
+            // Loops and broken links, only reported when following links
+            Err(err) if options.follow_links && err.io_error().is_none_or(is_link_error) => {
+                eprintln!("Skipping link: {}", err);
+                continue;
+            }
+            Err(err) => {
+                let path = err.path().map_or(walk_root.clone(), Path::to_owned);
+                let path = replace_prefix(&path, &walk_root, root_dir);
+                let path = replace_prefix(&path, root_dir, replace_prefix_to);
+                record_crawl_error(&path, &io::Error::from(err));
+                continue;
+            }
+        };
+        let mut path = replace_prefix(dir_entry.path(), &walk_root, root_dir);
+        // Mounted below the root, they are added as empty dirs. Crawling one is still possible.
+        if !options.virtual_file_systems
+            && dir_entry.file_type().is_dir()
+            && dir_entry.metadata().is_ok_and(|metadata| get_device(&metadata) != root_device)
+            && is_virtual_file_system(dir_entry.path())
+        {
+            println!("Not descending into virtual file system at {:?}", path);
+            walker.skip_current_dir();
+        }
+        if options.skip_backup_excluded && dir_entry.depth() > 0 {
+            let is_dir = dir_entry.file_type().is_dir();
+            if dir_entry.metadata().is_ok_and(|metadata| is_nodump(dir_entry.path(), &metadata)) {
+                println!("Skipping {:?}, flagged nodump", path);
+                if is_dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            if is_dir && is_cache_dir(dir_entry.path()) {
+                println!("Not descending into cache dir {:?}", path);
+                walker.skip_current_dir();
+            }
+        }
+
+        if dir_entry.depth() > 0 && is_excluded(dir_entry.file_name(), options) {
+            println!("Excluding {:?}", path);
+            if dir_entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+
+        if is_cancelled(options.cancellation.as_ref()) {
+            println!("Interrupted, stopped before {:?}", path);
+            return false;
+        }
+
+        // When dealing with an archive that was unpacked to a temporary directory:
+        // Replace the path prefix of the temporary directory with the full path to
+        // the archive. Proceed with handling as usual. The archive is thus handled
+        // like a directory, with the contents added beneath it.
+        path = replace_prefix(&path, root_dir, replace_prefix_to);
+        if let Some(progress) = &options.progress {
+            progress.report(&path);
+        }
+
+        let path_os_str = path.as_os_str();
+
+        if let Some(index) = path_to_index.get(path_os_str) {
+            if is_update && replace_prefix_to.as_os_str().is_empty() {
+                let mut entry = file_db.entry_mut(*index);
+                match dir_entry.metadata() {
+                    Ok(metadata) => {
+                        set_owner_and_mode(&mut entry, &metadata);
+                        if options.xattrs || options.mac_metadata {
+                            entry.xattrs = get_entry_xattrs(dir_entry.path(), &metadata, options);
+                        }
+                    }
+                    Err(err) => record_crawl_error(&path, &io::Error::from(err)),
+                }
+            }
+            continue;
+        }
+
+        let metadata = match dir_entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                record_crawl_error(&path, &io::Error::from(err));
+                if dir_entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+        };
+        let mut is_dir = metadata.is_dir();
+        if options.one_file_system && is_dir && get_device(&metadata) != root_device {
+            println!("Not descending into other file system at {:?}", path);
+        }
+        let link_target = if metadata.file_type().is_symlink() {
+            Some(fs::read_link(dir_entry.path()).unwrap_or_default())
+        } else {
+            None
+        };
+
+        let parent_path = path.parent().unwrap();
+        let parent_index = *path_to_index.get(parent_path.as_os_str()).unwrap();
+
+        let file_name = path.file_name().unwrap().to_os_string();
+
+        if is_update {
+            if is_dir {
+                let entry = path_to_index.get(path_os_str);
+                if entry.is_some() {
+                    continue 'walker;
+                }
+            } else {
+                if let Some(parent_entry) = path_to_index.get(parent_path.as_os_str()) {
+                    if let Some(file_entries) = dir_to_file_indexes.get(parent_entry) {
+                        for file_index in file_entries {
+                            if file_db.get(*file_index).name == file_name {
+                                // Owner, permissions and allocation change without modifying
+                                // the file
+                                if replace_prefix_to.as_os_str().is_empty() {
+                                    let mut entry = file_db.entry_mut(*file_index);
+                                    set_owner_and_mode(&mut entry, &metadata);
+                                    *entry.allocated = get_allocated(&metadata);
+                                    if options.xattrs || options.mac_metadata {
+                                        entry.xattrs =
+                                            get_entry_xattrs(dir_entry.path(), &metadata, options);
+                                    }
+                                    if options.mime && entry.mime.is_empty() {
+                                        entry.mime = get_mime(dir_entry.path());
+                                    }
+                                    if options.alternate_streams {
+                                        add_alternate_streams(
+                                            file_db,
+                                            parent_index,
+                                            dir_entry.path(),
+                                            &metadata,
+                                            file_entries,
+                                            options,
+                                        );
+                                    }
+                                }
+                                continue 'walker;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Archives that can be read are added like a directory, with the contents beneath
+        let mut archive = None;
+        if options.index_archives && !is_dir && link_target.is_none() && is_archive(&path) {
+            archive = read_archive(dir_entry.path(), &path, options, nesting);
+            is_dir = archive.is_some();
+        }
+
+        let modified_secs = get_secs(&metadata.modified().unwrap());
+        let accessed_secs = get_secs(&metadata.accessed().unwrap());
+        // Inodes and owners of unpacked archive contents are meaningless
+        let (inode, (uid, gid, mut mode)) = if replace_prefix_to.as_os_str().is_empty() {
+            (get_inode(&metadata), get_owner_and_mode(&metadata))
+        } else {
+            (0, (0, 0, 0))
+        };
+        if link_target.is_some() {
+            mode = (mode & !MODE_TYPE_MASK) | MODE_LINK;
+        }
+        // Unpacked archive contents are assumed not to be sparse
+        let allocated = match (is_dir, replace_prefix_to.as_os_str().is_empty()) {
+            (true, _) => 0,
+            _ if link_target.is_some() => 0,
+            (false, true) => get_allocated(&metadata),
+            (false, false) => metadata.len(),
+        };
+        let mime = if options.mime && metadata.is_file() {
+            get_mime(dir_entry.path())
+        } else {
+            String::new()
+        };
+        let xattrs = if replace_prefix_to.as_os_str().is_empty() {
+            get_entry_xattrs(dir_entry.path(), &metadata, options)
+        } else {
+            vec![]
+        };
+        let moved_from = if is_dir || inode == 0 || link_target.is_some() {
+            None
+        } else {
+            vanished_files.get(&(metadata.len(), modified_secs, get_device(&metadata), inode))
+        };
+        let (hash, verified, pre_hash) = match (moved_from, &link_target) {
+            (_, Some(target)) => {
+                println!("Adding link {:?} to {:?}", &path, target);
+                (get_link_hash(target), 0, 0)
+            }
+            (Some(moved_from), None) => {
+                println!("Moved to {:?}", &path);
+                (moved_from.hash, moved_from.verified, moved_from.pre_hash)
+            }
+            (None, None) => {
+                println!("Adding {:?}", &path);
+                // Unpacked archive contents are not available later
+                let is_on_disk = replace_prefix_to.as_os_str().is_empty();
+                if options.no_hash && is_on_disk {
+                    (EMPTY_HASH, 0, 0)
+                } else if options.lazy_hash && is_on_disk && !is_dir {
+                    match get_pre_hash(dir_entry.path()) {
+                        Ok(pre_hash) => (EMPTY_HASH, 0, pre_hash),
+                        Err(err) => {
+                            record_crawl_error(&path, &err);
+                            (EMPTY_HASH, 0, 0)
+                        }
+                    }
+                } else if is_dir {
+                    (EMPTY_HASH, 0, 0)
+                } else {
+                    let algorithm = options.hash_algorithm.unwrap_or_default();
+                    match get_hash_for_file(dir_entry.path(), algorithm) {
+                        Ok(hash) => (hash, 0, 0),
+                        Err(err) => {
+                            record_crawl_error(&path, &err);
+                            (EMPTY_HASH, 0, 0)
+                        }
+                    }
+                }
+            }
+        };
+
+        let file_db_entry = FileDbEntry {
+            name: file_name,
+            is_dir: is_dir,
+            parent: parent_index,
+            size: if is_dir || link_target.is_some() { 0 } else { metadata.len() },
+            allocated,
+            modified: modified_secs, // Note: For unpacked archive contents,
+            accessed: accessed_secs, // this may be the depack time
+            hash: hash,
+            verified,
+            inode,
+            uid,
+            gid,
+            mode,
+            xattrs,
+            mime,
+            pre_hash,
+            origin: 0,
+        };
+        let len_before = file_db.len();
+        add_file_db_entry(file_db, file_db_entry);
+        if (file_db.len() % 1000) == 0 {
+            println!("{}", file_db.len());
+        }
+
+        if is_dir {
+            let path_owned = path.as_os_str().to_owned();
+            assert!(!path_to_index.contains_key(&path_owned));
+            path_to_index.insert(path_owned, (file_db.len() - 1) as EntryIndex);
+        } else if options.alternate_streams
+            && link_target.is_none()
+            && replace_prefix_to.as_os_str().is_empty()
+        {
+            let existing = dir_to_file_indexes.get(&parent_index).map_or(&[][..], Vec::as_slice);
+            add_alternate_streams(
+                file_db,
+                parent_index,
+                dir_entry.path(),
+                &metadata,
+                existing,
+                options,
+            );
+        }
+
+        match archive {
+            Some(ArchiveContents::Listed(rar_entries)) => {
+                add_rar_entries(file_db, path_to_index, &path, &metadata, rar_entries);
+            }
+            Some(ArchiveContents::Unpacked { tmp_dir, hash, size }) => {
+                let mut hashes = nesting.hashes.clone();
+                hashes.push(hash);
+                let inner_nesting = ArchiveNesting {
+                    hashes,
+                    unpacked_size: nesting.unpacked_size + size,
+                };
+                let completed = add_dir_recursive_ext(
+                    tmp_dir.path(),
+                    file_db,
+                    path_to_index,
+                    dir_to_file_indexes,
+                    &path,
+                    options,
+                    &inner_nesting,
+                    &VanishedFilesMap::new(),
+                    None,
+                );
+                if !completed {
+                    // Drop the partial archive, so it is added again when resuming
+                    file_db.truncate(len_before);
+                    path_to_index.retain(|_, index| (*index as usize) < len_before);
+                    return false;
+                }
+            }
+            None => {}
+        }
+
+        if let Some(checkpointer) = checkpointer.as_mut() {
+            checkpointer.save_if_due(file_db, &path);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+fn crawl_initial(root_dir: &Path) -> FileDb
+{
+    let mut file_db = FileDb::new();
+    let mut path_to_index: PathToIndexMap = HashMap::new();
+    let dir_to_file_indexes = DirToFilesMap::new();
+
+    add_dir_recursive(
+        root_dir,
+        &mut file_db,
+        &mut path_to_index,
+        &dir_to_file_indexes,
+        Path::new(""),
+        HashAlgorithm::default(),
+    );
+    file_db
+}
+
+fn build_path_to_index_map(file_db: &FileDb, children: &ChildrenIndex) -> PathToIndexMap
+{
+    let mut path_to_index = PathToIndexMap::new();
+    if file_db.is_empty() {
+        return path_to_index;
+    }
+    // Top down, so the path of each dir extends that of its parent
+    let mut stack = vec![(0, PathBuf::from(file_db.get(0).name))];
+    while let Some((index, path)) = stack.pop() {
+        for child in children.get(index) {
+            let entry = file_db.get(*child);
+            if entry.is_dir {
+                stack.push((*child, path.join(entry.name)));
+            }
+        }
+        path_to_index.insert(path.into_os_string(), index);
+    }
+    path_to_index
+}
+
+// Like build_path_to_index_map, but with files, too
+fn build_all_paths_to_index_map(file_db: &FileDb) -> PathToIndexMap
+{
+    let mut path_to_index = PathToIndexMap::new();
+    for index in 0..file_db.len() {
+        let path = get_full_path(file_db, index as EntryIndex);
+        path_to_index.insert(path.into_os_string(), index as EntryIndex);
+    }
+    path_to_index
+}
+
+// Removes the given entries and everything beneath them, returns the number of removed entries.
+// Indexes of the remaining entries change.
+fn remove_subtrees(file_db: &mut FileDb, subtree_roots: &HashSet<EntryIndex>) -> usize
+{
+    assert!(!subtree_roots.contains(&0), "Cannot remove the root");
+    let mut is_removed: Vec<Option<bool>> = vec![None; file_db.len()];
+    for entry_index in 0..file_db.len() {
+        let mut chain = vec![];
+        let mut index = entry_index as EntryIndex;
+        let removed = loop {
+            if let Some(removed) = is_removed[index as usize] {
+                break removed;
+            }
+            chain.push(index);
+            if subtree_roots.contains(&index) {
+                break true;
+            }
+            if is_root_index(index) {
+                break false;
+            }
+            index = file_db.get(index).parent;
+        };
+        for index in chain {
+            is_removed[index as usize] = Some(removed);
+        }
+    }
+
+    let mut new_indexes = vec![EntryIndex::MAX; file_db.len()];
+    let mut num_kept = 0;
+    for (index, removed) in is_removed.iter().enumerate() {
+        if !removed.unwrap() {
+            new_indexes[index] = num_kept;
+            num_kept += 1;
+        }
+    }
+    let old_file_db = std::mem::take(file_db);
+    let num_removed = old_file_db.len() - num_kept as usize;
+    for (index, entry) in old_file_db.iter().enumerate() {
+        if is_removed[index].unwrap() {
+            continue;
+        }
+        let mut entry = entry.to_entry();
+        if !is_root_index(index as EntryIndex) {
+            entry.parent = new_indexes[entry.parent as usize];
+        }
+        file_db.push(entry);
+    }
+    num_removed
+}
+
+fn crawl_add(file_db: &mut FileDb, root_dir: &Path, hash_algorithm: HashAlgorithm)
+{
+    let mut path_to_index = build_path_to_index_map(file_db, &ChildrenIndex::new(file_db));
+
+    let dir_to_file_indexes = DirToFilesMap::new();
+
+    add_dir_recursive(
+        Path::new(root_dir),
+        file_db,
+        &mut path_to_index,
+        &dir_to_file_indexes,
+        Path::new(""),
+        hash_algorithm,
+    );
+}
+
+// Stored in front of the FileDb in <db>.checkpoint
+#[derive(Serialize, Deserialize)]
+struct CheckpointHeader
+{
+    root_dir: PathBuf,
+    snapshot: Option<String>,
+    last_path: PathBuf, // Last path added before the checkpoint was written
+}
+
+fn save_checkpoint(checkpoint_name: &Path, header: &CheckpointHeader, file_db: &FileDb)
+{
+    println!("Saving checkpoint to {:?}", checkpoint_name);
+    write_file_atomically(checkpoint_name, false, false, |file| {
+        let mut encoder = ZlibEncoder::new(io::BufWriter::new(file), Compression::fast());
+        bincode::serialize_into(&mut encoder, header).unwrap();
+        bincode::serialize_into(&mut encoder, file_db).unwrap();
+        encoder.finish().unwrap().flush().unwrap();
+    });
+}
+
+fn load_checkpoint(checkpoint_name: &Path) -> (CheckpointHeader, FileDb)
+{
+    println!("Loading checkpoint from {:?}", checkpoint_name);
+    let reader = io::BufReader::new(File::open(checkpoint_name).unwrap());
+    let mut decoder = ZlibDecoder::new(reader);
+    let header = bincode::deserialize_from(&mut decoder).unwrap();
+    let file_db = bincode::deserialize_from(&mut decoder).unwrap();
+    (header, file_db)
+}
+
+struct Checkpointer
+{
+    checkpoint_name: PathBuf,
+    header: CheckpointHeader,
+    last_save: time::Instant,
+    last_save_len: usize,
+}
+
+impl Checkpointer
+{
+    fn save_if_due(&mut self, file_db: &FileDb, last_path: &Path)
+    {
+        if file_db.len() - self.last_save_len >= CHECKPOINT_INTERVAL_ENTRIES
+            || self.last_save.elapsed() >= CHECKPOINT_INTERVAL
+        {
+            self.save(file_db, last_path);
+        }
+    }
+
+    fn save(&mut self, file_db: &FileDb, last_path: &Path)
+    {
+        self.header.last_path = last_path.to_path_buf();
+        save_checkpoint(&self.checkpoint_name, &self.header, file_db);
+        self.last_save = time::Instant::now();
+        self.last_save_len = file_db.len();
+    }
+}
+
+// With snapshot set, the tree is added to the named snapshot, which is created if needed.
+// With resume set, an add of root_dir that was interrupted continues from its checkpoint.
+// On SIGINT/SIGTERM, a checkpoint is saved instead of the db.
+// options with the hash algorithm of db, refusing to add hashes of another one
+fn get_db_crawl_options(db: &Db, options: &CrawlOptions) -> CrawlOptions
+{
+    if let Some(hash_algorithm) = options.hash_algorithm {
+        assert!(
+            hash_algorithm == db.hash_algorithm,
+            "Db is hashed with {}, cannot mix in {}",
+            db.hash_algorithm.get_name(),
+            hash_algorithm.get_name()
+        );
+    }
+    CrawlOptions { hash_algorithm: Some(db.hash_algorithm), ..options.clone() }
+}
+
+pub fn add(
+    file_db_name: &Path,
+    root_dir: &Path,
+    snapshot: Option<&str>,
+    resume: bool,
+    options: &CrawlOptions,
+)
+{
+    install_interrupt_handler();
+    let checkpoint_name = get_db_side_file_name(file_db_name, "checkpoint");
+    assert!(!resume || checkpoint_name.exists(), "No checkpoint {:?} to resume from", checkpoint_name);
+    if !resume && checkpoint_name.exists() {
+        panic!(
+            "Checkpoint {:?} of an interrupted add exists, continue with add --resume or delete it",
+            checkpoint_name
+        );
+    }
+    let mut db;
+    if fs::metadata(file_db_name).map_or(false, |metadata| metadata.is_file()) {
+        db = load_compressed(file_db_name);
+    } else {
+        db = Db::default();
+        db.hash_algorithm = options.hash_algorithm.unwrap_or_default();
+    }
+    let options = &get_db_crawl_options(&db, options);
+    if let Some(name) = snapshot {
+        if get_snapshot_index(&db, name).is_none() {
+            db.snapshots.push(Snapshot {
+                name: name.to_string(),
+                created: get_secs(&time::SystemTime::now()),
+                file_db: FileDb::new(),
+            });
+        }
+    }
+    let file_db = get_file_db_mut(&mut db, snapshot);
+    if resume {
+        let (header, checkpoint_file_db) = load_checkpoint(&checkpoint_name);
+        assert!(
+            header.root_dir == root_dir && header.snapshot.as_deref() == snapshot,
+            "Checkpoint is for {:?} (snapshot {:?})",
+            header.root_dir,
+            header.snapshot
+        );
+        println!("Resuming after {:?}", header.last_path);
+        *file_db = checkpoint_file_db;
+    }
+
+    let children = ChildrenIndex::new(file_db);
+    let mut path_to_index = build_path_to_index_map(file_db, &children);
+    // When resuming, skip everything that made it into the checkpoint, like update does
+    let dir_to_file_indexes = if resume {
+        build_dir_to_files_map(file_db, &children)
+    } else {
+        DirToFilesMap::new()
+    };
+    let mut checkpointer = Checkpointer {
+        checkpoint_name: checkpoint_name.clone(),
+        header: CheckpointHeader {
+            root_dir: root_dir.to_path_buf(),
+            snapshot: snapshot.map(str::to_string),
+            last_path: PathBuf::new(),
+        },
+        last_save: time::Instant::now(),
+        last_save_len: file_db.len(),
+    };
+    let completed = add_dir_recursive_ext(
+        root_dir,
+        file_db,
+        &mut path_to_index,
+        &dir_to_file_indexes,
+        Path::new(""),
+        options,
+        &ArchiveNesting::default(),
+        &VanishedFilesMap::new(),
+        Some(&mut checkpointer),
+    );
+    report_crawl_errors(options.error_report.as_deref());
+    if !completed {
+        let last_path = get_full_path(file_db, (file_db.len() - 1) as EntryIndex);
+        checkpointer.save(file_db, &last_path);
+        println!("Stopped after {:?}, continue with add --resume {:?}", last_path, root_dir);
+        return;
+    }
+
+    propagate_sizes(file_db);
+    update_link_targets(&mut db, snapshot);
+    if options.media {
+        update_media_info(&mut db, snapshot);
+    }
+    if options.phash {
+        update_image_hashes(&mut db, snapshot);
+    }
+    if options.audio_fingerprint {
+        update_audio_fingerprints(&mut db, snapshot);
+    }
+    if options.fuzzy_hash {
+        update_fuzzy_hashes(&mut db, snapshot);
+    }
+    if options.chunk_hashes {
+        update_chunks(&mut db, snapshot, options.chunk_min_file_size);
+    }
+    roots::register_root(&mut db, root_dir);
+
+    save_compressed(file_db_name, &db);
+    if checkpoint_name.exists() {
+        fs::remove_file(&checkpoint_name).unwrap();
+    }
+}
+
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "tif", "tiff", "webp", "gif", "bmp"];
+const VIDEO_EXTENSIONS: [&str; 3] = ["mp4", "mov", "m4v"];
+const AUDIO_EXTENSIONS: [&str; 7] = ["flac", "mp3", "ogg", "oga", "wav", "m4a", "aac"];
+
+fn has_extension(entry: &Entry, extensions: &[&str]) -> bool
+{
+    let extension = Path::new(&entry.name).extension().map(OsStr::to_ascii_lowercase);
+    extension.is_some_and(|extension| extensions.iter().any(|known| extension == *known))
+}
+
+// Files worth reading media info of, by detected type or extension
+fn is_media_candidate(entry: &Entry) -> bool
+{
+    entry.mime.starts_with("image/")
+        || entry.mime.starts_with("video/")
+        || has_extension(entry, &IMAGE_EXTENSIONS)
+        || has_extension(entry, &["heic", "heif"])
+        || has_extension(entry, &VIDEO_EXTENSIONS)
+}
+
+fn is_image_candidate(entry: &Entry) -> bool
+{
+    entry.mime.starts_with("image/") || has_extension(entry, &IMAGE_EXTENSIONS)
+}
+
+fn is_audio_candidate(entry: &Entry) -> bool
+{
+    entry.mime.starts_with("audio/") || has_extension(entry, &AUDIO_EXTENSIONS)
+}
+
+// Contents of all trees in the db
+fn get_all_hashes(db: &Db) -> HashSet<Hash256>
+{
+    let file_dbs = std::iter::once(&db.file_db).chain(db.snapshots.iter().map(|s| &s.file_db));
+    file_dbs.flat_map(|file_db| file_db.iter()).map(|entry| entry.hash).collect()
+}
+
+// Reads a value for each content of the tree of snapshot matching is_candidate that is not
+// in known yet, one copy per content. Archive contents are not available and skipped.
+fn read_unknown_contents<T>(
+    db: &Db,
+    snapshot: Option<&str>,
+    known: &HashMap<Hash256, T>,
+    is_candidate: impl Fn(&Entry) -> bool,
+    read: fn(&Path) -> Option<T>,
+) -> Vec<(Hash256, T)>
+{
+    let file_db = match snapshot {
+        Some(name) => &db.snapshots[get_snapshot_index(db, name).unwrap()].file_db,
+        None => &db.file_db,
+    };
+    let mut candidates = HashMap::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        let is_new = !entry.is_dir
+            && entry.size > 0
+            && is_hashed(&entry)
+            && !known.contains_key(&entry.hash);
+        if is_new && is_candidate(&entry) {
+            candidates.entry(entry.hash).or_insert(index as EntryIndex);
+        }
+    }
+    candidates
+        .into_iter()
+        .filter_map(|(hash, index)| Some((hash, read(&get_full_path(file_db, index))?)))
+        .collect()
+}
+
+// Reads the media info of the photos and videos in the tree of snapshot that lack it, and
+// drops that of contents no longer in the db
+fn update_media_info(db: &mut Db, snapshot: Option<&str>)
+{
+    let hashes = get_all_hashes(db);
+    db.media.retain(|hash, _| hashes.contains(hash));
+    let media =
+        read_unknown_contents(db, snapshot, &db.media, is_media_candidate, media::read_media_info);
+    println!("Read media info of {} files", media.len());
+    db.media.extend(media);
+}
+
+// Reads the targets of the links in the tree of snapshot that lack them, and drops those of
+// links no longer in the db. Links are not followed when reading them.
+fn update_link_targets(db: &mut Db, snapshot: Option<&str>)
+{
+    let hashes = get_all_hashes(db);
+    db.link_targets.retain(|hash, _| hashes.contains(hash));
+    let file_db = get_file_db_mut(db, snapshot);
+    let mut targets = HashMap::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        if is_link(&entry) && !targets.contains_key(&entry.hash) {
+            if let Ok(target) = fs::read_link(get_full_path(file_db, index as EntryIndex)) {
+                targets.insert(entry.hash, target);
+            }
+        }
+    }
+    db.link_targets.extend(targets);
+}
+
+// Like update_media_info, for perceptual hashes of images
+fn update_image_hashes(db: &mut Db, snapshot: Option<&str>)
+{
+    let hashes = get_all_hashes(db);
+    db.image_hashes.retain(|hash, _| hashes.contains(hash));
+    let image_hashes = read_unknown_contents(
+        db,
+        snapshot,
+        &db.image_hashes,
+        is_image_candidate,
+        media::get_image_hash,
+    );
+    println!("Computed perceptual hashes of {} images", image_hashes.len());
+    db.image_hashes.extend(image_hashes);
+}
+
+// Like update_media_info, for fingerprints of audio files
+fn update_audio_fingerprints(db: &mut Db, snapshot: Option<&str>)
+{
+    let hashes = get_all_hashes(db);
+    db.audio_fingerprints.retain(|hash, _| hashes.contains(hash));
+    let fingerprints = read_unknown_contents(
+        db,
+        snapshot,
+        &db.audio_fingerprints,
+        is_audio_candidate,
+        audio::get_audio_fingerprint,
+    );
+    println!("Computed fingerprints of {} audio files", fingerprints.len());
+    db.audio_fingerprints.extend(fingerprints);
+}
+
+// Like update_media_info, for similarity digests of all files
+fn update_fuzzy_hashes(db: &mut Db, snapshot: Option<&str>)
+{
+    let hashes = get_all_hashes(db);
+    db.fuzzy_hashes.retain(|hash, _| hashes.contains(hash));
+    let fuzzy_hashes =
+        read_unknown_contents(db, snapshot, &db.fuzzy_hashes, |_| true, fuzzy::get_fuzzy_hash);
+    println!("Computed similarity digests of {} files", fuzzy_hashes.len());
+    db.fuzzy_hashes.extend(fuzzy_hashes);
+}
+
+// Like update_media_info, for chunk hashes of files of at least min_size
+fn update_chunks(db: &mut Db, snapshot: Option<&str>, min_size: u64)
+{
+    let hashes = get_all_hashes(db);
+    db.chunks.retain(|hash, _| hashes.contains(hash));
+    let is_candidate = |entry: &Entry| entry.size >= min_size;
+    let chunks = read_unknown_contents(db, snapshot, &db.chunks, is_candidate, chunks::get_chunks);
+    println!("Computed chunk hashes of {} files", chunks.len());
+    db.chunks.extend(chunks);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PruneState
+{
+    Kept,
+    Deleted,
+    InArchive, // Unchanged archive or its contents, which cannot be checked on the file system
+}
+
+// The device of a vanished file is not in the db, it is taken from the nearest dir above it
+// that still exists. devices caches those of the dirs looked at.
+fn add_vanished_file(
+    file_db: &FileDb,
+    index: EntryIndex,
+    vanished_files: &mut VanishedFilesMap,
+    devices: &mut HashMap<EntryIndex, u64>,
+)
+{
+    let entry = file_db.get(index);
+    if entry.is_dir || entry.inode == 0 {
+        return;
+    }
+    let mut dir_indices = vec![];
+    let mut dir_index = entry.parent;
+    let device = loop {
+        if let Some(device) = devices.get(&dir_index) {
+            break *device;
+        }
+        dir_indices.push(dir_index);
+        if let Ok(metadata) = fs::metadata(get_full_path(file_db, dir_index)) {
+            break get_device(&metadata);
+        }
+        if is_root_index(dir_index) {
+            return;
+        }
+        dir_index = file_db.get(dir_index).parent;
+    };
+    for dir_index in dir_indices {
+        devices.insert(dir_index, device);
+    }
+    vanished_files.insert((entry.size, entry.modified, device, entry.inode), entry.to_entry());
+}
+
+fn get_prune_state(
+    file_db: &FileDb,
+    index: EntryIndex,
+    vanished_files: &mut VanishedFilesMap,
+    devices: &mut HashMap<EntryIndex, u64>,
+) -> PruneState
+{
+    let path = get_full_path(file_db, index);
+    let entry = file_db.get(index);
+    let metadata = match fs::symlink_metadata(&path) {
+        // Followed with CrawlOptions::follow_links
+        Ok(metadata) if metadata.file_type().is_symlink() && !is_link(&entry) => {
+            fs::metadata(&path)
+        }
+        metadata => metadata,
+    };
+    match metadata {
+        Ok(metadata) => {
+            let modified = get_secs(&metadata.modified().unwrap());
+            let is_changed = modified != entry.modified
+                || (!is_link(&entry) && metadata.len() != entry.size);
+            if entry.is_dir && !metadata.is_dir() && is_archive(&path) {
+                // Indexed archive, the entry has the archive's time
+                if modified == entry.modified { PruneState::InArchive } else { PruneState::Deleted }
+            } else if metadata.is_dir() != entry.is_dir
+                || metadata.file_type().is_symlink() != is_link(&entry)
+                || (!metadata.is_dir() && is_changed)
+            {
+                PruneState::Deleted
+            } else {
+                PruneState::Kept
+            }
+        }
+        Err(_) => {
+            add_vanished_file(file_db, index, vanished_files, devices);
+            PruneState::Deleted
+        }
+    }
+}
+
+// Removes changed and deleted entries, including everything beneath deleted dirs.
+// Returns the files that no longer exist, for detecting moves.
+fn prune_deleted_paths(file_db: &mut FileDb) -> VanishedFilesMap
+{
+    prune_deleted_paths_in(file_db, 0..file_db.len() as EntryIndex)
+}
+
+// Only checks the given entries and the dirs above them
+fn prune_deleted_paths_in(
+    file_db: &mut FileDb,
+    indices: impl IntoIterator<Item = EntryIndex>,
+) -> VanishedFilesMap
+{
+    println!("Pruning deleted paths");
+    let mut vanished_files = VanishedFilesMap::new();
+    let mut devices = HashMap::new();
+    let mut states: Vec<Option<PruneState>> = vec![None; file_db.len()];
+    // Only the topmost deleted entries, everything beneath goes with them
+    let mut deleted = HashSet::new();
+    for entry_index in indices {
+        // Parents are not necessarily stored before their children
+        let mut chain = vec![];
+        let mut index = entry_index;
+        while states[index as usize].is_none() {
+            chain.push(index);
+            if is_root_index(index) {
+                break;
+            }
+            index = file_db.get(index).parent;
+        }
+        for index in chain.into_iter().rev() {
+            let entry = file_db.get(index);
+            let parent_state = if is_root_index(index) {
+                PruneState::Kept
+            } else {
+                states[entry.parent as usize].unwrap()
+            };
+            let state = match parent_state {
+                PruneState::Kept => {
+                    get_prune_state(file_db, index, &mut vanished_files, &mut devices)
+                }
+                PruneState::InArchive => PruneState::InArchive,
+                PruneState::Deleted => {
+                    add_vanished_file(file_db, index, &mut vanished_files, &mut devices);
+                    PruneState::Deleted
+                }
+            };
+            if state == PruneState::Deleted && parent_state != PruneState::Deleted {
+                assert!(!is_root_index(index), "Root {:?} is gone", get_full_path(file_db, index));
+                deleted.insert(index);
+            }
+            states[index as usize] = Some(state);
+        }
+    }
+    let old_len = file_db.len();
+    let deleted_entries = if deleted.is_empty() { 0 } else { remove_subtrees(file_db, &deleted) };
+    println!(
+        "Pruned {} paths ({} within deleted dirs), old: {}, new: {}",
+        deleted_entries,
+        deleted_entries - deleted.len(),
+        old_len,
+        file_db.len()
+    );
+    file_db.shrink_to_fit();
+    vanished_files
+}
+
+// root_dir must be the original root dir used for the file_db,
+// otherwise behavior is undefined (may still work but untested).
+// On SIGINT/SIGTERM, the partial result is saved, running update again continues.
+pub fn update(
+    file_db_name: &Path,
+    root_dir: &Path,
+    snapshot: Option<&str>,
+    options: &CrawlOptions,
+)
+{
+    update_helper(file_db_name, UpdateScope::Root(root_dir), snapshot, options);
+}
+
+// Rescans all roots registered by add, see roots
+pub fn update_all_roots(file_db_name: &Path, snapshot: Option<&str>, options: &CrawlOptions)
+{
+    update_helper(file_db_name, UpdateScope::AllRoots, snapshot, options);
+}
+
+// Only prunes and rescans the dir prefix below one of the roots, when it is known that
+// nothing else changed. Returns false if prefix is not such a dir in the db.
+pub fn update_prefix(
+    file_db_name: &Path,
+    prefix: &Path,
+    snapshot: Option<&str>,
+    options: &CrawlOptions,
+) -> bool
+{
+    update_helper(file_db_name, UpdateScope::Prefix(prefix), snapshot, options)
+}
+
+enum UpdateScope<'a>
+{
+    Root(&'a Path),
+    AllRoots,
+    Prefix(&'a Path),
+}
+
+fn update_helper(
+    file_db_name: &Path,
+    scope: UpdateScope,
+    snapshot: Option<&str>,
+    options: &CrawlOptions,
+) -> bool
+{
+    install_interrupt_handler();
+    let num_errors = crawl_errors::get_num_crawl_errors();
+    let mut db = load_compressed(file_db_name);
+    let options = &get_db_crawl_options(&db, options);
+    let change_base = changes::get_change_base(&db, snapshot, options);
+    let root_dirs = match scope {
+        UpdateScope::Root(root_dir) | UpdateScope::Prefix(root_dir) => vec![root_dir.to_owned()],
+        UpdateScope::AllRoots => roots::get_update_roots(&db),
+    };
+    if root_dirs.is_empty() {
+        eprintln!("No roots in {:?}, give the path to update", file_db_name);
+        return false;
+    }
+    if let UpdateScope::Prefix(prefix) = scope {
+        if !db.roots.iter().any(|root| prefix.starts_with(&root.path)) {
+            eprintln!("{:?} is not below any root of {:?}", prefix, file_db_name);
+            return false;
+        }
+    }
+    let file_db = get_file_db_mut(&mut db, snapshot);
+    let vanished_files = match scope {
+        UpdateScope::Prefix(prefix) => {
+            let children = ChildrenIndex::new(file_db);
+            match children.find_path(file_db, prefix) {
+                Some(index) if file_db.get(index).is_dir => {
+                    prune_deleted_paths_in(file_db, children.get_subtree(index))
+                }
+                _ => {
+                    eprintln!("{:?} is not a dir in {:?}", prefix, file_db_name);
+                    return false;
+                }
+            }
+        }
+        _ => prune_deleted_paths(file_db),
+    };
+
+    let children = ChildrenIndex::new(file_db);
+    let mut path_to_index = build_path_to_index_map(file_db, &children);
+    let dir_to_files = build_dir_to_files_map(file_db, &children);
+
+    let mut completed = true;
+    for root_dir in &root_dirs {
+        if !root_dir.is_dir() {
+            eprintln!("{:?} is not a dir, not updating it", root_dir);
+            continue;
+        }
+        println!("Updating {:?}", root_dir);
+        completed = add_dir_recursive_ext(
+            root_dir,
+            file_db,
+            &mut path_to_index,
+            &dir_to_files,
+            Path::new(""),
+            options,
+            &ArchiveNesting::default(),
+            &vanished_files,
+            None,
+        );
+        if !completed {
+            break;
+        }
+    }
+    report_crawl_errors(options.error_report.as_deref());
+
+    propagate_sizes(file_db);
+    propagate_hashes(file_db);
+    update_link_targets(&mut db, snapshot);
+    if options.media {
+        update_media_info(&mut db, snapshot);
+    }
+    if options.phash {
+        update_image_hashes(&mut db, snapshot);
+    }
+    if options.audio_fingerprint {
+        update_audio_fingerprints(&mut db, snapshot);
+    }
+    if options.fuzzy_hash {
+        update_fuzzy_hashes(&mut db, snapshot);
+    }
+    if options.chunk_hashes {
+        update_chunks(&mut db, snapshot, options.chunk_min_file_size);
+    }
+
+    match change_base {
+        Some(change_base) => changes::save_changes(file_db_name, &db, change_base),
+        None => save_compressed(file_db_name, &db),
+    }
+    if !completed {
+        println!("Stopped early, run update again to continue");
+    }
+    let num_errors = crawl_errors::get_num_crawl_errors() - num_errors;
+    let results = serde_json::json!({
+        "ok": completed && num_errors == 0,
+        "completed": completed,
+        "entries": get_file_db_mut(&mut db, snapshot).len(),
+        "crawl_errors": num_errors,
+    });
+    alerts::notify("update", file_db_name, results);
+    true
+}
+
+// Parses sizes like 1000, 4K, 1.5G (binary units)
+pub fn parse_size(size: &str) -> Option<u64>
+{
+    let size = size.trim();
+    let size = size.strip_suffix(&['B', 'b'][..]).unwrap_or(size);
+    let (number, factor) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1u64 << 10),
+        'M' => (&size[..size.len() - 1], 1u64 << 20),
+        'G' => (&size[..size.len() - 1], 1u64 << 30),
+        'T' => (&size[..size.len() - 1], 1u64 << 40),
+        _ => (size, 1),
+    };
+    if let Ok(value) = number.parse::<u64>() {
+        return value.checked_mul(factor);
+    }
+    let value = number.parse::<f64>().ok()?;
+    if value < 0.0 {
+        return None;
+    }
+    Some((value * factor as f64) as u64)
+}
+
+// Parses durations like 30d, 12h, 2w, 1y (s, m, h, d, w, y), days without unit
+pub fn parse_duration(duration: &str) -> Option<u64>
+{
+    let duration = duration.trim();
+    let factor = match duration.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        'y' => 365 * 24 * 60 * 60,
+        _ => return duration.parse::<u64>().ok()?.checked_mul(24 * 60 * 60),
+    };
+    duration[..duration.len() - 1].parse::<u64>().ok()?.checked_mul(factor)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VerifySample
+{
+    All,
+    Percent(f64),
+    MaxBytes(u64),
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum VerifyResult
+{
+    Ok,
+    Mismatch,
+    Missing,
+    Unreadable,
+    Changed,
+}
+
+// Files whose size or modification time changed were modified legitimately, only a hash
+// mismatch with unchanged metadata indicates corruption
+fn verify_entry(path: &Path, entry: &Entry, algorithm: HashAlgorithm) -> VerifyResult
+{
+    if is_link(entry) {
+        // The target is all there is to verify
+        return match fs::read_link(path) {
+            Ok(target) if get_link_hash(&target) == entry.hash => VerifyResult::Ok,
+            Ok(_) => VerifyResult::Changed,
+            Err(_) => VerifyResult::Missing,
+        };
+    }
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return VerifyResult::Missing,
+    };
+    if metadata.len() != entry.size || get_secs(&metadata.modified().unwrap()) != entry.modified {
+        return VerifyResult::Changed;
+    }
+    match get_hash_for_file(path, algorithm) {
+        Ok(hash) if hash == entry.hash => VerifyResult::Ok,
+        Ok(_) => VerifyResult::Mismatch,
+        Err(_) => VerifyResult::Unreadable,
+    }
+}
+
+// Random selection of due files weighted by size, until budget_bytes is reached
+fn select_entries_to_verify<R: Rng>(
+    file_db: &FileDb,
+    verify_cycle_start: u64,
+    budget_bytes: u64,
+    rng: &mut R,
+) -> Vec<EntryIndex>
+{
+    // Weighted sampling without replacement (Efraimidis-Spirakis): smallest keys win
+    let mut keyed = file_db
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| {
+            !entry.is_dir && is_hashed(entry) && entry.verified < verify_cycle_start
+        })
+        .map(|(index, entry)| {
+            let u: f64 = 1.0 - rng.gen::<f64>();
+            let key = -u.ln() / std::cmp::max(entry.size, 1) as f64;
+            (key, index as EntryIndex)
+        })
+        .collect::<Vec<_>>();
+    keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut selected = Vec::new();
+    let mut selected_bytes = 0;
+    for (_, index) in keyed {
+        if selected_bytes >= budget_bytes {
+            break;
+        }
+        selected_bytes += file_db.get(index).size;
+        selected.push(index);
+    }
+    selected
+}
+
+// Re-hash files and compare with the db. With sampling, only part of the files is checked
+// per run, rotating through all files across runs.
+// With cancellation, stops before the next file. The files verified so far are saved, so a
+// sampled verification continues with the others.
+pub fn verify(
+    file_db_name: &Path,
+    sample: VerifySample,
+    cancellation: Option<&api::CancellationToken>,
+)
+{
+    let mut db = load_compressed(file_db_name);
+    let now = get_secs(&time::SystemTime::now());
+    let indices = match sample {
+        // Files not hashed yet have nothing to verify against
+        VerifySample::All => (0..db.file_db.len() as EntryIndex)
+            .filter(|index| {
+                let entry = db.file_db.get(*index);
+                !entry.is_dir && is_hashed(&entry)
+            })
+            .collect::<Vec<_>>(),
+        _ => {
+            let is_due = |entry: Entry| {
+                !entry.is_dir && is_hashed(&entry) && entry.verified < db.verify_cycle_start
+            };
+            if !db.file_db.iter().any(is_due) {
+                println!("Starting new verification cycle");
+                db.verify_cycle_start = now;
+            }
+            let budget_bytes = match sample {
+                VerifySample::Percent(percent) => {
+                    let total_bytes = db
+                        .file_db
+                        .iter()
+                        .filter(|entry| !entry.is_dir)
+                        .map(|entry| entry.size)
+                        .sum::<u64>();
+                    (total_bytes as f64 * percent / 100.0) as u64
+                }
+                VerifySample::MaxBytes(max_bytes) => max_bytes,
+                VerifySample::All => unreachable!(),
+            };
+            select_entries_to_verify(
+                &db.file_db,
+                db.verify_cycle_start,
+                budget_bytes,
+                &mut rand::thread_rng(),
+            )
+        }
+    };
+
+    let mut num_bytes = 0;
+    let mut num_mismatched = 0;
+    let mut num_missing = 0;
+    let mut num_unreadable = 0;
+    let mut num_changed = 0;
+    let mut num_verified = 0;
+    for index in &indices {
+        if is_cancelled(cancellation) {
+            println!("Cancelled, not verifying the remaining files");
+            break;
+        }
+        let path = get_full_path(&db.file_db, *index);
+        let entry = db.file_db.get(*index);
+        match verify_entry(&path, &entry, db.hash_algorithm) {
+            VerifyResult::Ok => {}
+            VerifyResult::Mismatch => {
+                println!("Hash mismatch: {:?}", path);
+                num_mismatched += 1;
+            }
+            VerifyResult::Missing => {
+                println!("File missing: {:?}", path);
+                num_missing += 1;
+            }
+            VerifyResult::Unreadable => {
+                println!("Error reading: {:?}", path);
+                num_unreadable += 1;
+            }
+            VerifyResult::Changed => {
+                println!("Changed since indexed: {:?}", path);
+                num_changed += 1;
+            }
+        }
+        num_bytes += entry.size;
+        db.file_db.entry_mut(*index).verified = now;
+        num_verified += 1;
+    }
+    println!(
+        "Verified files: {}, size: {}",
+        num_verified.separated_string(),
+        num_bytes.separated_string()
+    );
+    println!(
+        "Hash mismatches: {}, missing: {}, unreadable: {}, changed: {}",
+        num_mismatched, num_missing, num_unreadable, num_changed
+    );
+    if sample != VerifySample::All {
+        let num_due = db
+            .file_db
+            .iter()
+            .filter(|entry| {
+                !entry.is_dir && is_hashed(entry) && entry.verified < db.verify_cycle_start
+            })
+            .count();
+        println!("Files remaining in verification cycle: {}", num_due.separated_string());
+    }
+    save_compressed(file_db_name, &db);
+    let results = serde_json::json!({
+        "ok": num_mismatched == 0 && num_missing == 0 && num_unreadable == 0,
+        "verified": num_verified,
+        "bytes": num_bytes,
+        "mismatched": num_mismatched,
+        "missing": num_missing,
+        "unreadable": num_unreadable,
+        "changed": num_changed,
+    });
+    alerts::notify("verify", file_db_name, results);
+}
+
+// Hashes the files at indices on all cores and updates their entries. Files that changed since
+// being indexed get their new size and time with update_changed and are skipped otherwise. The
+// db is saved every CHECKPOINT_INTERVAL and when interrupted, so running again continues with
+// the files not hashed yet. Returns whether all files were hashed.
+fn hash_entries(
+    file_db_name: &Path,
+    db: &mut Db,
+    indices: &[EntryIndex],
+    update_changed: bool,
+) -> bool
+{
+    let paths = indices.iter().map(|index| get_full_path(&db.file_db, *index)).collect::<Vec<_>>();
+    let total_bytes = indices.iter().map(|index| db.file_db.get(*index).size).sum::<u64>();
+    println!(
+        "Files to hash: {}, size: {}",
+        indices.len().separated_string(),
+        total_bytes.separated_string()
+    );
+    install_interrupt_handler();
+    let now = get_secs(&time::SystemTime::now());
+    let num_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let algorithm = db.hash_algorithm;
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut num_bytes = 0;
+    let mut num_hashed = 0;
+    let mut num_failed = 0;
+    let mut num_mismatched = 0;
+    let mut last_save = time::Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..num_threads {
+            let (next, paths, sender) = (&next, &paths, sender.clone());
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= paths.len() || is_interrupted() {
+                    break;
+                }
+                // Size and time before hashing, a file changing meanwhile is caught next time
+                let result = fs::metadata(&paths[i]).map(|metadata| {
+                    let modified = get_secs(&metadata.modified().unwrap());
+                    (metadata.len(), modified, get_hash_for_file(&paths[i], algorithm))
+                });
+                if sender.send((i, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+        for (n, (i, result)) in receiver.iter().enumerate() {
+            let (path, entry) = (&paths[i], db.file_db.get(indices[i]));
+            num_bytes += entry.size;
+            let progress = format!(
+                "[{}/{}, {}%]",
+                n + 1,
+                indices.len(),
+                num_bytes * 100 / total_bytes.max(1)
+            );
+            match result {
+                Err(err) => {
+                    eprintln!("{} Error accessing {:?}: {}", progress, path, err);
+                    num_failed += 1;
+                }
+                Ok((size, modified, _))
+                    if !update_changed && (size, modified) != (entry.size, entry.modified) =>
+                {
+                    eprintln!("{} Changed since indexed, run update: {:?}", progress, path);
+                    num_failed += 1;
+                }
+                Ok((_, _, Err(err))) => {
+                    eprintln!("{} Error hashing {:?}: {}", progress, path, err);
+                    num_failed += 1;
+                }
+                Ok((size, modified, Ok(hash))) => {
+                    let is_unchanged = (size, modified) == (entry.size, entry.modified);
+                    if is_hashed(&entry) && is_unchanged && hash != entry.hash {
+                        println!("{} Hash mismatch, replaced: {:?}", progress, path);
+                        num_mismatched += 1;
+                    } else {
+                        println!("{} Hashed {:?}", progress, path);
+                    }
+                    let mut entry = db.file_db.entry_mut(indices[i]);
+                    if !is_unchanged {
+                        entry.pre_hash = 0;
+                    }
+                    *entry.size = size;
+                    entry.modified = modified;
+                    *entry.hash = hash;
+                    entry.verified = now;
+                    num_hashed += 1;
+                }
+            }
+            if last_save.elapsed() >= CHECKPOINT_INTERVAL {
+                save_compressed(file_db_name, db);
+                last_save = time::Instant::now();
+            }
+        }
+    });
+    propagate_sizes(&mut db.file_db);
+    propagate_hashes(&mut db.file_db);
+    println!(
+        "Hashed: {}, failed: {}, hash mismatches: {}",
+        num_hashed, num_failed, num_mismatched
+    );
+    save_compressed(file_db_name, db);
+    num_failed == 0 && !is_interrupted()
+}
+
+// Computes the hashes of the files added with --no-hash, or that could not be read before,
+// below prefix and with a size in min_size..=max_size. Files that changed since being indexed
+// are left for update. Returns whether all selected files were hashed.
+pub fn hash_missing(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    min_size: u64,
+    max_size: Option<u64>,
+) -> bool
+{
+    let mut db = load_compressed(file_db_name);
+    let indices = (0..db.file_db.len() as EntryIndex)
+        .filter(|index| {
+            let entry = db.file_db.get(*index);
+            !entry.is_dir
+                && !is_hashed(&entry)
+                && entry.size >= min_size
+                && max_size.is_none_or(|max_size| entry.size <= max_size)
+                && prefix.is_none_or(|prefix| {
+                    get_full_path(&db.file_db, *index).starts_with(prefix)
+                })
+        })
+        .collect::<Vec<_>>();
+    hash_entries(file_db_name, &mut db, &indices, false)
+}
+
+// Files rehash computes the hashes of again
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RehashSelection
+{
+    // Files without hash and those whose size or modification time changed
+    Stale,
+    OnlyEmpty,
+    All,
+}
+
+// Recomputes the hashes of the selected files below prefix in place, also taking over their
+// current size and modification time. Hashes differing for files that did not change are
+// reported as mismatches. Interrupted runs continue when run again: With All, from the start
+// time kept in <db>.rehash, the other selections just no longer include the files done.
+// Returns whether all selected files were hashed.
+pub fn rehash(file_db_name: &Path, prefix: Option<&Path>, selection: RehashSelection) -> bool
+{
+    let mut db = load_compressed(file_db_name);
+    let started_name = get_db_side_file_name(file_db_name, "rehash");
+    let started = match fs::read_to_string(&started_name) {
+        Ok(started) if selection == RehashSelection::All => {
+            println!("Continuing interrupted rehash");
+            started.trim().parse::<u64>().unwrap()
+        }
+        _ => get_secs(&time::SystemTime::now()),
+    };
+    let indices = (0..db.file_db.len() as EntryIndex)
+        .filter(|index| {
+            let entry = db.file_db.get(*index);
+            if entry.is_dir {
+                return false;
+            }
+            let path = get_full_path(&db.file_db, *index);
+            if prefix.is_some_and(|prefix| !path.starts_with(prefix)) {
+                return false;
+            }
+            match selection {
+                RehashSelection::Stale => {
+                    // Vanished files are left for update
+                    !is_hashed(&entry)
+                        || fs::metadata(&path).is_ok_and(|metadata| {
+                            metadata.len() != entry.size
+                                || get_secs(&metadata.modified().unwrap()) != entry.modified
+                        })
+                }
+                RehashSelection::OnlyEmpty => !is_hashed(&entry),
+                RehashSelection::All => entry.verified < started,
+            }
+        })
+        .collect::<Vec<_>>();
+    if selection == RehashSelection::All {
+        fs::write(&started_name, started.to_string()).unwrap();
+    }
+    let all_hashed = hash_entries(file_db_name, &mut db, &indices, true);
+    if all_hashed && selection == RehashSelection::All {
+        fs::remove_file(&started_name).unwrap();
+    }
+    all_hashed
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DedupAction<'a>
+{
+    // Only print the dupes
+    Report,
+    // Move all but the first copy to the given dir
+    MoveDupes(&'a Path),
+    // Replace all but the first copy with hardlinks to it. Only done for files on the same
+    // file system which are identical byte by byte.
+    Hardlink,
+    // Like Hardlink, but the copies share only their data and remain separate files
+    Reflink,
+    // Run the command for all but the kept copy, see exec
+    Exec(&'a ExecCommand),
+}
+
+fn files_equal(path_a: &Path, path_b: &Path) -> io::Result<bool>
+{
+    let mut reader_a = BufReader::new(File::open(path_a)?);
+    let mut reader_b = BufReader::new(File::open(path_b)?);
+    loop {
+        let len = {
+            let buf_a = reader_a.fill_buf()?;
+            let buf_b = reader_b.fill_buf()?;
+            if buf_a.is_empty() || buf_b.is_empty() {
+                return Ok(buf_a.is_empty() && buf_b.is_empty());
+            }
+            let len = buf_a.len().min(buf_b.len());
+            if buf_a[..len] != buf_b[..len] {
+                return Ok(false);
+            }
+            len
+        };
+        reader_a.consume(len);
+        reader_b.consume(len);
+    }
+}
+
+// Makes dest share the data extents of src without copying, dest must not exist
+#[cfg(target_os = "linux")]
+fn reflink_file(src: &Path, dest: &Path) -> io::Result<()>
+{
+    use std::os::unix::io::AsRawFd;
+    let src_file = File::open(src)?;
+    let dest_file = fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+    let result =
+        unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE as _, src_file.as_raw_fd()) };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        drop(dest_file);
+        let _ = fs::remove_file(dest);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink_file(src: &Path, dest: &Path) -> io::Result<()>
+{
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dest = CString::new(dest.as_os_str().as_bytes())?;
+    if unsafe { libc::clonefile(src.as_ptr(), dest.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink_file(_src: &Path, _dest: &Path) -> io::Result<()>
+{
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Reflinks not supported on this platform"))
+}
+
+// Physical ranges (start, length) holding the data of the file, from FIEMAP. Files sharing
+// them were reflinked or deduplicated by the file system already.
+#[cfg(target_os = "linux")]
+fn get_extents(path: &Path) -> io::Result<Vec<(u64, u64)>>
+{
+    use std::os::unix::io::AsRawFd;
+
+    // From linux/fiemap.h, libc lacks them
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct FiemapExtent
+    {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+    const EXTENTS_PER_CALL: usize = 64;
+    #[repr(C)]
+    struct Fiemap
+    {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; EXTENTS_PER_CALL],
+    }
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    // Extents whose physical location is unknown, or not only holding this file's data
+    const FIEMAP_EXTENT_NO_LOCATION: u32 = 0x2 | 0x4 | 0x200 | 0x400;
+
+    let file = File::open(path)?;
+    let mut extents = vec![];
+    let mut fiemap = Fiemap {
+        start: 0,
+        length: u64::MAX,
+        flags: FIEMAP_FLAG_SYNC,
+        mapped_extents: 0,
+        extent_count: EXTENTS_PER_CALL as u32,
+        reserved: 0,
+        extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+    };
+    loop {
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mapped = &fiemap.extents[..fiemap.mapped_extents as usize];
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_NO_LOCATION == 0 {
+                extents.push((extent.physical, extent.length));
+            }
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                fiemap.start = last.logical + last.length;
+                fiemap.length = u64::MAX - fiemap.start;
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_extents(_path: &Path) -> io::Result<Vec<(u64, u64)>>
+{
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Extents not available on this platform"))
+}
+
+// Bytes the two files share on disk, as hardlinks or in extents, which dedup cannot reclaim.
+// 0 if that cannot be determined.
+fn get_shared_bytes(path_a: &Path, path_b: &Path) -> u64
+{
+    let (metadata_a, metadata_b) = match (fs::metadata(path_a), fs::metadata(path_b)) {
+        (Ok(metadata_a), Ok(metadata_b)) => (metadata_a, metadata_b),
+        _ => return 0,
+    };
+    let inode = get_inode(&metadata_a);
+    if inode != 0
+        && inode == get_inode(&metadata_b)
+        && get_device(&metadata_a) == get_device(&metadata_b)
+    {
+        return metadata_a.len();
+    }
+    if get_device(&metadata_a) != get_device(&metadata_b) {
+        return 0;
+    }
+    match (get_extents(path_a), get_extents(path_b)) {
+        // Extents are whole blocks, the file may end before
+        (Ok(extents_a), Ok(extents_b)) => {
+            get_extents_overlap(&extents_a, &extents_b).min(metadata_a.len())
+        }
+        _ => 0,
+    }
+}
+
+fn get_extents_overlap(extents_a: &[(u64, u64)], extents_b: &[(u64, u64)]) -> u64
+{
+    let mut overlap = 0;
+    for (start_a, length_a) in extents_a {
+        for (start_b, length_b) in extents_b {
+            let start = *start_a.max(start_b);
+            let end = (start_a + length_a).min(start_b + length_b);
+            overlap += end.saturating_sub(start);
+        }
+    }
+    overlap
+}
+
+// A reflink is a separate file, it keeps the permissions and times of the replaced dupe
+fn reflink_dupe(kept_path: &Path, dupe_metadata: &fs::Metadata, tmp_path: &Path) -> io::Result<()>
+{
+    reflink_file(kept_path, tmp_path)?;
+    let file = fs::OpenOptions::new().write(true).open(tmp_path)?;
+    file.set_permissions(dupe_metadata.permissions())?;
+    let times = fs::FileTimes::new()
+        .set_modified(dupe_metadata.modified()?)
+        .set_accessed(dupe_metadata.accessed()?);
+    file.set_times(times)
+}
+
+// Replaces the dupe with a hardlink or reflink to the kept file. Returns false if skipped.
+fn link_dupe(
+    file_db: &mut FileDb,
+    kept_index: EntryIndex,
+    dupe_index: EntryIndex,
+    reflink: bool,
+    options: &RemoveOptions,
+) -> bool
+{
+    let kept_path = get_full_path(file_db, kept_index);
+    let dupe_path = get_full_path(file_db, dupe_index);
+    if let Some(pattern) = options.protected.get_protecting(&dupe_path) {
+        println!("      Protected by {}, skipping", pattern);
+        return false;
+    }
+    let (kept_metadata, dupe_metadata) =
+        match (fs::symlink_metadata(&kept_path), fs::symlink_metadata(&dupe_path)) {
+            (Ok(kept), Ok(dupe)) if kept.is_file() && dupe.is_file() => (kept, dupe),
+            _ => {
+                println!("      No longer a file, skipping");
+                return false;
+            }
+        };
+    if get_device(&kept_metadata) != get_device(&dupe_metadata) {
+        println!("      On another file system, skipping");
+        return false;
+    }
+    let inode = get_inode(&kept_metadata);
+    if inode != 0 && inode == get_inode(&dupe_metadata) {
+        println!("      Already hardlinked");
+        return false;
+    }
+    if reflink && get_shared_bytes(&kept_path, &dupe_path) >= kept_metadata.len() {
+        println!("      Already sharing all extents");
+        return false;
+    }
+    match files_equal(&kept_path, &dupe_path) {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("      Content differs despite same hash, skipping");
+            return false;
+        }
+        Err(err) => {
+            eprintln!("      Error comparing: {}, skipping", err);
+            return false;
+        }
+    }
+    if options.dry_run {
+        println!("      Would {}", if reflink { "reflink" } else { "hardlink" });
+        return true;
+    }
+    // Link next to the dupe and rename over it, so the dupe is never missing
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(dupe_path.file_name().unwrap());
+    tmp_name.push(".filedb-link");
+    let tmp_path = dupe_path.with_file_name(tmp_name);
+    if reflink {
+        if let Err(err) = reflink_dupe(&kept_path, &dupe_metadata, &tmp_path) {
+            eprintln!(
+                "      Error reflinking: {}, skipping. The file system may not support reflinks \
+                 (btrfs, XFS and APFS do), use --hardlink instead.",
+                err
+            );
+            let _ = fs::remove_file(&tmp_path);
+            return false;
+        }
+    } else if let Err(err) = fs::hard_link(&kept_path, &tmp_path) {
+        eprintln!("      Error linking: {}, skipping", err);
+        return false;
+    }
+    if let Err(err) = fs::rename(&tmp_path, &dupe_path) {
+        eprintln!("      Error replacing: {}, skipping", err);
+        let _ = fs::remove_file(&tmp_path);
+        return false;
+    }
+    println!("      {}", if reflink { "Reflinked" } else { "Hardlinked" });
+    let metadata = fs::symlink_metadata(&dupe_path).unwrap();
+    let mut entry = file_db.entry_mut(dupe_index);
+    entry.modified = get_secs(&metadata.modified().unwrap());
+    entry.accessed = get_secs(&metadata.accessed().unwrap());
+    entry.inode = get_inode(&metadata);
+    set_owner_and_mode(&mut entry, &metadata);
+    true
+}
+
+// Rules choosing which copy survives dedup, in priority order: The first rule preferring
+// one copy over another decides. Without rules or on ties, the default of the command applies.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum KeepRule
+{
+    PathPrefix(PathBuf),
+    Newest,
+    Oldest,
+    ShortestPath,
+}
+
+// Less if the copy at a is preferred over b
+fn compare_for_keeping(
+    file_db: &FileDb,
+    keep_rules: &[KeepRule],
+    (a, a_path): (EntryIndex, &Path),
+    (b, b_path): (EntryIndex, &Path),
+) -> std::cmp::Ordering
+{
+    let (a_entry, b_entry) = (file_db.get(a), file_db.get(b));
+    for rule in keep_rules {
+        let ordering = match rule {
+            KeepRule::PathPrefix(prefix) => {
+                b_path.starts_with(prefix).cmp(&a_path.starts_with(prefix))
+            }
+            KeepRule::Newest => b_entry.modified.cmp(&a_entry.modified),
+            KeepRule::Oldest => a_entry.modified.cmp(&b_entry.modified),
+            KeepRule::ShortestPath => a_path.as_os_str().len().cmp(&b_path.as_os_str().len()),
+        };
+        if ordering != std::cmp::Ordering::Equal {
+            return ordering;
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+// On ties, the lowest path is kept, so the choice does not depend on the order of the db
+fn choose_kept(file_db: &FileDb, keep_rules: &[KeepRule], indices: &[EntryIndex]) -> EntryIndex
+{
+    let paths = indices.iter().map(|index| get_full_path(file_db, *index)).collect::<Vec<_>>();
+    let kept = (0..indices.len()).min_by(|a, b| {
+        compare_for_keeping(
+            file_db,
+            keep_rules,
+            (indices[*a], &paths[*a]),
+            (indices[*b], &paths[*b]),
+        )
+        .then_with(|| paths[*a].cmp(&paths[*b]))
+    });
+    indices[kept.unwrap()]
+}
+
+// Thresholds for the groups of dupes dedup considers, to leave out the many small ones
+#[derive(Default, Clone, Copy, Debug)]
+pub struct DupeFilter
+{
+    // Size of each copy
+    pub min_size: u64,
+    // Bytes reclaimed by keeping only one copy
+    pub min_group_savings: u64,
+}
+
+// Files added with --lazy-hash, which only have a pre-hash yet
+fn has_lazy_hash(entry: &Entry) -> bool
+{
+    !is_hashed(entry) && entry.pre_hash != 0
+}
+
+// Computes the hashes of the files added with --lazy-hash that may have copies, as they share
+// size and pre-hash with other files. Files hashed in full lack pre-hashes, they are computed
+// for those of the sizes in question. Returns whether any entry changed.
+fn hash_lazy_candidates(file_db: &mut FileDb, algorithm: HashAlgorithm) -> bool
+{
+    let mut size_to_indices = HashMap::<u64, Vec<EntryIndex>>::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        if !entry.is_dir && entry.size > 0 {
+            size_to_indices.entry(entry.size).or_default().push(index as EntryIndex);
+        }
+    }
+    let mut changed = false;
+    for indices in size_to_indices.values().filter(|indices| indices.len() > 1) {
+        if !indices.iter().any(|index| has_lazy_hash(&file_db.get(*index))) {
+            continue;
+        }
+        let mut pre_hash_to_indices = HashMap::<u64, Vec<EntryIndex>>::new();
+        for index in indices {
+            if file_db.get(*index).pre_hash == 0 {
+                let path = get_full_path(file_db, *index);
+                match get_pre_hash(&path) {
+                    Ok(pre_hash) => file_db.entry_mut(*index).pre_hash = pre_hash,
+                    Err(err) => {
+                        eprintln!("Error accessing {:?}: {}", path, err);
+                        continue;
+                    }
+                }
+                changed = true;
+            }
+            pre_hash_to_indices.entry(file_db.get(*index).pre_hash).or_default().push(*index);
+        }
+        for indices in pre_hash_to_indices.values().filter(|indices| indices.len() > 1) {
+            for index in indices {
+                let entry = file_db.get(*index);
+                if is_hashed(&entry) {
+                    continue;
+                }
+                let path = get_full_path(file_db, *index);
+                let is_unchanged = fs::metadata(&path).is_ok_and(|metadata| {
+                    metadata.len() == entry.size
+                        && get_secs(&metadata.modified().unwrap()) == entry.modified
+                });
+                if !is_unchanged {
+                    eprintln!("Changed since indexed, run update: {:?}", path);
+                    continue;
+                }
+                match get_hash_for_file(&path, algorithm) {
+                    Ok(hash) => {
+                        println!("Hashed {:?}", path);
+                        *file_db.entry_mut(*index).hash = hash;
+                        changed = true;
+                    }
+                    Err(err) => eprintln!("Error hashing {:?}: {}", path, err),
+                }
+            }
+        }
+    }
+    changed
+}
+
+// Loads the db for finding dupes, after computing the hashes of files added with --lazy-hash
+// that are needed for that. They are saved, so that is only done once.
+fn load_for_dedup(file_db_name: &Path, snapshot: Option<&str>) -> Db
+{
+    let mut db = load_compressed(file_db_name);
+    let algorithm = db.hash_algorithm;
+    let file_db = get_file_db_mut(&mut db, snapshot);
+    if hash_lazy_candidates(file_db, algorithm) {
+        propagate_hashes(file_db);
+        save_compressed(file_db_name, &db);
+    }
+    db
+}
+
+// Groups of entries with the same hash and size passing filter, the most reclaimable bytes
+// first
+fn get_dupe_groups(file_db: &FileDb, filter: &DupeFilter) -> Vec<(u64, Vec<EntryIndex>)>
+{
+    let mut hash_and_size_to_indices = HashMap::<(Hash256, u64), Vec<EntryIndex>>::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        if !is_hashed(&entry) {
+            continue;
+        }
+        let path = get_full_path(file_db, index as EntryIndex);
+        if path.starts_with(BACKUP_DIR) {
+            continue;
+        }
+        let indices = hash_and_size_to_indices
+            .entry((entry.hash, entry.size))
+            .or_insert(Vec::<EntryIndex>::new());
+        indices.push(index as EntryIndex);
+    }
+    let mut groups = hash_and_size_to_indices
+        .into_iter()
+        .filter(|((_, size), indices)| {
+            let savings = size * (indices.len() as u64).saturating_sub(1);
+            indices.len() > 1 && *size >= filter.min_size && savings >= filter.min_group_savings
+        })
+        .map(|((_, size), indices)| (size, indices))
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|(size, indices)| std::cmp::Reverse(size * (indices.len() as u64 - 1)));
+    groups
+}
+
+pub fn dedup(
+    file_db_name: &Path,
+    action: DedupAction,
+    keep_rules: &[KeepRule],
+    filter: &DupeFilter,
+    options: &RemoveOptions,
+    json: bool,
+    snapshot: Option<&str>,
+)
+{
+    let mut db = load_for_dedup(file_db_name, snapshot);
+    let file_db = get_file_db_mut(&mut db, snapshot);
+    propagate_hashes(file_db);
+
+    let mut num_duped_bytes = 0;
+    let mut num_shared_bytes = 0;
+    let mut max_dupe_count = 0;
+    let mut num_linked = 0;
+    let mut num_linked_bytes = 0;
+    let mut num_moved_bytes = 0;
+    let mut journal = Journal::new(file_db_name);
+    let mut json_groups = vec![];
+    let mut exec_targets = vec![];
+    for (size, indices) in get_dupe_groups(file_db, filter) {
+        if is_cancelled(options.cancellation.as_ref()) {
+            eprintln!("Cancelled, stopped before the remaining groups of dupes");
+            break;
+        }
+        let dupe_count = indices.len() - 1;
+        if dupe_count > max_dupe_count {
+            max_dupe_count = dupe_count;
+        }
+        let duped_bytes = dupe_count as u64 * size;
+        let kept_index = choose_kept(file_db, keep_rules, &indices);
+        let kept_path = get_full_path(file_db, kept_index);
+        // Dirs are linked file by file, their contents are dupes as well
+        let can_link = !file_db.get(kept_index).is_dir && size > 0;
+        let shared_bytes =
+            |path: &Path| if can_link { get_shared_bytes(&kept_path, path) } else { 0 };
+        num_duped_bytes += duped_bytes;
+        if json {
+            let paths = indices.iter().map(|index| get_full_path(file_db, *index));
+            let group_shared_bytes = paths
+                .clone()
+                .filter(|path| *path != kept_path)
+                .map(|path| shared_bytes(&path))
+                .sum::<u64>();
+            num_shared_bytes += group_shared_bytes;
+            json_groups.push(serde_json::json!({
+                "size": size,
+                "hash": get_hash_string(&file_db.get(kept_index).hash),
+                "dupes": dupe_count,
+                "duped_bytes": duped_bytes,
+                "shared_bytes": group_shared_bytes,
+                "kept": kept_path.to_string_lossy(),
+                "paths": paths.map(|path| path.to_string_lossy().into_owned()).collect::<Vec<_>>(),
+            }));
+            continue;
+        }
+        println!(
+            "Duplicated data of size: {} dupes: {} duped GB: {}",
+            size.separated_string(),
+            dupe_count,
+            duped_bytes / 1024 / 1024 / 1024
+        );
+        println!("  Dupe locations:");
+        for index in &indices {
+            let path = get_full_path(file_db, *index);
+            println!("    {:?}", path);
+            if *index == kept_index {
+                println!("      Keeping");
+                continue;
+            }
+            let shared = shared_bytes(&path);
+            if shared > 0 {
+                println!("      Shares bytes with the kept copy: {}", shared.separated_string());
+                num_shared_bytes += shared;
+            }
+            match action {
+                DedupAction::Report => {}
+                DedupAction::MoveDupes(_) if options.protected.get_protecting(&path).is_some() => {
+                    println!("      Protected, skipping");
+                }
+                DedupAction::MoveDupes(backup_dir) => {
+                    // File may have been removed by a previous operation which moved a parent dir
+                    if Path::new(&path).exists() {
+                        num_moved_bytes += size;
+                        if options.dry_run {
+                            println!("      Would move to {:?}", backup_dir);
+                            continue;
+                        }
+                        journal.log(file_db, *index, kept_index).unwrap();
+                        println!("      Moving");
+                        let dest_dir = backup_dir.join(path.file_name().unwrap());
+                        assert!(!Path::new(&dest_dir).exists());
+                        fs_extra::move_items(&[path], backup_dir, &CopyOptions::new()).unwrap();
+                    }
+                }
+                DedupAction::Hardlink | DedupAction::Reflink => {
+                    let reflink = action == DedupAction::Reflink;
+                    if can_link && link_dupe(file_db, kept_index, *index, reflink, options)
+                    {
+                        num_linked += 1;
+                        num_linked_bytes += size;
+                    }
+                }
+                DedupAction::Exec(_) => exec_targets.push((path, Some(kept_path.clone()))),
+            }
+        }
+    }
+    if json {
+        let result = serde_json::json!({
+            "groups": json_groups,
+            "duped_bytes": num_duped_bytes,
+            "shared_bytes": num_shared_bytes,
+            "max_dupe_count": max_dupe_count,
+        });
+        println!("{}", result);
+        return;
+    }
+    println!("Total duped bytes: {}", num_duped_bytes.separated_string());
+    // Hardlinked or sharing extents, deduplicated on disk already
+    println!("Already shared bytes: {}", num_shared_bytes.separated_string());
+    println!(
+        "Reclaimable bytes: {}",
+        num_duped_bytes.saturating_sub(num_shared_bytes).separated_string()
+    );
+    println!("Max dupe count: {}", max_dupe_count);
+    if let DedupAction::Exec(exec) = action {
+        exec::run_exec(exec, &exec_targets, options.dry_run);
+    }
+    if let DedupAction::MoveDupes(_) = action {
+        let verb = if options.dry_run { "Would move" } else { "Moved" };
+        println!("{} bytes: {}", verb, num_moved_bytes.separated_string());
+        journal.print_summary(file_db_name);
+    }
+    if action == DedupAction::Hardlink || action == DedupAction::Reflink {
+        println!(
+            "{} {} files, reclaimed bytes: {}",
+            if options.dry_run { "Would link" } else { "Linked" },
+            num_linked,
+            num_linked_bytes.separated_string()
+        );
+        if !options.dry_run {
+            save_compressed(file_db_name, &db);
+        }
+    }
+}
+
+// Groups of dupes for reviewing in the terminal, with the copy the keep rules prefer selected
+fn get_dupe_review(
+    file_db: &FileDb,
+    keep_rules: &[KeepRule],
+    filter: &DupeFilter,
+) -> tui::DupeReview
+{
+    let mut groups = vec![];
+    for (size, indices) in get_dupe_groups(file_db, filter) {
+        // Dirs are reviewed file by file, restore only copies files back
+        if file_db.get(indices[0]).is_dir {
+            continue;
+        }
+        let kept_index = choose_kept(file_db, keep_rules, &indices);
+        let files = indices
+            .iter()
+            .map(|index| tui::ReviewFile {
+                index: *index,
+                path: get_full_path(file_db, *index),
+                modified: get_time_string(file_db.get(*index).modified),
+                remove: false,
+            })
+            .collect();
+        let selected = indices.iter().position(|index| *index == kept_index).unwrap();
+        groups.push(tui::ReviewGroup { size, files, selected });
+    }
+    tui::DupeReview { groups, group: 0 }
+}
+
+// Removes the (index, kept_index) dupes, skipping those whose kept copy is gone
+fn remove_dupes(
+    file_db_name: &Path,
+    db: &mut Db,
+    removals: &[(EntryIndex, EntryIndex)],
+    options: &RemoveOptions,
+)
+{
+    let file_db = &mut db.file_db;
+    let mut journal = Journal::new(file_db_name);
+    let mut num_removed_bytes = 0;
+    let mut num_refused = 0;
+    let removed_paths = removals.iter().map(|(index, _)| get_full_path(file_db, *index)).collect();
+    for (index, kept_index) in removals {
+        let path = get_full_path(file_db, *index);
+        let kept_path = get_full_path(file_db, *kept_index);
+        // May have been in a dir removed before
+        if fs::symlink_metadata(&path).is_err() {
+            println!("{:?} no longer exists, skipping", path);
+            continue;
+        }
+        if let Some(pattern) = options.protected.get_protecting(&path) {
+            println!("{:?} is protected by {}, skipping", path, pattern);
+            continue;
+        }
+        if let Err(reason) = safety::check_kept_copy(file_db, *index, *kept_index, &removed_paths) {
+            eprintln!("Not removing {:?}, {}", path, reason);
+            num_refused += 1;
+            continue;
+        }
+        if options.verify_content && !file_db.get(*index).is_dir {
+            match files_equal(&path, &kept_path) {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("Content of {:?} differs from {:?}, aborting", path, kept_path);
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("Error comparing {:?} to {:?}: {}, aborting", path, kept_path, err);
+                    break;
+                }
+            }
+        }
+        num_removed_bytes += file_db.get(*index).size;
+        if options.dry_run {
+            println!("Would remove {:?}", path);
+            continue;
+        }
+        if let Err(err) = journal.log(file_db, *index, *kept_index) {
+            eprintln!("Error writing journal {:?}: {}, aborting", journal.file_name, err);
+            break;
+        }
+        println!("Removing {:?}", path);
+        if let Err(err) = remove_path(&path, &options.target) {
+            eprintln!("Error removing {:?}: {}", path, err);
+        }
+    }
+    if num_refused > 0 {
+        println!("Dupes not removed to keep their last copy: {}", num_refused.separated_string());
+    }
+    let verb = if options.dry_run { "would be removed" } else { "removed" };
+    println!("Num bytes {}: {}", verb, num_removed_bytes.separated_string());
+    journal.print_summary(file_db_name);
+    if !options.dry_run {
+        prune_deleted_paths(file_db);
+        propagate_sizes(file_db);
+        propagate_hashes(file_db);
+        save_compressed(file_db_name, db);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction
+{
+    Remove,
+    Hardlink,
+    Reflink,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PlanEntry
+{
+    action: PlanAction,
+    path: PathBuf,
+    kept_path: PathBuf,
+    hash: String,
+    size: u64,
+}
+
+// Written by dedup --plan, to be reviewed and edited, then executed by apply-plan
+#[derive(Serialize, Deserialize, Debug)]
+struct DedupPlan
+{
+    entries: Vec<PlanEntry>,
+}
+
+// Writes a plan to apply action to all but the kept copy of each group of duplicate files
+pub fn dedup_plan(
+    file_db_name: &Path,
+    plan_name: &Path,
+    action: PlanAction,
+    keep_rules: &[KeepRule],
+    filter: &DupeFilter,
+)
+{
+    let file_db = load_for_dedup(file_db_name, None).file_db;
+    let mut entries = vec![];
+    for (size, indices) in get_dupe_groups(&file_db, filter) {
+        // Dirs are planned file by file
+        if size == 0 || file_db.get(indices[0]).is_dir {
+            continue;
+        }
+        let kept_index = choose_kept(&file_db, keep_rules, &indices);
+        for index in indices.into_iter().filter(|index| *index != kept_index) {
+            entries.push(PlanEntry {
+                action,
+                path: get_full_path(&file_db, index),
+                kept_path: get_full_path(&file_db, kept_index),
+                hash: get_hash_string(&file_db.get(index).hash),
+                size,
+            });
+        }
+    }
+    let num_bytes = entries.iter().map(|entry| entry.size).sum::<u64>();
+    let plan = serde_json::to_string_pretty(&DedupPlan { entries }).unwrap();
+    fs::write(plan_name, plan + "\n").unwrap();
+    println!("Wrote plan {:?}, bytes affected: {}", plan_name, num_bytes.separated_string());
+}
+
+// Checks that both files of a plan entry still have the size and hash of the plan
+fn verify_plan_entry(entry: &PlanEntry, algorithm: HashAlgorithm) -> Result<(), String>
+{
+    let hash = parse_hash_string(&entry.hash).ok_or("Invalid hash")?;
+    if entry.path == entry.kept_path {
+        return Err("Path and kept path are the same".to_string());
+    }
+    for path in [&entry.path, &entry.kept_path] {
+        let metadata = fs::symlink_metadata(path).map_err(|err| format!("{:?}: {}", path, err))?;
+        if !metadata.is_file() || metadata.len() != entry.size {
+            return Err(format!("{:?} changed", path));
+        }
+        match get_hash_for_file(path, algorithm) {
+            Ok(file_hash) if file_hash == hash => {}
+            Ok(_) => return Err(format!("{:?} changed", path)),
+            Err(err) => return Err(format!("{:?}: {}", path, err)),
+        }
+    }
+    Ok(())
+}
+
+// Executes a plan written by dedup_plan, after hashing both files of each entry again. Entries
+// whose files changed or are not in the db are skipped. Returns false if the plan cannot be
+// read.
+pub fn apply_plan(file_db_name: &Path, plan_name: &Path, options: &RemoveOptions) -> bool
+{
+    let plan = fs::read_to_string(plan_name).map_err(|err| err.to_string()).and_then(|plan| {
+        serde_json::from_str::<DedupPlan>(&plan).map_err(|err| err.to_string())
+    });
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(err) => {
+            eprintln!("Cannot read plan {:?}: {}", plan_name, err);
+            return false;
+        }
+    };
+    let mut db = load_compressed(file_db_name);
+    let children = ChildrenIndex::new(&db.file_db);
+    let mut removals = vec![];
+    let mut num_linked = 0;
+    let mut num_skipped = 0;
+    for entry in &plan.entries {
+        let index = children.find_path(&db.file_db, &entry.path);
+        let kept_index = children.find_path(&db.file_db, &entry.kept_path);
+        let (index, kept_index) = match (index, kept_index) {
+            (Some(index), Some(kept_index)) => (index, kept_index),
+            _ => {
+                eprintln!("{:?} or {:?} not in db, skipping", entry.path, entry.kept_path);
+                num_skipped += 1;
+                continue;
+            }
+        };
+        if let Err(err) = verify_plan_entry(entry, db.hash_algorithm) {
+            eprintln!("{}, skipping {:?}", err, entry.path);
+            num_skipped += 1;
+            continue;
+        }
+        match entry.action {
+            PlanAction::Remove => removals.push((index, kept_index)),
+            PlanAction::Hardlink | PlanAction::Reflink => {
+                println!("{:?}", entry.path);
+                let reflink = entry.action == PlanAction::Reflink;
+                if link_dupe(&mut db.file_db, kept_index, index, reflink, options) {
+                    num_linked += 1;
+                }
+            }
+        }
+    }
+    println!("Entries skipped: {}", num_skipped.separated_string());
+    if num_linked > 0 {
+        let verb = if options.dry_run { "would be linked" } else { "linked" };
+        println!("Files {}: {}", verb, num_linked.separated_string());
+    }
+    if !removals.is_empty() {
+        // Also saves the db
+        remove_dupes(file_db_name, &mut db, &removals, options);
+    } else if num_linked > 0 && !options.dry_run {
+        save_compressed(file_db_name, &db);
+    }
+    true
+}
+
+// Groups of dirs below prefix with the same hash and size, the largest first, each sorted by
+// path. Groups of dirs whose parents are all duplicates themselves are left out, as their
+// parents are reported. Dirs without any bytes are not worth reporting.
+fn get_dupe_dir_groups(file_db: &FileDb, prefix: Option<&Path>) -> Vec<(u64, Vec<EntryIndex>)>
+{
+    let mut hash_and_size_to_indices = HashMap::<(Hash256, u64), Vec<EntryIndex>>::new();
+    for (index, entry) in file_db.iter().enumerate().skip(1) {
+        if !entry.is_dir || entry.size == 0 || !is_hashed(&entry) {
+            continue;
+        }
+        let path = get_full_path(file_db, index as EntryIndex);
+        if path.starts_with(BACKUP_DIR) || prefix.is_some_and(|prefix| !path.starts_with(prefix)) {
+            continue;
+        }
+        let indices = hash_and_size_to_indices.entry((entry.hash, entry.size)).or_default();
+        indices.push(index as EntryIndex);
+    }
+    let is_duped = |index: EntryIndex| {
+        let entry = file_db.get(index);
+        hash_and_size_to_indices.get(&(entry.hash, entry.size)).is_some_and(|i| i.len() > 1)
+    };
+    let mut groups = hash_and_size_to_indices
+        .iter()
+        .filter(|(_, indices)| indices.len() > 1)
+        .filter(|(_, indices)| {
+            !indices.iter().all(|index| is_duped(file_db.get(*index).parent))
+        })
+        .map(|((_, size), indices)| {
+            let mut indices = indices.clone();
+            indices.sort_by_key(|index| get_full_path(file_db, *index));
+            (*size, indices)
+        })
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|(size, indices)| (std::cmp::Reverse(*size), indices[0]));
+    groups
+}
+
+// List groups of identical dirs, see get_dupe_dir_groups
+pub fn dedup_dirs(file_db_name: &Path, prefix: Option<&Path>, snapshot: Option<&str>)
+{
+    let mut db = load_compressed(file_db_name);
+    let file_db = get_file_db_mut(&mut db, snapshot);
+    propagate_hashes(file_db);
+
+    let mut num_duped_bytes = 0;
+    let groups = get_dupe_dir_groups(file_db, prefix);
+    for (size, indices) in &groups {
+        let duped_bytes = (indices.len() as u64 - 1) * size;
+        num_duped_bytes += duped_bytes;
+        println!(
+            "Duplicated dir of size: {} copies: {} duped bytes: {}",
+            size.separated_string(),
+            indices.len(),
+            duped_bytes.separated_string()
+        );
+        for index in indices {
+            println!("    {}", format_path_for_output(&get_full_path(file_db, *index)));
+        }
+    }
+    println!(
+        "Duplicated dirs: {}, duped bytes: {}",
+        groups.len().separated_string(),
+        num_duped_bytes.separated_string()
+    );
+}
+
+// Non-empty, hashed files below the dir at index
+fn get_files_below(
+    file_db: &FileDb,
+    children: &ChildrenIndex,
+    index: EntryIndex,
+    files: &mut Vec<EntryIndex>,
+)
+{
+    for child in children.get(index) {
+        let entry = file_db.get(*child);
+        if entry.is_dir {
+            get_files_below(file_db, children, *child, files);
+        } else if entry.size > 0 && is_hashed(&entry) {
+            files.push(*child);
+        }
+    }
+}
+
+struct DirComparison
+{
+    // Files of either dir with a copy in the other one, and all files
+    num_shared: usize,
+    num_files: usize,
+    only_a: Vec<EntryIndex>,
+    only_b: Vec<EntryIndex>,
+}
+
+impl DirComparison
+{
+    fn get_similarity(&self) -> f64
+    {
+        if self.num_files == 0 {
+            return 0.0;
+        }
+        100.0 * self.num_shared as f64 / self.num_files as f64
+    }
+}
+
+// Compares the files below dirs a and b by hash and size, not by name
+fn compare_dirs(
+    file_db: &FileDb,
+    children: &ChildrenIndex,
+    a: EntryIndex,
+    b: EntryIndex,
+) -> DirComparison
+{
+    let (mut files_a, mut files_b) = (vec![], vec![]);
+    get_files_below(file_db, children, a, &mut files_a);
+    get_files_below(file_db, children, b, &mut files_b);
+    let get_contents = |files: &[EntryIndex]| {
+        let entries = files.iter().map(|index| file_db.get(*index));
+        entries.map(|entry| (entry.hash, entry.size)).collect::<HashSet<_>>()
+    };
+    let (contents_a, contents_b) = (get_contents(&files_a), get_contents(&files_b));
+    let get_only = |files: &[EntryIndex], other_contents: &HashSet<(Hash256, u64)>| {
+        let mut only = files
+            .iter()
+            .copied()
+            .filter(|index| {
+                let entry = file_db.get(*index);
+                !other_contents.contains(&(entry.hash, entry.size))
+            })
+            .collect::<Vec<_>>();
+        only.sort_by_key(|index| get_full_path(file_db, *index));
+        only
+    };
+    let only_a = get_only(&files_a, &contents_b);
+    let only_b = get_only(&files_b, &contents_a);
+    let num_files = files_a.len() + files_b.len();
+    DirComparison { num_shared: num_files - only_a.len() - only_b.len(), num_files, only_a, only_b }
+}
+
+// Ancestors of the entry at index, from its parent up to the root
+fn get_ancestors(file_db: &FileDb, mut index: EntryIndex) -> Vec<EntryIndex>
+{
+    let mut ancestors = vec![];
+    while index != 0 {
+        index = file_db.get(index).parent;
+        ancestors.push(index);
+    }
+    ancestors
+}
+
+// Pairs of dirs with different hashes sharing at least min_similarity percent of their files,
+// each ordered by path, with their comparison, the most similar first. Candidates are the
+// dirs at the same depth above copies of files, below the first dir they have in common, so
+// copies of trees are found wherever they are. Pairs whose parents are reported as well are
+// left out.
+fn get_similar_dirs(
+    file_db: &FileDb,
+    children: &ChildrenIndex,
+    min_similarity: f64,
+    min_files: usize,
+) -> Vec<(EntryIndex, EntryIndex, DirComparison)>
+{
+    let mut hash_to_indices = HashMap::<(Hash256, u64), Vec<EntryIndex>>::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        if !entry.is_dir && entry.size > 0 && is_hashed(&entry) {
+            hash_to_indices.entry((entry.hash, entry.size)).or_default().push(index as EntryIndex);
+        }
+    }
+    // Files with many copies, like license texts, would pair up too many dirs
+    let mut shared_files = HashMap::<(EntryIndex, EntryIndex), usize>::new();
+    for indices in hash_to_indices.values().filter(|indices| (2..=16).contains(&indices.len())) {
+        let ancestors = indices.iter().map(|index| get_ancestors(file_db, *index));
+        let ancestors = ancestors.collect::<Vec<_>>();
+        for (n, ancestors_a) in ancestors.iter().enumerate() {
+            for ancestors_b in &ancestors[n + 1..] {
+                let common = ancestors_a
+                    .iter()
+                    .rev()
+                    .zip(ancestors_b.iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let below_a = &ancestors_a[..ancestors_a.len() - common];
+                let below_b = &ancestors_b[..ancestors_b.len() - common];
+                for (a, b) in below_a.iter().zip(below_b) {
+                    *shared_files.entry((*a.min(b), *a.max(b))).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let mut similar_dirs = shared_files
+        .into_iter()
+        .filter(|((a, b), num_shared)| {
+            *num_shared >= min_files / 2 && file_db.get(*a).hash != file_db.get(*b).hash
+        })
+        .filter_map(|((a, b), _)| {
+            let (a, b) = if get_full_path(file_db, a) < get_full_path(file_db, b) {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            let comparison = compare_dirs(file_db, children, a, b);
+            let is_similar = comparison.num_files >= min_files
+                && comparison.get_similarity() >= min_similarity;
+            is_similar.then_some((a, b, comparison))
+        })
+        .collect::<Vec<_>>();
+    let pairs = similar_dirs.iter().map(|(a, b, _)| (*a, *b)).collect::<HashSet<_>>();
+    similar_dirs.retain(|(a, b, _)| {
+        let (parent_a, parent_b) = (file_db.get(*a).parent, file_db.get(*b).parent);
+        !pairs.contains(&(parent_a, parent_b)) && !pairs.contains(&(parent_b, parent_a))
+    });
+    similar_dirs.sort_by(|(a1, b1, comparison1), (a2, b2, comparison2)| {
+        let similarity = comparison2.get_similarity().total_cmp(&comparison1.get_similarity());
+        let num_shared = comparison2.num_shared.cmp(&comparison1.num_shared);
+        similarity.then(num_shared).then((a1, b1).cmp(&(a2, b2)))
+    });
+    similar_dirs
+}
+
+fn print_paths(file_db: &FileDb, indices: &[EntryIndex])
+{
+    for index in indices {
+        println!("    {}", format_path_for_output(&get_full_path(file_db, *index)));
+    }
+}
+
+// Compare the files of two dirs by hash, printing the percentage of files with a copy on the
+// other side and the files only on each side. Without dirs, list pairs of similar dirs with
+// at least min_files files, see get_similar_dirs.
+pub fn similar_dirs(
+    file_db_name: &Path,
+    dirs: Option<(&Path, &Path)>,
+    min_similarity: f64,
+    min_files: usize,
+    snapshot: Option<&str>,
+) -> bool
+{
+    let (file_db, children) = load_file_db_with_children(file_db_name, snapshot);
+    let (path_a, path_b) = match dirs {
+        Some(dirs) => dirs,
+        None => {
+            let similar_dirs = get_similar_dirs(&file_db, &children, min_similarity, min_files);
+            for (a, b, comparison) in &similar_dirs {
+                println!(
+                    "{:>5.1}% {} {}",
+                    comparison.get_similarity(),
+                    format_path_for_output(&get_full_path(&file_db, *a)),
+                    format_path_for_output(&get_full_path(&file_db, *b))
+                );
+            }
+            println!("Similar dir pairs: {}", similar_dirs.len().separated_string());
+            return true;
+        }
+    };
+    let mut indices = vec![];
+    for path in [path_a, path_b] {
+        match children.find_path(&file_db, path) {
+            Some(index) if file_db.get(index).is_dir => indices.push(index),
+            _ => {
+                eprintln!("{:?} is not a dir in the db", path);
+                return false;
+            }
+        }
+    }
+    let comparison = compare_dirs(&file_db, &children, indices[0], indices[1]);
+    println!("Only in {}:", format_path_for_output(path_a));
+    print_paths(&file_db, &comparison.only_a);
+    println!("Only in {}:", format_path_for_output(path_b));
+    print_paths(&file_db, &comparison.only_b);
+    println!(
+        "Similarity: {:.1}%, files with a copy on the other side: {} of {}",
+        comparison.get_similarity(),
+        comparison.num_shared.separated_string(),
+        comparison.num_files.separated_string()
+    );
+    true
+}
+
+// Walks through the groups of dupes in a terminal UI, where the copies to remove are marked,
+// then removes them
+pub fn dedup_interactive(
+    file_db_name: &Path,
+    keep_rules: &[KeepRule],
+    filter: &DupeFilter,
+    options: &RemoveOptions,
+)
+{
+    let mut db = load_for_dedup(file_db_name, None);
+    propagate_hashes(&mut db.file_db);
+    let mut review = get_dupe_review(&db.file_db, keep_rules, filter);
+    if review.groups.is_empty() {
+        println!("No dupes");
+        return;
+    }
+    let removals = match tui::review_dupes(&mut review) {
+        Ok(Some(removals)) => removals,
+        Ok(None) => {
+            println!("Cancelled, nothing removed");
+            return;
+        }
+        Err(err) => {
+            eprintln!("Error in terminal UI: {}", err);
+            return;
+        }
+    };
+    remove_dupes(file_db_name, &mut db, &removals, options);
+}
+
+fn get_hash_string(hash: &Hash256) -> String
+{
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hash_string(hash_string: &str) -> Option<Hash256>
+{
+    let mut hash = EMPTY_HASH;
+    if hash_string.len() != 2 * hash.len() || !hash_string.is_ascii() {
+        return None;
+    }
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash_string[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JournalEntry
+{
+    path: PathBuf,
+    hash: String,
+    size: u64,
+    modified: u64,
+    kept_path: PathBuf,
+    // Of the db, journals written before it was recorded are blake3
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+}
+
+// Files removed by dedup, one JSON object per line in <db>.<time>.journal. Each entry is
+// written before the file is removed, so the journal is complete even after a crash.
+// restore copies the files back from the kept copies.
+struct Journal
+{
+    file_name: PathBuf,
+    file: Option<File>,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl Journal
+{
+    fn new(file_db_name: &Path) -> Self
+    {
+        let suffix = format!("{}.journal", Local::now().format("%Y%m%d-%H%M%S"));
+        Journal {
+            file_name: get_db_side_file_name(file_db_name, &suffix),
+            file: None,
+            hash_algorithm: read_hash_algorithm(file_db_name),
+        }
+    }
+
+    fn log(&mut self, file_db: &FileDb, index: EntryIndex, kept_index: EntryIndex) -> io::Result<()>
+    {
+        let entry = file_db.get(index);
+        let journal_entry = JournalEntry {
+            path: get_full_path(file_db, index),
+            hash: get_hash_string(&entry.hash),
+            size: entry.size,
+            modified: entry.modified,
+            kept_path: get_full_path(file_db, kept_index),
+            hash_algorithm: self.hash_algorithm,
+        };
+        let mut line = serde_json::to_string(&journal_entry)?;
+        line.push('\n');
+        if self.file.is_none() {
+            let file = fs::OpenOptions::new().create(true).append(true).open(&self.file_name)?;
+            self.file = Some(file);
+        }
+        let file = self.file.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+
+    fn print_summary(&self, file_db_name: &Path)
+    {
+        if self.file.is_some() {
+            println!("Removed files are listed in journal {:?}, undo with:", self.file_name);
+            println!("  filedb {:?} restore {:?}", file_db_name, self.file_name);
+        }
+    }
+}
+
+// Copies the files of a journal back from the kept copies, unless they exist again
+pub fn restore(journal_name: &Path, dry_run: bool)
+{
+    let journal = BufReader::new(File::open(journal_name).unwrap());
+    let mut num_restored = 0;
+    let mut num_failed = 0;
+    for line in journal.lines() {
+        // The last line may be incomplete after a crash
+        let entry: JournalEntry = match serde_json::from_str(&line.unwrap()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                eprintln!("Invalid journal entry: {}", err);
+                num_failed += 1;
+                continue;
+            }
+        };
+        if fs::symlink_metadata(&entry.path).is_ok() {
+            println!("Exists, skipping {:?}", entry.path);
+            continue;
+        }
+        match get_hash_for_file(&entry.kept_path, entry.hash_algorithm) {
+            Ok(hash) if get_hash_string(&hash) == entry.hash => {}
+            _ => {
+                eprintln!(
+                    "Kept copy {:?} is missing or changed, cannot restore {:?}",
+                    entry.kept_path, entry.path
+                );
+                num_failed += 1;
+                continue;
+            }
+        }
+        num_restored += 1;
+        if dry_run {
+            println!("Would restore {:?} from {:?}", entry.path, entry.kept_path);
+            continue;
+        }
+        println!("Restoring {:?} from {:?}", entry.path, entry.kept_path);
+        let modified = time::UNIX_EPOCH + time::Duration::from_secs(entry.modified);
+        let restored = fs::create_dir_all(entry.path.parent().unwrap())
+            .and_then(|()| fs::copy(&entry.kept_path, &entry.path))
+            .and_then(|_| File::options().write(true).open(&entry.path))
+            .and_then(|file| file.set_modified(modified));
+        if let Err(err) = restored {
+            eprintln!("Error restoring {:?}: {}", entry.path, err);
+            num_restored -= 1;
+            num_failed += 1;
+        }
+    }
+    let verb = if dry_run { "Would restore" } else { "Restored" };
+    println!("{} {} files, failed: {}", verb, num_restored, num_failed);
+}
+
+// Where removed files go
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub enum RemoveTarget
+{
+    #[default]
+    Delete,
+    // The freedesktop.org trash of the user
+    Trash,
+    // This dir, with the full path of removed files beneath it
+    Quarantine(PathBuf),
+}
+
+fn remove_path(path: &Path, target: &RemoveTarget) -> io::Result<()>
+{
+    match target {
+        RemoveTarget::Delete if fs::symlink_metadata(path)?.is_dir() => fs::remove_dir_all(path),
+        RemoveTarget::Delete => fs::remove_file(path),
+        RemoveTarget::Trash => trash::move_to_trash(path).map(|_| ()),
+        RemoveTarget::Quarantine(quarantine_dir) => {
+            trash::move_to_quarantine(path, quarantine_dir).map(|_| ())
+        }
+    }
+}
+
+// Settings for operations removing files
+#[derive(Default, Clone, Debug)]
+pub struct RemoveOptions
+{
+    // Compare a file byte by byte to the copy that is kept right before removing it,
+    // instead of relying on hash and size
+    pub verify_content: bool,
+    // Only print what would be done, without changing files or the db
+    pub dry_run: bool,
+    pub target: RemoveTarget,
+    // Never removed or replaced, nor are the dirs containing them
+    pub protected: ProtectedPaths,
+    // Stops dedup before the next group of dupes, see CancellationToken
+    pub cancellation: Option<api::CancellationToken>,
+}
+
+// Check whether all files in backup_dir are elsewhere, and list those that aren't
+// Comparison is done by 256bit hash and size, not by name or content
+// What all_files_elsewhere prints
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElsewhereOutput
+{
+    Report,
+    Json,
+    // Nothing, for callers that print the returned paths
+    Quiet,
+}
+
+// Ignores empty files (also does not remove them)
+// Files in backup_dir preferred by keep_rules over all their copies elsewhere are kept
+// With against_db_name, copies are looked up in that db instead, e.g. of an offline backup
+// drive. Keep rules do not apply then, and no files are removed.
+// Returns the paths of the files missing
+#[allow(clippy::too_many_arguments)]
+pub fn all_files_elsewhere(
+    file_db_name: &Path,
+    backup_dir: &Path,
+    opt_other_dir: Option<&Path>,
+    against_db_name: Option<&Path>,
+    remove_dupes: bool,
+    keep_rules: &[KeepRule],
+    options: &RemoveOptions,
+    output: ElsewhereOutput,
+    snapshot: Option<&str>,
+) -> Vec<PathBuf>
+{
+    let json = output == ElsewhereOutput::Json;
+    let report = output == ElsewhereOutput::Report;
+    if let Some(against_db_name) = against_db_name {
+        check_same_hash_algorithm(file_db_name, against_db_name);
+    }
+    // Add all files outside of backup_dir to lookup structure, all files of the other db
+    let file_db = load_file_db(file_db_name, snapshot);
+    let against_db = against_db_name.map(|against_db_name| load_file_db(against_db_name, None));
+    let lookup_db = against_db.as_ref().unwrap_or(&file_db);
+    let mut hash_to_index: HashMap<Hash256, Vec<EntryIndex>> = HashMap::new();
+    for (i, entry) in lookup_db.iter().enumerate() {
+        // Files not hashed yet count as missing, so they are never removed
+        if entry.is_dir || entry.size == 0 || !is_hashed(&entry) {
+            continue;
+        }
+        let entry_path = get_full_path(lookup_db, i as EntryIndex);
+        if against_db.is_some() || !entry_path.starts_with(backup_dir) {
+            let map_entry = hash_to_index.entry(entry.hash).or_insert(Vec::<EntryIndex>::new());
+            (*map_entry).push(i as EntryIndex);
+        }
+    }
+
+    let mut num_dupes = 0;
+    let mut num_files_missing = 0;
+    let mut num_dupes_sum = 0;
+    let mut num_dupe_entries = 0;
+    let mut min_num_dupes = std::usize::MAX;
+    let mut max_num_dupes = 0;
+    let mut entry_and_dupes = vec![];
+    let mut num_duped_bytes = 0;
+    let mut num_missing_bytes = 0;
+    let mut num_removed_bytes = 0;
+    let mut journal = Journal::new(file_db_name);
+    let mut num_dirs = 0;
+    let mut num_empty_files = 0;
+    let mut missing = vec![];
+    let get_paths = |indices: &[EntryIndex]| {
+        indices.iter().map(|index| get_full_path(&file_db, *index)).collect::<Vec<_>>()
+    };
+    let mut kept = vec![];
+    // Everything in backup_dir may be removed, so kept copies must be elsewhere
+    let removed = HashSet::from([backup_dir.to_path_buf()]);
+    // Iterate all files in backup_dir and check if they are present in lookup structure
+    for (i, entry) in file_db.iter().enumerate() {
+        let entry_path = get_full_path(&file_db, i as EntryIndex);
+        if !entry_path.starts_with(backup_dir) {
+            continue;
+        }
+        if entry.is_dir {
+            num_dirs += 1;
+            continue;
+        }
+        if entry.size == 0 {
+            num_empty_files += 1;
+            continue;
+        }
+        let hash = entry.hash;
+        let value = hash_to_index.get(&hash);
+        if value.is_none() {
+            if report {
+                println!("File missing: {:?}", entry_path);
+            }
+            missing.push(i as EntryIndex);
+            num_files_missing += 1;
+            num_missing_bytes += entry.size;
+        } else {
+            let dupe_list = value.unwrap();
+            let mut copies = vec![];
+            for dupe_index in dupe_list {
+                let dupe_entry = lookup_db.get(*dupe_index);
+                if dupe_entry.size == entry.size
+                /*&& dupe_entry.name == entry.name*/
+                {
+                    if let Some(other_dir) = opt_other_dir {
+                        let dupe_path = get_full_path(lookup_db, *dupe_index);
+                        if dupe_path.starts_with(other_dir) {
+                            copies.push(*dupe_index);
+                        }
+                    } else {
+                        copies.push(*dupe_index);
+                    }
+                }
+            }
+            let found = !copies.is_empty();
+            // Copies in the other db are in another index space, and never kept for removal
+            let kept_index = match against_db {
+                None if found => {
+                    let copy_index = choose_kept(&file_db, keep_rules, &copies);
+                    let copy_path = get_full_path(&file_db, copy_index);
+                    // Ties go to the copies elsewhere, regardless of the paths
+                    let ordering = compare_for_keeping(
+                        &file_db,
+                        keep_rules,
+                        (i as EntryIndex, &entry_path),
+                        (copy_index, &copy_path),
+                    );
+                    match ordering {
+                        std::cmp::Ordering::Less => i as EntryIndex,
+                        _ => copy_index,
+                    }
+                }
+                _ => i as EntryIndex,
+            };
+            if found && against_db.is_none() && kept_index == i as EntryIndex {
+                if report {
+                    println!("Keeping {:?}, preferred by keep rules", entry_path);
+                }
+                kept.push(i as EntryIndex);
+            } else if !found {
+                if report {
+                    println!("File missing: {:?}", entry_path);
+                }
+                missing.push(i as EntryIndex);
+                num_files_missing += 1;
+                num_missing_bytes += entry.size;
+            } else {
+                let num_entry_dupes = dupe_list.len();
+                num_dupes_sum += num_entry_dupes;
+                num_dupe_entries += 1;
+                min_num_dupes = std::cmp::min(min_num_dupes, num_dupes);
+                max_num_dupes = std::cmp::max(max_num_dupes, num_dupes);
+                entry_and_dupes.push((i as EntryIndex, dupe_list));
+                num_dupes += 1;
+                num_duped_bytes += entry.size;
+                if fs::metadata(&entry_path).is_ok() {
+                    if remove_dupes {
+                        if let Some(pattern) = options.protected.get_protecting(&entry_path) {
+                            println!("Keeping {:?}, protected by {}", entry_path, pattern);
+                            continue;
+                        }
+                        let index = i as EntryIndex;
+                        let kept_copy =
+                            safety::check_kept_copy(&file_db, index, kept_index, &removed);
+                        if let Err(reason) = kept_copy {
+                            eprintln!("Not removing {:?}, {}", entry_path, reason);
+                            continue;
+                        }
+                        if options.verify_content {
+                            let kept_path = get_full_path(&file_db, kept_index);
+                            match files_equal(&entry_path, &kept_path) {
+                                Ok(true) => {}
+                                Ok(false) => {
+                                    eprintln!(
+                                        "Content of {:?} differs from {:?}, aborting",
+                                        entry_path, kept_path
+                                    );
+                                    return get_paths(&missing);
+                                }
+                                Err(err) => {
+                                    eprintln!(
+                                        "Error comparing {:?} to {:?}: {}, aborting",
+                                        entry_path, kept_path, err
+                                    );
+                                    return get_paths(&missing);
+                                }
+                            }
+                        }
+                        num_removed_bytes += entry.size;
+                        if options.dry_run {
+                            println!("Would remove {:?} and its parent dirs if empty", entry_path);
+                            continue;
+                        }
+                        if let Err(err) = journal.log(&file_db, i as EntryIndex, kept_index) {
+                            let journal_name = &journal.file_name;
+                            eprintln!("Error writing journal {:?}: {}, aborting", journal_name, err);
+                            return get_paths(&missing);
+                        }
+                        println!("Removing {:?}", entry_path);
+                        let res = remove_path(&entry_path, &options.target);
+                        if res.is_err() {
+                            println!("Error removing {:?}", entry_path);
+                        }
+                        let mut parent = entry_path.parent().unwrap();
+                        while fs::remove_dir(parent).is_ok() {
+                            // Will only remove empty dirs
+                            println!("Removed parent dir {:?}", parent);
+                            parent = parent.parent().unwrap();
+                        }
+                    } else if report {
+                        println!("Would remove {:?}", entry_path);
+                    }
+                }
+            }
+        }
+    }
+
+    let missing_paths = get_paths(&missing);
+    let results = serde_json::json!({
+        "ok": num_files_missing == 0,
+        "dir": backup_dir.to_string_lossy(),
+        "files_missing": num_files_missing,
+        "missing_bytes": num_missing_bytes,
+        "missing": alerts::get_notify_paths(&missing_paths),
+    });
+    alerts::notify("all_files_elsewhere", file_db_name, results);
+
+    if json {
+        let get_file = |index: &EntryIndex| {
+            let path = get_full_path(&file_db, *index);
+            let entry = file_db.get(*index);
+            serde_json::json!({
+                "path": path.to_string_lossy(),
+                "size": entry.size,
+                "hash": get_hash_string(&entry.hash),
+            })
+        };
+        let dupes = entry_and_dupes
+            .iter()
+            .map(|(index, dupe_list)| {
+                let mut dupe = get_file(index);
+                let copies = dupe_list.iter().map(|index| get_full_path(lookup_db, *index));
+                let copies = copies.map(|path| path.to_string_lossy().into_owned());
+                dupe["copies"] = copies.collect();
+                dupe
+            })
+            .collect::<Vec<_>>();
+        let result = serde_json::json!({
+            "num_dupes": num_dupes,
+            "files_missing": num_files_missing,
+            "dirs": num_dirs,
+            "empty_files": num_empty_files,
+            "min_num_dupes": if num_dupe_entries == 0 { 0 } else { min_num_dupes },
+            "max_num_dupes": max_num_dupes,
+            "avg_num_dupes": num_dupes_sum.checked_div(num_dupe_entries).unwrap_or(0),
+            "duped_bytes": num_duped_bytes,
+            "missing_bytes": num_missing_bytes,
+            "removed_bytes": num_removed_bytes,
+            "missing": missing.iter().map(get_file).collect::<Vec<_>>(),
+            "kept": kept.iter().map(get_file).collect::<Vec<_>>(),
+            "dupes": dupes,
+        });
+        println!("{}", result);
+        if remove_dupes {
+            journal.print_summary(file_db_name);
+        }
+        return missing_paths;
+    }
+    if !report {
+        return missing_paths;
+    }
+    println!("Num dupes: {}", num_dupes);
+    println!("Files missing: {}", num_files_missing);
+    println!("Dirs: {}", num_dirs);
+    println!("Empty files: {}", num_empty_files);
+    println!("");
+    println!("Min num dupes: {}", if num_dupe_entries == 0 { 0 } else { min_num_dupes });
+    println!("Max num dupes: {}", max_num_dupes);
+    println!("Avg num dupes: {}", num_dupes_sum.checked_div(num_dupe_entries).unwrap_or(0));
+    println!("Num duped bytes: {}", num_duped_bytes);
+    println!("Num missing bytes: {}", num_missing_bytes);
+    if remove_dupes {
+        let verb = if options.dry_run { "would be removed" } else { "removed" };
+        println!("Num bytes {}: {}", verb, num_removed_bytes);
+    }
+    journal.print_summary(file_db_name);
+    missing_paths
+}
+
+// Writes paths relative to root, each terminated by NUL, for
+// rsync -a --from0 --files-from=list_name root/ dest
+pub fn write_files_from(list_name: &Path, root: &Path, paths: &[PathBuf]) -> io::Result<()>
+{
+    let mut writer = io::BufWriter::new(File::create(list_name)?);
+    for path in paths {
+        let relative_path = path.strip_prefix(root).unwrap_or(path);
+        writer.write_all(&get_path_bytes(relative_path))?;
+        writer.write_all(b"\0")?;
+    }
+    writer.flush()
+}
+
+fn append_to_tar<W: Write>(writer: W, root: &Path, paths: &[PathBuf]) -> io::Result<W>
+{
+    let mut builder = tar::Builder::new(writer);
+    for path in paths {
+        builder.append_path_with_name(path, path.strip_prefix(root).unwrap_or(path))?;
+    }
+    builder.into_inner()
+}
+
+// Packs the files at paths into the archive archive_name, at their paths relative to root.
+// The format follows the extension: .tar, .tar.gz/.tgz, .tar.zst/.tzst or .zip.
+pub fn pack_files(archive_name: &Path, root: &Path, paths: &[PathBuf]) -> io::Result<()>
+{
+    let name = archive_name.to_string_lossy();
+    let has_suffix = |suffixes: &[&str]| suffixes.iter().any(|suffix| name.ends_with(suffix));
+    if !has_suffix(&[".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst", ".zip"]) {
+        let message = "unknown archive format, use .tar, .tar.gz, .tar.zst or .zip";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    let file = io::BufWriter::new(File::create(archive_name)?);
+    if has_suffix(&[".tar.gz", ".tgz"]) {
+        let encoder = append_to_tar(GzEncoder::new(file, Compression::default()), root, paths)?;
+        encoder.finish()?.flush()
+    } else if has_suffix(&[".tar.zst", ".tzst"]) {
+        append_to_tar(zstd::Encoder::new(file, 0)?, root, paths)?.finish()?.flush()
+    } else if has_suffix(&[".zip"]) {
+        let mut zip = zip::ZipWriter::new(file);
+        for path in paths {
+            let relative_path = path.strip_prefix(root).unwrap_or(path);
+            zip.start_file(relative_path.to_string_lossy(), Default::default())?;
+            io::copy(&mut File::open(path)?, &mut zip)?;
+        }
+        zip.finish()?.flush()
+    } else {
+        append_to_tar(file, root, paths)?.flush()
+    }
+}
+
+// Files below prefix whose contents have no copy outside of it, or in lookup_db if given.
+// Empty files are left out, files not hashed yet have no copies.
+fn get_missing_files(file_db: &FileDb, prefix: &Path, lookup_db: Option<&FileDb>) -> Vec<EntryIndex>
+{
+    let is_below_prefix =
+        |index: usize| get_full_path(file_db, index as EntryIndex).starts_with(prefix);
+    let copies = match lookup_db {
+        Some(lookup_db) => lookup_db
+            .iter()
+            .filter(|entry| is_hashed(entry))
+            .map(|entry| (entry.hash, entry.size))
+            .collect(),
+        None => file_db
+            .iter()
+            .enumerate()
+            .filter(|(index, entry)| is_hashed(entry) && !is_below_prefix(*index))
+            .map(|(_, entry)| (entry.hash, entry.size))
+            .collect::<HashSet<_>>(),
+    };
+    (0..file_db.len())
+        .filter(|index| {
+            let entry = file_db.get((*index) as EntryIndex);
+            !entry.is_dir
+                && entry.size > 0
+                && is_below_prefix(*index)
+                && !copies.contains(&(entry.hash, entry.size))
+        })
+        .map(|index| index as EntryIndex)
+        .collect()
+}
+
+// Copies path to dest_path through a temporary file whose hash is checked before renaming it.
+// Returns false if dest_path already is a copy.
+fn sync_file(
+    path: &Path,
+    dest_path: &Path,
+    entry: &Entry,
+    algorithm: HashAlgorithm,
+) -> io::Result<bool>
+{
+    if !is_hashed(entry) {
+        return Err(io::Error::other("not hashed yet, run hash first"));
+    }
+    if fs::symlink_metadata(dest_path).is_ok() {
+        let is_copy = fs::metadata(dest_path)?.len() == entry.size
+            && get_hash_for_file(dest_path, algorithm)? == entry.hash;
+        if is_copy {
+            return Ok(false);
+        }
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "other file at destination"));
+    }
+    fs::create_dir_all(dest_path.parent().unwrap())?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(dest_path.file_name().unwrap());
+    tmp_name.push(".filedb-sync");
+    let tmp_path = dest_path.with_file_name(tmp_name);
+    fs::copy(path, &tmp_path)?;
+    let modified = fs::metadata(path)?.modified()?;
+    let file = fs::OpenOptions::new().write(true).open(&tmp_path)?;
+    file.set_times(fs::FileTimes::new().set_modified(modified))?;
+    drop(file);
+    if get_hash_for_file(&tmp_path, algorithm)? != entry.hash {
+        fs::remove_file(&tmp_path)?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "copy differs from indexed hash"));
+    }
+    fs::rename(&tmp_path, dest_path)?;
+    Ok(true)
+}
+
+// Copies the files below source_prefix without copies elsewhere in the db, or in the db
+// against_db_name, to dest_dir, at their paths relative to source_prefix. Copies already at
+// the destination are skipped, so an interrupted sync continues when run again.
+// Returns whether all files were copied.
+pub fn sync_missing(
+    file_db_name: &Path,
+    source_prefix: &Path,
+    dest_dir: &Path,
+    against_db_name: Option<&Path>,
+    dry_run: bool,
+) -> bool
+{
+    if let Some(against_db_name) = against_db_name {
+        check_same_hash_algorithm(file_db_name, against_db_name);
+    }
+    let algorithm = read_hash_algorithm(file_db_name);
+    let file_db = load_file_db(file_db_name, None);
+    let against_db = against_db_name.map(|against_db_name| load_file_db(against_db_name, None));
+    let missing = get_missing_files(&file_db, source_prefix, against_db.as_ref());
+    let total_bytes = missing.iter().map(|index| file_db.get(*index).size).sum::<u64>();
+    install_interrupt_handler();
+    let mut num_bytes = 0;
+    let mut num_copied = 0;
+    let mut num_failed = 0;
+    for (i, index) in missing.iter().enumerate() {
+        if is_interrupted() {
+            break;
+        }
+        let entry = file_db.get(*index);
+        let path = get_full_path(&file_db, *index);
+        let dest_path = dest_dir.join(path.strip_prefix(source_prefix).unwrap());
+        num_bytes += entry.size;
+        let progress = format!(
+            "[{}/{}, {}%]",
+            i + 1,
+            missing.len(),
+            num_bytes * 100 / total_bytes.max(1)
+        );
+        if dry_run {
+            println!("{} Would copy {:?} to {:?}", progress, path, dest_path);
+            continue;
+        }
+        match sync_file(&path, &dest_path, &entry, algorithm) {
+            Ok(true) => {
+                println!("{} Copied {:?}", progress, path);
+                num_copied += 1;
+            }
+            Ok(false) => println!("{} Already copied {:?}", progress, path),
+            Err(err) => {
+                eprintln!("{} Error copying {:?} to {:?}: {}", progress, path, dest_path, err);
+                num_failed += 1;
+            }
+        }
+    }
+    println!(
+        "Files missing: {}, size: {}",
+        missing.len().separated_string(),
+        total_bytes.separated_string()
+    );
+    if !dry_run {
+        println!("Copied: {}, failed: {}", num_copied, num_failed);
+    }
+    num_failed == 0 && !is_interrupted()
+}
+
+// Additional reports of stats
+#[derive(Default, Clone, Debug)]
+pub struct StatsOptions
+{
+    pub by_extension: bool,
+    pub size_histogram: bool,
+    pub age: bool,
+    pub by_owner: bool,
+    pub by_host: bool,
+    pub json: bool,
+}
+
+// Exclusive upper bounds of the size histogram bins, with their labels. Files larger than
+// the last bound are in an extra bin.
+const SIZE_HISTOGRAM_BINS: [(u64, &str); 6] = [
+    (1, "0"),
+    (4 << 10, "<4K"),
+    (64 << 10, "<64K"),
+    (1 << 20, "<1M"),
+    (100 << 20, "<100M"),
+    (1 << 30, "<1G"),
+];
+
+// Exclusive upper bounds of the age histogram bins in seconds, with their labels. Older files
+// are in an extra bin.
+const AGE_HISTOGRAM_BINS: [(u64, &str); 3] = [
+    (30 * 24 * 60 * 60, "<30d"),
+    (365 * 24 * 60 * 60, "<1y"),
+    (5 * 365 * 24 * 60 * 60, "<5y"),
+];
+
+// Count and bytes of files per lowercase extension, the most bytes first
+fn get_extension_stats(file_db: &FileDb, indices: &[EntryIndex]) -> Vec<(String, u64, u64)>
+{
+    let mut extension_to_stats = HashMap::<String, (u64, u64)>::new();
+    for index in indices {
+        let entry = file_db.get(*index);
+        if entry.is_dir {
+            continue;
+        }
+        let extension = match Path::new(&entry.name).extension() {
+            Some(extension) => extension.to_string_lossy().to_lowercase(),
+            None => String::new(),
+        };
+        let stats = extension_to_stats.entry(extension).or_default();
+        stats.0 += 1;
+        stats.1 += entry.size;
+    }
+    let mut extension_stats = extension_to_stats
+        .into_iter()
+        .map(|(extension, (count, bytes))| (extension, count, bytes))
+        .collect::<Vec<_>>();
+    extension_stats.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    extension_stats
+}
+
+// Count and bytes of files per uid, the most bytes first. None for files whose owner is
+// unknown, like those added by older versions.
+fn get_owner_stats(file_db: &FileDb, indices: &[EntryIndex]) -> Vec<(Option<u32>, u64, u64)>
+{
+    let mut owner_to_stats = HashMap::<Option<u32>, (u64, u64)>::new();
+    for index in indices {
+        let entry = file_db.get(*index);
+        if entry.is_dir {
+            continue;
+        }
+        let owner = if entry.mode == 0 { None } else { Some(entry.uid) };
+        let stats = owner_to_stats.entry(owner).or_default();
+        stats.0 += 1;
+        stats.1 += entry.size;
+    }
+    let mut owner_stats = owner_to_stats
+        .into_iter()
+        .map(|(owner, (count, bytes))| (owner, count, bytes))
+        .collect::<Vec<_>>();
+    owner_stats.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    owner_stats
+}
+
+#[cfg(unix)]
+fn get_user_name(uid: u32) -> Option<String>
+{
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+    };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn get_user_name(_uid: u32) -> Option<String>
+{
+    None
+}
+
+// User name if the uid is known on this system, the uid otherwise
+fn get_owner_name(owner: Option<u32>) -> String
+{
+    match owner {
+        Some(uid) => get_user_name(uid).unwrap_or_else(|| uid.to_string()),
+        None => "(unknown)".to_string(),
+    }
+}
+
+fn print_owner_stats(file_db: &FileDb, indices: &[EntryIndex], total_size: u64)
+{
+    println!("By owner:");
+    println!("  {:<16} {:>15} {:>23} {:>7}", "Owner", "Files", "Bytes", "%");
+    for (owner, count, bytes) in get_owner_stats(file_db, indices) {
+        let percent = if total_size == 0 { 0.0 } else { 100.0 * bytes as f64 / total_size as f64 };
+        println!(
+            "  {:<16} {:>15} {:>23} {:>7.2}",
+            get_owner_name(owner),
+            count.separated_string(),
+            bytes.separated_string(),
+            percent
+        );
+    }
+}
+
+#[derive(Default)]
+struct HostStats
+{
+    origin: u16,
+    files: u64,
+    bytes: u64,
+    // Files with a copy on another host, anywhere in the db
+    files_on_other_hosts: u64,
+    bytes_on_other_hosts: u64,
+}
+
+fn get_host_stats(file_db: &FileDb, indices: &[EntryIndex]) -> Vec<HostStats>
+{
+    let mut hash_to_origins = HashMap::<Hash256, Vec<u16>>::new();
+    for entry in file_db.iter() {
+        if entry.is_dir || entry.size == 0 || !is_hashed(&entry) {
+            continue;
+        }
+        let origins = hash_to_origins.entry(entry.hash).or_default();
+        if !origins.contains(&entry.origin) {
+            origins.push(entry.origin);
+        }
+    }
+    let mut origin_to_stats = HashMap::<u16, HostStats>::new();
+    for index in indices {
+        let entry = file_db.get(*index);
+        if entry.is_dir {
+            continue;
+        }
+        let stats = origin_to_stats
+            .entry(entry.origin)
+            .or_insert_with(|| HostStats { origin: entry.origin, ..Default::default() });
+        stats.files += 1;
+        stats.bytes += entry.size;
+        if hash_to_origins.get(&entry.hash).is_some_and(|origins| origins.len() > 1) {
+            stats.files_on_other_hosts += 1;
+            stats.bytes_on_other_hosts += entry.size;
+        }
+    }
+    let mut host_stats = origin_to_stats.into_values().collect::<Vec<_>>();
+    host_stats.sort_by_key(|stats| stats.origin);
+    host_stats
+}
+
+fn print_host_stats(file_db: &FileDb, indices: &[EntryIndex], hosts: &[String])
+{
+    println!("By host:");
+    println!(
+        "  {:<16} {:>15} {:>23} {:>15} {:>23}",
+        "Host", "Files", "Bytes", "On other hosts", "Bytes"
+    );
+    for stats in get_host_stats(file_db, indices) {
+        println!(
+            "  {:<16} {:>15} {:>23} {:>15} {:>23}",
+            agent::get_origin_name(hosts, stats.origin),
+            stats.files.separated_string(),
+            stats.bytes.separated_string(),
+            stats.files_on_other_hosts.separated_string(),
+            stats.bytes_on_other_hosts.separated_string()
+        );
+    }
+}
+
+fn print_extension_stats(file_db: &FileDb, indices: &[EntryIndex], total_size: u64)
+{
+    println!("By extension:");
+    println!("  {:<16} {:>15} {:>23} {:>7}", "Extension", "Files", "Bytes", "%");
+    for (extension, count, bytes) in get_extension_stats(file_db, indices) {
+        let extension = if extension.is_empty() { "(none)".to_string() } else { extension };
+        let percent = if total_size == 0 { 0.0 } else { 100.0 * bytes as f64 / total_size as f64 };
+        println!(
+            "  {:<16} {:>15} {:>23} {:>7.2}",
+            extension,
+            count.separated_string(),
+            bytes.separated_string(),
+            percent
+        );
+    }
+}
+
+// Count and bytes of files per bin of SIZE_HISTOGRAM_BINS, plus the bin of larger files
+fn get_size_histogram(file_db: &FileDb, indices: &[EntryIndex]) -> Vec<(u64, u64)>
+{
+    let mut histogram = vec![(0, 0); SIZE_HISTOGRAM_BINS.len() + 1];
+    for index in indices {
+        let entry = file_db.get(*index);
+        if entry.is_dir {
+            continue;
+        }
+        let bin = SIZE_HISTOGRAM_BINS.iter().position(|(bound, _)| entry.size < *bound);
+        let bin = &mut histogram[bin.unwrap_or(SIZE_HISTOGRAM_BINS.len())];
+        bin.0 += 1;
+        bin.1 += entry.size;
+    }
+    histogram
+}
+
+fn size_histogram_labels() -> impl Iterator<Item = &'static str>
+{
+    SIZE_HISTOGRAM_BINS.iter().map(|(_, label)| *label).chain(std::iter::once(">=1G"))
+}
+
+fn print_size_histogram(file_db: &FileDb, indices: &[EntryIndex])
+{
+    println!("Size histogram:");
+    println!("  {:<8} {:>15} {:>23} {:>23}", "Size", "Files", "Bytes", "Cumulative bytes");
+    let mut cumulative_bytes = 0u64;
+    for (label, (count, bytes)) in size_histogram_labels().zip(get_size_histogram(file_db, indices))
+    {
+        cumulative_bytes += bytes;
+        println!(
+            "  {:<8} {:>15} {:>23} {:>23}",
+            label,
+            count.separated_string(),
+            bytes.separated_string(),
+            cumulative_bytes.separated_string()
+        );
+    }
+}
+
+// Count and bytes of files per bin of AGE_HISTOGRAM_BINS, plus the bin of older files. The
+// age is taken from the time returned by get_time, times in the future count as age 0.
+fn get_age_histogram(
+    file_db: &FileDb,
+    indices: &[EntryIndex],
+    now: u64,
+    get_time: fn(&Entry) -> u64,
+) -> Vec<(u64, u64)>
+{
+    let mut histogram = vec![(0, 0); AGE_HISTOGRAM_BINS.len() + 1];
+    for index in indices {
+        let entry = file_db.get(*index);
+        if entry.is_dir {
+            continue;
+        }
+        let age = now.saturating_sub(get_time(&entry));
+        let bin = AGE_HISTOGRAM_BINS.iter().position(|(bound, _)| age < *bound);
+        let bin = &mut histogram[bin.unwrap_or(AGE_HISTOGRAM_BINS.len())];
+        bin.0 += 1;
+        bin.1 += entry.size;
+    }
+    histogram
+}
+
+fn age_histogram_labels() -> impl Iterator<Item = &'static str>
+{
+    AGE_HISTOGRAM_BINS.iter().map(|(_, label)| *label).chain(std::iter::once("older"))
+}
+
+fn print_age_histogram(file_db: &FileDb, indices: &[EntryIndex])
+{
+    let now = get_secs(&time::SystemTime::now());
+    let modified = get_age_histogram(file_db, indices, now, |entry| entry.modified);
+    let accessed = get_age_histogram(file_db, indices, now, |entry| entry.accessed);
+    println!("Age histogram:");
+    println!(
+        "  {:<8} {:>15} {:>23} {:>15} {:>23}",
+        "Age", "Modified files", "Bytes", "Accessed files", "Bytes"
+    );
+    let bins = modified.iter().zip(&accessed);
+    for (label, (modified, accessed)) in age_histogram_labels().zip(bins) {
+        println!(
+            "  {:<8} {:>15} {:>23} {:>15} {:>23}",
+            label,
+            modified.0.separated_string(),
+            modified.1.separated_string(),
+            accessed.0.separated_string(),
+            accessed.1.separated_string()
+        );
+    }
+}
+
+pub fn stats(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    options: &StatsOptions,
+    snapshot: Option<&str>,
+)
+{
+    let (file_db, children) = match prefix {
+        Some(prefix) => load_file_db_for_path(file_db_name, prefix, snapshot),
+        None => load_file_db_with_children(file_db_name, snapshot),
+    };
+
+    // With a prefix, only its subtree is visited
+    let indices = match prefix.map(|prefix| children.find_path(&file_db, prefix)) {
+        Some(Some(index)) => {
+            let mut subtree = children.get_subtree(index);
+            subtree.sort_unstable();
+            subtree
+        }
+        Some(None) => vec![],
+        None => (0..file_db.len() as EntryIndex).collect(),
+    };
+    let hosts = if options.by_host { load_compressed(file_db_name).hosts } else { vec![] };
+    let mut num_files = 0;
+    let mut num_dirs = 0;
+    let mut size = 0;
+    for index in &indices {
+        let entry = file_db.get(*index);
+        if entry.is_dir {
+            num_dirs += 1;
+        } else {
+            num_files += 1;
+            size += entry.size;
+        }
+    }
+    let (largest_entry_name, largest_entry_size) = indices
+        .iter()
+        .map(|index| file_db.get(*index))
+        .map(|entry| (entry.name, entry.size))
+        .max_by_key(|elem| elem.1)
+        .unwrap_or_default();
+    if options.json {
+        let mut result = serde_json::json!({
+            "prefix": prefix.map(|prefix| prefix.to_string_lossy()),
+            "entries": indices.len(),
+            "files": num_files,
+            "dirs": num_dirs,
+            "size": size,
+            "largest_entry": {
+                "name": largest_entry_name.to_string_lossy(),
+                "size": largest_entry_size,
+            },
+        });
+        if options.by_extension {
+            result["by_extension"] = get_extension_stats(&file_db, &indices)
+                .into_iter()
+                .map(|(extension, count, bytes)| {
+                    serde_json::json!({ "extension": extension, "files": count, "bytes": bytes })
+                })
+                .collect();
+        }
+        if options.by_owner {
+            result["by_owner"] = get_owner_stats(&file_db, &indices)
+                .into_iter()
+                .map(|(owner, count, bytes)| {
+                    serde_json::json!({
+                        "uid": owner,
+                        "owner": get_owner_name(owner),
+                        "files": count,
+                        "bytes": bytes,
+                    })
+                })
+                .collect();
+        }
+        if options.by_host {
+            result["by_host"] = get_host_stats(&file_db, &indices)
+                .into_iter()
+                .map(|stats| {
+                    serde_json::json!({
+                        "host": agent::get_origin_name(&hosts, stats.origin),
+                        "files": stats.files,
+                        "bytes": stats.bytes,
+                        "files_on_other_hosts": stats.files_on_other_hosts,
+                        "bytes_on_other_hosts": stats.bytes_on_other_hosts,
+                    })
+                })
+                .collect();
+        }
+        if options.size_histogram {
+            result["size_histogram"] = size_histogram_labels()
+                .zip(get_size_histogram(&file_db, &indices))
+                .map(|(label, (count, bytes))| {
+                    serde_json::json!({ "size": label, "files": count, "bytes": bytes })
+                })
+                .collect();
+        }
+        if options.age {
+            let now = get_secs(&time::SystemTime::now());
+            let modified = get_age_histogram(&file_db, &indices, now, |entry| entry.modified);
+            let accessed = get_age_histogram(&file_db, &indices, now, |entry| entry.accessed);
+            result["age"] = age_histogram_labels()
+                .zip(modified.iter().zip(&accessed))
+                .map(|(label, (modified, accessed))| {
+                    serde_json::json!({
+                        "age": label,
+                        "modified_files": modified.0,
+                        "modified_bytes": modified.1,
+                        "accessed_files": accessed.0,
+                        "accessed_bytes": accessed.1,
+                    })
+                })
+                .collect();
+        }
+        println!("{}", result);
+        return;
+    }
+    println!(
+        "Entries: {}, files: {}, dirs: {}, size: {}",
+        indices.len().separated_string(),
+        num_files.separated_string(),
+        num_dirs.separated_string(),
+        size.separated_string()
+    );
+    println!(
+        "Largest entry: {}, size: {}",
+        largest_entry_name.to_str().unwrap(),
+        largest_entry_size.separated_string()
+    );
+    if options.by_extension {
+        print_extension_stats(&file_db, &indices, size);
+    }
+    if options.by_owner {
+        print_owner_stats(&file_db, &indices, size);
+    }
+    if options.by_host {
+        print_host_stats(&file_db, &indices, &hosts);
+    }
+    if options.size_histogram {
+        print_size_histogram(&file_db, &indices);
+    }
+    if options.age {
+        print_age_histogram(&file_db, &indices);
+    }
+}
+
+// Files below prefix not accessed for at least min_age seconds, the largest first
+fn get_cold_files(
+    file_db: &FileDb,
+    prefix: Option<&Path>,
+    min_age: u64,
+    now: u64,
+) -> Vec<EntryIndex>
+{
+    let mut indices = (0..file_db.len() as EntryIndex)
+        .filter(|index| {
+            let entry = file_db.get(*index);
+            !entry.is_dir
+                && now.saturating_sub(entry.accessed) >= min_age
+                && prefix.is_none_or(|prefix| get_full_path(file_db, *index).starts_with(prefix))
+        })
+        .collect::<Vec<_>>();
+    indices.sort_by_key(|index| std::cmp::Reverse(file_db.get(*index).size));
+    indices
+}
+
+// Files below prefix of at least min_size bytes, which allocate at most 1/min_ratio of their
+// size on disk, the most unallocated bytes first
+fn get_sparse_files(
+    file_db: &FileDb,
+    prefix: Option<&Path>,
+    min_size: u64,
+    min_ratio: f64,
+) -> Vec<EntryIndex>
+{
+    let mut indices = (0..file_db.len() as EntryIndex)
+        .filter(|index| {
+            let entry = file_db.get(*index);
+            !entry.is_dir
+                && entry.size >= min_size
+                && (entry.allocated as f64) * min_ratio <= entry.size as f64
+                && prefix.is_none_or(|prefix| get_full_path(file_db, *index).starts_with(prefix))
+        })
+        .collect::<Vec<_>>();
+    let unallocated = |index: &EntryIndex| {
+        let entry = file_db.get(*index);
+        entry.size.saturating_sub(entry.allocated)
+    };
+    indices.sort_by_key(|index| std::cmp::Reverse(unallocated(index)));
+    indices
+}
+
+// List sparse files with their size and allocated bytes
+pub fn sparse(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    min_size: u64,
+    min_ratio: f64,
+    snapshot: Option<&str>,
+)
+{
+    let file_db = load_file_db(file_db_name, snapshot);
+    let indices = get_sparse_files(&file_db, prefix, min_size, min_ratio);
+    let mut size = 0;
+    let mut allocated = 0;
+    println!("{:>19} {:>19} Path", "Size", "Allocated");
+    for index in &indices {
+        let entry = file_db.get(*index);
+        size += entry.size;
+        allocated += entry.allocated;
+        println!(
+            "{:>19} {:>19} {}",
+            entry.size.separated_string(),
+            entry.allocated.separated_string(),
+            format_path_for_output(&get_full_path(&file_db, *index))
+        );
+    }
+    println!(
+        "Sparse files: {}, size: {}, allocated: {}",
+        indices.len().separated_string(),
+        size.separated_string(),
+        allocated.separated_string()
+    );
+}
+
+// Files below prefix with media info, in the order they were taken, unknown dates last
+fn get_media_files<'a>(
+    file_db: &FileDb,
+    media: &'a HashMap<Hash256, media::MediaInfo>,
+    prefix: Option<&Path>,
+) -> Vec<(EntryIndex, &'a media::MediaInfo)>
+{
+    let mut media_files = (0..file_db.len() as EntryIndex)
+        .filter_map(|index| {
+            let entry = file_db.get(index);
+            let info = media.get(&entry.hash).filter(|_| !entry.is_dir)?;
+            let in_prefix =
+                prefix.is_none_or(|prefix| get_full_path(file_db, index).starts_with(prefix));
+            if in_prefix {
+                Some((index, info))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    media_files.sort_by(|a, b| match (&a.1.taken, &b.1.taken) {
+        (Some(taken_a), Some(taken_b)) => taken_a.cmp(taken_b),
+        (taken_a, taken_b) => taken_b.is_some().cmp(&taken_a.is_some()),
+    });
+    media_files
+}
+
+// Groups of files with different contents taken at the same time with the same camera, like
+// resized or edited copies of a photo. The largest dimensions first.
+fn get_same_shots(
+    file_db: &FileDb,
+    media_files: &[(EntryIndex, &media::MediaInfo)],
+) -> Vec<Vec<EntryIndex>>
+{
+    let mut shots = HashMap::<(&str, &str), Vec<(EntryIndex, &media::MediaInfo)>>::new();
+    for (index, info) in media_files {
+        if let Some(taken) = &info.taken {
+            let camera = info.camera.as_deref().unwrap_or("");
+            shots.entry((taken, camera)).or_default().push((*index, *info));
+        }
+    }
+    let mut groups = shots
+        .into_iter()
+        .filter(|(_, files)| {
+            let hashes = files.iter().map(|(index, _)| file_db.get(*index).hash);
+            hashes.collect::<HashSet<_>>().len() > 1
+        })
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|(shot, _)| *shot);
+    groups
+        .into_iter()
+        .map(|(_, mut files)| {
+            let pixels = |info: &media::MediaInfo| info.width as u64 * info.height as u64;
+            files.sort_by_key(|(_, info)| std::cmp::Reverse(pixels(info)));
+            files.into_iter().map(|(index, _)| index).collect()
+        })
+        .collect()
+}
+
+// List photos and videos with capture date, camera and dimensions, or only the groups of
+// different files showing the same shot
+pub fn media(file_db_name: &Path, prefix: Option<&Path>, same_shot: bool, snapshot: Option<&str>)
+{
+    let mut db = load_compressed(file_db_name);
+    let file_db = std::mem::take(get_file_db_mut(&mut db, snapshot));
+    let media_files = get_media_files(&file_db, &db.media, prefix);
+    let print_file = |index: EntryIndex, info: &media::MediaInfo| {
+        println!(
+            "{:<19}  {:<24}  {:>11}  {}",
+            info.taken.as_deref().unwrap_or("-"),
+            info.camera.as_deref().unwrap_or("-"),
+            format!("{}x{}", info.width, info.height),
+            format_path_for_output(&get_full_path(&file_db, index))
+        );
+    };
+    if !same_shot {
+        for (index, info) in &media_files {
+            print_file(*index, info);
+        }
+        println!("Media files: {}", media_files.len().separated_string());
+        return;
+    }
+    let groups = get_same_shots(&file_db, &media_files);
+    for group in &groups {
+        println!("Same shot:");
+        for index in group {
+            print_file(*index, &db.media[&file_db.get(*index).hash]);
+        }
+    }
+    println!("Shots with different files: {}", groups.len().separated_string());
+}
+
+fn find_root(parents: &mut [usize], mut i: usize) -> usize
+{
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+// Contents of the files below prefix with a value in values, sorted by hash, with the indices
+// of their files
+fn get_contents_below<T>(
+    file_db: &FileDb,
+    values: &HashMap<Hash256, T>,
+    prefix: Option<&Path>,
+) -> Vec<(Hash256, Vec<EntryIndex>)>
+{
+    let mut contents = HashMap::<Hash256, Vec<EntryIndex>>::new();
+    for (index, entry) in file_db.iter().enumerate() {
+        if entry.is_dir || !values.contains_key(&entry.hash) {
+            continue;
+        }
+        let index = index as EntryIndex;
+        if prefix.is_none_or(|prefix| get_full_path(file_db, index).starts_with(prefix)) {
+            contents.entry(entry.hash).or_default().push(index);
+        }
+    }
+    let mut contents = contents.into_iter().collect::<Vec<_>>();
+    contents.sort_by_key(|(hash, _)| *hash);
+    contents
+}
+
+// The files of the contents joined in parents with more than one content, largest first
+fn get_clusters(
+    file_db: &FileDb,
+    contents: &[(Hash256, Vec<EntryIndex>)],
+    parents: &mut [usize],
+) -> Vec<Vec<EntryIndex>>
+{
+    let mut clusters = HashMap::<usize, Vec<usize>>::new();
+    for i in 0..contents.len() {
+        clusters.entry(find_root(parents, i)).or_default().push(i);
+    }
+    let mut groups = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|cluster| {
+            let mut indices =
+                cluster.iter().flat_map(|i| contents[*i].1.iter().copied()).collect::<Vec<_>>();
+            indices.sort_by_key(|index| (std::cmp::Reverse(file_db.get(*index).size), *index));
+            indices
+        })
+        .collect::<Vec<_>>();
+    groups.sort();
+    groups
+}
+
+// Groups of images below prefix with different contents whose perceptual hashes differ in at
+// most max_distance bits, like resized or re-encoded copies. Largest files first.
+fn get_similar_images(
+    file_db: &FileDb,
+    image_hashes: &HashMap<Hash256, u64>,
+    prefix: Option<&Path>,
+    max_distance: u32,
+) -> Vec<Vec<EntryIndex>>
+{
+    let contents = get_contents_below(file_db, image_hashes, prefix);
+    let phashes = contents.iter().map(|(hash, _)| image_hashes[hash]).collect::<Vec<_>>();
+
+    // Hashes within max_distance agree in at least one of max_distance + 1 chunks, so only
+    // hashes sharing a chunk need to be compared
+    let num_chunks = max_distance.min(63) + 1;
+    let mut parents = (0..contents.len()).collect::<Vec<_>>();
+    for chunk in 0..num_chunks {
+        let start = 64 * chunk / num_chunks;
+        let end = 64 * (chunk + 1) / num_chunks;
+        let mask = (u64::MAX >> (64 - (end - start))) << start;
+        let mut buckets = HashMap::<u64, Vec<usize>>::new();
+        for (i, phash) in phashes.iter().enumerate() {
+            buckets.entry(phash & mask).or_default().push(i);
+        }
+        for bucket in buckets.values() {
+            for (n, i) in bucket.iter().enumerate() {
+                for j in &bucket[n + 1..] {
+                    if (phashes[*i] ^ phashes[*j]).count_ones() <= max_distance {
+                        let root_i = find_root(&mut parents, *i);
+                        parents[root_i] = find_root(&mut parents, *j);
+                    }
                 }
             }
-            let mut outfile = File::create(&outpath).unwrap();
-            io::copy(&mut file, &mut outfile).unwrap();
         }
+    }
+    get_clusters(file_db, &contents, &mut parents)
+}
 
-        // Get and Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
+// List groups of visually similar images with different contents, see get_similar_images
+pub fn similar_images(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    max_distance: u32,
+    snapshot: Option<&str>,
+)
+{
+    let mut db = load_compressed(file_db_name);
+    let file_db = std::mem::take(get_file_db_mut(&mut db, snapshot));
+    if db.image_hashes.is_empty() {
+        println!("No perceptual hashes in db, add or update with --phash first");
+        return;
+    }
+    let groups = get_similar_images(&file_db, &db.image_hashes, prefix, max_distance);
+    for group in &groups {
+        println!("Similar images:");
+        for index in group {
+            let entry = file_db.get(*index);
+            println!(
+                "{:>19} {:016x} {}",
+                entry.size.separated_string(),
+                db.image_hashes[&entry.hash],
+                format_path_for_output(&get_full_path(&file_db, *index))
+            );
+        }
+    }
+    println!("Groups of similar images: {}", groups.len().separated_string());
+}
 
-            if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode)).unwrap();
+// Groups of audio files below prefix with different contents whose fingerprints differ in at
+// most max_error of their bits, like the same recording in other formats. Largest files first.
+fn get_similar_audio(
+    file_db: &FileDb,
+    fingerprints: &HashMap<Hash256, audio::AudioFingerprint>,
+    prefix: Option<&Path>,
+    max_error: f32,
+) -> Vec<Vec<EntryIndex>>
+{
+    let contents = get_contents_below(file_db, fingerprints, prefix);
+    // Only contents sharing some frames are compared. Frames most contents have, like
+    // silence, tell nothing.
+    let mut frame_contents = HashMap::<u32, Vec<usize>>::new();
+    for (i, (hash, _)) in contents.iter().enumerate() {
+        for frame in fingerprints[hash].frames.iter().collect::<HashSet<_>>() {
+            frame_contents.entry(*frame).or_default().push(i);
+        }
+    }
+    let mut shared_frames = HashMap::<(usize, usize), u32>::new();
+    for frame_contents in frame_contents.values().filter(|contents| contents.len() <= 100) {
+        for (n, i) in frame_contents.iter().enumerate() {
+            for j in &frame_contents[n + 1..] {
+                *shared_frames.entry((*i, *j)).or_default() += 1;
+            }
+        }
+    }
+    let mut parents = (0..contents.len()).collect::<Vec<_>>();
+    for ((i, j), count) in shared_frames {
+        if count < 4 {
+            continue;
+        }
+        let frames_i = &fingerprints[&contents[i].0].frames;
+        let frames_j = &fingerprints[&contents[j].0].frames;
+        if audio::get_error_rate(frames_i, frames_j) <= max_error {
+            let root_i = find_root(&mut parents, i);
+            parents[root_i] = find_root(&mut parents, j);
+        }
+    }
+    get_clusters(file_db, &contents, &mut parents)
+}
+
+// List groups of audio files with different contents that are the same recording, see
+// get_similar_audio
+pub fn similar_audio(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    max_error: f32,
+    snapshot: Option<&str>,
+)
+{
+    let mut db = load_compressed(file_db_name);
+    let file_db = std::mem::take(get_file_db_mut(&mut db, snapshot));
+    if db.audio_fingerprints.is_empty() {
+        println!("No audio fingerprints in db, add or update with --audio-fingerprint first");
+        return;
+    }
+    let groups = get_similar_audio(&file_db, &db.audio_fingerprints, prefix, max_error);
+    for group in &groups {
+        println!("Similar audio:");
+        for index in group {
+            let entry = file_db.get(*index);
+            let duration = db.audio_fingerprints[&entry.hash].duration;
+            println!(
+                "{:>19} {:>4}:{:02} {}",
+                entry.size.separated_string(),
+                duration / 60,
+                duration % 60,
+                format_path_for_output(&get_full_path(&file_db, *index))
+            );
+        }
+    }
+    println!("Groups of similar audio files: {}", groups.len().separated_string());
+}
+
+// Pairs of files below prefix with different contents whose similarity digests score at
+// least threshold (0 to 100), like slightly edited documents. The most similar first.
+fn get_near_dupes(
+    file_db: &FileDb,
+    fuzzy_hashes: &HashMap<Hash256, fuzzy::FuzzyHash>,
+    prefix: Option<&Path>,
+    threshold: u32,
+) -> Vec<(u32, EntryIndex, EntryIndex)>
+{
+    let contents = get_contents_below(file_db, fuzzy_hashes, prefix);
+    // Only digests of the same block size sharing a substring can score above 0
+    let mut gram_contents = HashMap::<(u32, Vec<u8>), Vec<usize>>::new();
+    for (i, (hash, _)) in contents.iter().enumerate() {
+        let fuzzy_hash = &fuzzy_hashes[hash];
+        let block_size = fuzzy_hash.block_size;
+        for gram in fuzzy::get_grams(&fuzzy_hash.digest) {
+            gram_contents.entry((block_size, gram)).or_default().push(i);
+        }
+        for gram in fuzzy::get_grams(&fuzzy_hash.double_digest) {
+            gram_contents.entry((2 * block_size, gram)).or_default().push(i);
+        }
+    }
+    let mut pairs = HashSet::new();
+    for gram_contents in gram_contents.values() {
+        for (n, i) in gram_contents.iter().enumerate() {
+            for j in &gram_contents[n + 1..] {
+                pairs.insert((*i, *j));
+            }
+        }
+    }
+    let mut near_dupes = pairs
+        .into_iter()
+        .filter_map(|(i, j)| {
+            let (hash_i, hash_j) = (&contents[i].0, &contents[j].0);
+            let score = fuzzy::get_similarity(&fuzzy_hashes[hash_i], &fuzzy_hashes[hash_j]);
+            if score >= threshold {
+                Some((score, contents[i].1[0], contents[j].1[0]))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    near_dupes.sort_by_key(|(score, a, b)| (std::cmp::Reverse(*score), *a, *b));
+    near_dupes
+}
+
+// List pairs of files with mostly identical contents, see get_near_dupes. Of files with the
+// same contents, only the first is listed.
+pub fn near_dupes(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    threshold: u32,
+    snapshot: Option<&str>,
+)
+{
+    let mut db = load_compressed(file_db_name);
+    let file_db = std::mem::take(get_file_db_mut(&mut db, snapshot));
+    if db.fuzzy_hashes.is_empty() {
+        println!("No similarity digests in db, add or update with --fuzzy-hash first");
+        return;
+    }
+    let near_dupes = get_near_dupes(&file_db, &db.fuzzy_hashes, prefix, threshold);
+    for (score, a, b) in &near_dupes {
+        println!(
+            "{:>3} {} {}",
+            score,
+            format_path_for_output(&get_full_path(&file_db, *a)),
+            format_path_for_output(&get_full_path(&file_db, *b))
+        );
+    }
+    println!("Near duplicate pairs: {}", near_dupes.len().separated_string());
+}
+
+// Pairs of files below prefix with different contents sharing chunks, with the bytes they
+// share, the most first. Also returns the bytes that storing each distinct chunk only once
+// would save, which includes chunks repeated within files.
+fn get_chunk_dupes(
+    file_db: &FileDb,
+    chunks: &HashMap<Hash256, Vec<chunks::Chunk>>,
+    prefix: Option<&Path>,
+) -> (Vec<(u64, EntryIndex, EntryIndex)>, u64)
+{
+    let contents = get_contents_below(file_db, chunks, prefix);
+    let mut chunk_contents = HashMap::<[u8; 16], (u64, Vec<usize>)>::new();
+    let mut total_size = 0;
+    for (i, (hash, _)) in contents.iter().enumerate() {
+        for chunk in &chunks[hash] {
+            total_size += chunk.size as u64;
+            let (size, chunk_contents) = chunk_contents.entry(chunk.hash).or_default();
+            *size = chunk.size as u64;
+            if chunk_contents.last() != Some(&i) {
+                chunk_contents.push(i);
+            }
+        }
+    }
+    let unique_size = chunk_contents.values().map(|(size, _)| size).sum::<u64>();
+    let mut shared = HashMap::<(usize, usize), u64>::new();
+    for (size, chunk_contents) in chunk_contents.values() {
+        for (n, i) in chunk_contents.iter().enumerate() {
+            for j in &chunk_contents[n + 1..] {
+                *shared.entry((*i, *j)).or_default() += size;
+            }
+        }
+    }
+    let mut chunk_dupes = shared
+        .into_iter()
+        .map(|((i, j), size)| (size, contents[i].1[0], contents[j].1[0]))
+        .collect::<Vec<_>>();
+    chunk_dupes.sort_by_key(|(size, a, b)| (std::cmp::Reverse(*size), *a, *b));
+    (chunk_dupes, total_size - unique_size)
+}
+
+// List pairs of files sharing chunks with the bytes they share, see get_chunk_dupes. Of files
+// with the same contents, only the first is listed.
+pub fn chunk_dupes(file_db_name: &Path, prefix: Option<&Path>, snapshot: Option<&str>)
+{
+    let mut db = load_compressed(file_db_name);
+    let file_db = std::mem::take(get_file_db_mut(&mut db, snapshot));
+    if db.chunks.is_empty() {
+        println!("No chunk hashes in db, add or update with --chunk-hashes first");
+        return;
+    }
+    let (chunk_dupes, savable) = get_chunk_dupes(&file_db, &db.chunks, prefix);
+    for (size, a, b) in &chunk_dupes {
+        println!(
+            "{:>19} {} {}",
+            size.separated_string(),
+            format_path_for_output(&get_full_path(&file_db, *a)),
+            format_path_for_output(&get_full_path(&file_db, *b))
+        );
+    }
+    println!(
+        "Pairs sharing chunks: {}, bytes savable by storing each chunk once: {}",
+        chunk_dupes.len().separated_string(),
+        savable.separated_string()
+    );
+}
+
+// List the largest files not accessed for at least min_age seconds, at most limit
+pub fn cold_files(
+    file_db_name: &Path,
+    prefix: Option<&Path>,
+    min_age: u64,
+    limit: usize,
+    snapshot: Option<&str>,
+)
+{
+    let file_db = load_file_db(file_db_name, snapshot);
+    let now = get_secs(&time::SystemTime::now());
+    let indices = get_cold_files(&file_db, prefix, min_age, now);
+    let mut size = 0;
+    for index in &indices {
+        size += file_db.get(*index).size;
+    }
+    for index in indices.iter().take(limit) {
+        let entry = file_db.get(*index);
+        println!(
+            "{:>19} {} {}",
+            entry.size.separated_string(),
+            get_time_string(entry.accessed),
+            format_path_for_output(&get_full_path(&file_db, *index))
+        );
+    }
+    println!("Cold files: {}, size: {}", indices.len().separated_string(), size.separated_string());
+}
+
+// List the entries of a dir in the db with type (d: dir, f: file, a: archive with indexed
+// contents), size and modification time, or the entry itself if it is a file.
+// Returns false if path is not in the db.
+pub fn ls(file_db_name: &Path, path: &Path, snapshot: Option<&str>) -> bool
+{
+    let (file_db, children) = load_file_db_for_path(file_db_name, path, snapshot);
+    let index = match children.find_path(&file_db, path) {
+        Some(index) => index,
+        None => {
+            eprintln!("{:?} is not in the db", path);
+            return false;
+        }
+    };
+    let mut indices = children.get(index).to_vec();
+    if indices.is_empty() && !file_db.get(index).is_dir {
+        indices.push(index);
+    }
+    indices.sort_by(|a, b| file_db.get(*a).name.cmp(file_db.get(*b).name));
+    for index in indices {
+        let entry = file_db.get(index);
+        let (entry_type, suffix) = match (entry.is_dir, children.get(index).is_empty()) {
+            (true, _) => ("d", "/"),
+            (false, true) => ("f", ""),
+            (false, false) => ("a", ""),
+        };
+        println!(
+            "{} {:>19} {} {}{}",
+            entry_type,
+            entry.size.separated_string(),
+            get_time_string(entry.modified),
+            format_path_for_output(Path::new(&entry.name)),
+            suffix
+        );
+    }
+    true
+}
+
+fn print_tree(
+    file_db: &FileDb,
+    children: &ChildrenIndex,
+    index: EntryIndex,
+    level: usize,
+    max_depth: Option<usize>,
+    min_size: u64,
+)
+{
+    if max_depth.is_some_and(|max_depth| level >= max_depth) {
+        return;
+    }
+    let mut indices = children.get(index).to_vec();
+    indices.sort_by_key(|index| std::cmp::Reverse(file_db.get(*index).size));
+    let mut num_small = 0;
+    let mut small_size = 0;
+    for child in indices {
+        let entry = file_db.get(child);
+        if entry.size < min_size {
+            num_small += 1;
+            small_size += entry.size;
+            continue;
+        }
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let name = format_path_for_output(Path::new(&entry.name));
+        let size = entry.size.separated_string();
+        println!("{:>19}  {}{}{}", size, "  ".repeat(level + 1), name, suffix);
+        print_tree(file_db, children, child, level + 1, max_depth, min_size);
+    }
+    if num_small > 0 {
+        let size = small_size.separated_string();
+        println!("{:>19}  {}({} smaller entries)", size, "  ".repeat(level + 1), num_small);
+    }
+}
+
+// Print the tree beneath path with sizes, largest entries first. Entries smaller than
+// min_size are summed up per dir. Returns false if path is not in the db.
+pub fn tree(
+    file_db_name: &Path,
+    path: &Path,
+    max_depth: Option<usize>,
+    min_size: u64,
+    snapshot: Option<&str>,
+) -> bool
+{
+    let (file_db, children) = load_file_db_for_path(file_db_name, path, snapshot);
+    let index = match children.find_path(&file_db, path) {
+        Some(index) => index,
+        None => {
+            eprintln!("{:?} is not in the db", path);
+            return false;
+        }
+    };
+    let size = file_db.get(index).size.separated_string();
+    println!("{:>19}  {}", size, format_path_for_output(path));
+    print_tree(&file_db, &children, index, 0, max_depth, min_size);
+    true
+}
+
+// Size as GNU du prints it: In 1K blocks, or with human readable, in powers of 1024 with one
+// decimal below 10, always rounded up
+fn format_du_size(size: u64, human_readable: bool) -> String
+{
+    if !human_readable {
+        return size.div_ceil(1024).to_string();
+    }
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let units = ["", "K", "M", "G", "T", "P", "E"];
+    if value < 10.0 {
+        let value = (value * 10.0).ceil() / 10.0;
+        if value < 10.0 {
+            return format!("{:.1}{}", value, units[unit]);
+        }
+    }
+    let value = value.ceil();
+    if value >= 1024.0 {
+        return format!("1.0{}", units[unit + 1]);
+    }
+    format!("{}{}", value, units[unit])
+}
+
+// Dirs beneath index, children before their parent as du lists them, at most max_depth
+// levels deep. Always includes index itself, even if it is a file.
+fn get_du_indices(
+    file_db: &FileDb,
+    children: &ChildrenIndex,
+    index: EntryIndex,
+    depth: usize,
+    max_depth: Option<usize>,
+    indices: &mut Vec<EntryIndex>,
+)
+{
+    for child in children.get(index) {
+        if file_db.get(*child).is_dir {
+            get_du_indices(file_db, children, *child, depth + 1, max_depth, indices);
+        }
+    }
+    if depth == 0 || (file_db.get(index).is_dir && max_depth.is_none_or(|max| depth <= max)) {
+        indices.push(index);
+    }
+}
+
+// Print the sizes of the dirs beneath path like GNU du does, the allocated sizes or with
+// apparent_size the logical ones. Returns false if path is not in the db.
+pub fn du(
+    file_db_name: &Path,
+    path: &Path,
+    max_depth: Option<usize>,
+    human_readable: bool,
+    apparent_size: bool,
+    snapshot: Option<&str>,
+) -> bool
+{
+    let (file_db, children) = load_file_db_for_path(file_db_name, path, snapshot);
+    let index = match children.find_path(&file_db, path) {
+        Some(index) => index,
+        None => {
+            eprintln!("{:?} is not in the db", path);
+            return false;
+        }
+    };
+    let mut indices = vec![];
+    get_du_indices(&file_db, &children, index, 0, max_depth, &mut indices);
+    for index in indices {
+        let entry = file_db.get(index);
+        let size = if apparent_size { entry.size } else { entry.allocated };
+        let size = format_du_size(size, human_readable);
+        println!("{}\t{}", size, format_path_for_output(&get_full_path(&file_db, index)));
+    }
+    true
+}
+
+// Navigate the tree of the db in a terminal UI, without the files having to be available
+pub fn browse(file_db_name: &Path, snapshot: Option<&str>)
+{
+    let file_db = load_file_db(file_db_name, snapshot);
+    let mut browser = tui::Browser::new(&file_db);
+    if let Err(err) = tui::browse(&mut browser) {
+        eprintln!("Error in terminal UI: {}", err);
+    }
+}
+
+// Answer queries on the db over HTTP with JSON, until killed
+pub fn serve(file_db_name: &Path, listen: &str, snapshot: Option<&str>)
+{
+    let file_db = load_file_db(file_db_name, snapshot);
+    if let Err(err) = server::serve(&file_db, listen) {
+        eprintln!("Error serving on {}: {}", listen, err);
+    }
+}
+
+pub fn mv(file_db_name: &Path, from_dir: &Path, to_dir: &Path, dry_run: bool)
+{
+    let (from_dir_buf, to_dir_buf) = (normalize_path(from_dir), normalize_path(to_dir));
+    let (from_dir, to_dir) = (from_dir_buf.as_path(), to_dir_buf.as_path());
+    let mut db = load_compressed(file_db_name);
+    let file_db = &mut db.file_db;
+    let from_metadata = fs::metadata(from_dir);
+    let to_metadata = fs::metadata(to_dir);
+    if from_metadata.is_err() {
+        panic!("source directory does not exist: {:?}", from_dir);
+    }
+    if to_metadata.is_err() {
+        panic!("target directory does not exist: {:?}", to_dir);
+    }
+    if from_metadata.unwrap().is_dir() && to_metadata.unwrap().is_dir() {
+        let mut target_path = PathBuf::from(to_dir);
+        target_path.push(from_dir.file_name().unwrap());
+        let target_metadata = fs::metadata(&target_path);
+        if !target_metadata.is_err() {
+            panic!("Target dir {:?} exists", target_path);
+        }
+        let mut to_index = std::usize::MAX;
+        let mut from_index = std::usize::MAX;
+        for entry_index in 0..file_db.len() {
+            if !file_db.get(entry_index as EntryIndex).is_dir {
+                continue;
+            }
+            let full_path = get_full_path(file_db, entry_index as EntryIndex);
+            if full_path == to_dir {
+                to_index = entry_index;
+            }
+            if full_path == from_dir {
+                from_index = entry_index;
+            }
+        }
+        assert!(to_index != std::usize::MAX && from_index != std::usize::MAX);
+        if dry_run {
+            println!(
+                "Would move {:?} to {:?}, bytes: {}",
+                from_dir,
+                target_path,
+                file_db.get(from_index as EntryIndex).size.separated_string()
+            );
+            println!("Would update db entry {:?} to {:?}", from_dir, target_path);
+            return;
+        }
+        *file_db.entry_mut(from_index as EntryIndex).parent = to_index as EntryIndex;
+        println!("Moving data");
+        fs_extra::move_items(&[from_dir], to_dir, &CopyOptions::new()).unwrap();
+        propagate_sizes(file_db);
+        save_compressed(file_db_name, &db);
+    }
+}
+
+// For moves already done outside of filedb: Updates the db as mv would, if from is now at
+// to/<name of from> on disk, without moving any data or hashing again
+pub fn mv_db_only(file_db_name: &Path, from: &Path, to_dir: &Path, dry_run: bool) -> bool
+{
+    let mut db = load_compressed(file_db_name);
+    let target_path = to_dir.join(from.file_name().unwrap());
+    if fs::symlink_metadata(&target_path).is_err() {
+        eprintln!("{:?} does not exist, move it first", target_path);
+        return false;
+    }
+    if dry_run {
+        println!("Would update db entry {:?} to {:?}", from, target_path);
+        return true;
+    }
+    if !roots::relocate_subtree(&mut db.file_db, from, &target_path, false) {
+        eprintln!("Cannot update {:?} to {:?}, not in db or target in db", from, target_path);
+        return false;
+    }
+    println!("Updated db entry {:?} to {:?}", from, target_path);
+    save_compressed(file_db_name, &db);
+    true
+}
+
+pub fn rm_recursive(file_db_name: &Path, rm_path: &Path, options: &RemoveOptions)
+{
+    if let Some(pattern) = options.protected.get_protecting(rm_path) {
+        eprintln!("{:?} is protected by {}, not removing it", rm_path, pattern);
+        return;
+    }
+    if options.dry_run {
+        let file_db = load_file_db(file_db_name, None);
+        let mut num_bytes = 0;
+        for (index, entry) in file_db.iter().enumerate() {
+            let path = get_full_path(&file_db, index as EntryIndex);
+            if path.starts_with(rm_path) {
+                println!("Would remove {:?} from disk and db", path);
+                if !entry.is_dir {
+                    num_bytes += entry.size;
+                }
             }
         }
+        println!("Would remove bytes: {}", num_bytes.separated_string());
+        return;
+    }
+    println!("Removing path {:?}, are you sure?", rm_path);
+    let mut words = String::new();
+    io::stdin()
+        .read_line(&mut words)
+        .ok()
+        .expect("Failed to read line.");
+    if words.trim() != "y" {
+        return;
+    }
+    rm_recursive_confirmed(file_db_name, rm_path, options);
+}
+
+fn rm_recursive_confirmed(file_db_name: &Path, rm_path: &Path, options: &RemoveOptions)
+{
+    let mut db = load_compressed(file_db_name);
+    remove_path(rm_path, &options.target).unwrap();
+    let file_db = &mut db.file_db;
+    prune_deleted_paths(file_db);
+    propagate_sizes(file_db);
+    propagate_hashes(file_db);
+    save_compressed(file_db_name, &db);
+}
+
+// Removes prefix and everything beneath it from the db only, e.g. for a dir excluded after
+// adding it or a drive that is gone. Named roots beneath it are removed, too.
+pub fn forget(file_db_name: &Path, prefix: &Path, dry_run: bool) -> bool
+{
+    let mut db = load_compressed(file_db_name);
+    let children = ChildrenIndex::new(&db.file_db);
+    let index = match children.find_path(&db.file_db, prefix) {
+        Some(index) if !is_root_index(index) => index,
+        _ => {
+            eprintln!("{:?} not in db or the root", prefix);
+            return false;
+        }
+    };
+    let subtree = children.get_subtree(index);
+    let num_bytes = db.file_db.get(index).size;
+    if dry_run {
+        println!(
+            "Would forget {} entries, bytes: {}",
+            subtree.len().separated_string(),
+            num_bytes.separated_string()
+        );
+        return true;
     }
+    let file_db = &mut db.file_db;
+    let num_removed = remove_subtrees(file_db, &HashSet::from([index]));
+    propagate_sizes(file_db);
+    propagate_hashes(file_db);
+    db.roots.retain(|root| !root.path.starts_with(prefix));
+    println!(
+        "Forgot {} entries, bytes: {}",
+        num_removed.separated_string(),
+        num_bytes.separated_string()
+    );
+    save_compressed(file_db_name, &db);
+    true
 }
 
-fn decompress_to_tmp_dir(path: &Path) -> Option<TempDir>
+// Hands each entry of the current tree with its path to visit as the db is decoded, without
+// loading it. Parents come before their children, so only the paths of the dirs are kept to
+// build those of the entries beneath them. Returns false for dbs of older formats.
+fn stream_entries(file_db_name: &Path, mut visit: impl FnMut(&Path, &FileDbEntry)) -> bool
 {
-    println!("Depacking {:?}", path);
+    use bincode::Options;
 
-    let ext_str = get_ext(path).unwrap();
-    let file = File::open(path).unwrap();
-    let tmp_dir = TempDir::new("filedb-decomp").unwrap();
-    match ext_str {
-        "gz" => {
-            let mut gz_decoder = GzDecoder::new(file);
-            let mut path_buf = PathBuf::from(tmp_dir.path());
-            path_buf.push(path.file_stem().unwrap());
-            let mut dest_file = File::create(path_buf).unwrap();
-            if io::copy(&mut gz_decoder, &mut dest_file).is_err() {
-                return None;
-            }
-        }
-        "tar" => {
-            let mut archive = Archive::new(file);
-            if archive.unpack(tmp_dir.path()).is_err() {
-                return None;
-            }
-        }
-        "tgz" => {
-            let gz_decoder = GzDecoder::new(file);
-            let mut archive = Archive::new(gz_decoder);
-            if archive.unpack(tmp_dir.path()).is_err() {
-                return None;
-            }
-        }
-        "xz" => {
-            let mut xz_decoder = XzDecoder::new(file);
-            let mut path_buf = PathBuf::from(tmp_dir.path());
-            path_buf.push(path.file_stem().unwrap());
-            let mut dest_file = File::create(path_buf).unwrap();
-            if io::copy(&mut xz_decoder, &mut dest_file).is_err() {
-                return None;
-            }
-        }
-        "zip" => decompress_zip(path, &tmp_dir),
-        _ => panic!("unreachable"),
+    if roots::has_root_overrides() || changes::has_changes(file_db_name) {
+        return false;
     }
-    Some(tmp_dir)
+    signing::check_signature(file_db_name);
+    let mut reader = io::BufReader::new(File::open(file_db_name).unwrap());
+    match read_db_header(file_db_name, &mut reader) {
+        Some((DB_FORMAT_VERSION, _)) => {}
+        _ => return false,
+    }
+    let mut dir_paths = HashMap::<EntryIndex, PathBuf>::new();
+    let mut index = 0;
+    let stream = FileDbStream(|entry: FileDbEntry| {
+        let path = if is_root_index(index) {
+            PathBuf::from(&entry.name)
+        } else {
+            dir_paths[&entry.parent].join(&entry.name)
+        };
+        visit(&path, &entry);
+        if entry.is_dir {
+            dir_paths.insert(index, path);
+        }
+        index += 1;
+    });
+    // The Db starts with the FileDb of the current tree, the rest is not read. The options
+    // are those of bincode::deserialize_from.
+    let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+    options.deserialize_from_seed(stream, ZlibDecoder::new(reader)).unwrap();
+    true
 }
 
-fn add_files_from_archive(
-    path: &Path,
-    file_db: &mut FileDb,
-    path_to_index: &mut PathToIndexMap,
-    dir_to_file_indexes: &DirToFilesMap,
-    replace_prefix_to: &Path,
+// Streamed if possible, see stream_entries
+fn for_each_entry(
+    file_db_name: &Path,
+    snapshot: Option<&str>,
+    mut visit: impl FnMut(&Path, &FileDbEntry),
 )
 {
-    let tmp_dir = decompress_to_tmp_dir(path);
-    if tmp_dir.is_some() {
-        add_dir_recursive(
-            tmp_dir.unwrap().path(),
-            file_db,
-            path_to_index,
-            dir_to_file_indexes,
-            replace_prefix_to,
-        );
-    } else {
-        eprintln!("Error unpacking archive {:?}", path);
+    if snapshot.is_none() && stream_entries(file_db_name, &mut visit) {
+        return;
+    }
+    let file_db = load_file_db(file_db_name, snapshot);
+    for index in 0..file_db.len() as EntryIndex {
+        visit(&get_full_path(&file_db, index), &file_db.get(index).to_entry());
     }
 }
 
-fn replace_prefix(path: &Path, replace_from: &Path, replace_to: &Path) -> PathBuf
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DumpOrder
 {
-    if replace_to.as_os_str().len() == 0 {
-        return path.to_path_buf();
+    // Largest first
+    Size,
+    // By path
+    Name,
+    // Most recently modified first
+    Modified,
+}
+
+// Which entries dump and dump_full print, and in which order
+#[derive(Default, Debug)]
+pub struct DumpOptions
+{
+    pub prefix: Option<PathBuf>,
+    // Only dirs (true) or only files (false)
+    pub is_dir: Option<bool>,
+    // Levels beneath prefix, or the root
+    pub max_depth: Option<usize>,
+    pub min_size: u64,
+    // Sorting keeps the matching entries in memory, otherwise they are printed as decoded
+    pub sort: Option<DumpOrder>,
+    pub path_format: PathFormat,
+}
+
+fn matches_dump_options(path: &Path, entry: &FileDbEntry, options: &DumpOptions) -> bool
+{
+    let is_normal = |component: &std::path::Component| {
+        matches!(component, std::path::Component::Normal(_))
+    };
+    let depth = match &options.prefix {
+        Some(prefix) => match path.strip_prefix(prefix) {
+            Ok(relative_path) => relative_path.components().count(),
+            Err(_) => return false,
+        },
+        None => path.components().filter(is_normal).count(),
+    };
+    options.is_dir.is_none_or(|is_dir| entry.is_dir == is_dir)
+        && options.max_depth.is_none_or(|max_depth| depth <= max_depth)
+        && entry.size >= options.min_size
+}
+
+// With PathFormat::NullTerminated, the whole line of dump_full is terminated by NUL
+fn write_dump_entry(
+    out: &mut impl Write,
+    path: &Path,
+    entry: &FileDbEntry,
+    path_format: PathFormat,
+    full: bool,
+) -> io::Result<()>
+{
+    if !full {
+        return write_path(out, path, path_format);
     }
-    if replace_to.as_os_str().len() > 0 {
-        let path_stripped = path.strip_prefix(replace_from).unwrap();
-        if path_stripped.to_str().unwrap().len() == 0 {
-            replace_to.to_path_buf()
+    match path_format {
+        PathFormat::Lines => write!(out, "{}", format_path_for_output(path))?,
+        PathFormat::NullTerminated => out.write_all(&get_path_bytes(path))?,
+        PathFormat::Escaped => write!(out, "{}", shell_escape(&get_path_bytes(path)))?,
+    }
+    write!(
+        out,
+        " {} {:?} {:o} {}:{} {}",
+        entry.size, entry.hash, entry.mode, entry.uid, entry.gid, entry.mime
+    )?;
+    match path_format {
+        PathFormat::NullTerminated => out.write_all(b"\0"),
+        _ => writeln!(out),
+    }
+}
+
+fn for_each_dump_entry(
+    file_db_name: &Path,
+    snapshot: Option<&str>,
+    options: &DumpOptions,
+    mut output: impl FnMut(&Path, &FileDbEntry),
+)
+{
+    let mut sorted = vec![];
+    let mut visit = |path: &Path, entry: &FileDbEntry| {
+        if !matches_dump_options(path, entry, options) {
+            return;
+        }
+        if options.sort.is_some() {
+            sorted.push((path.to_path_buf(), entry.clone()));
         } else {
-            replace_to.to_path_buf().join(path_stripped)
+            output(path, entry);
+        }
+    };
+    // Only the partition below prefix if there is one
+    let partition = match &options.prefix {
+        Some(prefix) if snapshot.is_none() => load_partition(file_db_name, prefix),
+        _ => None,
+    };
+    if let Some(file_db) = partition {
+        for index in 0..file_db.len() as EntryIndex {
+            visit(&get_full_path(&file_db, index), &file_db.get(index).to_entry());
         }
     } else {
-        path.to_path_buf()
+        for_each_entry(file_db_name, snapshot, visit);
+    }
+
+    match options.sort {
+        Some(DumpOrder::Size) => sorted.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.size)),
+        Some(DumpOrder::Name) => sorted.sort_by(|(a, _), (b, _)| a.cmp(b)),
+        Some(DumpOrder::Modified) => {
+            sorted.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.modified))
+        }
+        None => {}
+    }
+    for (path, entry) in &sorted {
+        output(path, entry);
     }
 }
 
-fn get_hash_for_file(path: &Path) -> io::Result<Hash256>
+fn dump_helper(file_db_name: &Path, snapshot: Option<&str>, options: &DumpOptions, full: bool)
 {
-    let mut file = File::open(path)?;
-    let mut hasher = blake3::Hasher::new();
-    io::copy(&mut file, &mut hasher)?;
-    Ok(hasher.finalize().into())
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for_each_dump_entry(file_db_name, snapshot, options, |path, entry| {
+        write_dump_entry(&mut out, path, entry, options.path_format, full).unwrap()
+    });
 }
 
-fn add_file_db_entry(file_db: &mut FileDb, file_db_entry: FileDbEntry) -> u32
+pub fn dump(file_db_name: &Path, snapshot: Option<&str>, options: &DumpOptions)
 {
-    assert!(
-        file_db.len() < (std::u32::MAX - 1) as usize,
-        "Maximum size exceeded, only {} files/dirs supported",
-        std::u32::MAX
-    );
-    file_db.push(file_db_entry);
-    (file_db.len() - 1) as u32
+    dump_helper(file_db_name, snapshot, options, false);
 }
 
-fn add_root_path_components(
-    root_dir: &Path,
-    file_db: &mut FileDb,
-    path_to_index: &mut PathToIndexMap,
-)
+pub fn dump_full(file_db_name: &Path, snapshot: Option<&str>, options: &DumpOptions)
 {
-    assert!(
-        !DECOMPRESS_ARCHIVES,
-        "Not implemented in this code block, please fix if needed"
-    );
+    dump_helper(file_db_name, snapshot, options, true);
+}
 
-    assert!(
-        !path_to_index.contains_key(root_dir.as_os_str()),
-        "Existing path added, not supported"
-    );
+#[derive(Serialize, Default, Debug)]
+struct SizeChange
+{
+    path: String,
+    size_a: u64,
+    size_b: u64,
+}
 
-    // Add all components of the root dir, including "/", as separate entries, since WalkDir won't report them
-    let mut root_dir_prefix = root_dir;
-    let mut components = Vec::<&OsStr>::new();
-    let mut found;
-    loop {
-        found = path_to_index.contains_key(root_dir_prefix.as_os_str());
-        if found || root_dir_prefix.parent().is_none() {
-            break;
-        } else {
-            components.push(root_dir_prefix.file_name().unwrap());
-            root_dir_prefix = root_dir_prefix.parent().unwrap();
-        }
-    }
-    let mut parent_index = std::u32::MAX;
-    if found {
-        parent_index = *path_to_index.get(root_dir_prefix.as_os_str()).unwrap();
-    } else {
-        components.push(root_dir_prefix.as_os_str());
-    }
-    let mut root_dir_tmp = PathBuf::new();
-    root_dir_tmp.push(root_dir_prefix);
-    for dir_name in components.into_iter().rev() {
-        root_dir_tmp.push(dir_name);
-        let metadata = fs::metadata(&root_dir_tmp).unwrap();
-        let file_db_entry = FileDbEntry {
-            name: dir_name.to_owned(),
-            is_dir: true,
-            parent: parent_index,
-            size: 0,
-            modified: get_secs(&metadata.modified().unwrap()),
-            accessed: get_secs(&metadata.accessed().unwrap()),
-            hash: EMPTY_HASH,
-        };
-        parent_index = file_db.len() as u32;
-        add_file_db_entry(file_db, file_db_entry);
-        path_to_index.insert(root_dir_tmp.as_os_str().to_owned(), parent_index);
-    }
+#[derive(Serialize, Default, Debug)]
+struct DiffResult
+{
+    added: Vec<String>,
+    removed: Vec<String>,
+    size_changed: Vec<SizeChange>,
+    content_changed: Vec<String>,
 }
 
-fn build_dir_to_files_map(file_db: &FileDb, path_to_index: &PathToIndexMap) -> DirToFilesMap
+fn build_path_to_entry_map(file_db: &FileDb) -> HashMap<PathBuf, Entry<'_>>
 {
-    let mut dir_to_files_map = DirToFilesMap::new();
-    for (path, path_index) in path_to_index {
-        assert!(PathBuf::from(path) == get_full_path(file_db, *path_index));
-        dir_to_files_map.insert(*path_index, Vec::<u32>::new());
-    }
+    let mut path_to_entry = HashMap::new();
     for (index, entry) in file_db.iter().enumerate() {
-        if !entry.is_dir {
-            let path_entry = dir_to_files_map.get_mut(&entry.parent).unwrap();
-            path_entry.push(index as u32);
-        }
+        path_to_entry.insert(get_full_path(file_db, index as EntryIndex), entry);
     }
-    dir_to_files_map
+    path_to_entry
 }
 
-// Works for files and dirs, returns 0 for dirs
-fn get_hash_for_path(path: &Path, is_dir: bool) -> Hash256
+// Directories are only reported as added/removed, their sizes and hashes are derived
+fn diff_file_dbs(file_db_a: &FileDb, file_db_b: &FileDb) -> DiffResult
 {
-    let mut hash = EMPTY_HASH;
-    if !is_dir {
-        let hash_result = get_hash_for_file(path);
-        if hash_result.is_err() {
-            eprintln!("Error accessing {:?}", path);
-        } else {
-            hash = hash_result.unwrap();
+    let path_to_entry_a = build_path_to_entry_map(file_db_a);
+    let path_to_entry_b = build_path_to_entry_map(file_db_b);
+    let mut result = DiffResult::default();
+    let mut paths_a = path_to_entry_a.keys().collect::<Vec<_>>();
+    paths_a.sort();
+    for path in paths_a {
+        let entry_a = path_to_entry_a[path];
+        let path_string = path.to_string_lossy().into_owned();
+        match path_to_entry_b.get(path) {
+            None => result.removed.push(path_string),
+            Some(entry_b) => {
+                if entry_a.is_dir != entry_b.is_dir {
+                    result.removed.push(path_string.clone());
+                    result.added.push(path_string);
+                } else if entry_a.is_dir {
+                    continue;
+                } else if entry_a.size != entry_b.size {
+                    result.size_changed.push(SizeChange {
+                        path: path_string,
+                        size_a: entry_a.size,
+                        size_b: entry_b.size,
+                    });
+                } else if entry_a.hash != entry_b.hash {
+                    result.content_changed.push(path_string);
+                }
+            }
         }
     }
-    hash
+    let mut paths_b = path_to_entry_b
+        .keys()
+        .filter(|path| !path_to_entry_a.contains_key(*path))
+        .collect::<Vec<_>>();
+    paths_b.sort();
+    for path in paths_b {
+        result.added.push(path.to_string_lossy().into_owned());
+    }
+    result.added.sort();
+    result
 }
 
-fn add_dir_recursive(
-    root_dir_: &Path,
-    file_db: &mut FileDb,
-    path_to_index: &mut PathToIndexMap,
-    dir_to_file_indexes: &DirToFilesMap,
-    replace_prefix_to: &Path,
+// Compare two dbs, or two snapshots of the same db
+pub fn diff(
+    file_db_name_a: &Path,
+    snapshot_a: Option<&str>,
+    file_db_name_b: &Path,
+    snapshot_b: Option<&str>,
+    summary: bool,
+    json: bool,
 )
 {
-    println!("Adding dir {:?}", root_dir_);
-    assert!(root_dir_.is_absolute());
-
-    let is_update = dir_to_file_indexes.len() > 0;
+    check_same_hash_algorithm(file_db_name_a, file_db_name_b);
+    let file_db_a = load_file_db(file_db_name_a, snapshot_a);
+    let file_db_b = load_file_db(file_db_name_b, snapshot_b);
+    let result = diff_file_dbs(&file_db_a, &file_db_b);
 
-    // Remove trailing slashes
-    let mut root_dir_str = String::from(root_dir_.as_os_str().to_str().unwrap());
-    while root_dir_str.ends_with('/') {
-        root_dir_str.pop();
+    if json {
+        if summary {
+            let counts = serde_json::json!({
+                "added": result.added.len(),
+                "removed": result.removed.len(),
+                "size_changed": result.size_changed.len(),
+                "content_changed": result.content_changed.len(),
+            });
+            println!("{}", counts);
+        } else {
+            println!("{}", serde_json::to_string(&result).unwrap());
+        }
+        return;
     }
-    let root_dir_buf = PathBuf::from(root_dir_str);
-    let root_dir = root_dir_buf.as_path();
+    print_diff_result(&result, summary);
+}
 
-    if !is_update {
-        add_root_path_components(root_dir, file_db, path_to_index);
+fn print_diff_result(result: &DiffResult, summary: bool)
+{
+    if !summary {
+        for path in &result.added {
+            println!("Added: {:?}", path);
+        }
+        for path in &result.removed {
+            println!("Removed: {:?}", path);
+        }
+        for change in &result.size_changed {
+            println!(
+                "Size changed: {:?} {} -> {}",
+                change.path,
+                change.size_a.separated_string(),
+                change.size_b.separated_string()
+            );
+        }
+        for path in &result.content_changed {
+            println!("Content changed: {:?}", path);
+        }
     }
+    println!(
+        "Added: {}, removed: {}, size changed: {}, content changed: {}",
+        result.added.len(),
+        result.removed.len(),
+        result.size_changed.len(),
+        result.content_changed.len()
+    );
+}
 
-    'walker: for result_dir_entry in WalkDir::new(root_dir)
-        .follow_links(false)
-        .contents_first(false)
-    {
-        let dir_entry = result_dir_entry.unwrap();
-        let mut path: PathBuf = dir_entry.path().to_path_buf();
-
-        // When dealing with an archive that was unpacked to a temporary directory:
-        // Replace the path prefix of the temporary directory with the full path to
-        // the archive. Proceed with handling as usual. The archive is thus handled
-        // like a directory, with the contents added beneath it.
-        path = replace_prefix(&path, root_dir, replace_prefix_to);
+#[derive(Default, Debug)]
+struct MergeStats
+{
+    added: usize,
+    collisions: usize,
+    skipped: usize,
+}
 
-        let path_os_str = path.as_os_str();
+// Merge file_db into out_file_db by full path. Directories present in both (e.g. shared
+// root components) are unified. For files present in both the newer one wins. Entries
+// whose type conflicts with an existing entry are skipped along with their children.
+fn merge_file_db(
+    out_file_db: &mut FileDb,
+    out_path_to_index: &mut PathToIndexMap,
+    file_db: &FileDb,
+) -> MergeStats
+{
+    let mut stats = MergeStats::default();
+    let mut paths = (0..file_db.len())
+        .map(|index| (get_full_path(file_db, index as EntryIndex), index))
+        .collect::<Vec<_>>();
+    // Parents before children
+    paths.sort_by_key(|(path, _)| path.components().count());
 
-        if path_to_index.contains_key(path_os_str) {
+    for (path, index) in paths {
+        let entry = file_db.get(index as EntryIndex);
+        if is_root_index(index as EntryIndex) {
+            if out_file_db.is_empty() {
+                add_file_db_entry(out_file_db, entry.to_entry());
+                out_path_to_index.insert(path.into_os_string(), 0);
+            } else {
+                assert!(
+                    out_file_db.get(0).name == entry.name,
+                    "Cannot merge dbs with different roots {:?} and {:?}",
+                    out_file_db.get(0).name,
+                    entry.name
+                );
+            }
             continue;
         }
-
-        let metadata = dir_entry.metadata().unwrap();
-        let is_dir = metadata.is_dir();
-
-        if DECOMPRESS_ARCHIVES && is_archive(&path) && !is_dir {
-            add_files_from_archive(
-                dir_entry.path(),
-                file_db,
-                path_to_index,
-                dir_to_file_indexes,
-                &path,
-            );
-        } else {
-            let parent_path = path.parent().unwrap();
-            let parent_index = *path_to_index.get(parent_path.as_os_str()).unwrap();
-
-            let file_name = path.file_name().unwrap().to_os_string();
-
-            if is_update {
-                if is_dir {
-                    let entry = path_to_index.get(path_os_str);
-                    if entry.is_some() {
-                        continue 'walker;
-                    }
-                } else {
-                    let dir_entry = path_to_index.get(parent_path.as_os_str());
-                    if dir_entry.is_some() {
-                        let file_entries_opt = dir_to_file_indexes.get(dir_entry.unwrap());
-                        if file_entries_opt.is_some() {
-                            for file_index in file_entries_opt.unwrap() {
-                                let entry = &file_db[*file_index as usize];
-                                if entry.name == file_name {
-                                    continue 'walker;
-                                }
-                            }
-                        }
-                    }
+        if let Some(existing_index) = out_path_to_index.get(path.as_os_str()) {
+            let existing_entry = out_file_db.get(*existing_index);
+            if existing_entry.is_dir != entry.is_dir {
+                println!("Type conflict, skipping {:?}", path);
+                stats.skipped += 1;
+            } else if !entry.is_dir {
+                stats.collisions += 1;
+                if entry.modified > existing_entry.modified {
+                    // Same path, so only the parent and name stay
+                    let mut existing_entry = out_file_db.entry_mut(*existing_index);
+                    *existing_entry.size = entry.size;
+                    *existing_entry.allocated = entry.allocated;
+                    *existing_entry.hash = entry.hash;
+                    *existing_entry = EntryMeta::clone(&entry);
                 }
             }
-
-            let modified_secs = get_secs(&metadata.modified().unwrap());
-            let accessed_secs = get_secs(&metadata.accessed().unwrap());
-            let hash = get_hash_for_path(dir_entry.path(), is_dir);
-            println!("Adding {:?}", &path);
-
-            let file_db_entry = FileDbEntry {
-                name: file_name,
-                is_dir: is_dir,
-                parent: parent_index,
-                size: if is_dir { 0 } else { metadata.len() },
-                modified: modified_secs, // Note: For archives,
-                accessed: accessed_secs, // this is the depack time
-                hash: hash,
-            };
-            add_file_db_entry(file_db, file_db_entry);
-            if (file_db.len() % 1000) == 0 {
-                println!("{}", file_db.len());
+            continue;
+        }
+        let parent_index = match out_path_to_index.get(path.parent().unwrap().as_os_str()) {
+            Some(parent_index) if out_file_db.get(*parent_index).is_dir => *parent_index,
+            _ => {
+                stats.skipped += 1;
+                continue;
             }
+        };
+        let mut new_entry = entry.to_entry();
+        new_entry.parent = parent_index;
+        let new_index = add_file_db_entry(out_file_db, new_entry);
+        out_path_to_index.insert(path.into_os_string(), new_index);
+        stats.added += 1;
+    }
+    stats
+}
 
-            if is_dir {
-                let path_owned = path.as_os_str().to_owned();
-                assert!(!path_to_index.contains_key(&path_owned));
-                path_to_index.insert(path_owned, (file_db.len() - 1) as u32);
-            }
-        }
+pub fn merge(out_file_db_name: &Path, in_file_db_names: &[&Path])
+{
+    if out_file_db_name.exists() {
+        panic!("Output db {:?} exists", out_file_db_name);
+    }
+    for in_file_db_name in in_file_db_names.iter().skip(1) {
+        check_same_hash_algorithm(in_file_db_names[0], in_file_db_name);
+    }
+    let mut out_file_db = FileDb::new();
+    // Contains files, too
+    let mut out_path_to_index = PathToIndexMap::new();
+    for in_file_db_name in in_file_db_names {
+        let file_db = load_file_db(in_file_db_name, None);
+        let stats = merge_file_db(&mut out_file_db, &mut out_path_to_index, &file_db);
+        println!(
+            "Merged {:?}: added: {}, collisions resolved: {}, skipped: {}",
+            in_file_db_name,
+            stats.added.separated_string(),
+            stats.collisions.separated_string(),
+            stats.skipped.separated_string()
+        );
+    }
+    propagate_sizes(&mut out_file_db);
+    propagate_hashes(&mut out_file_db);
+    let mut out_db = new_db(out_file_db);
+    if let Some(in_file_db_name) = in_file_db_names.first() {
+        out_db.hash_algorithm = read_hash_algorithm(in_file_db_name);
     }
+    save_compressed(out_file_db_name, &out_db);
 }
 
-fn crawl_initial(root_dir: &Path) -> FileDb
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SetOperation
 {
-    let mut file_db: FileDb = Vec::new();
-    let mut path_to_index: PathToIndexMap = HashMap::new();
-    let dir_to_file_indexes = DirToFilesMap::new();
+    Intersect,
+    Subtract,
+    UnionByHash,
+}
 
-    add_dir_recursive(
-        root_dir,
-        &mut file_db,
-        &mut path_to_index,
-        &dir_to_file_indexes,
-        Path::new(""),
-    );
+fn get_hash_and_size_set(file_db: &FileDb) -> HashSet<(Hash256, u64)>
+{
     file_db
+        .iter()
+        .filter(|entry| !entry.is_dir && is_hashed(entry))
+        .map(|entry| (entry.hash, entry.size))
+        .collect()
 }
 
-fn build_path_to_index_map(file_db: &FileDb) -> PathToIndexMap
+// Returns the selected file indices of a and b
+fn select_set_operation(
+    file_db_a: &FileDb,
+    file_db_b: &FileDb,
+    operation: SetOperation,
+) -> (Vec<EntryIndex>, Vec<EntryIndex>)
 {
-    let mut path_to_index = PathToIndexMap::new();
+    let set_a = get_hash_and_size_set(file_db_a);
+    let set_b = get_hash_and_size_set(file_db_b);
+    let select = |file_db: &FileDb, predicate: &dyn Fn(&Entry) -> bool| {
+        (0..file_db.len() as EntryIndex)
+            .filter(|index| {
+                let entry = file_db.get(*index);
+                !entry.is_dir && predicate(&entry)
+            })
+            .collect::<Vec<_>>()
+    };
+    let in_b = |entry: &Entry| set_b.contains(&(entry.hash, entry.size));
+    match operation {
+        SetOperation::Intersect => (select(file_db_a, &in_b), vec![]),
+        SetOperation::Subtract => (select(file_db_a, &|entry| !in_b(entry)), vec![]),
+        SetOperation::UnionByHash => (
+            select(file_db_a, &|_| true),
+            select(file_db_b, &|entry| !set_a.contains(&(entry.hash, entry.size))),
+        ),
+    }
+}
 
-    for (i, entry) in file_db.iter().enumerate() {
-        if entry.is_dir {
-            let path = get_full_path(&file_db, i as u32);
-            path_to_index.insert(path.as_os_str().to_owned(), i as u32);
+// Builds a new FileDb with the given entries and all their parent dirs
+fn extract_entries(file_db: &FileDb, indices: &[EntryIndex]) -> FileDb
+{
+    let mut new_file_db = FileDb::new();
+    let mut old_to_new_index = HashMap::<EntryIndex, EntryIndex>::new();
+    for index in indices {
+        let mut chain = vec![*index];
+        let mut current = *index;
+        while !is_root_index(current) && !old_to_new_index.contains_key(&current) {
+            current = file_db.get(current).parent;
+            chain.push(current);
+        }
+        for old_index in chain.into_iter().rev() {
+            if old_to_new_index.contains_key(&old_index) {
+                continue;
+            }
+            let mut entry = file_db.get(old_index).to_entry();
+            if !is_root_index(old_index) {
+                entry.parent = old_to_new_index[&entry.parent];
+            }
+            let new_index = add_file_db_entry(&mut new_file_db, entry);
+            old_to_new_index.insert(old_index, new_index);
         }
     }
-    path_to_index
+    new_file_db
 }
 
-fn crawl_add(file_db: &mut FileDb, root_dir: &Path)
+fn format_path_for_output(path: &Path) -> String
 {
-    let mut path_to_index = build_path_to_index_map(file_db);
-
-    let dir_to_file_indexes = DirToFilesMap::new();
-
-    add_dir_recursive(
-        Path::new(root_dir),
-        file_db,
-        &mut path_to_index,
-        &dir_to_file_indexes,
-        Path::new(""),
-    );
+    let out_string = format!("{:?}", path);
+    out_string
+        .strip_prefix('"')
+        .unwrap()
+        .strip_suffix('"')
+        .unwrap()
+        .to_string()
 }
 
-pub fn add(file_db_name: &Path, root_dir: &Path)
+// Files in the db with the given hash, and size if known
+fn find_copies(file_db: &FileDb, hash: &Hash256, size: Option<u64>) -> Vec<EntryIndex>
 {
-    let mut file_db;
-    if fs::metadata(file_db_name).map_or(false, |metadata| metadata.is_file()) {
-        file_db = load_compressed(file_db_name);
-        crawl_add(&mut file_db, root_dir);
-    } else {
-        file_db = crawl_initial(root_dir);
-    }
-
-    propagate_sizes(&mut file_db);
-
-    save_compressed(file_db_name, &file_db);
+    let is_copy = |entry: &Entry| {
+        !entry.is_dir && entry.hash == *hash && size.is_none_or(|size| entry.size == size)
+    };
+    (0..file_db.len() as EntryIndex).filter(|index| is_copy(&file_db.get(*index))).collect()
 }
 
-fn prune_deleted_paths(file_db: &mut FileDb)
+// Print where a local file, or a file with the given hex hash, is in the db, by hash and size.
+// Returns whether there is a copy.
+pub fn has(file_db_name: &Path, file_or_hash: &str, snapshot: Option<&str>) -> bool
 {
-    println!("Pruning deleted paths");
-    let mut new_file_db = FileDb::new();
-    let mut deleted_entries = 0;
-    let mut path_to_index = PathToIndexMap::new();
-    for entry_index in 0..file_db.len() {
-        let path = get_full_path(file_db, entry_index as u32);
-        let metadata_res = fs::symlink_metadata(&path);
-        if metadata_res.is_ok() {
-            let metadata = metadata_res.unwrap();
-            let entry = &file_db[entry_index as usize];
-            if metadata.is_dir() != entry.is_dir
-                || (!metadata.is_dir() && metadata.len() != entry.size)
-                || (!metadata.is_dir() && get_secs(&metadata.modified().unwrap()) != entry.modified)
-            {
-                assert!(
-                    !entry.is_dir,
-                    "Not implemented (need to remove all referencing paths) {:?}",
-                    path
-                );
-                deleted_entries += 1;
-            } else {
-                let mut entry_copy = entry.clone();
-                if !is_root_index(entry_index as u32) {
-                    let parent_path = path.parent().unwrap();
-                    let parent_index = path_to_index.get(parent_path.as_os_str()).unwrap();
-                    entry_copy.parent = *parent_index;
-                }
-                add_file_db_entry(&mut new_file_db, entry_copy);
-                if entry.is_dir {
-                    path_to_index
-                        .insert(path.as_os_str().to_owned(), (new_file_db.len() - 1) as u32);
-                }
+    let path = Path::new(file_or_hash);
+    let (hash, size) = if path.is_file() {
+        let size = fs::metadata(path).unwrap().len();
+        (get_hash_for_file(path, read_hash_algorithm(file_db_name)).unwrap(), Some(size))
+    } else {
+        match parse_hash_string(file_or_hash) {
+            Some(hash) => (hash, None),
+            None => {
+                eprintln!("{:?} is neither a file nor a hash", file_or_hash);
+                return false;
             }
-        } else {
-            deleted_entries += 1;
+        }
+    };
+    let file_db = load_file_db(file_db_name, snapshot);
+    let copies = find_copies(&file_db, &hash, size);
+    let is_collected = |index: &EntryIndex| file_db.get(*index).origin != 0;
+    let hosts =
+        if copies.iter().any(is_collected) { load_compressed(file_db_name).hosts } else { vec![] };
+    for index in &copies {
+        let path = format_path_for_output(&get_full_path(&file_db, *index));
+        match file_db.get(*index).origin {
+            0 => println!("{}", path),
+            origin => println!("{} (on {})", path, agent::get_origin_name(&hosts, origin)),
         }
     }
-    println!(
-        "Pruned {} paths, old: {}, new: {}",
-        deleted_entries,
-        file_db.len(),
-        new_file_db.len()
-    );
-    *file_db = new_file_db;
-    file_db.shrink_to_fit();
+    if copies.is_empty() {
+        println!("Not in db");
+    }
+    !copies.is_empty()
 }
 
-// root_dir must be the original root dir used for the file_db,
-// otherwise behavior is undefined (may still work but untested)
-pub fn update(file_db_name: &Path, root_dir: &Path)
+// Raw bytes of path, for output that must not be escaped
+#[cfg(unix)]
+fn get_path_bytes(path: &Path) -> Vec<u8>
 {
-    let mut file_db = load_compressed(file_db_name);
-    prune_deleted_paths(&mut file_db);
-
-    let mut path_to_index = build_path_to_index_map(&file_db);
-    let dir_to_files = build_dir_to_files_map(&file_db, &path_to_index);
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
 
-    add_dir_recursive(
-        root_dir,
-        &mut file_db,
-        &mut path_to_index,
-        &dir_to_files,
-        Path::new(""),
-    );
+#[cfg(not(unix))]
+fn get_path_bytes(path: &Path) -> Vec<u8>
+{
+    path.to_string_lossy().into_owned().into_bytes()
+}
 
-    propagate_sizes(&mut file_db);
-    propagate_hashes(&mut file_db);
+// The reverse of get_path_bytes, for paths read from other programs
+#[cfg(unix)]
+fn get_path_from_bytes(bytes: Vec<u8>) -> PathBuf
+{
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
 
-    save_compressed(file_db_name, &file_db);
+#[cfg(not(unix))]
+fn get_path_from_bytes(bytes: Vec<u8>) -> PathBuf
+{
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
 }
 
-pub fn dedup(file_db_name: &Path, backup_dir: Option<&Path>)
+// How paths are printed by dump, find and all_files_elsewhere
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PathFormat
 {
-    let mut file_db = load_compressed(file_db_name);
-    propagate_hashes(&mut file_db);
+    // One per line, escaped as by {:?} but without the quotes
+    #[default]
+    Lines,
+    // Raw bytes, each terminated by NUL, as for xargs -0
+    NullTerminated,
+    // One per line, quoted for the shell where needed
+    Escaped,
+}
 
-    let mut hash_and_size_to_indices = HashMap::<(Hash256, u64), Vec<u32>>::new();
-    for (index, entry) in file_db.iter().enumerate() {
-        let path = get_full_path(&file_db, index as u32);
-        if path.starts_with(BACKUP_DIR) {
-            continue;
-        }
-        let indices = hash_and_size_to_indices
-            .entry((entry.hash, entry.size))
-            .or_insert(Vec::<u32>::new());
-        indices.push(index as u32);
+// Quotes bytes for sh. Control characters and invalid UTF-8 need bash's $'...'.
+fn shell_escape(bytes: &[u8]) -> String
+{
+    let is_plain = |c: &u8| c.is_ascii_alphanumeric() || b"_-+=.,:/@%".contains(c);
+    if !bytes.is_empty() && bytes.iter().all(is_plain) {
+        return String::from_utf8(bytes.to_vec()).unwrap();
     }
-    let mut num_duped_bytes = 0;
-    let mut max_dupe_count = 0;
-    let mut entries = hash_and_size_to_indices.iter().collect::<Vec<_>>();
-    entries.sort_by_key(|((_, size), dupes)| size * dupes.len() as u64);
-    for (key, indices) in entries.into_iter().rev() {
-        let (_, size) = key;
-        let dupe_count = indices.len() - 1;
-        if dupe_count == 0 {
-            continue;
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(char::is_control) => {
+            format!("'{}'", text.replace('\'', "'\\''"))
         }
-        if dupe_count > max_dupe_count {
-            max_dupe_count = dupe_count;
-        }
-        let duped_bytes = dupe_count as u64 * size;
-        println!(
-            "Duplicated data of size: {} dupes: {} duped GB: {}",
-            size.separated_string(),
-            dupe_count,
-            duped_bytes / 1024 / 1024 / 1024
-        );
-        println!("  Dupe locations:");
-        let mut first = true;
-        for index in indices {
-            let path = get_full_path(&file_db, *index);
-            println!("    {:?}", path);
-            if !first && backup_dir.is_some() {
-                // File may have been removed by a previous operation which moved a parent dir
-                if Path::new(&path).exists() {
-                    println!("      Moving");
-                    let dest_dir = backup_dir.unwrap().join(path.file_name().unwrap());
-                    assert!(!Path::new(&dest_dir).exists());
-                    fs_extra::move_items(&[path], backup_dir.unwrap(), &CopyOptions::new())
-                        .unwrap();
+        _ => {
+            let mut escaped = String::from("$'");
+            for chunk in bytes.utf8_chunks() {
+                for c in chunk.valid().chars() {
+                    match c {
+                        '\'' | '\\' => escaped.extend(['\\', c]),
+                        c if c.is_control() => escaped += &format!("\\x{:02x}", c as u32),
+                        c => escaped.push(c),
+                    }
+                }
+                for byte in chunk.invalid() {
+                    escaped += &format!("\\x{:02x}", byte);
                 }
-            } else {
-                first = false;
             }
+            escaped + "'"
         }
-        num_duped_bytes += duped_bytes;
     }
-    println!("Total duped bytes: {}", num_duped_bytes.separated_string());
-    println!("Max dupe count: {}", max_dupe_count);
 }
 
-// Check whether all files in backup_dir are elsewhere, and list those that aren't
-// Comparison is done by 256bit hash and size, not by name or content
-// Ignores empty files (also does not remove them)
-pub fn all_files_elsewhere(
-    file_db_name: &Path,
-    backup_dir: &Path,
-    opt_other_dir: Option<&Path>,
-    remove_dupes: bool,
-)
+fn write_path(out: &mut impl Write, path: &Path, format: PathFormat) -> io::Result<()>
 {
-    // Add all files outside of backup_dir to lookup structure
-    let file_db = load_compressed(file_db_name);
-    let mut hash_to_index: HashMap<Hash256, Vec<u32>> = HashMap::new();
-    for (i, entry) in file_db.iter().enumerate() {
-        if entry.is_dir || entry.size == 0 {
-            continue;
-        }
-        let entry_path = get_full_path(&file_db, i as u32);
-        if !entry_path.starts_with(backup_dir) {
-            let map_entry = hash_to_index.entry(entry.hash).or_insert(Vec::<u32>::new());
-            (*map_entry).push(i as u32);
+    match format {
+        PathFormat::Lines => writeln!(out, "{}", format_path_for_output(path)),
+        PathFormat::NullTerminated => {
+            out.write_all(&get_path_bytes(path))?;
+            out.write_all(b"\0")
         }
+        PathFormat::Escaped => writeln!(out, "{}", shell_escape(&get_path_bytes(path))),
     }
+}
 
-    let mut num_dupes = 0;
-    let mut num_files_missing = 0;
-    let mut num_dupes_sum = 0;
-    let mut num_dupe_entries = 0;
-    let mut min_num_dupes = std::usize::MAX;
-    let mut max_num_dupes = 0;
-    let mut entry_and_dupes = vec![];
-    let mut num_duped_bytes = 0;
-    let mut num_missing_bytes = 0;
-    let mut num_dirs = 0;
-    let mut num_empty_files = 0;
-    // Iterate all files in backup_dir and check if they are present in lookup structure
-    for (i, entry) in file_db.iter().enumerate() {
-        let entry_path = get_full_path(&file_db, i as u32);
-        if !entry_path.starts_with(backup_dir) {
-            continue;
-        }
-        if entry.is_dir {
-            num_dirs += 1;
-            continue;
+pub fn print_path_list(paths: &[PathBuf], format: PathFormat)
+{
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for path in paths {
+        write_path(&mut out, path, format).unwrap();
+    }
+}
+
+// Conditions on entries. Size and Age match if comparing the entry's value to the given one
+// yields the ordering: Greater for +value, Less for -value, Equal for value.
+#[derive(Clone, Debug)]
+pub enum FindPredicate
+{
+    Name(glob::Pattern),
+    // Case insensitive
+    IName(glob::Pattern),
+    Size(std::cmp::Ordering, u64),
+    // Seconds since last modification
+    Age(std::cmp::Ordering, u64),
+    IsDir(bool),
+    PathPrefix(PathBuf),
+    // Detected MIME type, like video/*
+    Mime(glob::Pattern),
+}
+
+// Alternatives, each matching if all its predicates match
+pub type FindExpression = Vec<Vec<FindPredicate>>;
+
+fn matches_find_predicate(entry: &Entry, path: &Path, now: u64, predicate: &FindPredicate)
+    -> bool
+{
+    match predicate {
+        FindPredicate::Name(pattern) => pattern.matches(&entry.name.to_string_lossy()),
+        FindPredicate::IName(pattern) => {
+            let options = glob::MatchOptions {
+                case_sensitive: false,
+                ..glob::MatchOptions::new()
+            };
+            pattern.matches_with(&entry.name.to_string_lossy(), options)
         }
-        if entry.size == 0 {
-            num_empty_files += 1;
-            continue;
+        FindPredicate::Size(ordering, size) => entry.size.cmp(size) == *ordering,
+        FindPredicate::Age(ordering, age) => {
+            now.saturating_sub(entry.modified).cmp(age) == *ordering
         }
-        let hash = entry.hash;
-        let value = hash_to_index.get(&hash);
-        if value.is_none() {
-            println!("File missing: {:?}", entry_path);
-            num_files_missing += 1;
-            num_missing_bytes += entry.size;
-        } else {
-            let dupe_list = value.unwrap();
-            let mut found = false;
-            for dupe_index in dupe_list {
-                let dupe_entry = &file_db[*dupe_index as usize];
-                if dupe_entry.size == entry.size
-                /*&& dupe_entry.name == entry.name*/
-                {
-                    if let Some(other_dir) = opt_other_dir {
-                        let dupe_path = get_full_path(&file_db, *dupe_index);
-                        if dupe_path.starts_with(other_dir) {
-                            found = true;
-                            break;
-                        }
-                    } else {
-                        found = true;
-                        break;
-                    }
-                }
-            }
-            if !found {
-                println!("File missing: {:?}", entry_path);
-                num_files_missing += 1;
-                num_missing_bytes += entry.size;
-            } else {
-                let num_entry_dupes = dupe_list.len();
-                num_dupes_sum += num_entry_dupes;
-                num_dupe_entries += 1;
-                min_num_dupes = std::cmp::min(min_num_dupes, num_dupes);
-                max_num_dupes = std::cmp::max(max_num_dupes, num_dupes);
-                entry_and_dupes.push((i as u32, dupe_list));
-                num_dupes += 1;
-                num_duped_bytes += entry.size;
-                if fs::metadata(&entry_path).is_ok() {
-                    if remove_dupes {
-                        println!("Removing {:?}", entry_path);
-                        let res = fs::remove_file(&entry_path);
-                        if res.is_err() {
-                            println!("Error removing {:?}", entry_path);
-                        }
-                        let mut parent = entry_path.parent().unwrap();
-                        while fs::remove_dir(parent).is_ok() {
-                            // Will only remove empty dirs
-                            println!("Removed parent dir {:?}", parent);
-                            parent = parent.parent().unwrap();
-                        }
-                    } else {
-                        println!("Would remove {:?}", entry_path);
-                    }
-                }
-            }
+        FindPredicate::IsDir(is_dir) => entry.is_dir == *is_dir,
+        FindPredicate::PathPrefix(prefix) => path.starts_with(prefix),
+        FindPredicate::Mime(pattern) => pattern.matches(&entry.mime),
+    }
+}
+
+fn find_matches(file_db: &FileDb, expression: &FindExpression, now: u64) -> Vec<EntryIndex>
+{
+    let mut matches = vec![];
+    for (index, path) in file_db.iter_paths() {
+        let entry = file_db.get(index);
+        let matches_all = |predicates: &Vec<FindPredicate>| {
+            predicates.iter().all(|predicate| matches_find_predicate(&entry, &path, now, predicate))
+        };
+        if expression.iter().any(matches_all) {
+            matches.push(index);
         }
     }
+    matches
+}
 
-    println!("Num dupes: {}", num_dupes);
-    println!("Files missing: {}", num_files_missing);
-    println!("Dirs: {}", num_dirs);
-    println!("Empty files: {}", num_empty_files);
-    println!("");
-    println!("Min num dupes: {}", min_num_dupes);
-    println!("Max num dupes: {}", max_num_dupes);
-    println!("Avg num dupes: {}", num_dupes_sum / num_dupe_entries);
-    println!("Num duped bytes: {}", num_duped_bytes);
-    println!("Num missing bytes: {}", num_missing_bytes);
+// Print the paths of all entries matching expression, without accessing the files
+pub fn find(
+    file_db_name: &Path,
+    expression: &FindExpression,
+    path_format: PathFormat,
+    snapshot: Option<&str>,
+)
+{
+    let file_db = load_file_db(file_db_name, snapshot);
+    let now = get_secs(&time::SystemTime::now());
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for index in find_matches(&file_db, expression, now) {
+        write_path(&mut out, &get_full_path(&file_db, index), path_format).unwrap();
+    }
 }
 
-pub fn stats(file_db_name: &Path, prefix: Option<&Path>)
+// Compares files by hash and size. Without output, the resulting paths are printed.
+pub fn set_operation(
+    file_db_name_a: &Path,
+    file_db_name_b: &Path,
+    operation: SetOperation,
+    output: Option<&Path>,
+)
 {
-    let file_db = load_compressed(file_db_name);
+    check_same_hash_algorithm(file_db_name_a, file_db_name_b);
+    let file_db_a = load_file_db(file_db_name_a, None);
+    let file_db_b = load_file_db(file_db_name_b, None);
+    let (indices_a, indices_b) = select_set_operation(&file_db_a, &file_db_b, operation);
 
-    let mut num_files = 0;
-    let mut num_dirs = 0;
-    let mut size = 0;
-    for (index, entry) in file_db.iter().enumerate() {
-        if prefix.is_some() {
-            let full_path = get_full_path(&file_db, index as u32);
-            if !full_path.starts_with(prefix.unwrap()) {
-                continue;
+    let num_files = indices_a.len() + indices_b.len();
+    let num_bytes = indices_a
+        .iter()
+        .map(|index| file_db_a.get(*index).size)
+        .chain(indices_b.iter().map(|index| file_db_b.get(*index).size))
+        .sum::<u64>();
+    match output {
+        Some(out_file_db_name) => {
+            let mut out_file_db = FileDb::new();
+            let mut out_path_to_index = PathToIndexMap::new();
+            for (file_db, indices) in [(&file_db_a, &indices_a), (&file_db_b, &indices_b)] {
+                if !indices.is_empty() {
+                    let extracted = extract_entries(file_db, indices);
+                    merge_file_db(&mut out_file_db, &mut out_path_to_index, &extracted);
+                }
             }
+            if !out_file_db.is_empty() {
+                propagate_sizes(&mut out_file_db);
+                propagate_hashes(&mut out_file_db);
+            }
+            let mut out_db = new_db(out_file_db);
+            out_db.hash_algorithm = read_hash_algorithm(file_db_name_a);
+            save_compressed(out_file_db_name, &out_db);
         }
-        if entry.is_dir {
-            num_dirs += 1;
-        } else {
-            num_files += 1;
-            size += entry.size;
+        None => {
+            for index in &indices_a {
+                println!("{}", format_path_for_output(&get_full_path(&file_db_a, *index)));
+            }
+            for index in &indices_b {
+                println!("{}", format_path_for_output(&get_full_path(&file_db_b, *index)));
+            }
         }
     }
-    let (largest_entry_name, largest_entry_size) = file_db
-        .iter()
-        .map(|entry| (&entry.name, entry.size))
-        .max_by_key(|elem| elem.1)
-        .unwrap();
-    println!(
-        "Entries: {}, files: {}, dirs: {}, size: {}",
-        file_db.len().separated_string(),
+    eprintln!(
+        "Files: {}, size: {}",
         num_files.separated_string(),
-        num_dirs.separated_string(),
-        size.separated_string()
-    );
-    println!(
-        "Largest entry: {}, size: {}",
-        largest_entry_name.to_str().unwrap(),
-        largest_entry_size.separated_string()
+        num_bytes.separated_string()
     );
 }
 
-pub fn mv(file_db_name: &Path, from_dir: &Path, to_dir: &Path)
+// Symlinks and junctions of the current tree beneath prefix, with their targets
+pub fn links(file_db_name: &Path, prefix: Option<&Path>)
 {
-    let mut file_db = load_compressed(file_db_name);
-    let from_metadata = fs::metadata(from_dir);
-    let to_metadata = fs::metadata(to_dir);
-    if from_metadata.is_err() {
-        panic!("source directory does not exist: {:?}", from_dir);
-    }
-    if to_metadata.is_err() {
-        panic!("target directory does not exist: {:?}", to_dir);
-    }
-    if from_metadata.unwrap().is_dir() && to_metadata.unwrap().is_dir() {
-        let mut target_path = PathBuf::from(to_dir);
-        target_path.push(from_dir.file_name().unwrap());
-        let target_metadata = fs::metadata(&target_path);
-        if !target_metadata.is_err() {
-            panic!("Target dir {:?} exists", target_path);
+    let db = load_compressed(file_db_name);
+    for (index, entry) in db.file_db.iter().enumerate() {
+        if !is_link(&entry) {
+            continue;
         }
-        let mut to_index = std::usize::MAX;
-        let mut from_index = std::usize::MAX;
-        for entry_index in 0..file_db.len() {
-            if !file_db[entry_index].is_dir {
-                continue;
-            }
-            let full_path = get_full_path(&file_db, entry_index as u32);
-            if full_path == to_dir {
-                to_index = entry_index;
-            }
-            if full_path == from_dir {
-                from_index = entry_index;
-            }
+        let path = get_full_path(&db.file_db, index as EntryIndex);
+        if prefix.is_some_and(|prefix| !path.starts_with(prefix)) {
+            continue;
         }
-        assert!(to_index != std::usize::MAX && from_index != std::usize::MAX);
-        file_db[from_index].parent = to_index as u32;
-        println!("Moving data");
-        fs_extra::move_items(&[from_dir], to_dir, &CopyOptions::new()).unwrap();
-        propagate_sizes(&mut file_db);
-        save_compressed(file_db_name, &file_db);
+        let target = match db.link_targets.get(&entry.hash) {
+            Some(target) => format_path_for_output(target),
+            None => "(unknown)".to_string(),
+        };
+        println!("{} -> {}", format_path_for_output(&path), target);
     }
 }
 
-pub fn rm_recursive(file_db_name: &Path, rm_path: &Path)
+pub fn snapshots_list(file_db_name: &Path)
 {
-    println!("Removing path {:?}, are you sure?", rm_path);
-    let mut words = String::new();
-    io::stdin()
-        .read_line(&mut words)
-        .ok()
-        .expect("Failed to read line.");
-    if words.trim() != "y" {
-        return;
-    }
-    let mut file_db = load_compressed(file_db_name);
-    let rm_path_metadata = fs::metadata(rm_path).unwrap();
-    if rm_path_metadata.is_dir() {
-        fs::remove_dir_all(rm_path).unwrap();
-    } else {
-        fs::remove_file(rm_path).unwrap();
+    let db = load_compressed(file_db_name);
+    for snapshot in &db.snapshots {
+        println!(
+            "{} created: {} entries: {}",
+            snapshot.name,
+            get_time_string(snapshot.created),
+            snapshot.file_db.len().separated_string()
+        );
     }
-    prune_deleted_paths(&mut file_db);
-    propagate_sizes(&mut file_db);
-    propagate_hashes(&mut file_db);
-    save_compressed(file_db_name, &file_db);
 }
 
-fn dump_helper(file_db: &FileDb, full: bool)
+pub fn snapshot_delete(file_db_name: &Path, snapshot: &str)
 {
-    for index in 0..file_db.len() {
-        let entry = &file_db[index];
-        let path = get_full_path(&file_db, index as u32);
-        let out_string = format!("{:?}", path);
-        let stripped_string = out_string
-            .strip_prefix("\"")
-            .unwrap()
-            .strip_suffix("\"")
-            .unwrap();
-        if full {
-            println!("{} {} {:?}", stripped_string, entry.size, entry.hash);
-        } else {
-            println!("{}", stripped_string);
+    let mut db = load_compressed(file_db_name);
+    match get_snapshot_index(&db, snapshot) {
+        Some(index) => {
+            db.snapshots.remove(index);
         }
+        None => panic!("Snapshot {:?} not found", snapshot),
     }
-}
-
-pub fn dump(file_db_name: &Path)
-{
-    let file_db = load_compressed(file_db_name);
-    dump_helper(&file_db, false);
-}
-
-pub fn dump_full(file_db_name: &Path)
-{
-    let file_db = load_compressed(file_db_name);
-    dump_helper(&file_db, true);
+    save_compressed(file_db_name, &db);
 }
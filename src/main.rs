@@ -1,108 +1,1653 @@
-use std::{env, path::Path, process};
+use std::{env, path::Path, path::PathBuf, process};
 
 use filedb;
 
-fn print_usage_and_exit_with_error()
+fn print_usage_and_exit_with_error() -> !
 {
     println!(
         "Usage: filedb path_to_filedb <command>
+       filedb diff path_to_filedb_a path_to_filedb_b [--summary] [--json]
+              [--snapshot-a name] [--snapshot-b name]
+       filedb merge path_to_out_filedb path_to_filedb1 path_to_filedb2 ...
+       filedb intersect|subtract|union-by-hash path_to_filedb_a path_to_filedb_b [--output path]
+              Files of a whose hash+size is also in b / is not in b, or all distinct
+              files of both. Printed as path list, or written to a new db with --output.
+       filedb agent [crawl options] [--connect address] path
+              Crawl path and send the tree to filedb collect on another machine, which
+              listens on address (host:port), or through stdout, see collect
 
     Where command is one of:
 
-    add path1 [path2] ...
-        Add given paths
-    update path
-        Rescan given path (path should be the initial path used to create the db)
-    dedup
-        Dedup and print results
-    dedup_move_dupes move_path
+    add [crawl options] path1 [path2] ...
+        Add given paths. A checkpoint is saved regularly while adding.
+    add --resume path
+        Continue an interrupted add of path from its checkpoint
+    add [crawl options] --remote host:/path [--prefix dir]
+        Add path on host through ssh, for machines without filedb (see collect). The
+        files are hashed on host with b3sum, sha256sum or xxh128sum if it has the tool
+        for the hash algorithm, otherwise streamed through tar and hashed here. Needs GNU
+        find on host. The tree is added at path, or below dir, replacing what was added
+        there before. FILEDB_SSH names a program to run instead of ssh.
+    update [crawl options] [path]
+        Rescan given path (path should be the initial path used to create the db), or all
+        roots (the paths added) without one
+    update [crawl options] --prefix dir
+        Only prune and rescan dir, below one of the roots, if nothing else changed
+    collect [--listen address] [--prefix dir]
+    collect --ssh host [--prefix dir] [crawl options] path
+        Add the tree filedb agent crawled on another machine, read from stdin (ssh host
+        filedb agent path | filedb db collect), from an agent connecting to address, or
+        by running the agent on host through ssh. The tree is added at its path on that
+        machine, or below dir. What was collected there before is replaced.
+    import-rclone [--prefix dir] [--hash-algorithm name] listing
+        Add the objects in listing, written by rclone lsjson -R --hash remote:path, below
+        dir (default /rclone/<name of listing>), replacing those imported there before.
+        Objects have hashes if the backend provides those of the db's hash algorithm (or
+        name for a new db), so all_files_elsewhere --against finds files already uploaded.
+    daemon [crawl options] [--interval duration] [--listen address] [path]
+        Keep db current as a service: update path, or all roots, every interval (6h by
+        default, 30m, 1d), and print what changed. With --listen, metrics for Prometheus
+        are served at http://address/metrics. The db is only locked while updating.
+    watch path
+        Keep db current by applying file system changes below path as they happen.
+        Run update first, changes made while not watching are not picked up. Saves every
+        5 minutes and when interrupted.
+    dedup [keep rules] [dupe filters] [--hardlink|--reflink|--exec command [--jobs n]]
+        Dedup and print results. With --hardlink, dupes are replaced with hardlinks to
+        the kept copy, if on the same file system and identical byte by byte.
+        --reflink clones the kept copy instead (btrfs, XFS, APFS), so the files share
+        their data but remain independently writable. Dupes already hardlinked or sharing
+        extents with the kept copy (Linux) count as shared, not reclaimable, bytes.
+        --exec runs command for each dupe, with {{}} replaced by its path and {{kept}} by the
+        kept copy (quoted for the shell), at most n (the number of cores) at a time.
+    dedup --interactive [keep rules] [dupe filters] [--verify-content] [trash options]
+        Walk through the groups of duplicate files, most reclaimable bytes first, and mark
+        the copies to remove. The copy preferred by the keep rules is selected initially.
+        The marked copies are removed once applied with 'a', see the keys at the bottom.
+    dedup-dirs [prefix]
+        List groups of dirs with identical contents, the largest first. Dirs within
+        duplicated dirs are only listed if they have copies elsewhere.
+    similar-dirs [--min-similarity percent] [--min-files n] [path_a path_b]
+        Print the percentage of files of two dirs with a copy in the other one, by hash,
+        and the files only on either side, e.g. for a copy that diverged slightly. Without
+        dirs, list pairs of dirs with at least n files (10 by default) of which at least
+        percent (80) have copies on the other side, the most similar first.
+    dedup --plan plan_file [keep rules] [dupe filters] [--hardlink|--reflink]
+        Write the removals (or hardlinks, reflinks) dedup would do to plan_file as JSON,
+        with hash and size of each file, for review and editing. Only files are planned.
+    apply-plan [--verify-content] [trash options] plan_file
+        Execute a plan written by dedup --plan. Both files of each entry are hashed again
+        first, entries whose files changed are skipped.
+    dedup_move_dupes [keep rules] [dupe filters] move_path
         Dedup and move dupes to move_path
-    all_files_elsewhere path [elsewhere_path]
+    all_files_elsewhere [keep rules] [missing options] path [elsewhere_path]
         Check that all files in path are available somewhere else. If elsewhere_path
-        is specified, all copies must reside there.
-    all_files_elsewhere_remove_dupes path
+        is specified, all copies must reside there. Exits with 1 if files are missing.
+    all_files_elsewhere --against other_db [missing options] path [elsewhere_path]
+        Check that all files in path are available in other_db (below elsewhere_path),
+        e.g. the db of an offline backup drive, by hash and size
+    sync-missing [--against other_db] source_prefix dest_dir
+        Copy the files below source_prefix that all_files_elsewhere (--against) finds
+        missing to dest_dir, at their paths relative to source_prefix. Each copy is hashed
+        before it gets its final name. Files already copied are skipped, so an interrupted
+        sync continues when run again.
+    all_files_elsewhere_remove_dupes [keep rules] [--verify-content] [trash options] path
         Check that all files in path are available somewhere else and if so, remove
-    mv from to
-        Move path on file system and in db
-    rm_recursive path
+        them. Files the keep rules prefer over all copies elsewhere are not removed.
+        With --verify-content, each file is compared byte by byte to the kept copy
+        before removing it, stopping at the first difference.
+    mv [--db-only] from to
+        Move path on file system and in db. With --db-only, from was already moved to
+        to/<name of from> and only the db is updated, without hashing again.
+    rm_recursive [trash options] path
         Remove path on file system and in db
-    stats
-    dump
-    dump_full
+    forget prefix
+        Remove prefix and everything beneath it from the db only, the files are kept
+    restore journal
+        Copy files removed by all_files_elsewhere_remove_dupes or moved by dedup_move_dupes
+        back from their kept copies. Each run writes a journal next to the db.
+    verify [--sample percent%] [--max-bytes size]
+        Re-hash files and report corruption. With --sample or --max-bytes, only that
+        amount of data is checked per run, rotating through all files across runs.
+    hash [--min-size size] [--max-size size] [prefix]
+        Compute the hashes of the files added with --no-hash (below prefix, of a size in
+        the given range). Interrupt to save the hashes computed so far and run again to
+        continue. Until hashed, files are not considered by dedup and count as missing.
+    rehash [--only-empty|--all] [prefix]
+        Recompute the hashes of the files below prefix on all cores and store them in
+        place, along with the current size and time. By default, files without hash and
+        those changed since indexed are hashed, with --only-empty just the former and with
+        --all every file, e.g. after suspected corruption (hashes that differ for unchanged
+        files are reported). Interrupt to save the progress and run again to continue.
+    find [-0|--escape] [predicates]
+        Print the paths of all entries matching the predicates, without accessing the
+        files. Predicates must all match, unless separated by --or:
+        --name glob, --iname glob (case insensitive)
+        --size [+-]size     Larger than, smaller than or exactly size
+        --mtime +-duration  Modified longer or less than duration ago (30d, 12h, 2w)
+        --type f|d, --path-prefix path
+        --mime glob         MIME type detected with --mime during add/update (video/*)
+        With -0, paths are separated by NUL and not escaped, as for xargs -0. With
+        --escape, they are quoted for the shell where needed, one per line.
+    ls path
+        List the entries of a dir with type (d: dir, f: file, a: archive with indexed
+        contents), size and modification time. Exits with 1 if path is not in the db.
+    tree path [--depth n] [--min-size size]
+        Print the tree beneath path with sizes, largest entries first, at most n levels
+        deep. Entries smaller than size are summed up per dir.
+    du [prefix] [--max-depth n] [-h] [--apparent-size]
+        Print the size of each dir beneath prefix (/ by default) like GNU du, in 1K blocks
+        or with -h human readable, at most n levels deep. Sizes are allocated bytes, or
+        with --apparent-size the logical ones.
+    has file|hash
+        Print where copies of a local file, or of a file with the given hex hash (algorithm
+        of the db), are in the db, by hash and size, with the host of those collected from
+        other machines. Exits with 1 if there are none.
+    stats [--by-extension] [--by-owner] [--by-host] [--size-histogram] [--age] [prefix1] ...
+        Print number of entries and size, of the whole db or per prefix. Optionally also
+        file count and bytes per extension, per owner, per host the files were collected
+        from (with those that have copies on other hosts), per size bin (0, <4K, <64K,
+        <1M, <100M, <1G, >=1G), or per age bin by modification and access time (<30d,
+        <1y, <5y, older).
+    partition
+        Split the db by top-level dir into path_to_filedb.partitions, so that stats, ls,
+        tree and du of a path below one only load and decompress its part. Saving the db
+        keeps the partitions current.
+    media [--same-shot] [prefix]
+        List photos and videos read with --media by capture date, with camera and
+        dimensions. With --same-shot, only different files taken at the same time with the
+        same camera, like resized copies, the largest first.
+    similar-images [--max-distance n] [prefix]
+        List groups of images with different contents that look the same, like resized or
+        re-encoded copies, by perceptual hashes computed with --phash differing in at most
+        n of 64 bits (4 by default). The largest files first.
+    similar-audio [--max-error ratio] [prefix]
+        List groups of audio files with different contents that are the same recording,
+        like a song as FLAC and MP3, by fingerprints computed with --audio-fingerprint
+        differing in at most ratio of their bits (0.15 by default). The largest files first.
+    near-dupes [--threshold n] [prefix]
+        List pairs of files with different but mostly identical contents, like slightly
+        edited documents, whose similarity digests computed with --fuzzy-hash score at
+        least n of 100 (60 by default). The most similar first.
+    chunk-dupes [prefix]
+        List pairs of files with different contents sharing chunks computed with
+        --chunk-hashes, like VM images, with the bytes they share, the most first. Also
+        print the bytes that storing each distinct chunk only once would save.
+    sparse [--min-size size] [--min-ratio r] [prefix]
+        List files of at least size (1M by default) allocating at most 1/r (1/2) of their
+        size on disk, the most unallocated bytes first
+    cold-files [--older-than duration] [--limit n] [prefix]
+        List the largest files not accessed for duration (2y by default, 30d, 12h, 2w), at
+        most n (100), and the total count and size, e.g. as candidates for archiving.
+    browse
+        Navigate the tree of the db in a terminal UI, like a disk usage analyzer. Works
+        without the files being available, e.g. for a drive that is not connected.
+    serve [--listen address]
+        Answer queries over HTTP with JSON, on 127.0.0.1:8080 by default. Endpoints:
+        /stats, /ls?path=dir, /search?name=glob[&limit=n], /hash/<hex>
+    dump [--prefix path] [--type f|d] [--max-depth n] [--min-size size]
+         [--sort size|name|mtime] [-0|--escape]
+    dump_full [same options as dump]
+        Print all entries, or only those matching the options. --max-depth counts levels
+        beneath --prefix. Without --sort, entries are printed as the db is read. -0 and
+        --escape format paths as for find, with -0 dump_full terminates each line by NUL.
+    links [path]
+        List the symlinks and junctions beneath path, or all, with their targets
+    root add name path
+        Name the dir at path a root, e.g. the mount point of a drive, so --root-override
+        can bind it to where the drive is mounted on another host. Paths added are roots
+        already, named after their last component.
+    root remove name
+        Stop tracking the root, its entries are kept
+    roots
+        List named roots with the path they are bound to
+    rebase old_mount new_mount
+        Move the entries below old_mount to new_mount, e.g. for a drive mounted elsewhere
+        now, instead of adding it again
+    changes [--paths]
+        List the changes update appended with --append-changes since the db was last saved
+        as a whole: when, and the number of entries added, changed and removed, with
+        --paths also their paths
+    compact
+        Save the db with the changes appended to it, removing them
+    snapshots list
+        List named snapshots
+    snapshot delete name
+        Delete named snapshot
+
+    Options:
+
+    --config file
+        Read defaults from file instead of ~/.config/filedb/config.toml: the db to use
+        without path_to_filedb (db), protect, limit-rate, threads, compression, no-cache,
+        wait, and crawl options
+        in a [crawl] table, e.g. xattrs = true, hash-algorithm = \"xxh128\". Options given
+        take precedence, protected paths are added.
+    --wait
+        If the db is locked by another filedb process, wait instead of failing
+    --json
+        Print the results as JSON object instead of text (stats, one object per prefix;
+        dedup without --hardlink/--reflink/--interactive; all_files_elsewhere)
+    --dry-run
+        Only print the moves, copies, removals, links, commands and db changes that would be
+        done, and the bytes affected (dedup, apply-plan, dedup_move_dupes,
+        all_files_elsewhere_remove_dupes, all_files_elsewhere --exec, mv, rm_recursive,
+        forget, restore, sync-missing)
+    --no-cache
+        Drop files from the page cache once they are hashed, so hashing large trees does not
+        evict what other programs use (Linux only)
+    --limit-rate size
+        Read at most size bytes per second for hashing, e.g. 100M, so indexing a NAS does
+        not starve other users (add, update, verify)
+    --threads n
+        Hash files of 64 MB and more on n threads instead of all cores (add, update, verify,
+        dedup)
+    --compression level
+        Compress the db with zlib level 0 to 9 when saving it, 1 by default. Higher levels
+        make dbs smaller, but saving slower.
+    --protect pattern
+        Never remove, move or link over files matching pattern, an absolute path with
+        wildcards (*, ?, [...]) within components, nor dirs containing them. Can be given
+        several times, e.g. --protect '/immens/originals/**' (dedup, dedup_move_dupes,
+        apply-plan, all_files_elsewhere_remove_dupes, rm_recursive)
+    --snapshot name
+        Operate on the named snapshot instead of the current tree (add, update, dedup,
+        dedup-dirs, similar-dirs, all_files_elsewhere, ls, tree, du, find, has, stats, media,
+        similar-images, similar-audio, near-dupes, chunk-dupes, sparse, cold-files, browse,
+        serve, dump). add creates the snapshot if needed.
+    --root-override name=path
+        Bind the named root to path, for all commands. Commands that change the db store
+        the new binding. Can be given several times.
+    --notify-webhook url, --notify-command command
+        Send a JSON summary of the results of update, verify and all_files_elsewhere
+        (including daemon runs) to url, POSTed with curl, or to the stdin of command run
+        by the shell, e.g. mail -s filedb me@example.com. Its field ok is false if files
+        could not be read, fail verification, or are missing elsewhere.
+    --sign-key private_key, --trusted-key public_key
+        Sign the db with the ed25519 key private_key (ssh-keygen -t ed25519) whenever it is
+        saved, written to <db>.sig by ssh-keygen. With --trusted-key, dbs are only loaded
+        if signed with the key of public_key, so a db kept with backups cannot be changed
+        unnoticed to hide missing or altered files.
+    --append-changes
+        Make update append the entries it added, changed and removed to <db>.changes
+        instead of rewriting the whole db, so frequent updates of large dbs are fast.
+        Loading applies them. Once they reach a quarter of the size of the db, or 32
+        updates, or another command saves the db, they are compacted into it. Not for
+        snapshots or updates computing media info, image, audio, fuzzy or chunk hashes.
+
+    Trash options (all_files_elsewhere_remove_dupes, rm_recursive, dedup --interactive,
+    apply-plan):
+
+    --trash
+        Move removed files to the trash (freedesktop.org), instead of deleting them
+    --trash-dir path
+        Move removed files to path, keeping their full path beneath it
+
+    Keep rules (dedup, all_files_elsewhere):
+
+    --keep-path-prefix path, --keep-newest, --keep-oldest, --keep-shortest-path
+        Choose which copy survives: The one below path, with the newest or oldest
+        modification time, or with the shortest path. Rules apply in the order given,
+        later ones only decide ties. Without rules or on remaining ties, dedup keeps the copy
+        with the lowest path.
+
+    Missing options (all_files_elsewhere):
+
+    --output-files-from list_file
+        Write the missing files relative to path, separated by NUL, to copy them with
+        rsync -a --from0 --files-from=list_file path/ dest
+    --pack archive
+        Pack the missing files into archive, at their paths relative to path. The
+        format follows the extension: .tar, .tar.gz, .tar.zst or .zip
+    -0, --escape
+        Print only the paths of the missing files instead of the report, separated by
+        NUL or quoted for the shell, see find
+    --exec command [--jobs n]
+        Run command for each missing file, with {{}} replaced by its path (quoted for the
+        shell), at most n (the number of cores) at a time, e.g. to upload it
+
+    Dupe filters (dedup, dedup_move_dupes):
+
+    --min-size size
+        Only consider groups of dupes whose files have at least size bytes (e.g. 1M)
+    --min-group-savings size
+        Only consider groups of dupes where removing all but one copy frees at least size
+        bytes, leaving out the many small dupes like configs and READMEs
+
+    Crawl options (add, update):
+
+    --hash-algorithm name
+        Hash files with blake3 (default), sha256 or xxh128 (fast, but not cryptographic).
+        Set when the db is created; dbs with different ones cannot be mixed or compared.
+    --no-hash
+        Only record names, sizes and times of new files, for a quick inventory of huge
+        trees. Compute their hashes later with the hash command.
+    --lazy-hash
+        Only read the first and last 64K of new files for a pre-hash. Their full hashes are
+        computed by dedup once files share size and pre-hash, or by the hash command.
+    --xattrs
+        Capture extended attributes (SELinux labels, user.* tags, ...). They are included
+        in the hashes of dirs, so trees only match if their xattrs do.
+    --mac-metadata
+        macOS only: Capture file flags, Finder info, quarantine and resource fork sizes
+        with the xattrs, so restores of Mac backups can be verified to keep them
+    --alternate-streams
+        Windows only: Add the named NTFS streams of files as files file:stream next to
+        them, with their sizes and hashes, so they count in stats and dedup
+    --follow-links
+        Descend into the dirs symlinks and junctions point to, instead of adding them as
+        links (see links). Links back to a dir above them and broken ones are skipped.
+    --one-file-system
+        Stay on the file system of the path added or updated. Mount points of other file
+        systems (backup drives, network shares, /proc) are added as empty dirs.
+    --include-virtual-fs
+        Also descend into virtual file systems (proc, sysfs, devtmpfs, tmpfs, cgroup, ...)
+        mounted below the path, which are added as empty dirs by default (Linux)
+    --skip-backup-excluded
+        Leave out what backup tools do: Files and dirs flagged nodump (chattr +d, chflags
+        nodump), and the contents of cache dirs tagged with a CACHEDIR.TAG
+    --exclude pattern
+        Leave out files and dirs whose names match pattern, with wildcards (*, ?, [...]),
+        e.g. --exclude '*.tmp' --exclude node_modules. Can be given several times.
+    --error-report file
+        Write the entries that could not be read (permission denied, vanished while
+        crawling, IO errors) to file as JSON. A summary is printed at the end either way.
+    --mime
+        Detect the MIME type of files from their contents, for find --mime
+    --media
+        Read capture date, camera and dimensions of photos (EXIF) and videos (MP4,
+        QuickTime) not read before, for the media command
+    --phash
+        Compute perceptual hashes of images not hashed before, for similar-images
+    --audio-fingerprint
+        Compute fingerprints of the first two minutes of audio files (FLAC, MP3, Ogg
+        Vorbis, WAV, AAC, ALAC) not fingerprinted before, for similar-audio
+    --fuzzy-hash
+        Compute similarity digests (like ssdeep) of files not digested before, for
+        near-dupes. Reads all files again.
+    --chunk-hashes, --chunk-min-file-size size
+        Split files of at least size (64M by default) not chunked before into content
+        defined chunks (FastCDC, 1M on average) and hash those, for chunk-dupes
+    --index-archives
+        Add the contents of archives beneath them (tar, gz, tgz, xz, bz2, zst, zip, 7z;
+        rar contents are listed without hashes). Archives within archives are unpacked too.
+    --archive-depth n
+        Unpack archives nested at most n deep (default 4), deeper ones are added as files
+    --archive-max-nested-size size
+        Add archives within archives as files once the enclosing archives unpacked to
+        more than size (default 16G). Archives containing themselves are never unpacked.
+    --archive-max-size size, --archive-max-entries n, --archive-max-ratio n
+        Add an archive as file if unpacking it exceeds size bytes (default 16G), n entries
+        (default 1000000) or n times its own size (default 10000)
     "
     );
     process::exit(1);
 }
 
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool
+{
+    match args.iter().position(|arg| arg == flag) {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String>
+{
+    let pos = args.iter().position(|arg| arg == name)?;
+    if pos + 1 >= args.len() {
+        print_usage_and_exit_with_error();
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+// Options shared by add and update
+fn take_crawl_options(args: &mut Vec<String>) -> filedb::CrawlOptions
+{
+    let mut options = filedb::CrawlOptions {
+        index_archives: take_flag(args, "--index-archives"),
+        xattrs: take_flag(args, "--xattrs"),
+        mac_metadata: take_flag(args, "--mac-metadata"),
+        alternate_streams: take_flag(args, "--alternate-streams"),
+        follow_links: take_flag(args, "--follow-links"),
+        one_file_system: take_flag(args, "--one-file-system"),
+        virtual_file_systems: take_flag(args, "--include-virtual-fs"),
+        skip_backup_excluded: take_flag(args, "--skip-backup-excluded"),
+        mime: take_flag(args, "--mime"),
+        media: take_flag(args, "--media"),
+        phash: take_flag(args, "--phash"),
+        audio_fingerprint: take_flag(args, "--audio-fingerprint"),
+        fuzzy_hash: take_flag(args, "--fuzzy-hash"),
+        chunk_hashes: take_flag(args, "--chunk-hashes"),
+        no_hash: take_flag(args, "--no-hash"),
+        lazy_hash: take_flag(args, "--lazy-hash"),
+        ..filedb::CrawlOptions::default()
+    };
+    if let Some(depth) = take_option(args, "--archive-depth") {
+        options.archive_depth = match depth.parse::<usize>() {
+            Ok(depth) if depth > 0 => depth,
+            _ => print_usage_and_exit_with_error(),
+        };
+    }
+    if let Some(max_size) = take_option(args, "--archive-max-nested-size") {
+        options.archive_max_nested_size = match filedb::parse_size(&max_size) {
+            Some(max_size) => max_size,
+            None => print_usage_and_exit_with_error(),
+        };
+    }
+    if let Some(name) = take_option(args, "--hash-algorithm") {
+        match filedb::HashAlgorithm::from_name(&name) {
+            Some(hash_algorithm) => options.hash_algorithm = Some(hash_algorithm),
+            None => print_usage_and_exit_with_error(),
+        }
+    }
+    options.error_report = take_option(args, "--error-report").map(PathBuf::from);
+    while let Some(pattern) = take_option(args, "--exclude") {
+        match glob::Pattern::new(&pattern) {
+            Ok(pattern) => options.excludes.push(pattern),
+            Err(_) => print_usage_and_exit_with_error(),
+        }
+    }
+    if let Some(min_size) = take_option(args, "--chunk-min-file-size") {
+        options.chunk_min_file_size = match filedb::parse_size(&min_size) {
+            Some(min_size) => min_size,
+            None => print_usage_and_exit_with_error(),
+        };
+    }
+    let limits = [
+        ("--archive-max-size", &mut options.archive_max_size),
+        ("--archive-max-entries", &mut options.archive_max_entries),
+        ("--archive-max-ratio", &mut options.archive_max_ratio),
+    ];
+    for (name, limit) in limits {
+        if let Some(value) = take_option(args, name) {
+            *limit = match filedb::parse_size(&value) {
+                Some(value) => value,
+                None => print_usage_and_exit_with_error(),
+            };
+        }
+    }
+    options
+}
+
+// Crawl options of the config not given on the command line. They are parsed on their own
+// first, so unknown ones are not taken for paths.
+fn add_config_crawl_args(args: &mut Vec<String>, config: &filedb::Config)
+{
+    let config_args = match config.get_crawl_args() {
+        Ok(config_args) => config_args,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let mut unknown_args = config_args
+        .iter()
+        .flat_map(|(name, value)| std::iter::once(name.clone()).chain(value.clone()))
+        .collect::<Vec<_>>();
+    take_crawl_options(&mut unknown_args);
+    if let Some(arg) = unknown_args.first() {
+        eprintln!("Unknown crawl option {} in config", arg);
+        process::exit(1);
+    }
+    let given_args = args.clone();
+    for (name, value) in config_args {
+        if !given_args.contains(&name) {
+            args.extend(std::iter::once(name).chain(value));
+        }
+    }
+}
+
+fn take_exec(args: &mut Vec<String>) -> Option<filedb::ExecCommand>
+{
+    let command = take_option(args, "--exec")?;
+    let jobs = match take_option(args, "--jobs").map(|jobs| jobs.parse()) {
+        Some(Ok(jobs)) if jobs > 0 => jobs,
+        Some(_) => print_usage_and_exit_with_error(),
+        None => std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+    };
+    Some(filedb::ExecCommand { command, jobs })
+}
+
+fn take_dupe_filter(args: &mut Vec<String>) -> filedb::DupeFilter
+{
+    let mut filter = filedb::DupeFilter::default();
+    let thresholds = [
+        ("--min-size", &mut filter.min_size),
+        ("--min-group-savings", &mut filter.min_group_savings),
+    ];
+    for (name, threshold) in thresholds {
+        if let Some(value) = take_option(args, name) {
+            *threshold = match filedb::parse_size(&value) {
+                Some(value) => value,
+                None => print_usage_and_exit_with_error(),
+            };
+        }
+    }
+    filter
+}
+
+fn take_dump_options(args: &mut Vec<String>) -> filedb::DumpOptions
+{
+    let mut options = filedb::DumpOptions {
+        prefix: take_option(args, "--prefix").map(std::path::PathBuf::from),
+        path_format: take_path_format(args),
+        ..Default::default()
+    };
+    options.is_dir = match take_option(args, "--type").as_deref() {
+        Some("f") => Some(false),
+        Some("d") => Some(true),
+        Some(_) => print_usage_and_exit_with_error(),
+        None => None,
+    };
+    options.max_depth = match take_option(args, "--max-depth").map(|depth| depth.parse()) {
+        Some(Ok(max_depth)) => Some(max_depth),
+        Some(Err(_)) => print_usage_and_exit_with_error(),
+        None => None,
+    };
+    if let Some(size) = take_option(args, "--min-size") {
+        options.min_size = match filedb::parse_size(&size) {
+            Some(min_size) => min_size,
+            None => print_usage_and_exit_with_error(),
+        };
+    }
+    options.sort = match take_option(args, "--sort").as_deref() {
+        Some("size") => Some(filedb::DumpOrder::Size),
+        Some("name") => Some(filedb::DumpOrder::Name),
+        Some("mtime") => Some(filedb::DumpOrder::Modified),
+        Some(_) => print_usage_and_exit_with_error(),
+        None => None,
+    };
+    options
+}
+
+// Keep rules in the order given, the first one has the highest priority
+fn take_keep_rules(args: &mut Vec<String>) -> Vec<filedb::KeepRule>
+{
+    let mut keep_rules = vec![];
+    let mut pos = 0;
+    while pos < args.len() {
+        let rule = match args[pos].as_str() {
+            "--keep-path-prefix" if pos + 1 < args.len() => {
+                let prefix = args.remove(pos + 1);
+                filedb::KeepRule::PathPrefix(prefix.into())
+            }
+            "--keep-path-prefix" => print_usage_and_exit_with_error(),
+            "--keep-newest" => filedb::KeepRule::Newest,
+            "--keep-oldest" => filedb::KeepRule::Oldest,
+            "--keep-shortest-path" => filedb::KeepRule::ShortestPath,
+            _ => {
+                pos += 1;
+                continue;
+            }
+        };
+        args.remove(pos);
+        keep_rules.push(rule);
+    }
+    keep_rules
+}
+
+// +value: greater, -value: less, value: equal
+fn parse_comparison(value: &str) -> (std::cmp::Ordering, &str)
+{
+    match value.split_at(value.len().min(1)) {
+        ("+", rest) => (std::cmp::Ordering::Greater, rest),
+        ("-", rest) => (std::cmp::Ordering::Less, rest),
+        _ => (std::cmp::Ordering::Equal, value),
+    }
+}
+
+// -0 or --escape, not both
+fn take_path_format(args: &mut Vec<String>) -> filedb::PathFormat
+{
+    match (take_flag(args, "-0"), take_flag(args, "--escape")) {
+        (false, false) => filedb::PathFormat::Lines,
+        (true, false) => filedb::PathFormat::NullTerminated,
+        (false, true) => filedb::PathFormat::Escaped,
+        (true, true) => print_usage_and_exit_with_error(),
+    }
+}
+
+// All args after the command are predicates, --or separates alternatives
+fn take_find_expression(args: &mut Vec<String>) -> filedb::FindExpression
+{
+    let mut expression = vec![vec![]];
+    while args.len() > 3 {
+        let arg = args.remove(3);
+        if arg == "--and" {
+            continue;
+        }
+        if arg == "--or" {
+            if expression.last().unwrap().is_empty() || args.len() == 3 {
+                print_usage_and_exit_with_error();
+            }
+            expression.push(vec![]);
+            continue;
+        }
+        if args.len() == 3 {
+            print_usage_and_exit_with_error();
+        }
+        let value = args.remove(3);
+        let pattern =
+            || glob::Pattern::new(&value).unwrap_or_else(|_| print_usage_and_exit_with_error());
+        let (ordering, number) = parse_comparison(&value);
+        let predicate = match arg.as_str() {
+            "--name" => filedb::FindPredicate::Name(pattern()),
+            "--iname" => filedb::FindPredicate::IName(pattern()),
+            "--size" => match filedb::parse_size(number) {
+                Some(size) => filedb::FindPredicate::Size(ordering, size),
+                None => print_usage_and_exit_with_error(),
+            },
+            "--mtime" => match filedb::parse_duration(number) {
+                Some(age) if ordering != std::cmp::Ordering::Equal => {
+                    filedb::FindPredicate::Age(ordering, age)
+                }
+                _ => print_usage_and_exit_with_error(),
+            },
+            "--type" if value == "f" => filedb::FindPredicate::IsDir(false),
+            "--type" if value == "d" => filedb::FindPredicate::IsDir(true),
+            "--path-prefix" => filedb::FindPredicate::PathPrefix(value.into()),
+            "--mime" => filedb::FindPredicate::Mime(pattern()),
+            _ => print_usage_and_exit_with_error(),
+        };
+        expression.last_mut().unwrap().push(predicate);
+    }
+    expression
+}
+
+fn take_remove_target(args: &mut Vec<String>) -> filedb::RemoveTarget
+{
+    let trash = take_flag(args, "--trash");
+    match (trash, take_option(args, "--trash-dir")) {
+        (false, None) => filedb::RemoveTarget::Delete,
+        (true, None) => filedb::RemoveTarget::Trash,
+        (false, Some(trash_dir)) => filedb::RemoveTarget::Quarantine(trash_dir.into()),
+        (true, Some(_)) => print_usage_and_exit_with_error(),
+    }
+}
+
+fn lock_or_exit(db_file_name: &Path, wait: bool) -> filedb::DbLock
+{
+    match filedb::lock_db(db_file_name, wait) {
+        Some(lock) => lock,
+        None => {
+            eprintln!(
+                "Database {:?} is in use by another filedb process (use --wait to wait for it)",
+                db_file_name
+            );
+            process::exit(1);
+        }
+    }
+}
+
+// Commands after path_to_filedb, which may be left out if the config has a db
+const COMMANDS: [&str; 47] = [
+    "add",
+    "update",
+    "daemon",
+    "collect",
+    "import-rclone",
+    "watch",
+    "dedup",
+    "apply-plan",
+    "dedup_move_dupes",
+    "all_files_elsewhere",
+    "all_files_elsewhere_remove_dupes",
+    "sync-missing",
+    "verify",
+    "hash",
+    "rehash",
+    "stats",
+    "media",
+    "similar-images",
+    "similar-audio",
+    "near-dupes",
+    "dedup-dirs",
+    "similar-dirs",
+    "chunk-dupes",
+    "sparse",
+    "cold-files",
+    "mv",
+    "rm_recursive",
+    "forget",
+    "restore",
+    "browse",
+    "serve",
+    "find",
+    "ls",
+    "tree",
+    "du",
+    "has",
+    "partition",
+    "dump",
+    "dump_full",
+    "root",
+    "rebase",
+    "links",
+    "roots",
+    "changes",
+    "compact",
+    "snapshots",
+    "snapshot",
+];
+
+// dedup always counts, as it saves the hashes of files added with --lazy-hash it computes.
+// daemon locks the db itself for each update.
+fn is_mutating_command(command: &str) -> bool
+{
+    matches!(
+        command,
+        "add"
+            | "update"
+            | "collect"
+            | "import-rclone"
+            | "dedup"
+            | "dedup_move_dupes"
+            | "apply-plan"
+            | "all_files_elsewhere_remove_dupes"
+            | "mv"
+            | "rm_recursive"
+            | "forget"
+            | "verify"
+            | "hash"
+            | "rehash"
+            | "watch"
+            | "snapshot"
+            | "restore"
+            | "partition"
+            | "root"
+            | "rebase"
+            | "compact"
+    )
+}
+
+// Commands operating on several dbs, given as filedb <command> db1 db2 ...
+// Returns false if args[1] is not such a command
+fn run_multi_db_command(args: &mut Vec<String>, wait: bool, snapshot: Option<&str>) -> bool
+{
+    match args[1].as_str() {
+        "diff" => {
+            let summary = take_flag(args, "--summary");
+            let json = take_flag(args, "--json");
+            let snapshot_a = take_option(args, "--snapshot-a");
+            let snapshot_b = take_option(args, "--snapshot-b");
+            if args.len() != 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            filedb::diff(
+                Path::new(&args[2]),
+                snapshot_a.as_deref(),
+                Path::new(&args[3]),
+                snapshot_b.as_deref(),
+                summary,
+                json,
+            );
+        }
+        "merge" => {
+            if args.len() < 5 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let out_file_db_name = Path::new(&args[2]);
+            let _lock = lock_or_exit(out_file_db_name, wait);
+            let in_file_db_names = args[3..].iter().map(Path::new).collect::<Vec<_>>();
+            filedb::merge(out_file_db_name, &in_file_db_names);
+        }
+        "intersect" | "subtract" | "union-by-hash" => {
+            let operation = match args[1].as_str() {
+                "intersect" => filedb::SetOperation::Intersect,
+                "subtract" => filedb::SetOperation::Subtract,
+                _ => filedb::SetOperation::UnionByHash,
+            };
+            let output = take_option(args, "--output");
+            if args.len() != 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let _lock = output.as_ref().map(|output| lock_or_exit(Path::new(output), wait));
+            filedb::set_operation(
+                Path::new(&args[2]),
+                Path::new(&args[3]),
+                operation,
+                output.as_deref().map(Path::new),
+            );
+        }
+        _ => return false,
+    }
+    true
+}
+
 fn main()
 {
-    let args = env::args().collect::<Vec<_>>();
-    if args.len() < 2 {
+    let mut args = env::args().collect::<Vec<_>>();
+    let config_name = take_option(&mut args, "--config");
+    let config = match filedb::load_config(config_name.as_deref().map(Path::new)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let wait = take_flag(&mut args, "--wait") || config.wait;
+    let dry_run = take_flag(&mut args, "--dry-run");
+    filedb::set_no_cache(take_flag(&mut args, "--no-cache") || config.no_cache);
+    let limit_rate = take_option(&mut args, "--limit-rate").or_else(|| config.limit_rate.clone());
+    if let Some(limit_rate) = limit_rate {
+        match filedb::parse_size(&limit_rate) {
+            Some(limit_rate) => filedb::set_limit_rate(limit_rate),
+            None => print_usage_and_exit_with_error(),
+        }
+    }
+    let threads = take_option(&mut args, "--threads").map(|threads| threads.parse::<usize>());
+    match threads.or_else(|| config.threads.map(Ok)) {
+        Some(Ok(threads)) if threads > 0 => {
+            if let Err(err) = filedb::set_threads(threads) {
+                eprintln!("Cannot use {} threads: {}", threads, err);
+                process::exit(1);
+            }
+        }
+        Some(_) => print_usage_and_exit_with_error(),
+        None => {}
+    }
+    let compression = take_option(&mut args, "--compression").map(|level| level.parse::<u32>());
+    match compression.or_else(|| config.compression.map(Ok)) {
+        Some(Ok(level)) if level <= 9 => filedb::set_compression(level),
+        Some(_) => print_usage_and_exit_with_error(),
+        None => {}
+    }
+    let mut root_overrides = vec![];
+    while let Some(root_override) = take_option(&mut args, "--root-override") {
+        match root_override.split_once('=') {
+            Some((name, path)) if Path::new(path).is_absolute() => {
+                root_overrides.push((name.to_string(), path.into()))
+            }
+            _ => print_usage_and_exit_with_error(),
+        }
+    }
+    filedb::set_root_overrides(root_overrides);
+    let webhook = take_option(&mut args, "--notify-webhook");
+    let command = take_option(&mut args, "--notify-command");
+    filedb::set_notify_targets(filedb::NotifyTargets {
+        webhook: webhook.or_else(|| config.notify_webhook.clone()),
+        command: command.or_else(|| config.notify_command.clone()),
+    });
+    let sign_key = take_option(&mut args, "--sign-key").map(PathBuf::from);
+    let trusted_key = take_option(&mut args, "--trusted-key").map(PathBuf::from);
+    filedb::set_signing_keys(filedb::SigningKeys {
+        sign_key: sign_key.or_else(|| config.sign_key.clone()),
+        trusted_key: trusted_key.or_else(|| config.trusted_key.clone()),
+    });
+    filedb::set_append_changes(take_flag(&mut args, "--append-changes") || config.append_changes);
+    let mut protected_patterns = vec![];
+    while let Some(pattern) = take_option(&mut args, "--protect") {
+        protected_patterns.push(pattern);
+    }
+    protected_patterns.extend(config.protect.iter().cloned());
+    let protected = match filedb::ProtectedPaths::new(&protected_patterns) {
+        Ok(protected) => protected,
+        Err(err) => {
+            eprintln!("Invalid protected path pattern: {}", err);
+            process::exit(1);
+        }
+    };
+    let remove_options = filedb::RemoveOptions {
+        dry_run,
+        protected,
+        ..filedb::RemoveOptions::default()
+    };
+    let snapshot_arg = take_option(&mut args, "--snapshot");
+    let snapshot = snapshot_arg.as_deref();
+    if args.len() >= 2 && args[1] == "agent" {
+        add_config_crawl_args(&mut args, &config);
+        let options = take_crawl_options(&mut args);
+        let connect = take_option(&mut args, "--connect");
+        if args.len() != 3 || snapshot.is_some() {
+            print_usage_and_exit_with_error();
+        }
+        if !filedb::agent(Path::new(&args[2]), &options, connect.as_deref()) {
+            process::exit(1);
+        }
+        return;
+    }
+    if args.len() >= 2 && run_multi_db_command(&mut args, wait, snapshot) {
+        return;
+    }
+    if let Some(db) = &config.db {
+        if args.len() >= 2 && COMMANDS.contains(&args[1].as_str()) {
+            args.insert(1, db.to_string_lossy().into_owned());
+        }
+    }
+    let json = take_flag(&mut args, "--json");
+    if args.len() < 3 {
         print_usage_and_exit_with_error();
     }
-    let db_file_name = &args[1];
-    let command = &args[2];
+    let db_file_name = args[1].clone();
+    let command = args[2].clone();
+    if json && !matches!(command.as_str(), "stats" | "dedup" | "all_files_elsewhere") {
+        print_usage_and_exit_with_error();
+    }
+    let _lock = if is_mutating_command(&command) {
+        Some(lock_or_exit(Path::new(&db_file_name), wait))
+    } else {
+        None
+    };
     match command.as_str() {
         "add" => {
+            let resume = take_flag(&mut args, "--resume");
+            add_config_crawl_args(&mut args, &config);
+            let options = take_crawl_options(&mut args);
+            let remote = take_option(&mut args, "--remote");
+            let prefix = take_option(&mut args, "--prefix");
+            if let Some(remote) = remote {
+                if resume || args.len() != 3 || snapshot.is_some() {
+                    print_usage_and_exit_with_error();
+                }
+                let db_file_name = Path::new(&db_file_name);
+                let prefix = prefix.as_deref().map(Path::new);
+                if !filedb::add_remote(db_file_name, &remote, prefix, &options) {
+                    process::exit(1);
+                }
+                return;
+            }
+            if (resume && args.len() != 4) || prefix.is_some() {
+                print_usage_and_exit_with_error();
+            }
             for root_path in args.iter().skip(3) {
-                filedb::add(Path::new(db_file_name), Path::new(root_path));
+                filedb::add(
+                    Path::new(&db_file_name),
+                    Path::new(root_path),
+                    snapshot,
+                    resume,
+                    &options,
+                );
+                if filedb::is_interrupted() {
+                    process::exit(130);
+                }
             }
         }
         "update" => {
-            if args.len() != 4 {
+            add_config_crawl_args(&mut args, &config);
+            let options = take_crawl_options(&mut args);
+            let prefix = take_option(&mut args, "--prefix");
+            let db_file_name = Path::new(&db_file_name);
+            match (args.get(3), prefix) {
+                (None, Some(prefix)) => {
+                    let prefix = Path::new(&prefix);
+                    if !filedb::update_prefix(db_file_name, prefix, snapshot, &options) {
+                        process::exit(1);
+                    }
+                }
+                (Some(root_dir), None) if args.len() == 4 => {
+                    filedb::update(db_file_name, Path::new(root_dir), snapshot, &options)
+                }
+                (None, None) => filedb::update_all_roots(db_file_name, snapshot, &options),
+                _ => print_usage_and_exit_with_error(),
+            }
+            if filedb::is_interrupted() {
+                process::exit(130);
+            }
+        }
+        "daemon" => {
+            add_config_crawl_args(&mut args, &config);
+            let options = take_crawl_options(&mut args);
+            let interval = take_option(&mut args, "--interval");
+            let interval = match filedb::parse_duration(interval.as_deref().unwrap_or("6h")) {
+                Some(interval) if interval > 0 => std::time::Duration::from_secs(interval),
+                _ => print_usage_and_exit_with_error(),
+            };
+            let listen = take_option(&mut args, "--listen");
+            if args.len() > 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let root_dir = args.get(3).map(Path::new);
+            let db_file_name = Path::new(&db_file_name);
+            if !filedb::daemon(db_file_name, root_dir, interval, listen.as_deref(), &options) {
+                process::exit(1);
+            }
+            if filedb::is_interrupted() {
+                process::exit(130);
+            }
+        }
+        "watch" => {
+            if args.len() != 4 || snapshot.is_some() {
                 print_usage_and_exit_with_error();
             }
-            let root_dir = Path::new(&args[3]);
-            filedb::update(Path::new(db_file_name), root_dir);
+            filedb::watch(Path::new(&db_file_name), Path::new(&args[3]));
         }
         "dedup" => {
-            filedb::dedup(Path::new(db_file_name), None);
+            let keep_rules = take_keep_rules(&mut args);
+            let filter = take_dupe_filter(&mut args);
+            if take_flag(&mut args, "--interactive") {
+                let options = filedb::RemoveOptions {
+                    verify_content: take_flag(&mut args, "--verify-content"),
+                    target: take_remove_target(&mut args),
+                    ..remove_options
+                };
+                if args.len() != 3 || snapshot.is_some() || json {
+                    print_usage_and_exit_with_error();
+                }
+                let db_file_name = Path::new(&db_file_name);
+                filedb::dedup_interactive(db_file_name, &keep_rules, &filter, &options);
+                return;
+            }
+            let plan_name = take_option(&mut args, "--plan");
+            let hardlink = take_flag(&mut args, "--hardlink");
+            let reflink = take_flag(&mut args, "--reflink");
+            let exec = take_exec(&mut args);
+            if let Some(plan_name) = plan_name {
+                if exec.is_some() {
+                    print_usage_and_exit_with_error();
+                }
+                let action = match (hardlink, reflink) {
+                    (false, false) => filedb::PlanAction::Remove,
+                    (true, false) => filedb::PlanAction::Hardlink,
+                    (false, true) => filedb::PlanAction::Reflink,
+                    (true, true) => print_usage_and_exit_with_error(),
+                };
+                if args.len() != 3 || snapshot.is_some() || json {
+                    print_usage_and_exit_with_error();
+                }
+                let plan_name = Path::new(&plan_name);
+                let db_file_name = Path::new(&db_file_name);
+                filedb::dedup_plan(db_file_name, plan_name, action, &keep_rules, &filter);
+                return;
+            }
+            let action = match (hardlink, reflink, &exec) {
+                (false, false, None) => filedb::DedupAction::Report,
+                (true, false, None) => filedb::DedupAction::Hardlink,
+                (false, true, None) => filedb::DedupAction::Reflink,
+                (false, false, Some(exec)) => filedb::DedupAction::Exec(exec),
+                _ => print_usage_and_exit_with_error(),
+            };
+            if action != filedb::DedupAction::Report && (snapshot.is_some() || json) {
+                print_usage_and_exit_with_error();
+            }
+            filedb::dedup(
+                Path::new(&db_file_name),
+                action,
+                &keep_rules,
+                &filter,
+                &remove_options,
+                json,
+                snapshot,
+            );
+        }
+        "apply-plan" => {
+            let options = filedb::RemoveOptions {
+                verify_content: take_flag(&mut args, "--verify-content"),
+                target: take_remove_target(&mut args),
+                ..remove_options
+            };
+            if args.len() != 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            if !filedb::apply_plan(Path::new(&db_file_name), Path::new(&args[3]), &options) {
+                process::exit(1);
+            }
         }
         "dedup_move_dupes" => {
-            let backup_dir = Path::new(&args[3]);
-            filedb::dedup(Path::new(db_file_name), Some(backup_dir));
+            let keep_rules = take_keep_rules(&mut args);
+            let filter = take_dupe_filter(&mut args);
+            if args.len() != 4 {
+                print_usage_and_exit_with_error();
+            }
+            let action = filedb::DedupAction::MoveDupes(Path::new(&args[3]));
+            filedb::dedup(
+                Path::new(&db_file_name),
+                action,
+                &keep_rules,
+                &filter,
+                &remove_options,
+                false,
+                snapshot,
+            );
         }
         "all_files_elsewhere" => {
+            let keep_rules = take_keep_rules(&mut args);
+            let against = take_option(&mut args, "--against");
+            let files_from = take_option(&mut args, "--output-files-from");
+            let pack = take_option(&mut args, "--pack");
+            let exec = take_exec(&mut args);
+            let path_format = take_path_format(&mut args);
+            // Only the missing paths are printed with -0 or --escape
+            let output = match path_format {
+                filedb::PathFormat::Lines if json => filedb::ElsewhereOutput::Json,
+                filedb::PathFormat::Lines => filedb::ElsewhereOutput::Report,
+                _ if json => print_usage_and_exit_with_error(),
+                _ => filedb::ElsewhereOutput::Quiet,
+            };
             if args.len() != 4 && args.len() != 5 {
                 print_usage_and_exit_with_error();
             }
+            if against.is_some() && !keep_rules.is_empty() {
+                print_usage_and_exit_with_error();
+            }
             let backup_dir = Path::new(&args[3]);
             let opt_other_dir = args.get(4).map(Path::new);
-            filedb::all_files_elsewhere(Path::new(db_file_name), backup_dir, opt_other_dir, false);
+            let missing = filedb::all_files_elsewhere(
+                Path::new(&db_file_name),
+                backup_dir,
+                opt_other_dir,
+                against.as_deref().map(Path::new),
+                false,
+                &keep_rules,
+                &filedb::RemoveOptions::default(),
+                output,
+                snapshot,
+            );
+            if output == filedb::ElsewhereOutput::Quiet {
+                filedb::print_path_list(&missing, path_format);
+            }
+            if let Some(list_name) = files_from {
+                let list_name = Path::new(&list_name);
+                if let Err(err) = filedb::write_files_from(list_name, backup_dir, &missing) {
+                    eprintln!("Error writing {:?}: {}", list_name, err);
+                    process::exit(1);
+                }
+            }
+            if let Some(archive_name) = pack {
+                let archive_name = Path::new(&archive_name);
+                println!("Packing missing files into {:?}", archive_name);
+                if let Err(err) = filedb::pack_files(archive_name, backup_dir, &missing) {
+                    eprintln!("Error packing {:?}: {}", archive_name, err);
+                    process::exit(1);
+                }
+            }
+            if let Some(exec) = exec {
+                if !filedb::exec_for_paths(&exec, &missing, dry_run) {
+                    process::exit(1);
+                }
+            }
+            if !missing.is_empty() {
+                process::exit(1);
+            }
         }
         "all_files_elsewhere_remove_dupes" => {
+            let keep_rules = take_keep_rules(&mut args);
+            let options = filedb::RemoveOptions {
+                verify_content: take_flag(&mut args, "--verify-content"),
+                target: take_remove_target(&mut args),
+                ..remove_options
+            };
             if args.len() != 4 {
                 print_usage_and_exit_with_error();
             }
             let backup_dir = Path::new(&args[3]);
-            filedb::all_files_elsewhere(Path::new(db_file_name), backup_dir, None, true);
+            filedb::all_files_elsewhere(
+                Path::new(&db_file_name),
+                backup_dir,
+                None,
+                None,
+                true,
+                &keep_rules,
+                &options,
+                filedb::ElsewhereOutput::Report,
+                snapshot,
+            );
+        }
+        "sync-missing" => {
+            let against = take_option(&mut args, "--against");
+            if args.len() != 5 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let all_copied = filedb::sync_missing(
+                Path::new(&db_file_name),
+                Path::new(&args[3]),
+                Path::new(&args[4]),
+                against.as_deref().map(Path::new),
+                dry_run,
+            );
+            if filedb::is_interrupted() {
+                process::exit(130);
+            }
+            if !all_copied {
+                process::exit(1);
+            }
+        }
+        "verify" => {
+            let sample_arg = take_option(&mut args, "--sample");
+            let max_bytes_arg = take_option(&mut args, "--max-bytes");
+            if args.len() != 3 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let sample = match (sample_arg, max_bytes_arg) {
+                (None, None) => filedb::VerifySample::All,
+                (Some(percent), None) => match percent.trim_end_matches('%').parse::<f64>() {
+                    Ok(percent) if percent > 0.0 && percent <= 100.0 => {
+                        filedb::VerifySample::Percent(percent)
+                    }
+                    _ => print_usage_and_exit_with_error(),
+                },
+                (None, Some(max_bytes)) => match filedb::parse_size(&max_bytes) {
+                    Some(max_bytes) => filedb::VerifySample::MaxBytes(max_bytes),
+                    None => print_usage_and_exit_with_error(),
+                },
+                _ => print_usage_and_exit_with_error(),
+            };
+            filedb::verify(Path::new(&db_file_name), sample, None);
+        }
+        "hash" => {
+            let parse_size = |size: Option<String>| {
+                size.map(|size| match filedb::parse_size(&size) {
+                    Some(size) => size,
+                    None => print_usage_and_exit_with_error(),
+                })
+            };
+            let min_size = parse_size(take_option(&mut args, "--min-size"));
+            let max_size = parse_size(take_option(&mut args, "--max-size"));
+            if args.len() > 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let all_hashed = filedb::hash_missing(
+                Path::new(&db_file_name),
+                args.get(3).map(Path::new),
+                min_size.unwrap_or(0),
+                max_size,
+            );
+            if filedb::is_interrupted() {
+                process::exit(130);
+            }
+            if !all_hashed {
+                process::exit(1);
+            }
+        }
+        "rehash" => {
+            let only_empty = take_flag(&mut args, "--only-empty");
+            let selection = match (only_empty, take_flag(&mut args, "--all")) {
+                (false, false) => filedb::RehashSelection::Stale,
+                (true, false) => filedb::RehashSelection::OnlyEmpty,
+                (false, true) => filedb::RehashSelection::All,
+                (true, true) => print_usage_and_exit_with_error(),
+            };
+            if args.len() > 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let all_hashed =
+                filedb::rehash(Path::new(&db_file_name), args.get(3).map(Path::new), selection);
+            if filedb::is_interrupted() {
+                process::exit(130);
+            }
+            if !all_hashed {
+                process::exit(1);
+            }
         }
         "stats" => {
+            let options = filedb::StatsOptions {
+                by_extension: take_flag(&mut args, "--by-extension"),
+                size_histogram: take_flag(&mut args, "--size-histogram"),
+                age: take_flag(&mut args, "--age"),
+                by_owner: take_flag(&mut args, "--by-owner"),
+                by_host: take_flag(&mut args, "--by-host"),
+                json,
+            };
+            let db_file_name = Path::new(&db_file_name);
             if args.len() == 3 {
-                filedb::stats(Path::new(db_file_name), None);
+                filedb::stats(db_file_name, None, &options, snapshot);
             } else {
                 for root_path in args.iter().skip(3) {
-                    filedb::stats(Path::new(db_file_name), Some(Path::new(root_path)));
+                    filedb::stats(db_file_name, Some(Path::new(root_path)), &options, snapshot);
                 }
             }
         }
+        "media" => {
+            let same_shot = take_flag(&mut args, "--same-shot");
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::media(Path::new(&db_file_name), prefix, same_shot, snapshot);
+        }
+        "similar-images" => {
+            let max_distance =
+                match take_option(&mut args, "--max-distance").map(|distance| distance.parse()) {
+                    Some(Ok(max_distance)) if max_distance <= 64 => max_distance,
+                    Some(_) => print_usage_and_exit_with_error(),
+                    None => 4,
+                };
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::similar_images(Path::new(&db_file_name), prefix, max_distance, snapshot);
+        }
+        "similar-audio" => {
+            let max_error = match take_option(&mut args, "--max-error").map(|ratio| ratio.parse()) {
+                Some(Ok(max_error)) if (0.0..=1.0).contains(&max_error) => max_error,
+                Some(_) => print_usage_and_exit_with_error(),
+                None => 0.15,
+            };
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::similar_audio(Path::new(&db_file_name), prefix, max_error, snapshot);
+        }
+        "near-dupes" => {
+            let threshold = match take_option(&mut args, "--threshold").map(|n| n.parse()) {
+                Some(Ok(threshold)) if threshold <= 100 => threshold,
+                Some(_) => print_usage_and_exit_with_error(),
+                None => 60,
+            };
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::near_dupes(Path::new(&db_file_name), prefix, threshold, snapshot);
+        }
+        "dedup-dirs" => {
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::dedup_dirs(Path::new(&db_file_name), prefix, snapshot);
+        }
+        "similar-dirs" => {
+            let min_similarity =
+                match take_option(&mut args, "--min-similarity").map(|pct| pct.parse()) {
+                    Some(Ok(min_similarity)) if (0.0..=100.0).contains(&min_similarity) => {
+                        min_similarity
+                    }
+                    Some(_) => print_usage_and_exit_with_error(),
+                    None => 80.0,
+                };
+            let min_files = match take_option(&mut args, "--min-files").map(|n| n.parse()) {
+                Some(Ok(min_files)) => min_files,
+                Some(Err(_)) => print_usage_and_exit_with_error(),
+                None => 10,
+            };
+            let dirs = match args.len() {
+                3 => None,
+                5 => Some((Path::new(&args[3]), Path::new(&args[4]))),
+                _ => print_usage_and_exit_with_error(),
+            };
+            let db_file_name = Path::new(&db_file_name);
+            if !filedb::similar_dirs(db_file_name, dirs, min_similarity, min_files, snapshot) {
+                process::exit(1);
+            }
+        }
+        "chunk-dupes" => {
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::chunk_dupes(Path::new(&db_file_name), prefix, snapshot);
+        }
+        "sparse" => {
+            let min_size = match take_option(&mut args, "--min-size") {
+                Some(size) => match filedb::parse_size(&size) {
+                    Some(min_size) => min_size,
+                    None => print_usage_and_exit_with_error(),
+                },
+                None => 1 << 20,
+            };
+            let min_ratio = match take_option(&mut args, "--min-ratio").map(|ratio| ratio.parse()) {
+                Some(Ok(min_ratio)) if min_ratio > 0.0 => min_ratio,
+                Some(_) => print_usage_and_exit_with_error(),
+                None => 2.0,
+            };
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::sparse(Path::new(&db_file_name), prefix, min_size, min_ratio, snapshot);
+        }
+        "cold-files" => {
+            let min_age = match take_option(&mut args, "--older-than") {
+                Some(duration) => match filedb::parse_duration(&duration) {
+                    Some(min_age) => min_age,
+                    None => print_usage_and_exit_with_error(),
+                },
+                None => 2 * 365 * 24 * 60 * 60,
+            };
+            let limit = match take_option(&mut args, "--limit").map(|limit| limit.parse()) {
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => print_usage_and_exit_with_error(),
+                None => 100,
+            };
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let prefix = args.get(3).map(Path::new);
+            filedb::cold_files(Path::new(&db_file_name), prefix, min_age, limit, snapshot);
+        }
         "mv" => {
-            if args.len() != 5 {
+            let db_only = take_flag(&mut args, "--db-only");
+            if args.len() != 5 || snapshot.is_some() {
                 print_usage_and_exit_with_error();
             }
             let from_dir = Path::new(&args[3]);
             let to_dir = Path::new(&args[4]);
-            filedb::mv(Path::new(db_file_name), from_dir, to_dir);
+            if !db_only {
+                filedb::mv(Path::new(&db_file_name), from_dir, to_dir, dry_run);
+            } else if !filedb::mv_db_only(Path::new(&db_file_name), from_dir, to_dir, dry_run) {
+                process::exit(1);
+            }
         }
         "rm_recursive" => {
-            if args.len() != 4 {
+            let options = filedb::RemoveOptions {
+                target: take_remove_target(&mut args),
+                ..remove_options
+            };
+            if args.len() != 4 || snapshot.is_some() {
                 print_usage_and_exit_with_error();
             }
             let rm_path = Path::new(&args[3]);
-            filedb::rm_recursive(Path::new(db_file_name), rm_path);
+            filedb::rm_recursive(Path::new(&db_file_name), rm_path, &options);
+        }
+        "forget" => {
+            if args.len() != 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            if !filedb::forget(Path::new(&db_file_name), Path::new(&args[3]), dry_run) {
+                process::exit(1);
+            }
+        }
+        "restore" => {
+            if args.len() != 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            filedb::restore(Path::new(&args[3]), dry_run);
+        }
+        "browse" => {
+            if args.len() != 3 {
+                print_usage_and_exit_with_error();
+            }
+            filedb::browse(Path::new(&db_file_name), snapshot);
+        }
+        "serve" => {
+            let listen = take_option(&mut args, "--listen");
+            if args.len() != 3 {
+                print_usage_and_exit_with_error();
+            }
+            let listen = listen.as_deref().unwrap_or("127.0.0.1:8080");
+            filedb::serve(Path::new(&db_file_name), listen, snapshot);
+        }
+        "find" => {
+            let path_format = take_path_format(&mut args);
+            let expression = take_find_expression(&mut args);
+            filedb::find(Path::new(&db_file_name), &expression, path_format, snapshot);
+        }
+        "ls" => {
+            if args.len() != 4 {
+                print_usage_and_exit_with_error();
+            }
+            if !filedb::ls(Path::new(&db_file_name), Path::new(&args[3]), snapshot) {
+                process::exit(1);
+            }
+        }
+        "tree" => {
+            let max_depth = match take_option(&mut args, "--depth").map(|depth| depth.parse()) {
+                Some(Ok(max_depth)) => Some(max_depth),
+                Some(Err(_)) => print_usage_and_exit_with_error(),
+                None => None,
+            };
+            let min_size = match take_option(&mut args, "--min-size") {
+                Some(size) => match filedb::parse_size(&size) {
+                    Some(min_size) => min_size,
+                    None => print_usage_and_exit_with_error(),
+                },
+                None => 0,
+            };
+            if args.len() != 4 {
+                print_usage_and_exit_with_error();
+            }
+            let path = Path::new(&args[3]);
+            if !filedb::tree(Path::new(&db_file_name), path, max_depth, min_size, snapshot) {
+                process::exit(1);
+            }
+        }
+        "du" => {
+            let max_depth = match take_option(&mut args, "--max-depth").map(|depth| depth.parse()) {
+                Some(Ok(max_depth)) => Some(max_depth),
+                Some(Err(_)) => print_usage_and_exit_with_error(),
+                None => None,
+            };
+            let human_readable = take_flag(&mut args, "-h");
+            let apparent_size = take_flag(&mut args, "--apparent-size");
+            if args.len() > 4 {
+                print_usage_and_exit_with_error();
+            }
+            let path = Path::new(args.get(3).map_or("/", |path| path.as_str()));
+            let db_file_name = Path::new(&db_file_name);
+            if !filedb::du(db_file_name, path, max_depth, human_readable, apparent_size, snapshot)
+            {
+                process::exit(1);
+            }
+        }
+        "has" => {
+            if args.len() != 4 {
+                print_usage_and_exit_with_error();
+            }
+            if !filedb::has(Path::new(&db_file_name), &args[3], snapshot) {
+                process::exit(1);
+            }
+        }
+        "partition" => {
+            if args.len() != 3 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            filedb::partition(Path::new(&db_file_name));
+        }
+        "dump" | "dump_full" => {
+            let options = take_dump_options(&mut args);
+            if args.len() != 3 {
+                print_usage_and_exit_with_error();
+            }
+            if command == "dump" {
+                filedb::dump(Path::new(&db_file_name), snapshot, &options);
+            } else {
+                filedb::dump_full(Path::new(&db_file_name), snapshot, &options);
+            }
+        }
+        "root" => {
+            if snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let db_file_name = Path::new(&db_file_name);
+            match args.get(3).map(String::as_str) {
+                Some("add") if args.len() == 6 => {
+                    filedb::name_root(db_file_name, &args[4], Path::new(&args[5]))
+                }
+                Some("remove") if args.len() == 5 => {
+                    if !filedb::remove_root(db_file_name, &args[4]) {
+                        process::exit(1);
+                    }
+                }
+                _ => print_usage_and_exit_with_error(),
+            }
+        }
+        "collect" => {
+            let listen = take_option(&mut args, "--listen");
+            let ssh = take_option(&mut args, "--ssh");
+            let prefix = take_option(&mut args, "--prefix");
+            let agent_args = args.split_off(3);
+            let source = match (&listen, &ssh) {
+                (None, None) if agent_args.is_empty() => filedb::AgentSource::Stdin,
+                (Some(listen), None) if agent_args.is_empty() => {
+                    filedb::AgentSource::Listen(listen)
+                }
+                (None, Some(host)) if !agent_args.is_empty() => {
+                    filedb::AgentSource::Ssh(host, &agent_args)
+                }
+                _ => print_usage_and_exit_with_error(),
+            };
+            if snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let db_file_name = Path::new(&db_file_name);
+            if !filedb::collect(db_file_name, source, prefix.as_deref().map(Path::new)) {
+                process::exit(1);
+            }
+        }
+        "import-rclone" => {
+            let prefix = take_option(&mut args, "--prefix");
+            let hash_algorithm = take_option(&mut args, "--hash-algorithm").map(|name| {
+                filedb::HashAlgorithm::from_name(&name)
+                    .unwrap_or_else(|| print_usage_and_exit_with_error())
+            });
+            if args.len() != 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            if !filedb::import_rclone(
+                Path::new(&db_file_name),
+                Path::new(&args[3]),
+                prefix.as_deref().map(Path::new),
+                hash_algorithm,
+            ) {
+                process::exit(1);
+            }
+        }
+        "rebase" => {
+            if args.len() != 5 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            let db_file_name = Path::new(&db_file_name);
+            if !filedb::rebase(db_file_name, Path::new(&args[3]), Path::new(&args[4])) {
+                process::exit(1);
+            }
+        }
+        "links" => {
+            if args.len() > 4 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            filedb::links(Path::new(&db_file_name), args.get(3).map(Path::new));
+        }
+        "roots" => {
+            if args.len() != 3 {
+                print_usage_and_exit_with_error();
+            }
+            filedb::roots_list(Path::new(&db_file_name));
+        }
+        "changes" => {
+            let paths = take_flag(&mut args, "--paths");
+            if args.len() != 3 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            if !filedb::list_changes(Path::new(&db_file_name), paths) {
+                process::exit(1);
+            }
+        }
+        "compact" => {
+            if args.len() != 3 || snapshot.is_some() {
+                print_usage_and_exit_with_error();
+            }
+            filedb::compact(Path::new(&db_file_name));
+        }
+        "snapshots" => {
+            if args.len() != 4 || args[3] != "list" {
+                print_usage_and_exit_with_error();
+            }
+            filedb::snapshots_list(Path::new(&db_file_name));
+        }
+        "snapshot" => {
+            if args.len() != 5 || args[3] != "delete" {
+                print_usage_and_exit_with_error();
+            }
+            filedb::snapshot_delete(Path::new(&db_file_name), &args[4]);
         }
-        "dump" => filedb::dump(Path::new(db_file_name)),
-        "dump_full" => filedb::dump_full(Path::new(db_file_name)),
         _ => print_usage_and_exit_with_error(),
     }
 }
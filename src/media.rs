@@ -0,0 +1,193 @@
+// Capture date, camera and dimensions of photos and videos, read from their EXIF data or
+// from the headers of MP4/QuickTime files. Perceptual hashes of images.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Seconds from 1904-01-01, the epoch of MP4 timestamps, to 1970-01-01
+const MP4_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Eq, Debug)]
+pub struct MediaInfo
+{
+    // As YYYY-MM-DD HH:MM:SS, in the local time of the camera for EXIF, UTC for videos
+    pub taken: Option<String>,
+    pub camera: Option<String>,
+    // 0 if unknown
+    pub width: u32,
+    pub height: u32,
+}
+
+fn get_exif_string(exif: &exif::Exif, tag: exif::Tag) -> Option<String>
+{
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    match &field.value {
+        exif::Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.to_string())
+            }
+        }
+        _ => None,
+    }
+}
+
+fn read_image_info(path: &Path) -> Option<MediaInfo>
+{
+    let mut info = MediaInfo::default();
+    if let Ok(size) = imagesize::size(path) {
+        info.width = size.width as u32;
+        info.height = size.height as u32;
+    }
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut reader) {
+        let taken = get_exif_string(&exif, exif::Tag::DateTimeOriginal)
+            .or_else(|| get_exif_string(&exif, exif::Tag::DateTime));
+        info.taken = taken.and_then(|taken| {
+            let taken = exif::DateTime::from_ascii(taken.as_bytes()).ok()?;
+            Some(format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                taken.year, taken.month, taken.day, taken.hour, taken.minute, taken.second
+            ))
+        });
+        let make = get_exif_string(&exif, exif::Tag::Make);
+        let model = get_exif_string(&exif, exif::Tag::Model);
+        info.camera = match (make, model) {
+            // Models mostly repeat the make, as in Canon / Canon EOS 5D
+            (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.or(model),
+        };
+    }
+    if info == MediaInfo::default() {
+        None
+    } else {
+        Some(info)
+    }
+}
+
+// Returns type, start and end of the content of the box at the current position
+fn read_box_header<R: Read + Seek>(reader: &mut R, end: u64) -> io::Result<([u8; 4], u64, u64)>
+{
+    let start = reader.stream_position()?;
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let box_type = [header[4], header[5], header[6], header[7]];
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let (content_start, box_end) = match size {
+        0 => (start + 8, end),
+        1 => {
+            let mut size = [0u8; 8];
+            reader.read_exact(&mut size)?;
+            (start + 16, start + u64::from_be_bytes(size))
+        }
+        size => (start + 8, start + size),
+    };
+    if box_end < content_start || box_end > end {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid box size"));
+    }
+    Ok((box_type, content_start, box_end))
+}
+
+// Position of the content and end of the first box of box_type between the current position
+// and end
+fn find_box<R: Read + Seek>(reader: &mut R, box_type: &[u8; 4], end: u64)
+    -> io::Result<Option<(u64, u64)>>
+{
+    while reader.stream_position()? + 8 <= end {
+        let (found_type, content_start, box_end) = read_box_header(reader, end)?;
+        if &found_type == box_type {
+            reader.seek(SeekFrom::Start(content_start))?;
+            return Ok(Some((content_start, box_end)));
+        }
+        reader.seek(SeekFrom::Start(box_end))?;
+    }
+    Ok(None)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32>
+{
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64>
+{
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+// Creation time from the movie header, dimensions from the first track with any
+pub(crate) fn read_mp4_info<R: Read + Seek>(reader: &mut R) -> io::Result<Option<MediaInfo>>
+{
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let (moov_start, moov_end) = match find_box(reader, b"moov", end)? {
+        Some(moov) => moov,
+        None => return Ok(None),
+    };
+    let mut info = MediaInfo::default();
+    if find_box(reader, b"mvhd", moov_end)?.is_some() {
+        let version = read_u32(reader)? >> 24;
+        let created = if version == 1 { read_u64(reader)? } else { read_u32(reader)? as u64 };
+        if created > MP4_EPOCH_OFFSET {
+            let created = chrono::DateTime::from_timestamp((created - MP4_EPOCH_OFFSET) as i64, 0);
+            info.taken = created.map(|created| created.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+    }
+    reader.seek(SeekFrom::Start(moov_start))?;
+    while let Some((_, trak_end)) = find_box(reader, b"trak", moov_end)? {
+        if find_box(reader, b"tkhd", trak_end)?.is_some() {
+            let version = read_u32(reader)? >> 24;
+            // Times, track id and duration, reserved, layer, group, volume and matrix
+            let skip = if version == 1 { 32 } else { 20 } + 8 + 8 + 36;
+            reader.seek(SeekFrom::Current(skip))?;
+            // 16.16 fixed point
+            let width = read_u32(reader)? >> 16;
+            let height = read_u32(reader)? >> 16;
+            if width > 0 && height > 0 {
+                info.width = width;
+                info.height = height;
+                break;
+            }
+        }
+        reader.seek(SeekFrom::Start(trak_end))?;
+    }
+    Ok(Some(info))
+}
+
+// None if path is neither a photo nor a video, or its metadata cannot be read
+pub fn read_media_info(path: &Path) -> Option<MediaInfo>
+{
+    let kind = infer::get_from_path(path).ok()??;
+    match (kind.matcher_type(), kind.mime_type()) {
+        (infer::MatcherType::Image, _) => read_image_info(path),
+        (infer::MatcherType::Video, "video/mp4" | "video/quicktime" | "video/x-m4v") => {
+            let mut reader = BufReader::new(File::open(path).ok()?);
+            read_mp4_info(&mut reader).ok()?
+        }
+        _ => None,
+    }
+}
+
+// Difference hash: Each bit tells whether a pixel of the image scaled down to 9x8 gray pixels
+// is brighter than its right neighbor. Resized and re-encoded copies differ in few bits.
+pub fn get_image_hash(path: &Path) -> Option<u64>
+{
+    let reader = image::ImageReader::open(path).ok()?.with_guessed_format().ok()?;
+    let small = reader.decode().ok()?.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | brighter as u64;
+        }
+    }
+    Some(hash)
+}
@@ -0,0 +1,213 @@
+// Lists the contents of rar archives. There is no decoder for rar compression, so the
+// contents are only listed, not unpacked. Supports RAR 4 and RAR 5 archives without
+// encrypted headers. Entries continued from a previous volume are skipped.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const RAR4_SIGNATURE: &[u8; 7] = b"Rar!\x1a\x07\x00";
+const RAR5_SIGNATURE: &[u8; 8] = b"Rar!\x1a\x07\x01\x00";
+
+pub struct RarEntry
+{
+    pub name: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+fn invalid(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Returns false at the end of the file
+fn read_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool>
+{
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn get_u32(data: &[u8], pos: usize) -> io::Result<u32>
+{
+    let bytes = data.get(pos..pos + 4).ok_or_else(|| invalid("Truncated header"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn get_name(data: &[u8], pos: usize, len: usize) -> io::Result<PathBuf>
+{
+    let name = data.get(pos..pos + len).ok_or_else(|| invalid("Truncated header"))?;
+    // Archives created on Windows use backslashes
+    Ok(PathBuf::from(String::from_utf8_lossy(name).replace('\\', "/")))
+}
+
+// Variable length integer of RAR 5, 7 bits per byte, high bit set if more bytes follow
+fn get_vint(data: &[u8], pos: &mut usize) -> io::Result<u64>
+{
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| invalid("Truncated header"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Invalid vint"))
+}
+
+// Skips the data following a header. Its size is read from the archive, so it must not reach
+// past the end.
+fn skip_data(reader: &mut (impl Read + Seek), data_size: u64, len: u64) -> io::Result<()>
+{
+    let pos = reader.stream_position()?;
+    if data_size > len.saturating_sub(pos) {
+        return Err(invalid("Data size past the end of the archive"));
+    }
+    reader.seek(SeekFrom::Current(data_size as i64))?;
+    Ok(())
+}
+
+fn list_v4(reader: &mut (impl Read + Seek), len: u64) -> io::Result<Vec<RarEntry>>
+{
+    let mut entries = vec![];
+    loop {
+        let mut head = [0u8; 7];
+        if !read_or_eof(reader, &mut head)? {
+            break;
+        }
+        let head_type = head[2];
+        let flags = u16::from_le_bytes([head[3], head[4]]);
+        let head_size = u16::from_le_bytes([head[5], head[6]]) as usize;
+        if head_size < head.len() {
+            return Err(invalid("Invalid header size"));
+        }
+        let mut header = vec![0u8; head_size - head.len()];
+        reader.read_exact(&mut header)?;
+        let mut data_size = if flags & 0x8000 != 0 { get_u32(&header, 0)? as u64 } else { 0 };
+        match head_type {
+            // Archive header
+            0x73 if flags & 0x80 != 0 => return Err(invalid("Encrypted headers not supported")),
+            // File header
+            0x74 => {
+                if header.len() < 25 {
+                    return Err(invalid("Truncated header"));
+                }
+                let mut size = get_u32(&header, 4)? as u64;
+                let name_size = u16::from_le_bytes([header[19], header[20]]) as usize;
+                let mut pos = 25;
+                if flags & 0x100 != 0 {
+                    data_size |= (get_u32(&header, 25)? as u64) << 32;
+                    size |= (get_u32(&header, 29)? as u64) << 32;
+                    pos = 33;
+                }
+                let mut name_len = name_size;
+                if flags & 0x200 != 0 {
+                    // Unicode names follow the plain name after a zero byte
+                    let name = header.get(pos..pos + name_size).unwrap_or(&[]);
+                    name_len = name.iter().position(|b| *b == 0).unwrap_or(name_size);
+                }
+                let is_continued = flags & 0x01 != 0;
+                if !is_continued {
+                    entries.push(RarEntry {
+                        name: get_name(&header, pos, name_len)?,
+                        is_dir: flags & 0xe0 == 0xe0,
+                        size,
+                    });
+                }
+            }
+            // End of archive
+            0x7b => break,
+            _ => {}
+        }
+        skip_data(reader, data_size, len)?;
+    }
+    Ok(entries)
+}
+
+fn list_v5(reader: &mut (impl Read + Seek), len: u64) -> io::Result<Vec<RarEntry>>
+{
+    let mut entries = vec![];
+    loop {
+        let mut crc = [0u8; 4];
+        if !read_or_eof(reader, &mut crc)? {
+            break;
+        }
+        // The header size is a vint of up to 3 bytes
+        let mut size_bytes = vec![];
+        loop {
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte)?;
+            size_bytes.push(byte[0]);
+            if byte[0] & 0x80 == 0 || size_bytes.len() == 3 {
+                break;
+            }
+        }
+        let header_size = get_vint(&size_bytes, &mut 0)? as usize;
+        let mut header = vec![0u8; header_size];
+        reader.read_exact(&mut header)?;
+
+        let mut pos = 0;
+        let header_type = get_vint(&header, &mut pos)?;
+        let flags = get_vint(&header, &mut pos)?;
+        if flags & 0x01 != 0 {
+            get_vint(&header, &mut pos)?; // Extra area size
+        }
+        let data_size = if flags & 0x02 != 0 { get_vint(&header, &mut pos)? } else { 0 };
+        match header_type {
+            // File header
+            2 => {
+                let file_flags = get_vint(&header, &mut pos)?;
+                let size = get_vint(&header, &mut pos)?;
+                get_vint(&header, &mut pos)?; // Attributes
+                if file_flags & 0x02 != 0 {
+                    pos += 4; // Modification time
+                }
+                if file_flags & 0x04 != 0 {
+                    pos += 4; // Data CRC32
+                }
+                get_vint(&header, &mut pos)?; // Compression info
+                get_vint(&header, &mut pos)?; // Host OS
+                let name_len = get_vint(&header, &mut pos)? as usize;
+                let is_continued = flags & 0x08 != 0;
+                if !is_continued {
+                    entries.push(RarEntry {
+                        name: get_name(&header, pos, name_len)?,
+                        is_dir: file_flags & 0x01 != 0,
+                        // Unknown unpacked size
+                        size: if file_flags & 0x08 != 0 { 0 } else { size },
+                    });
+                }
+            }
+            // Archive encryption header
+            4 => return Err(invalid("Encrypted headers not supported")),
+            // End of archive
+            5 => break,
+            _ => {}
+        }
+        skip_data(reader, data_size, len)?;
+    }
+    Ok(entries)
+}
+
+pub fn list(path: &Path) -> io::Result<Vec<RarEntry>>
+{
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature[..7])?;
+    if signature[..7] == RAR4_SIGNATURE[..] {
+        return list_v4(&mut reader, len);
+    }
+    reader.read_exact(&mut signature[7..])?;
+    if signature == *RAR5_SIGNATURE {
+        list_v5(&mut reader, len)
+    } else {
+        Err(invalid("Not a rar archive"))
+    }
+}
@@ -0,0 +1,150 @@
+// Objects in cloud storage, imported from the listings of rclone, so files already uploaded
+// are found by all_files_elsewhere --against:
+//
+//   rclone lsjson -R --hash remote:bucket > bucket.json
+//   filedb cloud.filedb import-rclone bucket.json
+//
+// The objects only have a hash if the backend provides the one of the db's algorithm, others
+// count as missing. Their times are those of the objects, owners and modes are unknown.
+
+use super::*;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct RcloneObject
+{
+    path: String,
+    // -1 for dirs
+    size: i64,
+    #[serde(default)]
+    mime_type: String,
+    mod_time: String,
+    is_dir: bool,
+    // By rclone's names of the hash types, e.g. sha256
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+}
+
+fn parse_mod_time(mod_time: &str) -> u64
+{
+    chrono::DateTime::parse_from_rfc3339(mod_time).map_or(0, |time| time.timestamp().max(0) as u64)
+}
+
+// The index of the dir at relative_path below the root, adding it and the dirs above it if
+// they are not listed
+fn get_dir_index(
+    file_db: &mut FileDb,
+    path_to_index: &mut HashMap<PathBuf, EntryIndex>,
+    relative_path: &Path,
+) -> EntryIndex
+{
+    if let Some(index) = path_to_index.get(relative_path) {
+        return *index;
+    }
+    let parent = get_dir_index(file_db, path_to_index, relative_path.parent().unwrap());
+    let name = relative_path.file_name().unwrap();
+    file_db.push(remote::new_remote_dir_entry(name, parent));
+    let index = file_db.len() as EntryIndex - 1;
+    path_to_index.insert(relative_path.to_owned(), index);
+    index
+}
+
+// Adds the objects of the listing below prefix, replacing those imported there before.
+// Without prefix, they are added below /rclone/<name of the listing>.
+pub fn import_rclone(
+    file_db_name: &Path,
+    listing_name: &Path,
+    prefix: Option<&Path>,
+    hash_algorithm: Option<HashAlgorithm>,
+) -> bool
+{
+    let prefix = match prefix {
+        Some(prefix) => prefix.to_owned(),
+        None => Path::new("/rclone").join(listing_name.file_stem().unwrap_or_default()),
+    };
+    if !prefix.is_absolute() || prefix.parent().is_none() {
+        eprintln!("Prefix {:?} is not an absolute path below /", prefix);
+        return false;
+    }
+    let hash_algorithm = match hash_algorithm {
+        Some(hash_algorithm) => hash_algorithm,
+        None if file_db_name.is_file() => read_hash_algorithm(file_db_name),
+        None => HashAlgorithm::default(),
+    };
+    let listing = fs::read_to_string(listing_name).map_err(|err| err.to_string());
+    let objects = listing.and_then(|listing| {
+        serde_json::from_str::<Vec<RcloneObject>>(&listing).map_err(|err| err.to_string())
+    });
+    let mut objects = match objects {
+        Ok(objects) => objects,
+        Err(err) => {
+            eprintln!("Cannot read rclone listing {:?}: {}", listing_name, err);
+            return false;
+        }
+    };
+    // Parents before their children
+    objects.sort_by(|a, b| Path::new(&a.path).cmp(Path::new(&b.path)));
+
+    let mut file_db = FileDb::new();
+    let mut parent = EntryIndex::MAX;
+    for component in prefix.components() {
+        file_db.push(remote::new_remote_dir_entry(component.as_os_str(), parent));
+        parent = file_db.len() as EntryIndex - 1;
+    }
+    let mut path_to_index = HashMap::from([(PathBuf::new(), parent)]);
+    let mut num_unhashed = 0;
+    for object in &objects {
+        let relative_path = Path::new(&object.path);
+        if relative_path.file_name().is_none() || relative_path.is_absolute() {
+            eprintln!("Skipping object with invalid path {:?}", object.path);
+            continue;
+        }
+        let modified = parse_mod_time(&object.mod_time);
+        if object.is_dir {
+            let index = get_dir_index(&mut file_db, &mut path_to_index, relative_path);
+            file_db.entry_mut(index).modified = modified;
+            continue;
+        }
+        let parent_path = relative_path.parent().unwrap();
+        let parent = get_dir_index(&mut file_db, &mut path_to_index, parent_path);
+        let hash = object
+            .hashes
+            .get(hash_algorithm.get_name())
+            .and_then(|hash| remote::parse_tool_hash(hash, hash_algorithm));
+        if hash.is_none() {
+            num_unhashed += 1;
+        }
+        let size = object.size.max(0) as u64;
+        file_db.push(FileDbEntry {
+            name: relative_path.file_name().unwrap().to_owned(),
+            is_dir: false,
+            parent,
+            size,
+            allocated: size,
+            modified,
+            accessed: modified,
+            hash: hash.unwrap_or(EMPTY_HASH),
+            verified: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            xattrs: vec![],
+            mime: object.mime_type.clone(),
+            pre_hash: 0,
+            origin: 0,
+        });
+    }
+    if num_unhashed > 0 {
+        println!(
+            "{} objects without {} hash, they count as missing for all_files_elsewhere",
+            num_unhashed.separated_string(),
+            hash_algorithm.get_name()
+        );
+    }
+    propagate_sizes(&mut file_db);
+    propagate_hashes(&mut file_db);
+    let host = String::from("rclone");
+    let tree = agent::AgentTree { host, root_dir: prefix, hash_algorithm, file_db };
+    agent::add_collected_tree(file_db_name, tree, None)
+}
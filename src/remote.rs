@@ -0,0 +1,361 @@
+// Crawling trees on machines that only offer ssh, where filedb cannot be installed (where it
+// can, see agent). find lists the tree on the host, which needs GNU find. Files are hashed on
+// the host if it has the tool for the hash algorithm (b3sum, sha256sum, xxh128sum), otherwise
+// they are streamed here through tar and hashed locally. The tree is then added like one sent
+// by an agent. FILEDB_SSH names a program to run instead of ssh, given the host and the shell
+// command like ssh.
+
+use super::*;
+
+use std::process::{Child, Command, Stdio};
+
+// Fields of each entry, each terminated by NUL: type, size, blocks of 512 bytes, modified,
+// accessed, inode, uid, gid, permission bits, link target, path below the root
+const FIND_FORMAT: &str = r"%y\0%s\0%b\0%T@\0%A@\0%i\0%U\0%G\0%m\0%l\0%P\0";
+const NUM_FIND_FIELDS: usize = 11;
+
+pub(crate) fn ssh_command(host: &str) -> Command
+{
+    let ssh = std::env::var_os("FILEDB_SSH").unwrap_or_else(|| "ssh".into());
+    let mut command = Command::new(ssh);
+    command.arg(host);
+    command
+}
+
+// user@host:/path, the path must be absolute
+fn parse_remote(remote: &str) -> Option<(&str, PathBuf)>
+{
+    let (host, path) = remote.split_once(':')?;
+    let path = normalize_path(Path::new(path));
+    if host.is_empty() || !path.is_absolute() {
+        return None;
+    }
+    Some((host, path))
+}
+
+// Runs command on host, writing input to its stdin
+fn spawn_remote(host: &str, command: &str, input: Vec<u8>) -> io::Result<Child>
+{
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // In a thread, the command may write output before reading all of its input
+    std::thread::spawn(move || stdin.write_all(&input));
+    Ok(child)
+}
+
+fn run_remote(host: &str, command: &str, input: Vec<u8>) -> io::Result<Vec<u8>>
+{
+    let output = spawn_remote(host, command, input)?.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("{} on {}: {}", command, host, output.status)));
+    }
+    Ok(output.stdout)
+}
+
+fn get_hash_tool(algorithm: HashAlgorithm) -> &'static str
+{
+    match algorithm {
+        HashAlgorithm::Blake3 => "b3sum",
+        HashAlgorithm::Sha256 => "sha256sum",
+        HashAlgorithm::Xxh128 => "xxh128sum",
+    }
+}
+
+// The hash as printed by the hash tool
+pub(crate) fn parse_tool_hash(hash_string: &str, algorithm: HashAlgorithm) -> Option<Hash256>
+{
+    match algorithm {
+        HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => parse_hash_string(hash_string),
+        HashAlgorithm::Xxh128 if hash_string.len() == 32 => {
+            let mut hash = EMPTY_HASH;
+            let digest = u128::from_str_radix(hash_string, 16).ok()?;
+            hash[..16].copy_from_slice(&digest.to_le_bytes());
+            Some(hash)
+        }
+        HashAlgorithm::Xxh128 => None,
+    }
+}
+
+fn parse_secs(field: &[u8]) -> u64
+{
+    let secs = field.split(|byte| *byte == b'.').next().unwrap();
+    std::str::from_utf8(secs).ok().and_then(|secs| secs.parse().ok()).unwrap_or(0)
+}
+
+fn parse_number<T: std::str::FromStr + Default>(field: &[u8]) -> T
+{
+    std::str::from_utf8(field).ok().and_then(|number| number.parse().ok()).unwrap_or_default()
+}
+
+fn get_mode_type(kind: u8) -> u32
+{
+    match kind {
+        b'd' => 0o040000,
+        b'l' => MODE_LINK,
+        b'p' => 0o010000,
+        b's' => 0o140000,
+        b'b' => 0o060000,
+        b'c' => 0o020000,
+        _ => 0o100000,
+    }
+}
+
+// Dirs above the root and others nothing is known about
+pub(crate) fn new_remote_dir_entry(name: &OsStr, parent: EntryIndex) -> FileDbEntry
+{
+    FileDbEntry {
+        name: name.to_owned(),
+        is_dir: true,
+        parent,
+        size: 0,
+        allocated: 0,
+        modified: 0,
+        accessed: 0,
+        hash: EMPTY_HASH,
+        verified: 0,
+        inode: 0,
+        uid: 0,
+        gid: 0,
+        mode: 0,
+        xattrs: vec![],
+        mime: String::new(),
+        pre_hash: 0,
+        origin: 0,
+    }
+}
+
+// The tree below root_dir as listed by find, with the paths of its files below root_dir
+fn list_remote_tree(
+    host: &str,
+    root_dir: &Path,
+    options: &CrawlOptions,
+) -> io::Result<(FileDb, Vec<(EntryIndex, PathBuf)>)>
+{
+    let command = format!(
+        "find {} {} {} -printf {}",
+        if options.follow_links { "-L" } else { "" },
+        shell_escape(&get_path_bytes(root_dir)),
+        if options.one_file_system { "-xdev" } else { "" },
+        shell_escape(FIND_FORMAT.as_bytes())
+    );
+    let listing = run_remote(host, &command, vec![])?;
+    let mut fields: Vec<&[u8]> = listing.split(|byte| *byte == 0).collect();
+    // After the terminating NUL of the last field
+    fields.pop();
+    if fields.is_empty() || !fields.len().is_multiple_of(NUM_FIND_FIELDS) || fields[0] != b"d" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected output of find"));
+    }
+
+    let mut file_db = FileDb::new();
+    let mut parent = EntryIndex::MAX;
+    for component in root_dir.parent().into_iter().flat_map(Path::components) {
+        file_db.push(new_remote_dir_entry(component.as_os_str(), parent));
+        parent = file_db.len() as EntryIndex - 1;
+    }
+    let mut path_to_index = HashMap::from([(PathBuf::new(), parent)]);
+    let mut files = vec![];
+    for fields in fields.chunks(NUM_FIND_FIELDS) {
+        let kind = fields[0].first().copied().unwrap_or(b'?');
+        let relative_path = get_path_from_bytes(fields[10].to_vec());
+        let path = root_dir.join(&relative_path);
+        if relative_path.iter().any(|name| is_excluded(name, options)) {
+            if relative_path.file_name().is_some_and(|name| is_excluded(name, options)) {
+                println!("Excluding {}:{:?}", host, path);
+            }
+            continue;
+        }
+        if let Some(progress) = &options.progress {
+            progress.report(&path);
+        }
+        let parent_path = relative_path.parent().unwrap_or(Path::new(""));
+        let parent = match path_to_index.get(parent_path) {
+            Some(parent) => *parent,
+            None => continue,
+        };
+        let is_dir = kind == b'd';
+        let is_file = kind == b'f';
+        let link_target = get_path_from_bytes(fields[9].to_vec());
+        let hash = if kind == b'l' { get_link_hash(&link_target) } else { EMPTY_HASH };
+        file_db.push(FileDbEntry {
+            name: path.file_name().unwrap_or(path.as_os_str()).to_owned(),
+            is_dir,
+            parent,
+            size: if is_file { parse_number(fields[1]) } else { 0 },
+            allocated: if is_file { parse_number::<u64>(fields[2]) * 512 } else { 0 },
+            modified: parse_secs(fields[3]),
+            accessed: parse_secs(fields[4]),
+            hash,
+            verified: 0,
+            inode: parse_number(fields[5]),
+            uid: parse_number(fields[6]),
+            gid: parse_number(fields[7]),
+            mode: get_mode_type(kind)
+                | u32::from_str_radix(&String::from_utf8_lossy(fields[8]), 8).unwrap_or(0),
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
+        });
+        let index = file_db.len() as EntryIndex - 1;
+        if is_dir {
+            path_to_index.insert(relative_path, index);
+        } else if is_file {
+            files.push((index, relative_path));
+        }
+    }
+    Ok((file_db, files))
+}
+
+fn get_path_list(files: &[(EntryIndex, PathBuf)]) -> Vec<u8>
+{
+    let mut list = vec![];
+    for (_, path) in files {
+        list.extend(get_path_bytes(path));
+        list.push(0);
+    }
+    list
+}
+
+// One hash per file, None for those that could not be read
+fn hash_on_host(
+    host: &str,
+    root_dir: &Path,
+    files: &[(EntryIndex, PathBuf)],
+    algorithm: HashAlgorithm,
+) -> io::Result<Vec<Option<Hash256>>>
+{
+    let tool = get_hash_tool(algorithm);
+    let script = format!("for f; do {} < \"$f\" || echo -; done", tool);
+    let command = format!(
+        "cd {} && xargs -0 sh -c {} sh",
+        shell_escape(&get_path_bytes(root_dir)),
+        shell_escape(script.as_bytes())
+    );
+    let output = run_remote(host, &command, get_path_list(files))?;
+    let output = String::from_utf8_lossy(&output);
+    let hashes: Vec<Option<Hash256>> = output
+        .lines()
+        .map(|line| parse_tool_hash(line.split_whitespace().next().unwrap_or(""), algorithm))
+        .collect();
+    if hashes.len() != files.len() {
+        return Err(io::Error::other(format!("{} printed an unexpected number of hashes", tool)));
+    }
+    Ok(hashes)
+}
+
+// Streams the files through tar and hashes them here. Stops at cancellation, the files not
+// received are left without hash.
+fn hash_streamed(
+    host: &str,
+    root_dir: &Path,
+    files: &[(EntryIndex, PathBuf)],
+    options: &CrawlOptions,
+) -> io::Result<Vec<Option<Hash256>>>
+{
+    let command = format!(
+        "cd {} && tar -cf - --null --no-recursion {} -T -",
+        shell_escape(&get_path_bytes(root_dir)),
+        if options.follow_links { "-h" } else { "" }
+    );
+    let mut child = spawn_remote(host, &command, get_path_list(files))?;
+    let mut archive = tar::Archive::new(child.stdout.take().unwrap());
+    let algorithm = options.hash_algorithm.unwrap_or_default();
+    let mut hashes = HashMap::<PathBuf, Hash256>::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = get_path_from_bytes(entry.path_bytes().into_owned());
+        if is_cancelled(options.cancellation.as_ref()) {
+            println!("Interrupted, stopped before {:?}", root_dir.join(&path));
+            break;
+        }
+        // Further links to a file already sent
+        let hash = if entry.header().entry_type().is_hard_link() {
+            let target = entry.link_name_bytes().map(|name| get_path_from_bytes(name.into_owned()));
+            match target.and_then(|target| hashes.get(&target).copied()) {
+                Some(hash) => hash,
+                None => continue,
+            }
+        } else {
+            println!("Hashing {:?}", root_dir.join(&path));
+            let size = entry.size();
+            get_hash_for_reader(&mut entry, size, algorithm)?
+        };
+        hashes.insert(path, hash);
+    }
+    drop(archive);
+    child.wait()?;
+    Ok(files.iter().map(|(_, path)| hashes.get(path).copied()).collect())
+}
+
+// Crawls the tree at remote (user@host:/path) over ssh and adds it below prefix, or at its
+// path on the host. Like collect, it replaces what was added at that path before.
+pub fn add_remote(
+    file_db_name: &Path,
+    remote: &str,
+    prefix: Option<&Path>,
+    options: &CrawlOptions,
+) -> bool
+{
+    let (host, root_dir) = match parse_remote(remote) {
+        Some(remote) => remote,
+        None => {
+            eprintln!("{} is not of the form host:/path", remote);
+            return false;
+        }
+    };
+    if prefix.is_some_and(|prefix| !prefix.is_absolute()) {
+        eprintln!("Prefix {:?} is not absolute", prefix.unwrap());
+        return false;
+    }
+    let hash_algorithm = match options.hash_algorithm {
+        Some(hash_algorithm) => hash_algorithm,
+        None if file_db_name.is_file() => read_hash_algorithm(file_db_name),
+        None => HashAlgorithm::default(),
+    };
+    let options = &CrawlOptions { hash_algorithm: Some(hash_algorithm), ..options.clone() };
+
+    println!("Listing {}:{:?}", host, root_dir);
+    let (mut file_db, files) = match list_remote_tree(host, &root_dir, options) {
+        Ok(tree) => tree,
+        Err(err) => {
+            eprintln!("Error listing {}:{:?}: {}", host, root_dir, err);
+            return false;
+        }
+    };
+    if !options.no_hash && !files.is_empty() {
+        let tool = get_hash_tool(hash_algorithm);
+        let has_tool = run_remote(host, &format!("command -v {}", tool), vec![]).is_ok();
+        let hashes = if has_tool {
+            println!("Hashing {} files with {} on {}", files.len().separated_string(), tool, host);
+            hash_on_host(host, &root_dir, &files, hash_algorithm)
+        } else {
+            println!("No {} on {}, streaming the files to hash them", tool, host);
+            hash_streamed(host, &root_dir, &files, options)
+        };
+        let hashes = match hashes {
+            Ok(hashes) => hashes,
+            Err(err) => {
+                eprintln!("Error hashing files on {}: {}", host, err);
+                return false;
+            }
+        };
+        for ((index, path), hash) in files.iter().zip(hashes) {
+            match hash {
+                Some(hash) => *file_db.entry_mut(*index).hash = hash,
+                None if is_cancelled(options.cancellation.as_ref()) => (),
+                None => record_crawl_error(&root_dir.join(path), &io::Error::other("not hashed")),
+            }
+        }
+    }
+    report_crawl_errors(options.error_report.as_deref());
+    propagate_sizes(&mut file_db);
+    propagate_hashes(&mut file_db);
+    // Like the agent, named without the user
+    let host = host.rsplit('@').next().unwrap().to_string();
+    let tree = agent::AgentTree { host, root_dir, hash_algorithm, file_db };
+    agent::add_collected_tree(file_db_name, tree, prefix)
+}
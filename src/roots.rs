@@ -0,0 +1,266 @@
+// Named roots are the paths added to the db, so update can rescan all of them. They also make
+// a db portable: Entries below a named root are reached through its name, so when the drive
+// holding them is mounted elsewhere, --root-override name=path binds the root to the new mount
+// point. The dirs above the stored path are replaced by those above path on load, and saving
+// stores the new binding.
+
+use super::*;
+
+// Set by --root-override, for all commands
+static ROOT_OVERRIDES: Mutex<Vec<(String, PathBuf)>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct NamedRoot
+{
+    pub name: String,
+    // Where the root was last bound
+    pub path: PathBuf,
+}
+
+pub fn set_root_overrides(overrides: Vec<(String, PathBuf)>)
+{
+    *ROOT_OVERRIDES.lock().unwrap() = overrides;
+}
+
+// Streaming and partitions read the stored paths, so they are bypassed while overriding
+pub fn has_root_overrides() -> bool
+{
+    !ROOT_OVERRIDES.lock().unwrap().is_empty()
+}
+
+// Returns whether any entries were moved, which invalidates a stored children index
+pub fn apply_root_overrides(db: &mut Db) -> bool
+{
+    bind_roots(db, &ROOT_OVERRIDES.lock().unwrap())
+}
+
+// Relocates the current tree and all snapshots to the given paths of named roots
+pub fn bind_roots(db: &mut Db, bindings: &[(String, PathBuf)]) -> bool
+{
+    let mut relocated = false;
+    for (name, path) in bindings {
+        let root = match db.roots.iter_mut().find(|root| root.name == *name) {
+            Some(root) => root,
+            None => {
+                eprintln!("Root {} not in db, not overriding it", name);
+                continue;
+            }
+        };
+        if root.path == *path {
+            continue;
+        }
+        eprintln!("Binding root {} at {:?} to {:?}", name, root.path, path);
+        if !relocate_subtree(&mut db.file_db, &root.path, path, true) {
+            panic!("Cannot bind root {} at {:?} to {:?}", name, root.path, path);
+        }
+        for snapshot in &mut db.snapshots {
+            relocate_subtree(&mut snapshot.file_db, &root.path, path, true);
+        }
+        root.path = path.clone();
+        relocated = true;
+    }
+    relocated
+}
+
+// Dirs above a relocated subtree, with the metadata of the dir at path if it exists
+fn new_dir_entry(name: &OsStr, parent: EntryIndex, path: &Path) -> FileDbEntry
+{
+    let metadata = fs::metadata(path).ok();
+    let (uid, gid, mode) = metadata.as_ref().map_or((0, 0, 0), get_owner_and_mode);
+    let get_time = |time: io::Result<time::SystemTime>| time.map_or(0, |time| get_secs(&time));
+    FileDbEntry {
+        name: name.to_owned(),
+        is_dir: true,
+        parent,
+        size: 0,
+        allocated: 0,
+        modified: metadata.as_ref().map_or(0, |metadata| get_time(metadata.modified())),
+        accessed: metadata.as_ref().map_or(0, |metadata| get_time(metadata.accessed())),
+        hash: EMPTY_HASH,
+        verified: 0,
+        inode: metadata.as_ref().map_or(0, get_inode),
+        uid,
+        gid,
+        mode,
+        xattrs: vec![],
+        mime: String::new(),
+        pre_hash: 0,
+        origin: 0,
+    }
+}
+
+// Moves the subtree at old_path to new_path, adding the dirs above new_path that are missing.
+// With remove_empty_parents, the dirs above old_path that become empty are removed, as for
+// the dirs above a mount point which are only there to reach it. The added dirs and the
+// subtree follow the dir they are moved to, the other entries keep their order, so parents
+// still precede their children.
+// Returns false if old_path is not in file_db, or new_path is in it already.
+pub fn relocate_subtree(
+    file_db: &mut FileDb,
+    old_path: &Path,
+    new_path: &Path,
+    remove_empty_parents: bool,
+) -> bool
+{
+    let children = ChildrenIndex::new(file_db);
+    let old_index = match children.find_path(file_db, old_path) {
+        Some(index) if !is_root_index(index) => index,
+        _ => return false,
+    };
+    let new_name = match new_path.file_name() {
+        Some(name) if new_path.is_absolute() => name,
+        _ => return false,
+    };
+    if new_path.starts_with(old_path) || children.find_path(file_db, new_path).is_some() {
+        return false;
+    }
+    let mut missing_dirs = vec![];
+    let mut parent_path = new_path.parent().unwrap();
+    let existing_parent = loop {
+        match children.find_path(file_db, parent_path) {
+            Some(index) if file_db.get(index).is_dir => break index,
+            Some(_) => return false,
+            None => missing_dirs.push(parent_path),
+        }
+        parent_path = parent_path.parent().unwrap();
+    };
+
+    let mut subtree = children.get_subtree(old_index);
+    subtree.sort_unstable();
+    let in_subtree = subtree.iter().copied().collect::<HashSet<_>>();
+    let old_parent = file_db.get(old_index).parent;
+    let num_entries = file_db.len() as EntryIndex;
+    let mut parent_index = existing_parent;
+    for (i, dir_path) in missing_dirs.iter().rev().enumerate() {
+        let name = dir_path.file_name().unwrap();
+        file_db.push(new_dir_entry(name, parent_index, dir_path));
+        parent_index = num_entries + i as EntryIndex;
+    }
+    file_db.set_name(old_index, new_name);
+    *file_db.entry_mut(old_index).parent = parent_index;
+
+    // Dirs above old_path that are left without children
+    let children = ChildrenIndex::new(file_db);
+    let mut removed = HashSet::new();
+    let mut index = old_parent;
+    while remove_empty_parents
+        && !is_root_index(index)
+        && children.get(index).iter().all(|child| removed.contains(child))
+    {
+        removed.insert(index);
+        index = file_db.get(index).parent;
+    }
+
+    let mut order = vec![];
+    let is_kept = |index: &EntryIndex| !removed.contains(index) && !in_subtree.contains(index);
+    for index in (0..num_entries).filter(is_kept) {
+        order.push(index);
+        if index == existing_parent {
+            order.extend(num_entries..file_db.len() as EntryIndex);
+            order.extend_from_slice(&subtree);
+        }
+    }
+    let mut new_indexes = vec![EntryIndex::MAX; file_db.len()];
+    for (new_index, index) in order.iter().enumerate() {
+        new_indexes[*index as usize] = new_index as EntryIndex;
+    }
+    let old_file_db = std::mem::take(file_db);
+    for index in order {
+        let mut entry = old_file_db.get(index).to_entry();
+        if !is_root_index(index) {
+            entry.parent = new_indexes[entry.parent as usize];
+        }
+        file_db.push(entry);
+    }
+    propagate_sizes(file_db);
+    propagate_hashes(file_db);
+    true
+}
+
+// For a drive remounted at new_mount, moves the entries below old_mount there instead of
+// crawling it again. Snapshots and named roots below old_mount are moved, too.
+pub fn rebase(file_db_name: &Path, old_mount: &Path, new_mount: &Path) -> bool
+{
+    let mut db = load_compressed(file_db_name);
+    if !new_mount.is_dir() {
+        eprintln!("{:?} is not a dir", new_mount);
+        return false;
+    }
+    if !relocate_subtree(&mut db.file_db, old_mount, new_mount, true) {
+        eprintln!("Cannot move {:?} to {:?}, not in db or target exists", old_mount, new_mount);
+        return false;
+    }
+    for snapshot in &mut db.snapshots {
+        relocate_subtree(&mut snapshot.file_db, old_mount, new_mount, true);
+    }
+    for root in &mut db.roots {
+        if let Ok(relative_path) = root.path.strip_prefix(old_mount) {
+            root.path = new_mount.join(relative_path);
+        }
+    }
+    println!("Moved {:?} to {:?}", old_mount, new_mount);
+    save_compressed(file_db_name, &db);
+    true
+}
+
+// Called by add. Roots are named after their last component, made unique with a number,
+// until named otherwise with name_root.
+pub fn register_root(db: &mut Db, path: &Path)
+{
+    if db.roots.iter().any(|root| root.path == path) {
+        return;
+    }
+    let base_name = path.file_name().map_or("root".into(), OsStr::to_string_lossy);
+    let mut name = base_name.to_string();
+    let mut number = 2;
+    while db.roots.iter().any(|root| root.name == name) {
+        name = format!("{}-{}", base_name, number);
+        number += 1;
+    }
+    db.roots.push(NamedRoot { name, path: path.to_owned() });
+}
+
+// The paths update rescans without a path given, roots beneath others are covered by those
+pub fn get_update_roots(db: &Db) -> Vec<PathBuf>
+{
+    let paths = db.roots.iter().map(|root| &root.path);
+    paths
+        .clone()
+        .filter(|path| !paths.clone().any(|other| other != *path && path.starts_with(other)))
+        .cloned()
+        .collect()
+}
+
+// Names the dir at path in the current tree, replacing the root of that name or path
+pub fn name_root(file_db_name: &Path, name: &str, path: &Path)
+{
+    let mut db = load_compressed(file_db_name);
+    if ChildrenIndex::new(&db.file_db).find_path(&db.file_db, path).is_none() {
+        panic!("{:?} not in db", path);
+    }
+    db.roots.retain(|root| root.name != name && root.path != path);
+    db.roots.push(NamedRoot { name: name.to_string(), path: path.to_owned() });
+    save_compressed(file_db_name, &db);
+}
+
+// Entries beneath the root are kept, update just no longer rescans it
+pub fn remove_root(file_db_name: &Path, name: &str) -> bool
+{
+    let mut db = load_compressed(file_db_name);
+    let num_roots = db.roots.len();
+    db.roots.retain(|root| root.name != name);
+    if db.roots.len() == num_roots {
+        eprintln!("Root {} not found", name);
+        return false;
+    }
+    save_compressed(file_db_name, &db);
+    true
+}
+
+pub fn roots_list(file_db_name: &Path)
+{
+    let db = load_compressed(file_db_name);
+    for root in &db.roots {
+        println!("{} {}", root.name, format_path_for_output(&root.path));
+    }
+}
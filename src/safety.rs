@@ -0,0 +1,84 @@
+// Checks before removing or replacing files: Paths that must never be touched, like the
+// originals of a photo collection, whichever copy the keep rules would prefer, and copies
+// that must remain of removed dupes.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{get_full_path, EntryIndex, FileDb};
+
+#[derive(Default, Clone, Debug)]
+pub struct ProtectedPaths
+{
+    // Each pattern as given and split into components
+    patterns: Vec<(String, Vec<glob::Pattern>)>,
+}
+
+impl ProtectedPaths
+{
+    // Patterns are absolute paths, matched component by component with the wildcards of
+    // glob (*, ?, [...]) within components. A path protects everything below it, a
+    // trailing /** may be given to make that explicit, as in /immens/originals/**.
+    pub fn new(patterns: &[String]) -> Result<Self, glob::PatternError>
+    {
+        let mut protected = ProtectedPaths::default();
+        for pattern in patterns {
+            let components = Path::new(pattern)
+                .components()
+                .map(|component| glob::Pattern::new(&component.as_os_str().to_string_lossy()))
+                .collect::<Result<Vec<_>, _>>()?;
+            protected.patterns.push((pattern.clone(), components));
+        }
+        Ok(protected)
+    }
+
+    // The pattern protecting path, if any: path matches it, is below a path matching it, or
+    // may contain paths matching it, so removing it would remove those
+    pub fn get_protecting(&self, path: &Path) -> Option<&str>
+    {
+        let components = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>();
+        self.patterns.iter().find_map(|(pattern, pattern_components)| {
+            for (component, pattern_component) in components.iter().zip(pattern_components) {
+                if pattern_component.as_str() == "**" {
+                    break;
+                }
+                if !pattern_component.matches(component) {
+                    return None;
+                }
+            }
+            Some(pattern.as_str())
+        })
+    }
+}
+
+// Checks right before removing the dupe at index that its kept copy is still there, so that
+// a stale db never causes removing the last copy: The db must list both with the same hash
+// and size, the kept copy must be outside the removed paths and, on disk, still have that
+// size. Returns why not otherwise.
+pub fn check_kept_copy(
+    file_db: &FileDb,
+    index: EntryIndex,
+    kept_index: EntryIndex,
+    removed_paths: &HashSet<PathBuf>,
+) -> Result<(), String>
+{
+    let (entry, kept_entry) = (file_db.get(index), file_db.get(kept_index));
+    if index == kept_index || entry.hash != kept_entry.hash || entry.size != kept_entry.size {
+        return Err("the db lists no copy of it to keep".to_string());
+    }
+    let kept_path = get_full_path(file_db, kept_index);
+    if kept_path.ancestors().any(|path| removed_paths.contains(path)) {
+        return Err(format!("its copy {:?} is removed as well", kept_path));
+    }
+    match fs::symlink_metadata(&kept_path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() != kept_entry.size => {
+            Err(format!("its copy {:?} changed size", kept_path))
+        }
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("its copy {:?} is gone", kept_path)),
+    }
+}
@@ -0,0 +1,213 @@
+// Read-only HTTP API answering queries on a db with JSON:
+//   /stats                   Number of entries, files, dirs and total size
+//   /ls?path=/some/dir       Entries of a dir
+//   /search?name=*.jpg       Entries whose name matches a glob pattern, at most limit (1000)
+//   /hash/<hex>              Files with the given hash
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::{get_full_path, get_hash_string, ChildrenIndex, EntryIndex, FileDb, Hash256};
+
+const DEFAULT_SEARCH_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+struct ApiEntry
+{
+    path: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+    hash: String,
+}
+
+#[derive(Serialize)]
+struct ApiStats
+{
+    entries: usize,
+    files: usize,
+    dirs: usize,
+    size: u64,
+}
+
+pub struct Index<'a>
+{
+    file_db: &'a FileDb,
+    children: ChildrenIndex,
+    hash_to_indices: HashMap<String, Vec<EntryIndex>>,
+}
+
+// Decodes %XX escapes and + in query values
+fn decode_query_value(value: &str) -> String
+{
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn get_query_param(query: &str, name: &str) -> Option<String>
+{
+    query.split('&').find_map(|param| {
+        let (param_name, value) = param.split_once('=').unwrap_or((param, ""));
+        if param_name == name {
+            Some(decode_query_value(value))
+        } else {
+            None
+        }
+    })
+}
+
+fn error(status: u16, message: &str) -> (u16, Value)
+{
+    (status, serde_json::json!({ "error": message }))
+}
+
+impl<'a> Index<'a>
+{
+    pub fn new(file_db: &'a FileDb) -> Self
+    {
+        let mut hash_to_indices = HashMap::<String, Vec<EntryIndex>>::new();
+        for (index, entry) in file_db.iter().enumerate() {
+            if !entry.is_dir {
+                let hash = get_hash_string(&entry.hash);
+                hash_to_indices.entry(hash).or_default().push(index as EntryIndex);
+            }
+        }
+        Index {
+            file_db,
+            children: ChildrenIndex::new(file_db),
+            hash_to_indices,
+        }
+    }
+
+    fn get_entry(&self, index: EntryIndex) -> ApiEntry
+    {
+        let entry = self.file_db.get(index);
+        ApiEntry {
+            path: get_full_path(self.file_db, index).to_string_lossy().into_owned(),
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified: entry.modified,
+            hash: get_hash_string(&entry.hash),
+        }
+    }
+
+    fn stats(&self) -> Value
+    {
+        let num_files = self.file_db.iter().filter(|entry| !entry.is_dir).count();
+        let stats = ApiStats {
+            entries: self.file_db.len(),
+            files: num_files,
+            dirs: self.file_db.len() - num_files,
+            size: self.file_db.iter().filter(|entry| !entry.is_dir).map(|entry| entry.size).sum(),
+        };
+        serde_json::to_value(stats).unwrap()
+    }
+
+    fn ls(&self, query: &str) -> (u16, Value)
+    {
+        let path = match get_query_param(query, "path") {
+            Some(path) => path,
+            None => return error(400, "path missing"),
+        };
+        let index = match self.children.find_path(self.file_db, Path::new(&path)) {
+            Some(index) => index,
+            None => return error(404, "path not found"),
+        };
+        let entries = self
+            .children
+            .get(index)
+            .iter()
+            .map(|child| self.get_entry(*child))
+            .collect::<Vec<_>>();
+        (200, serde_json::to_value(entries).unwrap())
+    }
+
+    fn search(&self, query: &str) -> (u16, Value)
+    {
+        let pattern = match get_query_param(query, "name").map(|name| glob::Pattern::new(&name)) {
+            Some(Ok(pattern)) => pattern,
+            Some(Err(err)) => return error(400, &err.to_string()),
+            None => return error(400, "name missing"),
+        };
+        let limit = match get_query_param(query, "limit").map(|limit| limit.parse()) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return error(400, "invalid limit"),
+            None => DEFAULT_SEARCH_LIMIT,
+        };
+        let entries = (0..self.file_db.len() as EntryIndex)
+            .filter(|index| pattern.matches(&self.file_db.get(*index).name.to_string_lossy()))
+            .take(limit)
+            .map(|index| self.get_entry(index))
+            .collect::<Vec<_>>();
+        (200, serde_json::to_value(entries).unwrap())
+    }
+
+    fn hash(&self, hash: &str) -> (u16, Value)
+    {
+        if hash.len() != 2 * std::mem::size_of::<Hash256>() {
+            return error(400, "invalid hash");
+        }
+        let indices = self.hash_to_indices.get(&hash.to_lowercase());
+        let entries = indices
+            .into_iter()
+            .flatten()
+            .map(|index| self.get_entry(*index))
+            .collect::<Vec<_>>();
+        (200, serde_json::to_value(entries).unwrap())
+    }
+
+    // Returns status and body for a request to url
+    pub fn handle(&self, url: &str) -> (u16, Value)
+    {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        match path {
+            "/stats" => (200, self.stats()),
+            "/ls" => self.ls(query),
+            "/search" => self.search(query),
+            _ => match path.strip_prefix("/hash/") {
+                Some(hash) => self.hash(hash),
+                None => error(404, "unknown endpoint"),
+            },
+        }
+    }
+}
+
+pub fn serve(file_db: &FileDb, listen: &str) -> io::Result<()>
+{
+    let server = tiny_http::Server::http(listen).map_err(|err| io::Error::other(err.to_string()))?;
+    println!("Listening on http://{}", listen);
+    let index = Index::new(file_db);
+    let content_type = tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap();
+    for request in server.incoming_requests() {
+        let (status, body) = if *request.method() == tiny_http::Method::Get {
+            index.handle(request.url())
+        } else {
+            error(405, "only GET is supported")
+        };
+        let response = tiny_http::Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type.clone());
+        if let Err(err) = request.respond(response) {
+            eprintln!("Error responding: {}", err);
+        }
+    }
+    Ok(())
+}
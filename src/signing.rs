@@ -0,0 +1,142 @@
+// Signatures protecting a db from silent modification, e.g. one kept with the backups to check
+// them against, which could otherwise be changed to hide missing or altered files. With a
+// signing key, each save writes <db>.sig, an ed25519 signature made with ssh-keygen (OpenSSH
+// 8.1 or later). The new db is signed before it replaces the old one, with the signature
+// written to <db>.sig.tmp and renamed right after the db. With a trusted public key, loading
+// fails unless the db is signed by it:
+//
+//   ssh-keygen -t ed25519 -f ~/.config/filedb/sign-key
+//   filedb --sign-key ~/.config/filedb/sign-key backup.filedb update
+//   filedb --trusted-key ~/.config/filedb/sign-key.pub backup.filedb all_files_elsewhere ...
+//
+// Keep the private key away from the db, whoever can read it can sign.
+
+use super::*;
+
+use std::process::{Command, Stdio};
+
+// Signatures for other purposes made with the same key are not accepted
+const SIGNATURE_NAMESPACE: &str = "filedb";
+
+#[derive(Clone, Default, Debug)]
+pub struct SigningKeys
+{
+    // Private key to sign dbs with when saving
+    pub sign_key: Option<PathBuf>,
+    // Public key dbs must be signed with to load
+    pub trusted_key: Option<PathBuf>,
+}
+
+static SIGNING_KEYS: Mutex<SigningKeys> =
+    Mutex::new(SigningKeys { sign_key: None, trusted_key: None });
+
+pub fn set_signing_keys(keys: SigningKeys)
+{
+    *SIGNING_KEYS.lock().unwrap() = keys;
+}
+
+// Partitions are not signed, queries read the db instead
+pub(crate) fn is_checking_signatures() -> bool
+{
+    SIGNING_KEYS.lock().unwrap().trusted_key.is_some()
+}
+
+fn get_signature(file_name: &Path, sign_key: &Path) -> io::Result<Vec<u8>>
+{
+    let output = Command::new("ssh-keygen")
+        .args(["-q", "-Y", "sign", "-n", SIGNATURE_NAMESPACE, "-f"])
+        .arg(sign_key)
+        .stdin(File::open(file_name)?)
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(message.trim().to_string()));
+    }
+    Ok(output.stdout)
+}
+
+// Whether saves are signed, with a signing key
+pub(crate) fn is_signing() -> bool
+{
+    SIGNING_KEYS.lock().unwrap().sign_key.is_some()
+}
+
+// Signs tmp_name, written to replace file_name, with the signing key if there is one. Returns
+// <file_name>.sig.tmp holding the signature, to be renamed once tmp_name is. Fails the save if
+// signing fails, so the old file keeps matching its signature.
+pub(crate) fn sign_before_rename(tmp_name: &Path, file_name: &Path) -> Option<PathBuf>
+{
+    let sign_key = SIGNING_KEYS.lock().unwrap().sign_key.clone()?;
+    let signature = match get_signature(tmp_name, &sign_key) {
+        Ok(signature) => signature,
+        Err(err) => panic!("Cannot sign {:?} with {:?}, not saving: {}", file_name, sign_key, err),
+    };
+    let signature_name = get_db_side_file_name(&get_db_side_file_name(file_name, "sig"), "tmp");
+    let mut file = File::create(&signature_name).unwrap();
+    file.write_all(&signature).unwrap();
+    file.sync_all().unwrap();
+    Some(signature_name)
+}
+
+fn verify_signature(
+    file_db_name: &Path,
+    signature_name: &Path,
+    allowed_signers_name: &Path,
+) -> io::Result<()>
+{
+    if !signature_name.is_file() {
+        return Err(io::Error::other(format!("{:?} is missing", signature_name)));
+    }
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "verify", "-I", SIGNATURE_NAMESPACE, "-n", SIGNATURE_NAMESPACE, "-f"])
+        .arg(allowed_signers_name)
+        .arg("-s")
+        .arg(signature_name)
+        .stdin(File::open(file_db_name)?)
+        .output()?;
+    if !output.status.success() {
+        // Signatures by other keys are reported on stdout
+        let message = [output.stderr, output.stdout].concat();
+        let message = String::from_utf8_lossy(&message);
+        return Err(io::Error::other(message.lines().next().unwrap_or_default().to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_signature_with(file_db_name: &Path, trusted_key: &Path) -> io::Result<()>
+{
+    let public_key = fs::read_to_string(trusted_key)?;
+    let public_key = public_key.lines().next().unwrap_or_default().trim();
+    if !public_key.starts_with("ssh-ed25519 ") {
+        return Err(io::Error::other(format!("{:?} is no ed25519 public key", trusted_key)));
+    }
+    let tmp_dir = TempDir::new("filedb-signers")?;
+    let allowed_signers_name = tmp_dir.path().join("allowed_signers");
+    fs::write(&allowed_signers_name, format!("{} {}\n", SIGNATURE_NAMESPACE, public_key))?;
+    let signature_name = get_db_side_file_name(file_db_name, "sig");
+    let result = verify_signature(file_db_name, &signature_name, &allowed_signers_name);
+    // Saving stopped between renaming the db and its signature
+    let tmp_signature_name = get_db_side_file_name(&signature_name, "tmp");
+    if result.is_err()
+        && tmp_signature_name.is_file()
+        && verify_signature(file_db_name, &tmp_signature_name, &allowed_signers_name).is_ok()
+    {
+        return Ok(());
+    }
+    result
+}
+
+// Before loading, with a trusted key
+pub(crate) fn check_signature(file_db_name: &Path)
+{
+    let trusted_key = SIGNING_KEYS.lock().unwrap().trusted_key.clone();
+    if let Some(trusted_key) = trusted_key {
+        if let Err(err) = check_signature_with(file_db_name, &trusted_key) {
+            panic!(
+                "{:?} is not signed with {:?}, it may have been tampered with: {}",
+                file_db_name, trusted_key, err
+            );
+        }
+    }
+}
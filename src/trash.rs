@@ -0,0 +1,149 @@
+// Moves files to the freedesktop.org trash, so they can be restored with a file manager,
+// or to a quarantine dir. See https://specifications.freedesktop.org/trash-spec/latest/
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf, Prefix};
+
+use chrono::Local;
+
+#[cfg(unix)]
+fn get_device(path: &Path) -> io::Result<u64>
+{
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::symlink_metadata(path)?.dev())
+}
+
+fn get_home_trash_dir() -> io::Result<PathBuf>
+{
+    let data_home = match env::var_os("XDG_DATA_HOME") {
+        Some(data_home) if !data_home.is_empty() => PathBuf::from(data_home),
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local/share"),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "HOME not set")),
+        },
+    };
+    Ok(data_home.join("Trash"))
+}
+
+// The trash on the file system of path: The home trash if it is on the same file system,
+// otherwise $topdir/.Trash-$uid on the mount point of path
+#[cfg(unix)]
+fn get_trash_dir(path: &Path) -> io::Result<PathBuf>
+{
+    let home_trash_dir = get_home_trash_dir()?;
+    fs::create_dir_all(&home_trash_dir)?;
+    let device = get_device(path)?;
+    if get_device(&home_trash_dir)? == device {
+        return Ok(home_trash_dir);
+    }
+    let mut top_dir = path;
+    while let Some(parent) = top_dir.parent() {
+        if get_device(parent)? != device {
+            break;
+        }
+        top_dir = parent;
+    }
+    let uid = unsafe { libc::getuid() };
+    Ok(top_dir.join(format!(".Trash-{}", uid)))
+}
+
+#[cfg(not(unix))]
+fn get_trash_dir(_path: &Path) -> io::Result<PathBuf>
+{
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Trash not supported on this platform"))
+}
+
+// Percent-encodes all but unreserved characters and slashes, as required for Path=
+fn encode_path(path: &Path) -> String
+{
+    let mut encoded = String::new();
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// path must be absolute. Returns the new location of path.
+pub fn move_to_trash_dir(path: &Path, trash_dir: &Path) -> io::Result<PathBuf>
+{
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    fs::create_dir_all(&files_dir)?;
+    fs::create_dir_all(&info_dir)?;
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    // Creating the info file reserves the name in the trash
+    for num in 1.. {
+        let trash_name = if num == 1 { name.clone() } else { format!("{}.{}", name, num) };
+        let info_path = info_dir.join(format!("{}.trashinfo", trash_name));
+        let info_file = fs::OpenOptions::new().write(true).create_new(true).open(&info_path);
+        let mut info_file = match info_file {
+            Ok(info_file) => info_file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        };
+        write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            encode_path(path),
+            Local::now().format("%Y-%m-%dT%H:%M:%S")
+        )?;
+        let trash_path = files_dir.join(&trash_name);
+        if let Err(err) = fs::rename(path, &trash_path) {
+            let _ = fs::remove_file(&info_path);
+            return Err(err);
+        }
+        return Ok(trash_path);
+    }
+    unreachable!()
+}
+
+pub fn move_to_trash(path: &Path) -> io::Result<PathBuf>
+{
+    move_to_trash_dir(path, &get_trash_dir(path)?)
+}
+
+// path without its root, the drive or share of Windows paths become the first components
+fn get_path_below_root(path: &Path) -> PathBuf
+{
+    let mut relative_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    relative_path.push((letter as char).to_string())
+                }
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    relative_path.push(server);
+                    relative_path.push(share);
+                }
+                _ => {}
+            },
+            Component::RootDir => {}
+            component => relative_path.push(component),
+        }
+    }
+    relative_path
+}
+
+// Keeps the full path of path beneath quarantine_dir, so it is clear where to restore it to
+pub fn move_to_quarantine(path: &Path, quarantine_dir: &Path) -> io::Result<PathBuf>
+{
+    let dest_path = quarantine_dir.join(get_path_below_root(path));
+    if dest_path.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Already in quarantine"));
+    }
+    fs::create_dir_all(dest_path.parent().unwrap())?;
+    if fs::rename(path, &dest_path).is_err() {
+        // Other file system, move_items copies and removes
+        let options = fs_extra::dir::CopyOptions::new();
+        fs_extra::move_items(&[path], dest_path.parent().unwrap(), &options)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+    }
+    Ok(dest_path)
+}
@@ -0,0 +1,397 @@
+// Terminal UIs, built with ratatui. The state is kept apart from the drawing, so it can be
+// tested without a terminal.
+
+use std::io;
+use std::path::PathBuf;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+use separator::Separatable;
+
+use crate::{get_full_path, get_time_string, ChildrenIndex, EntryIndex, FileDb};
+
+pub struct ReviewFile
+{
+    pub index: EntryIndex,
+    pub path: PathBuf,
+    pub modified: String,
+    pub remove: bool,
+}
+
+// Copies of the same data
+pub struct ReviewGroup
+{
+    pub size: u64,
+    pub files: Vec<ReviewFile>,
+    pub selected: usize,
+}
+
+pub struct DupeReview
+{
+    pub groups: Vec<ReviewGroup>,
+    pub group: usize,
+}
+
+impl DupeReview
+{
+    fn current(&mut self) -> &mut ReviewGroup
+    {
+        &mut self.groups[self.group]
+    }
+
+    pub fn select_next(&mut self)
+    {
+        let group = self.current();
+        if group.selected + 1 < group.files.len() {
+            group.selected += 1;
+        }
+    }
+
+    pub fn select_prev(&mut self)
+    {
+        let group = self.current();
+        group.selected = group.selected.saturating_sub(1);
+    }
+
+    pub fn next_group(&mut self)
+    {
+        if self.group + 1 < self.groups.len() {
+            self.group += 1;
+        }
+    }
+
+    pub fn prev_group(&mut self)
+    {
+        self.group = self.group.saturating_sub(1);
+    }
+
+    // Refuses to mark the last kept copy of a group
+    pub fn toggle_remove(&mut self)
+    {
+        let group = self.current();
+        let num_kept = group.files.iter().filter(|file| !file.remove).count();
+        let file = &mut group.files[group.selected];
+        if file.remove || num_kept > 1 {
+            file.remove = !file.remove;
+        }
+    }
+
+    pub fn keep_only_selected(&mut self)
+    {
+        let group = self.current();
+        let selected = group.selected;
+        for (i, file) in group.files.iter_mut().enumerate() {
+            file.remove = i != selected;
+        }
+    }
+
+    pub fn keep_all(&mut self)
+    {
+        for file in &mut self.current().files {
+            file.remove = false;
+        }
+    }
+
+    // (index, kept_index) of all copies marked for removal, kept_index is the first kept
+    // copy of the group
+    pub fn removals(&self) -> Vec<(EntryIndex, EntryIndex)>
+    {
+        let mut removals = vec![];
+        for group in &self.groups {
+            let kept = match group.files.iter().find(|file| !file.remove) {
+                Some(kept) => kept,
+                None => continue,
+            };
+            for file in group.files.iter().filter(|file| file.remove) {
+                removals.push((file.index, kept.index));
+            }
+        }
+        removals
+    }
+
+    pub fn removed_bytes(&self) -> u64
+    {
+        let num_removed = |group: &ReviewGroup| group.files.iter().filter(|f| f.remove).count();
+        self.groups.iter().map(|group| group.size * num_removed(group) as u64).sum()
+    }
+}
+
+const DUPE_REVIEW_HELP: &str =
+    "↑/↓ select  ←/→ group  space remove/keep  enter keep selected  u keep all  a apply  q quit";
+
+// Returns the removals to apply, or None if cancelled
+pub fn review_dupes(review: &mut DupeReview) -> io::Result<Option<Vec<(EntryIndex, EntryIndex)>>>
+{
+    let mut terminal = ratatui::init();
+    let result = review_dupes_loop(&mut terminal, review);
+    ratatui::restore();
+    result
+}
+
+fn review_dupes_loop(
+    terminal: &mut DefaultTerminal,
+    review: &mut DupeReview,
+) -> io::Result<Option<Vec<(EntryIndex, EntryIndex)>>>
+{
+    let mut table_state = TableState::default();
+    loop {
+        table_state.select(Some(review.groups[review.group].selected));
+        terminal.draw(|frame| draw_dupe_review(frame, review, &mut table_state))?;
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => review.select_prev(),
+            KeyCode::Down | KeyCode::Char('j') => review.select_next(),
+            KeyCode::Left | KeyCode::PageUp | KeyCode::Char('p') => review.prev_group(),
+            KeyCode::Right | KeyCode::PageDown | KeyCode::Char('n') => review.next_group(),
+            KeyCode::Char(' ') | KeyCode::Char('d') => review.toggle_remove(),
+            KeyCode::Enter => {
+                review.keep_only_selected();
+                review.next_group();
+            }
+            KeyCode::Char('u') => review.keep_all(),
+            KeyCode::Char('a') => return Ok(Some(review.removals())),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+fn draw_dupe_review(frame: &mut Frame, review: &DupeReview, table_state: &mut TableState)
+{
+    let [header_area, table_area, help_area] =
+        Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)])
+            .areas(frame.area());
+    let group = &review.groups[review.group];
+    let header = format!(
+        "Group {}/{}: {} copies of {} bytes | Marked for removal: {} bytes",
+        review.group + 1,
+        review.groups.len(),
+        group.files.len(),
+        group.size.separated_string(),
+        review.removed_bytes().separated_string()
+    );
+    frame.render_widget(Line::from(header), header_area);
+
+    let rows = group.files.iter().map(|file| {
+        let action = if file.remove { "remove" } else { "keep" };
+        Row::new(vec![
+            action.to_string(),
+            file.modified.clone(),
+            file.path.to_string_lossy().into_owned(),
+        ])
+    });
+    let widths = [Constraint::Length(6), Constraint::Length(19), Constraint::Min(0)];
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let table = Table::new(rows, widths)
+        .header(Row::new(vec!["Action", "Modified", "Path"]).style(bold))
+        .block(Block::default().borders(Borders::TOP))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(table, table_area, table_state);
+    frame.render_widget(Line::from(DUPE_REVIEW_HELP), help_area);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BrowseOrder
+{
+    Size,
+    Name,
+    Modified,
+}
+
+// Navigates the tree of a db like a file manager. Archives with indexed contents can be
+// entered like dirs.
+pub struct Browser<'a>
+{
+    file_db: &'a FileDb,
+    children: ChildrenIndex,
+    // Dirs entered so far, the current dir is last
+    pub path: Vec<EntryIndex>,
+    pub selected: usize,
+    pub order: BrowseOrder,
+}
+
+impl<'a> Browser<'a>
+{
+    pub fn new(file_db: &'a FileDb) -> Self
+    {
+        let mut browser = Browser {
+            file_db,
+            children: ChildrenIndex::new(file_db),
+            path: vec![0],
+            selected: 0,
+            order: BrowseOrder::Size,
+        };
+        browser.sort();
+        // Start at the first dir with several entries, usually the path added to the db
+        while browser.entries().len() == 1 && browser.enter() {}
+        browser
+    }
+
+    fn sort(&mut self)
+    {
+        let file_db = self.file_db;
+        let order = self.order;
+        for index in 0..file_db.len() {
+            self.children.get_mut(index as EntryIndex).sort_by(|a, b| {
+                let (a, b) = (file_db.get(*a), file_db.get(*b));
+                match order {
+                    BrowseOrder::Size => b.size.cmp(&a.size),
+                    BrowseOrder::Name => a.name.cmp(b.name),
+                    BrowseOrder::Modified => b.modified.cmp(&a.modified),
+                }
+            });
+        }
+    }
+
+    pub fn dir(&self) -> EntryIndex
+    {
+        *self.path.last().unwrap()
+    }
+
+    pub fn entries(&self) -> &[EntryIndex]
+    {
+        self.children.get(self.dir())
+    }
+
+    pub fn selected_entry(&self) -> Option<EntryIndex>
+    {
+        self.entries().get(self.selected).copied()
+    }
+
+    pub fn set_order(&mut self, order: BrowseOrder)
+    {
+        let selected_entry = self.selected_entry();
+        self.order = order;
+        self.sort();
+        let position = self.entries().iter().position(|index| Some(*index) == selected_entry);
+        self.selected = position.unwrap_or(0);
+    }
+
+    pub fn select_next(&mut self)
+    {
+        if self.selected + 1 < self.entries().len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_prev(&mut self)
+    {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    // Returns false if the selected entry has no entries beneath it
+    pub fn enter(&mut self) -> bool
+    {
+        match self.selected_entry() {
+            Some(index) if !self.children.get(index).is_empty() => {
+                self.path.push(index);
+                self.selected = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Selects the dir that was left
+    pub fn leave(&mut self)
+    {
+        if self.path.len() > 1 {
+            let left_dir = self.path.pop().unwrap();
+            self.selected = self.entries().iter().position(|index| *index == left_dir).unwrap();
+        }
+    }
+}
+
+const BROWSE_HELP: &str =
+    "↑/↓ select  enter/→ open  ←/backspace up  s sort by size  n name  m mtime  q quit";
+
+pub fn browse(browser: &mut Browser) -> io::Result<()>
+{
+    let mut terminal = ratatui::init();
+    let result = browse_loop(&mut terminal, browser);
+    ratatui::restore();
+    result
+}
+
+fn browse_loop(terminal: &mut DefaultTerminal, browser: &mut Browser) -> io::Result<()>
+{
+    let mut table_state = TableState::default();
+    loop {
+        table_state.select(Some(browser.selected));
+        terminal.draw(|frame| draw_browser(frame, browser, &mut table_state))?;
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => browser.select_prev(),
+            KeyCode::Down | KeyCode::Char('j') => browser.select_next(),
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                browser.enter();
+            }
+            KeyCode::Left | KeyCode::Backspace | KeyCode::Char('h') => browser.leave(),
+            KeyCode::Char('s') => browser.set_order(BrowseOrder::Size),
+            KeyCode::Char('n') => browser.set_order(BrowseOrder::Name),
+            KeyCode::Char('m') => browser.set_order(BrowseOrder::Modified),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+fn draw_browser(frame: &mut Frame, browser: &Browser, table_state: &mut TableState)
+{
+    let [header_area, table_area, help_area] =
+        Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)])
+            .areas(frame.area());
+    let file_db = browser.file_db;
+    let dir_size = file_db.get(browser.dir()).size;
+    let header = format!(
+        "{} | {} bytes in {} entries",
+        get_full_path(file_db, browser.dir()).to_string_lossy(),
+        dir_size.separated_string(),
+        browser.entries().len().separated_string()
+    );
+    frame.render_widget(Line::from(header), header_area);
+
+    const BAR_WIDTH: u64 = 10;
+    let rows = browser.entries().iter().map(|index| {
+        let entry = file_db.get(*index);
+        let bar_len = (entry.size * BAR_WIDTH).checked_div(dir_size).unwrap_or(0);
+        let bar = format!("[{:<width$}]", "#".repeat(bar_len as usize), width = BAR_WIDTH as usize);
+        let mut name = entry.name.to_string_lossy().into_owned();
+        if entry.is_dir {
+            name.push('/');
+        } else if !browser.children.get(*index).is_empty() {
+            // Archive with indexed contents
+            name.push_str(" [+]");
+        }
+        Row::new(vec![entry.size.separated_string(), bar, get_time_string(entry.modified), name])
+    });
+    let widths = [
+        Constraint::Length(19),
+        Constraint::Length(BAR_WIDTH as u16 + 2),
+        Constraint::Length(19),
+        Constraint::Min(0),
+    ];
+    let order = match browser.order {
+        BrowseOrder::Size => "Size",
+        BrowseOrder::Name => "Name",
+        BrowseOrder::Modified => "Modified",
+    };
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let table = Table::new(rows, widths)
+        .header(Row::new(vec!["Size", "", "Modified", "Name"]).style(bold))
+        .block(Block::default().borders(Borders::TOP).title(format!("Sorted by {}", order)))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(table, table_area, table_state);
+    frame.render_widget(Line::from(BROWSE_HELP), help_area);
+}
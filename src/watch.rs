@@ -0,0 +1,255 @@
+// Keeps a db current by applying file system events as they arrive, instead of rescanning
+// the whole tree with update. Changes made while not watching are not picked up, so run
+// update before starting to watch.
+
+use super::*;
+
+use std::collections::BTreeSet;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+const WATCH_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+// How often the wait for events checks for interrupts
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Events arriving within this delay of the first one are applied together
+const WATCH_BATCH_DELAY: Duration = Duration::from_secs(1);
+
+fn add_path(
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+    path: &Path,
+    metadata: &fs::Metadata,
+    hash_algorithm: HashAlgorithm,
+)
+{
+    let parent_index = match path_to_index.get(path.parent().unwrap().as_os_str()) {
+        Some(parent_index) if file_db.get(*parent_index).is_dir => *parent_index,
+        _ => {
+            eprintln!("Parent not in db, ignoring {:?}", path);
+            return;
+        }
+    };
+    if metadata.is_dir() {
+        let old_len = file_db.len();
+        add_dir_recursive(
+            path,
+            file_db,
+            path_to_index,
+            &DirToFilesMap::new(),
+            Path::new(""),
+            hash_algorithm,
+        );
+        // add_dir_recursive only records dirs
+        for index in old_len..file_db.len() {
+            if !file_db.get(index as EntryIndex).is_dir {
+                let file_path = get_full_path(file_db, index as EntryIndex);
+                path_to_index.insert(file_path.into_os_string(), index as EntryIndex);
+            }
+        }
+    } else {
+        println!("Adding {:?}", path);
+        let (uid, gid, mode) = get_owner_and_mode(metadata);
+        let file_db_entry = FileDbEntry {
+            name: path.file_name().unwrap().to_os_string(),
+            is_dir: false,
+            parent: parent_index,
+            size: metadata.len(),
+            allocated: get_allocated(metadata),
+            modified: get_secs(&metadata.modified().unwrap()),
+            accessed: get_secs(&metadata.accessed().unwrap()),
+            hash: get_hash_for_path(path, false, hash_algorithm),
+            verified: 0,
+            inode: get_inode(metadata),
+            uid,
+            gid,
+            mode,
+            xattrs: vec![],
+            mime: String::new(),
+            pre_hash: 0,
+            origin: 0,
+        };
+        let index = add_file_db_entry(file_db, file_db_entry);
+        path_to_index.insert(path.as_os_str().to_owned(), index);
+    }
+}
+
+// Returns the paths that need to be added again since their type changed
+fn update_path(
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+    removed: &mut HashSet<EntryIndex>,
+    path: &Path,
+    hash_algorithm: HashAlgorithm,
+) -> Option<PathBuf>
+{
+    let index = path_to_index.get(path.as_os_str()).copied();
+    match (fs::symlink_metadata(path), index) {
+        (Err(_), Some(index)) => {
+            println!("Removing {:?}", path);
+            removed.insert(index);
+        }
+        (Err(_), None) => {}
+        (Ok(metadata), Some(index)) => {
+            let mut entry = file_db.entry_mut(index);
+            if *entry.is_dir != metadata.is_dir() {
+                removed.insert(index);
+                return Some(path.to_path_buf());
+            }
+            let modified = get_secs(&metadata.modified().unwrap());
+            if !*entry.is_dir && (*entry.size != metadata.len() || entry.modified != modified) {
+                println!("Updating {:?}", path);
+                *entry.size = metadata.len();
+                *entry.allocated = get_allocated(&metadata);
+                entry.modified = modified;
+                entry.accessed = get_secs(&metadata.accessed().unwrap());
+                *entry.hash = get_hash_for_path(path, false, hash_algorithm);
+                entry.inode = get_inode(&metadata);
+            }
+            set_owner_and_mode(&mut entry, &metadata);
+        }
+        (Ok(metadata), None) => {
+            add_path(file_db, path_to_index, path, &metadata, hash_algorithm)
+        }
+    }
+    None
+}
+
+// path_to_index must contain files, too (see build_all_paths_to_index_map)
+pub(crate) fn apply_events(
+    file_db: &mut FileDb,
+    path_to_index: &mut PathToIndexMap,
+    root_dir: &Path,
+    events: Vec<Event>,
+    hash_algorithm: HashAlgorithm,
+)
+{
+    let mut paths = BTreeSet::new();
+    for event in events {
+        match event.kind {
+            EventKind::Access(_) => {}
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                // Relocate instead of removing and re-adding, so nothing is hashed again. The
+                // subtree is moved after its new parent, which may have been added later.
+                let (from, to) = (&event.paths[0], &event.paths[1]);
+                let is_known = |path: &Path| path_to_index.contains_key(path.as_os_str());
+                if is_known(from)
+                    && to.parent().is_some_and(is_known)
+                    && !is_known(to)
+                    && to.starts_with(root_dir)
+                    && roots::relocate_subtree(file_db, from, to, false)
+                {
+                    println!("Moving {:?} to {:?}", from, to);
+                    *path_to_index = build_all_paths_to_index_map(file_db);
+                } else {
+                    paths.extend(event.paths);
+                }
+            }
+            _ => paths.extend(event.paths),
+        }
+    }
+    let mut paths = paths
+        .into_iter()
+        .filter(|path| path.starts_with(root_dir) && path != root_dir)
+        .collect::<Vec<_>>();
+    // Parents before children
+    paths.sort_by_key(|path| path.components().count());
+    let mut removed = HashSet::new();
+    let mut readd = vec![];
+    for path in paths {
+        if let Some(path) = update_path(file_db, path_to_index, &mut removed, &path, hash_algorithm)
+        {
+            readd.push(path);
+        }
+    }
+    if !removed.is_empty() {
+        let num_removed = remove_subtrees(file_db, &removed);
+        println!("Removed {} entries", num_removed);
+        *path_to_index = build_all_paths_to_index_map(file_db);
+    }
+    for path in readd {
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            add_path(file_db, path_to_index, &path, &metadata, hash_algorithm);
+        }
+    }
+}
+
+// Waits up to timeout for an event, then collects those arriving until WATCH_BATCH_DELAY has
+// passed, so a tree that never quiets down is still applied. None once the watcher is gone.
+pub(crate) fn receive_batch<T>(rx: &mpsc::Receiver<T>, timeout: Duration) -> Option<Vec<T>>
+{
+    let mut events = vec![];
+    match rx.recv_timeout(timeout) {
+        Ok(event) => events.push(event),
+        Err(mpsc::RecvTimeoutError::Timeout) => return Some(events),
+        Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+    }
+    let deadline = Instant::now() + WATCH_BATCH_DELAY;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match rx.recv_timeout(remaining) {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
+    }
+    Some(events)
+}
+
+fn save_watched(file_db_name: &Path, db: &mut Db)
+{
+    propagate_sizes(&mut db.file_db);
+    propagate_hashes(&mut db.file_db);
+    save_compressed(file_db_name, db);
+}
+
+pub fn watch(file_db_name: &Path, root_dir: &Path)
+{
+    let mut db = load_compressed(file_db_name);
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).unwrap();
+    // Watch before crawling, so nothing gets lost in between
+    watcher.watch(root_dir, RecursiveMode::Recursive).unwrap();
+
+    let mut path_to_index = build_all_paths_to_index_map(&db.file_db);
+    if !path_to_index.contains_key(root_dir.as_os_str()) {
+        crawl_add(&mut db.file_db, root_dir, db.hash_algorithm);
+        path_to_index = build_all_paths_to_index_map(&db.file_db);
+        save_watched(file_db_name, &mut db);
+    }
+    println!("Watching {:?}", root_dir);
+
+    // Until interrupted, then the events applied since the last save are saved
+    install_interrupt_handler();
+    let mut last_save = Instant::now();
+    let mut dirty = false;
+    while !is_interrupted() {
+        let events = match receive_batch(&rx, WATCH_POLL_INTERVAL) {
+            Some(events) => events,
+            None => break,
+        };
+        let events = events
+            .into_iter()
+            .filter_map(|event| match event {
+                Ok(event) => Some(event),
+                Err(err) => {
+                    eprintln!("Watch error: {}", err);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if !events.is_empty() {
+            let file_db = &mut db.file_db;
+            apply_events(file_db, &mut path_to_index, root_dir, events, db.hash_algorithm);
+            dirty = true;
+        }
+        if dirty && last_save.elapsed() >= WATCH_SAVE_INTERVAL {
+            save_watched(file_db_name, &mut db);
+            dirty = false;
+            last_save = Instant::now();
+        }
+    }
+    if dirty {
+        save_watched(file_db_name, &mut db);
+    }
+}